@@ -1,7 +1,9 @@
 //! Benchmark tests for the Kitty graphics protocol library
 
 use criterion::{Criterion, Throughput, black_box, criterion_group, criterion_main};
-use kitty_graphics_protocol::{Action, Command, DeleteTarget, ImageFormat, Response};
+use kitty_graphics_protocol::{
+    Action, Command, DeleteTarget, ImageFormat, ImageId, PlacementId, Response,
+};
 use rand::Rng;
 
 // =============================================================================
@@ -55,7 +57,7 @@ fn bench_command_builder(c: &mut Criterion) {
             Command::builder()
                 .action(Action::Transmit)
                 .format(ImageFormat::Png)
-                .image_id(1)
+                .image_id(ImageId::new(1).unwrap())
                 .build()
         })
     });
@@ -67,8 +69,8 @@ fn bench_command_builder(c: &mut Criterion) {
                 .action(Action::TransmitAndDisplay)
                 .format(ImageFormat::Rgba)
                 .dimensions(1920, 1080)
-                .image_id(42)
-                .placement_id(1)
+                .image_id(ImageId::new(42).unwrap())
+                .placement_id(PlacementId::new(1).unwrap())
                 .z_index(-1)
                 .display_area(80, 24)
                 .source_rect(0, 0, 800, 600)
@@ -82,7 +84,7 @@ fn bench_command_builder(c: &mut Criterion) {
         b.iter(|| {
             Command::builder()
                 .action(Action::Frame)
-                .image_id(1)
+                .image_id(ImageId::new(1).unwrap())
                 .frame_number(2)
                 .frame_gap(16)
                 .build()
@@ -95,7 +97,7 @@ fn bench_command_builder(c: &mut Criterion) {
             Command::builder()
                 .action(Action::Delete)
                 .delete_target(DeleteTarget::ById { free_data: true })
-                .image_id(1)
+                .image_id(ImageId::new(1).unwrap())
                 .build()
         })
     });
@@ -428,8 +430,8 @@ fn bench_control_data(c: &mut Criterion) {
         .action(Action::TransmitAndDisplay)
         .format(ImageFormat::Rgba)
         .dimensions(1920, 1080)
-        .image_id(42)
-        .placement_id(7)
+        .image_id(ImageId::new(42).unwrap())
+        .placement_id(PlacementId::new(7).unwrap())
         .z_index(-100)
         .display_area(120, 40)
         .source_rect(100, 50, 800, 600)
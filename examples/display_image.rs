@@ -5,13 +5,18 @@
 //! 用法:
 //!   cargo run --example display_image <图片路径>
 //!   cargo run --example display_image -- --help
+//!
+//! 启用 `image` feature 后可直接显示 JPEG/GIF/BMP/WebP:
+//!   cargo run --features image --example display_image <图片路径>
 
 use std::env;
 use std::process;
 use std::thread;
 use std::time::Duration;
 
-use kitty_graphics_protocol::{check_protocol_support, clear_all_images, display_png_data};
+use kitty_graphics_protocol::{
+    DetectedFormat, ImageFormat, check_protocol_support, clear_all_images, display_png_data,
+};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -67,78 +72,37 @@ fn print_usage(program: &str) {
     println!();
     println!("支持的格式:");
     println!("  PNG (推荐)");
+    println!("  JPEG, GIF, BMP, WebP (需要 `image` feature)");
     println!();
     println!("示例:");
     println!("  {} photo.png", program);
     println!("  {} /path/to/image.png", program);
 }
 
-/// 图像格式类型
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ImageFormat {
-    Png,
-    Jpeg,
-    Gif,
-    Bmp,
-    WebP,
-    Unknown,
-}
-
-fn detect_format(data: &[u8]) -> ImageFormat {
-    if data.len() < 8 {
-        return ImageFormat::Unknown;
-    }
-
-    // PNG: 89 50 4E 47 0D 0A 1A 0A
-    if data[0] == 0x89 && data[1] == 0x50 && data[2] == 0x4E && data[3] == 0x47 {
-        return ImageFormat::Png;
-    }
-
-    // JPEG: FF D8 FF
-    if data[0] == 0xFF && data[1] == 0xD8 && data[2] == 0xFF {
-        return ImageFormat::Jpeg;
-    }
-
-    // GIF: 47 49 46 38
-    if data[0] == 0x47 && data[1] == 0x49 && data[2] == 0x46 && data[3] == 0x38 {
-        return ImageFormat::Gif;
-    }
-
-    // BMP: 42 4D
-    if data[0] == 0x42 && data[1] == 0x4D {
-        return ImageFormat::Bmp;
-    }
-
-    // WebP: 52 49 46 46 ... 57 45 42 50
-    if data[0] == 0x52
-        && data[1] == 0x49
-        && data[2] == 0x46
-        && data[3] == 0x46
-        && data.len() >= 12
-        && data[8] == 0x57
-        && data[9] == 0x45
-        && data[10] == 0x42
-        && data[11] == 0x50
-    {
-        return ImageFormat::WebP;
-    }
-
-    ImageFormat::Unknown
-}
-
 fn display_image(path: &str) -> Result<(), Box<dyn std::error::Error>> {
     // 读取图片文件
     let data = std::fs::read(path)?;
 
     // 检测图像格式
-    let format = detect_format(&data);
+    let format = ImageFormat::detect(&data);
+
+    // 启用 `image` feature 时由库负责解码其他格式
+    #[cfg(feature = "image")]
+    if matches!(
+        format,
+        DetectedFormat::Jpeg | DetectedFormat::Gif | DetectedFormat::Bmp | DetectedFormat::WebP
+    ) {
+        println!("✓ 检测到 {:?} 格式图片, 解码后显示", format);
+        kitty_graphics_protocol::ImageDisplay::new().display_image_file(path)?;
+        return Ok(());
+    }
 
     match format {
-        ImageFormat::Png => {
+        DetectedFormat::Png => {
             println!("✓ 检测到 PNG 格式图片");
             display_png_data(&data)?;
         }
-        ImageFormat::Jpeg => {
+        DetectedFormat::Jpeg => {
             eprintln!("✗ 错误: JPEG 格式不被原生支持");
             eprintln!();
             eprintln!("Kitty 图形协议原生只支持 PNG 格式。");
@@ -149,24 +113,35 @@ fn display_image(path: &str) -> Result<(), Box<dyn std::error::Error>> {
             eprintln!();
             eprintln!("  # 或使用 ffmpeg");
             eprintln!("  ffmpeg -i \"{}\" output.png", path);
+            eprintln!();
+            eprintln!("  # 或启用 `image` feature 由库直接解码");
+            eprintln!(
+                "  cargo run --features image --example display_image \"{}\"",
+                path
+            );
             return Err("不支持的图像格式: JPEG".into());
         }
-        ImageFormat::Gif => {
+        DetectedFormat::Gif => {
             eprintln!("✗ 错误: GIF 格式不被原生支持");
             eprintln!("请将图片转换为 PNG 格式后重试");
             return Err("不支持的图像格式: GIF".into());
         }
-        ImageFormat::Bmp => {
+        DetectedFormat::Bmp => {
             eprintln!("✗ 错误: BMP 格式不被原生支持");
             eprintln!("请将图片转换为 PNG 格式后重试");
             return Err("不支持的图像格式: BMP".into());
         }
-        ImageFormat::WebP => {
+        DetectedFormat::WebP => {
             eprintln!("✗ 错误: WebP 格式不被原生支持");
             eprintln!("请将图片转换为 PNG 格式后重试");
             return Err("不支持的图像格式: WebP".into());
         }
-        ImageFormat::Unknown => {
+        DetectedFormat::Tiff | DetectedFormat::Qoi => {
+            eprintln!("✗ 错误: {:?} 格式不被支持", format);
+            eprintln!("请将图片转换为 PNG 格式后重试");
+            return Err(format!("不支持的图像格式: {:?}", format).into());
+        }
+        DetectedFormat::Unknown => {
             eprintln!("✗ 错误: 无法识别的图像格式");
             eprintln!("请确保文件是有效的 PNG 图像");
             return Err("无法识别的图像格式".into());
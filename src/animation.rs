@@ -0,0 +1,947 @@
+//! Decoded animations and the frame commands that play them
+//!
+//! Animated GIF, APNG and WebP files describe each frame as a rectangle
+//! drawn over the previous canvas, followed by a disposal step. Kitty frames
+//! are full canvases, optionally built on top of an earlier frame (`c=`).
+//! [`AnimatedImage`] holds the format-independent description, and
+//! [`AnimatedImage::frame_commands`] maps it onto kitty frames so every
+//! format shares the same timing and composition logic.
+
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::types::{
+    Action, AnimationControl, CompositionMode, ImageFormat, ImageId, Passthrough, Quiet,
+};
+use std::io::Write;
+
+/// How often an animation is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Loop {
+    /// Play forever
+    #[default]
+    Infinite,
+    /// Play the given number of times
+    Times(u32),
+}
+
+impl Loop {
+    /// Value of the `v=` key starting the animation
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Infinite => 1,
+            Self::Times(n) => n.saturating_add(1),
+        }
+    }
+}
+
+/// What happens to a frame's rectangle before the next frame is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Disposal {
+    /// Leave the frame on the canvas
+    #[default]
+    Keep,
+    /// Clear the rectangle to transparent
+    Background,
+    /// Restore the canvas to what it was before the frame was drawn
+    Previous,
+}
+
+/// One frame of an animation: pixels drawn at an offset on the canvas
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnimatedFrame {
+    /// Left edge of the rectangle on the canvas
+    pub x: u32,
+    /// Top edge of the rectangle on the canvas
+    pub y: u32,
+    /// Width of the rectangle
+    pub width: u32,
+    /// Height of the rectangle
+    pub height: u32,
+    /// Format of `data`: RGB, RGBA or PNG
+    pub format: ImageFormat,
+    /// Pixels of the rectangle
+    pub data: Vec<u8>,
+    /// How long the frame is shown, in milliseconds
+    pub delay_ms: u32,
+    /// Alpha blend onto the canvas instead of replacing its pixels
+    pub blend: bool,
+    /// What happens to the rectangle afterwards
+    pub disposal: Disposal,
+}
+
+impl AnimatedFrame {
+    /// Check whether the frame covers the whole canvas
+    fn covers(&self, width: u32, height: u32) -> bool {
+        self.x == 0 && self.y == 0 && self.width == width && self.height == height
+    }
+
+    /// Command builder transmitting this frame's data as an `a=f` frame
+    fn builder(&self, image_id: ImageId) -> CommandBuilder {
+        let mode = if self.blend {
+            CompositionMode::AlphaBlend
+        } else {
+            CompositionMode::Replace
+        };
+        let builder = Command::builder()
+            .action(Action::Frame)
+            .image_id(image_id)
+            .format(self.format)
+            .source_position(self.x, self.y)
+            .composition_mode(mode);
+        match self.format {
+            ImageFormat::Png => builder,
+            _ => builder.dimensions(self.width, self.height),
+        }
+    }
+}
+
+/// A decoded animation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnimatedImage {
+    /// Canvas width in pixels
+    pub width: u32,
+    /// Canvas height in pixels
+    pub height: u32,
+    /// Frames in display order
+    pub frames: Vec<AnimatedFrame>,
+    /// How often the animation is played
+    pub loops: Loop,
+}
+
+/// Commands playing an [`AnimatedImage`], from [`AnimatedImage::frame_commands`]
+#[derive(Debug, Clone)]
+pub struct FrameCommands {
+    /// Format of `root`
+    pub root_format: ImageFormat,
+    /// Data of the first frame, covering the whole canvas
+    pub root: Vec<u8>,
+    /// Gap of the first frame (`a=a,r=1,z=`), sent after the root frame
+    pub root_gap: Command,
+    /// Frame data (`a=f`) to send after the root frame, in order
+    pub frames: Vec<(CommandBuilder, Vec<u8>)>,
+    /// Starts the animation (`a=a,s=3`)
+    pub start: Command,
+    /// Kitty frame number of each frame of the animation
+    pub numbers: Vec<u32>,
+    /// Number of kitty frames created, including gapless helper frames
+    pub frame_count: u32,
+}
+
+impl AnimatedImage {
+    /// Check that every frame lies within the canvas and has enough data
+    fn validate(&self) -> Result<()> {
+        let invalid = |width, height| Error::InvalidDimensions { width, height };
+        if self.width == 0 || self.height == 0 || self.frames.is_empty() {
+            return Err(invalid(self.width, self.height));
+        }
+        for frame in &self.frames {
+            let fits = frame
+                .x
+                .checked_add(frame.width)
+                .is_some_and(|r| r <= self.width)
+                && frame
+                    .y
+                    .checked_add(frame.height)
+                    .is_some_and(|b| b <= self.height);
+            let bpp = match frame.format {
+                ImageFormat::Rgb => 3,
+                ImageFormat::Rgba => 4,
+                ImageFormat::Png => 0,
+            };
+            let len = frame.width as usize * frame.height as usize * bpp;
+            if !fits || (bpp != 0 && frame.data.len() != len) {
+                return Err(invalid(frame.width, frame.height));
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the frames onto kitty animation commands for `image_id`
+    ///
+    /// Each frame is sent as its rectangle on top of the kitty frame holding
+    /// the canvas it is drawn over (`c=`), so disposal never needs decoded
+    /// pixels: [`Disposal::Previous`] reuses the frame the disposed one was
+    /// built on, and [`Disposal::Background`] adds a gapless frame with the
+    /// rectangle cleared, which is never shown on its own.
+    pub fn frame_commands(&self, image_id: ImageId) -> Result<FrameCommands> {
+        self.validate()?;
+        let first = &self.frames[0];
+        let mut frames = Vec::with_capacity(self.frames.len());
+
+        // The root frame must cover the canvas; start from a blank one if
+        // the first frame does not
+        let (root_format, root) = if first.covers(self.width, self.height) {
+            (first.format, first.data.clone())
+        } else {
+            frames.push((first.builder(image_id).edit_frame(1), first.data.clone()));
+            let blank = vec![0; self.width as usize * self.height as usize * 4];
+            (ImageFormat::Rgba, blank)
+        };
+
+        // Kitty frame holding the canvas the next frame is drawn over
+        let mut base = None;
+        let mut count = 0;
+        let mut numbers = Vec::with_capacity(self.frames.len());
+        for (index, frame) in self.frames.iter().enumerate() {
+            if index > 0 {
+                let builder = frame.builder(image_id).frame_gap(gap(frame.delay_ms));
+                let builder = match base {
+                    Some(n) => builder.base_frame(n),
+                    None => builder,
+                };
+                frames.push((builder, frame.data.clone()));
+            }
+            count += 1;
+            numbers.push(count);
+
+            base = match frame.disposal {
+                Disposal::Keep => Some(count),
+                Disposal::Previous => base,
+                Disposal::Background if frame.covers(self.width, self.height) => None,
+                Disposal::Background => {
+                    let clear = Command::builder()
+                        .action(Action::Frame)
+                        .image_id(image_id)
+                        .format(ImageFormat::Rgba)
+                        .source_position(frame.x, frame.y)
+                        .dimensions(frame.width, frame.height)
+                        .composition_mode(CompositionMode::Replace)
+                        .base_frame(count)
+                        .frame_gap(-1);
+                    let pixels = frame.width as usize * frame.height as usize * 4;
+                    frames.push((clear, vec![0; pixels]));
+                    count += 1;
+                    Some(count)
+                }
+            };
+        }
+
+        Ok(FrameCommands {
+            root_format,
+            root,
+            root_gap: Command::builder()
+                .action(Action::AnimationControl)
+                .image_id(image_id)
+                .edit_frame(1)
+                .frame_gap(gap(first.delay_ms))
+                .build(),
+            frames,
+            start: Command::builder()
+                .action(Action::AnimationControl)
+                .image_id(image_id)
+                .animation_control(AnimationControl::Run)
+                .loop_count(self.loops.code())
+                .build(),
+            numbers,
+            frame_count: count,
+        })
+    }
+}
+
+/// Frame gap for a delay, keeping zero delays from becoming gapless frames
+fn gap(delay_ms: u32) -> i32 {
+    delay_ms.clamp(1, i32::MAX as u32) as i32
+}
+
+/// Gap of frames pushed without one, matching kitty's default
+const DEFAULT_GAP_MS: u32 = 40;
+
+/// Builder sending a sequence of full canvases as a kitty animation
+///
+/// Takes care of the root frame, the per-frame `a=f` commands and starting
+/// the animation, so callers never deal with frame numbers:
+///
+/// ```no_run
+/// # fn main() -> kitty_graphics_protocol::Result<()> {
+/// use kitty_graphics_protocol::{Animation, ImageId, Loop, Terminal};
+///
+/// let mut term = Terminal::open()?;
+/// Animation::new(2, 1)
+///     .image_id(ImageId::new(42).unwrap())
+///     .push_frame(vec![255; 8], 100)
+///     .push_frame(vec![0; 8], 100)
+///     .loops(Loop::Infinite)
+///     .display()
+///     .transmit(&mut term)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Animation {
+    width: u32,
+    height: u32,
+    format: ImageFormat,
+    frames: Vec<(Vec<u8>, u32)>,
+    loops: Loop,
+    default_gap_ms: u32,
+    image_id: Option<ImageId>,
+    display: bool,
+    quiet: Quiet,
+    passthrough: Passthrough,
+}
+
+impl Animation {
+    /// Create an animation of `width`x`height` RGBA frames
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            format: ImageFormat::Rgba,
+            frames: Vec::new(),
+            loops: Loop::Infinite,
+            default_gap_ms: DEFAULT_GAP_MS,
+            image_id: None,
+            display: false,
+            quiet: Quiet::SuppressAll,
+            passthrough: Passthrough::None,
+        }
+    }
+
+    /// Set the format of every frame's data
+    pub fn format(mut self, format: ImageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Append a frame covering the whole canvas, shown for `gap_ms`
+    ///
+    /// A gap of 0 uses the [`default_gap`](Self::default_gap).
+    pub fn push_frame(mut self, data: impl Into<Vec<u8>>, gap_ms: u32) -> Self {
+        self.frames.push((data.into(), gap_ms));
+        self
+    }
+
+    /// Set the gap of frames pushed with a gap of 0 (40ms by default)
+    pub fn default_gap(mut self, gap_ms: u32) -> Self {
+        self.default_gap_ms = gap_ms;
+        self
+    }
+
+    /// Set how often the animation is played
+    pub fn loops(mut self, loops: Loop) -> Self {
+        self.loops = loops;
+        self
+    }
+
+    /// Set the ID of the image holding the frames
+    pub fn image_id(mut self, id: ImageId) -> Self {
+        self.image_id = Some(id);
+        self
+    }
+
+    /// Also display the animation at the cursor (a=T)
+    pub fn display(mut self) -> Self {
+        self.display = true;
+        self
+    }
+
+    /// Set quiet mode (suppress all replies by default)
+    pub fn quiet(mut self, mode: impl Into<Quiet>) -> Self {
+        self.quiet = mode.into();
+        self
+    }
+
+    /// Wrap emitted sequences for a terminal multiplexer
+    pub fn passthrough(mut self, mode: Passthrough) -> Self {
+        self.passthrough = mode;
+        self
+    }
+
+    /// The frames as an [`AnimatedImage`]
+    pub fn to_animated_image(&self) -> AnimatedImage {
+        let frames = self
+            .frames
+            .iter()
+            .map(|(data, gap_ms)| AnimatedFrame {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+                format: self.format,
+                data: data.clone(),
+                delay_ms: match gap_ms {
+                    0 => self.default_gap_ms,
+                    gap => *gap,
+                },
+                blend: false,
+                disposal: Disposal::Keep,
+            })
+            .collect();
+        AnimatedImage {
+            width: self.width,
+            height: self.height,
+            frames,
+            loops: self.loops,
+        }
+    }
+
+    /// Send the frames to `out` and start the animation
+    ///
+    /// Fails with [`Error::MissingField`] if no image ID was set, and with
+    /// [`Error::InvalidDimensions`] if there are no frames or a frame's data
+    /// does not match the canvas size.
+    pub fn transmit<W: Write>(&self, out: &mut W) -> Result<()> {
+        let image_id = self.image_id.ok_or(Error::MissingField("image_id"))?;
+        let commands = self.to_animated_image().frame_commands(image_id)?;
+        let action = if self.display {
+            Action::TransmitAndDisplay
+        } else {
+            Action::Transmit
+        };
+        let root = Command::builder()
+            .action(action)
+            .image_id(image_id)
+            .format(commands.root_format);
+        let root = match commands.root_format {
+            ImageFormat::Png => root,
+            _ => root.dimensions(self.width, self.height),
+        };
+        self.finish(root)
+            .serialize_chunked_into(&commands.root, out)?;
+        out.write_all(
+            &self
+                .finish(commands.root_gap.into_builder())
+                .serialize_control_bytes()?,
+        )?;
+        for (builder, data) in commands.frames {
+            self.finish(builder).serialize_chunked_into(&data, out)?;
+        }
+        out.write_all(
+            &self
+                .finish(commands.start.into_builder())
+                .serialize_control_bytes()?,
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Apply the quiet and passthrough modes to a command
+    fn finish(&self, builder: CommandBuilder) -> Command {
+        builder
+            .quiet(self.quiet)
+            .passthrough(self.passthrough)
+            .build()
+    }
+}
+
+/// PNG file signature
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Split a PNG into its chunks as `(type, data)`, up to and including IEND
+fn png_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let truncated = || Error::protocol("truncated PNG");
+    let mut rest = data
+        .strip_prefix(PNG_SIGNATURE)
+        .ok_or_else(|| Error::protocol("not a PNG image"))?;
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        let header = rest.get(..8).ok_or_else(truncated)?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let body = rest.get(8..8 + len).ok_or_else(truncated)?;
+        rest = rest.get(12 + len..).ok_or_else(truncated)?;
+        chunks.push((kind, body));
+        if &kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(chunks)
+}
+
+/// Append a chunk with its length and CRC to `png`
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(body);
+    png.extend_from_slice(&crc32(kind.iter().chain(body)).to_be_bytes());
+}
+
+/// CRC-32 as used by PNG chunks
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Read a big-endian `u32` at `offset`
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Check whether `data` is an animated PNG, i.e. has an acTL chunk before
+/// the image data
+pub fn is_apng(data: &[u8]) -> bool {
+    png_chunks(data).is_ok_and(|chunks| {
+        chunks
+            .iter()
+            .take_while(|(kind, _)| kind != b"IDAT")
+            .any(|(kind, _)| kind == b"acTL")
+    })
+}
+
+/// Split an animated PNG into one standalone PNG per frame
+///
+/// Nothing is decoded: each frame's fdAT data becomes the IDAT of a PNG
+/// with the frame's size and the original palette and color chunks, which
+/// the terminal decodes itself. The default image is the first frame when
+/// an fcTL chunk precedes it, as the APNG specification requires.
+pub fn split_apng(data: &[u8]) -> Result<AnimatedImage> {
+    let invalid = |what: &str| Error::protocol(format!("invalid APNG: {what}"));
+    let chunks = png_chunks(data)?;
+    let ihdr = match chunks.first() {
+        Some((kind, body)) if kind == b"IHDR" && body.len() == 13 => *body,
+        _ => return Err(invalid("missing IHDR")),
+    };
+
+    let mut loops = None;
+    // Chunks before the image data, repeated in every frame
+    let mut shared = Vec::new();
+    let mut controls: Vec<(&[u8], Vec<&[u8]>)> = Vec::new();
+    let mut seen_idat = false;
+    for &(kind, body) in &chunks[1..] {
+        match &kind {
+            b"acTL" if body.len() >= 8 => {
+                loops = Some(match be32(body, 4) {
+                    0 => Loop::Infinite,
+                    plays => Loop::Times(plays),
+                });
+            }
+            b"fcTL" if body.len() >= 26 => controls.push((body, Vec::new())),
+            b"IDAT" => {
+                // Only part of the animation if an fcTL came first
+                seen_idat = true;
+                if let Some((_, parts)) = controls.last_mut() {
+                    parts.push(body);
+                }
+            }
+            b"fdAT" if body.len() >= 4 => controls
+                .last_mut()
+                .ok_or_else(|| invalid("fdAT before fcTL"))?
+                .1
+                .push(&body[4..]),
+            b"acTL" | b"fcTL" | b"fdAT" => return Err(invalid("short animation chunk")),
+            b"IEND" => break,
+            _ if !seen_idat => shared.push((kind, body)),
+            _ => {}
+        }
+    }
+    let loops = loops.ok_or_else(|| invalid("missing acTL"))?;
+
+    let frames = controls
+        .into_iter()
+        .map(|(control, parts)| {
+            if parts.is_empty() {
+                return Err(invalid("frame without data"));
+            }
+            let (width, height) = (be32(control, 4), be32(control, 8));
+            let mut header = ihdr.to_vec();
+            header[..4].copy_from_slice(&width.to_be_bytes());
+            header[4..8].copy_from_slice(&height.to_be_bytes());
+
+            let mut png = PNG_SIGNATURE.to_vec();
+            write_chunk(&mut png, b"IHDR", &header);
+            for (kind, body) in &shared {
+                write_chunk(&mut png, kind, body);
+            }
+            for part in parts {
+                write_chunk(&mut png, b"IDAT", part);
+            }
+            write_chunk(&mut png, b"IEND", &[]);
+
+            let numerator = u32::from(u16::from_be_bytes([control[20], control[21]]));
+            let denominator = match u16::from_be_bytes([control[22], control[23]]) {
+                0 => 100,
+                d => u32::from(d),
+            };
+            Ok(AnimatedFrame {
+                x: be32(control, 12),
+                y: be32(control, 16),
+                width,
+                height,
+                format: ImageFormat::Png,
+                data: png,
+                delay_ms: numerator * 1000 / denominator,
+                blend: control[25] == 1,
+                disposal: match control[24] {
+                    1 => Disposal::Background,
+                    2 => Disposal::Previous,
+                    _ => Disposal::Keep,
+                },
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(AnimatedImage {
+        width: be32(ihdr, 0),
+        height: be32(ihdr, 4),
+        frames,
+        loops,
+    })
+}
+
+/// Decode an animated GIF
+///
+/// Delays below 20 ms are shown for 100 ms, as browsers do, since many GIFs
+/// rely on that.
+#[cfg(feature = "gif")]
+pub fn decode_gif(data: &[u8]) -> Result<AnimatedImage> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data)?;
+    let (width, height) = (u32::from(decoder.width()), u32::from(decoder.height()));
+
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        let delay_ms = match u32::from(frame.delay) * 10 {
+            delay if delay < 20 => 100,
+            delay => delay,
+        };
+        frames.push(AnimatedFrame {
+            x: u32::from(frame.left),
+            y: u32::from(frame.top),
+            width: u32::from(frame.width),
+            height: u32::from(frame.height),
+            format: ImageFormat::Rgba,
+            data: frame.buffer.to_vec(),
+            delay_ms,
+            blend: true,
+            disposal: match frame.dispose {
+                gif::DisposalMethod::Background => Disposal::Background,
+                gif::DisposalMethod::Previous => Disposal::Previous,
+                gif::DisposalMethod::Any | gif::DisposalMethod::Keep => Disposal::Keep,
+            },
+        });
+    }
+
+    let loops = match decoder.repeat() {
+        gif::Repeat::Infinite => Loop::Infinite,
+        gif::Repeat::Finite(n) => Loop::Times(u32::from(n) + 1),
+    };
+    Ok(AnimatedImage {
+        width,
+        height,
+        frames,
+        loops,
+    })
+}
+
+/// Decode an animated (or still) WebP image
+///
+/// The decoder composes every frame onto the full canvas; only the
+/// rectangle that changed since the previous frame is kept, so unchanged
+/// parts are not sent again.
+#[cfg(feature = "webp")]
+pub fn decode_webp(data: &[u8]) -> Result<AnimatedImage> {
+    let mut decoder = image_webp::WebPDecoder::new(std::io::Cursor::new(data))?;
+    let (width, height) = decoder.dimensions();
+    let (format, bpp) = if decoder.has_alpha() {
+        (ImageFormat::Rgba, 4)
+    } else {
+        (ImageFormat::Rgb, 3)
+    };
+    let size = decoder
+        .output_buffer_size()
+        .ok_or(Error::InvalidDimensions { width, height })?;
+
+    let full = |data: Vec<u8>, delay_ms| AnimatedFrame {
+        x: 0,
+        y: 0,
+        width,
+        height,
+        format,
+        data,
+        delay_ms,
+        blend: false,
+        disposal: Disposal::Keep,
+    };
+    if !decoder.is_animated() {
+        let mut canvas = vec![0; size];
+        decoder.read_image(&mut canvas)?;
+        return Ok(AnimatedImage {
+            width,
+            height,
+            frames: vec![full(canvas, 0)],
+            loops: Loop::Times(1),
+        });
+    }
+
+    let mut frames = Vec::with_capacity(decoder.num_frames() as usize);
+    let mut previous: Option<Vec<u8>> = None;
+    for _ in 0..decoder.num_frames() {
+        let mut canvas = vec![0; size];
+        let delay_ms = decoder.read_frame(&mut canvas)?;
+        let frame = match &previous {
+            None => full(canvas.clone(), delay_ms),
+            Some(previous) => {
+                let (x, y, w, h) = changed_rect(previous, &canvas, width, bpp);
+                let stride = width as usize * bpp;
+                let data = (y..y + h)
+                    .flat_map(|row| {
+                        let start = row as usize * stride + x as usize * bpp;
+                        &canvas[start..start + w as usize * bpp]
+                    })
+                    .copied()
+                    .collect();
+                AnimatedFrame {
+                    x,
+                    y,
+                    width: w,
+                    height: h,
+                    data,
+                    ..full(Vec::new(), delay_ms)
+                }
+            }
+        };
+        frames.push(frame);
+        previous = Some(canvas);
+    }
+
+    let loops = match decoder.loop_count() {
+        image_webp::LoopCount::Forever => Loop::Infinite,
+        image_webp::LoopCount::Times(n) => Loop::Times(u32::from(n.get())),
+    };
+    Ok(AnimatedImage {
+        width,
+        height,
+        frames,
+        loops,
+    })
+}
+
+/// Bounding box `(x, y, width, height)` of the pixels that differ between
+/// two canvases, or the top left pixel if none do
+#[cfg(feature = "webp")]
+fn changed_rect(before: &[u8], after: &[u8], width: u32, bpp: usize) -> (u32, u32, u32, u32) {
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    let pixels = before.chunks_exact(bpp).zip(after.chunks_exact(bpp));
+    for (index, (a, b)) in pixels.enumerate() {
+        if a != b {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+    }
+    if left == u32::MAX {
+        return (0, 0, 1, 1);
+    }
+    (left, top, right - left + 1, bottom - top + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(x: u32, y: u32, pixel: [u8; 4], disposal: Disposal) -> AnimatedFrame {
+        AnimatedFrame {
+            x,
+            y,
+            width: 1,
+            height: 1,
+            format: ImageFormat::Rgba,
+            data: pixel.to_vec(),
+            delay_ms: 50,
+            blend: true,
+            disposal,
+        }
+    }
+
+    #[test]
+    fn test_frame_commands_follow_disposal() {
+        let red = [255, 0, 0, 255];
+        let image = AnimatedImage {
+            width: 2,
+            height: 1,
+            frames: vec![
+                frame(0, 0, red, Disposal::Keep),
+                frame(1, 0, red, Disposal::Previous),
+                frame(1, 0, red, Disposal::Background),
+                frame(0, 0, red, Disposal::Keep),
+            ],
+            loops: Loop::Times(2),
+        };
+        let id = ImageId::new(3).unwrap();
+        let commands = image.frame_commands(id).unwrap();
+        // The first frame does not cover the canvas, so it is drawn into a
+        // blank root frame
+        assert_eq!(commands.root, [0; 8]);
+
+        let sent: Vec<String> = commands
+            .frames
+            .iter()
+            .map(|(b, data)| b.clone().build().serialize(data).unwrap())
+            .collect();
+        assert_eq!(sent.len(), 5);
+        assert!(sent[0].starts_with("\x1b_Ga=f,f=32,s=1,v=1,i=3,x=0,y=0,r=1;"));
+        // Frame 2 is disposed back to frame 1, so frame 3 builds on it too
+        assert!(sent[1].starts_with("\x1b_Ga=f,f=32,s=1,v=1,i=3,x=1,y=0,c=1,z=50;"));
+        assert!(sent[2].starts_with("\x1b_Ga=f,f=32,s=1,v=1,i=3,x=1,y=0,c=1,z=50;"));
+        // Frame 3 is cleared in a gapless frame, which frame 4 builds on
+        assert!(sent[3].starts_with("\x1b_Ga=f,f=32,s=1,v=1,i=3,x=1,y=0,c=3,z=-1,X=1;AAAAAA=="));
+        assert_eq!(commands.numbers, [1, 2, 3, 5]);
+        assert!(sent[4].starts_with("\x1b_Ga=f,f=32,s=1,v=1,i=3,x=0,y=0,c=4,z=50;"));
+
+        assert_eq!(
+            commands.start.serialize_control().unwrap(),
+            "\x1b_Ga=a,i=3,s=3,v=3\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_animation_builder() {
+        let mut out = Vec::new();
+        Animation::new(1, 1)
+            .image_id(ImageId::new(3).unwrap())
+            .push_frame(vec![0; 4], 100)
+            .push_frame(vec![255; 4], 0)
+            .loops(Loop::Times(2))
+            .transmit(&mut out)
+            .unwrap();
+        let sent = String::from_utf8(out).unwrap();
+        assert_eq!(
+            sent,
+            "\x1b_Ga=t,f=32,s=1,v=1,i=3,q=2,m=0;AAAAAA==\x1b\\\
+             \x1b_Ga=a,i=3,q=2,r=1,z=100\x1b\\\
+             \x1b_Ga=f,f=32,s=1,v=1,i=3,q=2,x=0,y=0,c=1,z=40,X=1,m=0;/////w==\x1b\\\
+             \x1b_Ga=a,i=3,q=2,s=3,v=3\x1b\\"
+        );
+
+        let no_id = Animation::new(1, 1).push_frame(vec![0; 4], 0);
+        assert!(no_id.transmit(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_split_apng() {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&4u32.to_be_bytes());
+        ihdr.extend_from_slice(&2u32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let fctl = |seq: u32, width: u32, x: u32, dispose: u8| {
+            let mut body = Vec::new();
+            for value in [seq, width, 2, x, 0] {
+                body.extend_from_slice(&value.to_be_bytes());
+            }
+            body.extend_from_slice(&[0, 1, 0, 10, dispose, 1]);
+            body
+        };
+
+        let mut apng = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut apng, b"IHDR", &ihdr);
+        write_chunk(&mut apng, b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+        write_chunk(&mut apng, b"fcTL", &fctl(0, 4, 0, 0));
+        write_chunk(&mut apng, b"IDAT", b"root");
+        write_chunk(&mut apng, b"fcTL", &fctl(1, 2, 2, 1));
+        write_chunk(&mut apng, b"fdAT", b"\0\0\0\x02next");
+        write_chunk(&mut apng, b"IEND", &[]);
+        assert!(is_apng(&apng));
+
+        let image = split_apng(&apng).unwrap();
+        assert_eq!(
+            (image.width, image.height, image.loops),
+            (4, 2, Loop::Infinite)
+        );
+        let [root, next] = &image.frames[..] else {
+            panic!("expected two frames");
+        };
+        assert_eq!((root.delay_ms, root.disposal), (100, Disposal::Keep));
+        assert_eq!(
+            (next.x, next.width, next.disposal),
+            (2, 2, Disposal::Background)
+        );
+        assert!(next.blend);
+
+        let chunks = png_chunks(&next.data).unwrap();
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(be32(chunks[0].1, 0), 2);
+        assert_eq!(chunks[1].1, b"next");
+        // The well-known CRC of an empty IEND chunk
+        assert!(next.data.ends_with(&[0xae, 0x42, 0x60, 0x82]));
+
+        let mut still = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut still, b"IHDR", &ihdr);
+        write_chunk(&mut still, b"IDAT", b"data");
+        write_chunk(&mut still, b"acTL", &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert!(!is_apng(&still));
+    }
+
+    #[cfg(feature = "gif")]
+    #[test]
+    fn test_decode_gif() {
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 2, 2, &[]).unwrap();
+            encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+            for (delay, dispose) in [
+                (5, gif::DisposalMethod::Keep),
+                (0, gif::DisposalMethod::Previous),
+            ] {
+                let mut pixels = [255, 0, 0, 255].repeat(4);
+                let mut frame = gif::Frame::from_rgba(2, 2, &mut pixels);
+                frame.delay = delay;
+                frame.dispose = dispose;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+
+        let image = decode_gif(&data).unwrap();
+        assert_eq!(
+            (image.width, image.height, image.loops),
+            (2, 2, Loop::Infinite)
+        );
+        assert_eq!(image.frames.len(), 2);
+        assert_eq!(image.frames[0].delay_ms, 50);
+        // Zero delays are shown like browsers show them
+        assert_eq!(image.frames[1].delay_ms, 100);
+        assert_eq!(image.frames[1].disposal, Disposal::Previous);
+        assert_eq!(&image.frames[0].data[..4], &[255, 0, 0, 255]);
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn test_decode_webp() {
+        let mut data = Vec::new();
+        let pixels = [0, 0, 255, 255].repeat(6);
+        image_webp::WebPEncoder::new(&mut data)
+            .encode(&pixels, 3, 2, image_webp::ColorType::Rgba8)
+            .unwrap();
+
+        let image = decode_webp(&data).unwrap();
+        assert_eq!(
+            (image.width, image.height, image.loops),
+            (3, 2, Loop::Times(1))
+        );
+        assert_eq!(image.frames[0].format, ImageFormat::Rgba);
+        assert_eq!(image.frames[0].data, pixels);
+
+        let after = [0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0, 9];
+        assert_eq!(changed_rect(&[0; 12], &after, 2, 3), (0, 0, 2, 2));
+        assert_eq!(changed_rect(&[0; 12], &[0; 12], 2, 3), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn test_rejects_frames_outside_canvas() {
+        let image = AnimatedImage {
+            width: 1,
+            height: 1,
+            frames: vec![frame(1, 0, [0; 4], Disposal::Keep)],
+            loops: Loop::Infinite,
+        };
+        assert!(image.frame_commands(ImageId::new(1).unwrap()).is_err());
+    }
+}
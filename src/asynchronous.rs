@@ -0,0 +1,199 @@
+//! Async serialization into tokio writers (requires the `tokio` feature)
+//!
+//! Chunks are encoded one at a time into a small buffer and written with
+//! `.await`, so large images can be transmitted from async applications
+//! without blocking the runtime. Terminal replies can be awaited the same
+//! way with [`ResponseStream`].
+
+use crate::command::{Command, encode_chunk};
+use crate::error::{Error, Result};
+use crate::response::{Response, ResponseParser};
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+impl Command {
+    /// Serialize the command in chunks into an async writer
+    ///
+    /// Produces the same escape sequences as [`Command::serialize_chunked`]
+    /// and flushes the writer at the end.
+    pub async fn write_async<W>(&self, data: &[u8], w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut chunks = self.serialize_chunked(data)?;
+        while let Some(chunk) = chunks.next_chunk() {
+            w.write_all(chunk).await?;
+        }
+        w.flush().await?;
+        Ok(())
+    }
+
+    /// Serialize the command in chunks read incrementally from an async reader
+    ///
+    /// The async counterpart of [`Command::serialize_chunked_from_reader`]:
+    /// only one chunk plus one chunk of lookahead is held in memory. Payloads
+    /// are sent as read: a declared compression marks the stream as already
+    /// compressed, and requesting `deflate` is an error.
+    pub async fn write_async_from_reader<R, W>(&self, mut reader: R, w: &mut W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if self.deflates() && !self.has_compression() {
+            return Err(Error::protocol("deflating async streams is not supported"));
+        }
+
+        let control = self.stream_control_data(self.has_compression())?;
+        let continuation = self.continuation_control();
+        let raw_chunk_size = self.chunk_size()? / 4 * 3;
+        let mut current = Vec::with_capacity(raw_chunk_size);
+        let mut lookahead = Vec::with_capacity(raw_chunk_size);
+        let mut buf = Vec::new();
+
+        fill(&mut reader, &mut current, raw_chunk_size).await?;
+        let mut is_first = true;
+        while !current.is_empty() {
+            fill(&mut reader, &mut lookahead, raw_chunk_size).await?;
+            let is_last = lookahead.is_empty();
+
+            let control = if is_first { &control } else { &continuation };
+            encode_chunk(&mut buf, self.passthrough(), control, &current, is_last);
+            w.write_all(&buf).await?;
+
+            std::mem::swap(&mut current, &mut lookahead);
+            is_first = false;
+        }
+
+        w.flush().await?;
+        Ok(())
+    }
+}
+
+/// Read up to `len` bytes into `buf`, stopping early only at end of input
+async fn fill<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>, len: usize) -> Result<()> {
+    buf.clear();
+    reader.take(len as u64).read_to_end(buf).await?;
+    Ok(())
+}
+
+/// Graphics responses read from an async reader over the tty
+///
+/// The tty should be in raw mode, or the terminal's replies only arrive
+/// once the user presses enter. Other terminal reports are skipped.
+#[derive(Debug)]
+pub struct ResponseStream<R> {
+    reader: R,
+    parser: ResponseParser,
+    ready: VecDeque<Response>,
+}
+
+impl<R: AsyncRead + Unpin> ResponseStream<R> {
+    /// Read responses from `reader`
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            parser: ResponseParser::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Wait for the next response, or `None` at end of input
+    pub async fn next_response(&mut self) -> Result<Option<Response>> {
+        let mut buf = [0u8; 256];
+        loop {
+            if let Some(response) = self.ready.pop_front() {
+                return Ok(Some(response));
+            }
+            let n = self.reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.ready.extend(self.parser.feed(&buf[..n]));
+        }
+    }
+
+    /// Wait for the next response for at most `timeout`
+    ///
+    /// Fails with [`Error::Timeout`] if no response arrives in time, and
+    /// with [`io::ErrorKind::UnexpectedEof`] at end of input.
+    pub async fn next_response_timeout(&mut self, timeout: Duration) -> Result<Response> {
+        match tokio::time::timeout(timeout, self.next_response()).await {
+            Ok(Ok(Some(response))) => Ok(response),
+            Ok(Ok(None)) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    /// Get back the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Wait for a single graphics response from an async reader
+///
+/// Bytes read past the response are discarded; use [`ResponseStream`] to
+/// read several responses from the same tty.
+pub async fn read_response_async<R>(reader: &mut R, timeout: Duration) -> Result<Response>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    ResponseStream::new(reader)
+        .next_response_timeout(timeout)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Compression, ImageFormat};
+
+    #[tokio::test]
+    async fn test_write_async_matches_sync() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let cmd = Command::builder().format(ImageFormat::Png).build();
+        let mut expected = Vec::new();
+        cmd.serialize_chunked_into(&data, &mut expected).unwrap();
+
+        let mut out = Vec::new();
+        cmd.write_async(&data, &mut out).await.unwrap();
+        assert_eq!(out, expected);
+
+        let mut out = Vec::new();
+        cmd.write_async_from_reader(&data[..], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, expected);
+
+        // Data compressed by the caller is streamed as-is in every build
+        let cmd = cmd.to_builder().compression(Compression::Zlib).build();
+        let mut expected = Vec::new();
+        cmd.serialize_chunked_into(&data, &mut expected).unwrap();
+        let mut out = Vec::new();
+        cmd.write_async_from_reader(&data[..], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[tokio::test]
+    async fn test_response_stream() {
+        let input: &[u8] = b"\x1b[?62c\x1b_Gi=1;OK\x1b\\\x1b_Gi=2;ENOENT:gone\x1b\\";
+        let mut stream = ResponseStream::new(input);
+        let first = stream.next_response().await.unwrap().unwrap();
+        assert_eq!(first.image_id.map(|id| id.get()), Some(1));
+        let second = stream
+            .next_response_timeout(Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(!second.success);
+        assert!(stream.next_response().await.unwrap().is_none());
+
+        let (mut reader, _writer) = tokio::io::duplex(64);
+        let result = read_response_async(&mut reader, Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+}
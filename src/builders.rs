@@ -0,0 +1,604 @@
+//! Typed per-action command builders
+//!
+//! Each builder only exposes the keys that are meaningful for its action, so
+//! fields that would be ignored or misencoded cannot be set in the first
+//! place. They all produce a regular [`Command`].
+
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::types::*;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// Forward builder setters to the wrapped [`CommandBuilder`]
+macro_rules! forward {
+    ($($(#[$doc:meta])* $name:ident => $inner:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            $(#[$doc])*
+            pub fn $name(mut self, $($arg: $ty),*) -> Self {
+                self.inner = self.inner.$inner($($arg),*);
+                self
+            }
+        )*
+    };
+}
+
+/// Setters shared by every action
+macro_rules! common_setters {
+    () => {
+        forward! {
+            /// Set the image ID
+            image_id => image_id(id: ImageId);
+            /// Set the image number (alternative to image ID)
+            image_number => image_number(number: u32);
+            /// Set quiet mode
+            quiet => quiet(mode: impl Into<Quiet>);
+            /// Wrap emitted sequences for a terminal multiplexer
+            passthrough => passthrough(mode: Passthrough);
+        }
+
+        /// Build the command
+        pub fn build(self) -> Command {
+            self.inner.build()
+        }
+    };
+}
+
+/// Setters for keys describing transmitted data
+macro_rules! data_setters {
+    () => {
+        forward! {
+            /// Set the image format
+            format => format(format: ImageFormat);
+            /// Set the transmission medium
+            medium => medium(medium: TransmissionMedium);
+            /// Set the image dimensions (width, height) in pixels
+            dimensions => dimensions(width: u32, height: u32);
+            /// Declare the payload as compressed already
+            compression => compression(compression: Compression);
+            /// Skip compression for payloads smaller than `bytes`
+            compress_threshold => compress_threshold(bytes: usize);
+            /// Set file path or shared memory name
+            path => path(path: impl Into<String>);
+            /// Set data size and offset for file/shared memory
+            data_range => data_range(size: usize, offset: usize);
+            /// Set the chunk size (encoded payload bytes per escape sequence)
+            chunk_size => chunk_size(size: usize);
+        }
+
+        /// Deflate the payload during serialization and send it with `o=z`
+        #[cfg(feature = "zlib")]
+        pub fn deflate(mut self) -> Self {
+            self.inner = self.inner.deflate();
+            self
+        }
+
+        /// Compress payloads only when it noticeably reduces their size
+        pub fn auto_compress(mut self) -> Self {
+            self.inner = self.inner.auto_compress();
+            self
+        }
+    };
+}
+
+/// Setters for keys controlling how an image is placed
+macro_rules! placement_setters {
+    () => {
+        forward! {
+            /// Set the placement ID
+            placement_id => placement_id(id: PlacementId);
+            /// Set the source rectangle (x, y, width, height)
+            source_rect => source_rect(x: u32, y: u32, width: u32, height: u32);
+            /// Set cell offset (X, Y) within the current cell
+            cell_offset => cell_offset(x: u32, y: u32);
+            /// Set display area in columns and rows
+            display_area => display_area(columns: u32, rows: u32);
+            /// Set z-index
+            z_index => z_index(z: i32);
+            /// Set cursor policy
+            cursor_policy => cursor_policy(policy: CursorPolicy);
+            /// Set unicode placeholder mode
+            unicode_placeholder => unicode_placeholder(columns: u16, rows: u16);
+        }
+    };
+}
+
+/// Builder for transmitting image data without displaying it (a=t)
+#[derive(Debug, Clone)]
+pub struct TransmitBuilder {
+    inner: CommandBuilder,
+}
+
+impl Default for TransmitBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransmitBuilder {
+    /// Create a new transmit builder
+    pub fn new() -> Self {
+        Self {
+            inner: CommandBuilder::new().action(Action::Transmit),
+        }
+    }
+
+    /// Also display the image once transmitted (a=T)
+    pub fn display(self) -> TransmitDisplayBuilder {
+        TransmitDisplayBuilder {
+            inner: self.inner.action(Action::TransmitAndDisplay),
+        }
+    }
+
+    forward! {
+        /// Set the placement ID
+        placement_id => placement_id(id: PlacementId);
+    }
+
+    common_setters!();
+    data_setters!();
+}
+
+/// Builder for transmitting and displaying an image (a=T)
+#[derive(Debug, Clone)]
+pub struct TransmitDisplayBuilder {
+    inner: CommandBuilder,
+}
+
+impl Default for TransmitDisplayBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransmitDisplayBuilder {
+    /// Create a new transmit-and-display builder
+    pub fn new() -> Self {
+        TransmitBuilder::new().display()
+    }
+
+    common_setters!();
+    data_setters!();
+    placement_setters!();
+}
+
+/// Builder for placing a previously transmitted image (a=p)
+#[derive(Debug, Clone)]
+pub struct PlaceBuilder {
+    inner: CommandBuilder,
+}
+
+impl PlaceBuilder {
+    /// Create a builder placing the image with the given ID
+    pub fn new(image_id: ImageId) -> Self {
+        Self {
+            inner: CommandBuilder::new()
+                .action(Action::Place)
+                .image_id(image_id),
+        }
+    }
+
+    /// Place the image relative to an existing placement (P, Q)
+    ///
+    /// Offsets can only be set once a parent is given, so a relative offset
+    /// without a parent cannot be built.
+    pub fn relative_to(
+        self,
+        parent_image: ImageId,
+        parent_placement: PlacementId,
+    ) -> RelativePlacement {
+        RelativePlacement {
+            inner: self.inner.parent(parent_image, parent_placement),
+        }
+    }
+
+    common_setters!();
+    placement_setters!();
+}
+
+/// Builder for placing an image relative to another placement (a=p with P, Q)
+///
+/// The placement moves with its parent and never moves the cursor, so the
+/// cursor policy cannot be set.
+#[derive(Debug, Clone)]
+pub struct RelativePlacement {
+    inner: CommandBuilder,
+}
+
+impl RelativePlacement {
+    /// Create a builder placing `image_id` relative to a parent placement
+    pub fn new(image_id: ImageId, parent_image: ImageId, parent_placement: PlacementId) -> Self {
+        PlaceBuilder::new(image_id).relative_to(parent_image, parent_placement)
+    }
+
+    forward! {
+        /// Set the placement ID
+        placement_id => placement_id(id: PlacementId);
+        /// Set the offset from the parent in cells (H, V)
+        offset => relative_offset(h: i32, v: i32);
+        /// Set the source rectangle (x, y, width, height)
+        source_rect => source_rect(x: u32, y: u32, width: u32, height: u32);
+        /// Set cell offset (X, Y) within the current cell
+        cell_offset => cell_offset(x: u32, y: u32);
+        /// Set display area in columns and rows
+        display_area => display_area(columns: u32, rows: u32);
+        /// Set z-index
+        z_index => z_index(z: i32);
+    }
+
+    common_setters!();
+
+    /// Build the command, rejecting placements the terminal would refuse,
+    /// such as a placement that is its own parent
+    pub fn try_build(self) -> Result<Command> {
+        self.inner.try_build()
+    }
+}
+
+/// Builder for a virtual placement shown through unicode placeholders (a=p, U=1)
+///
+/// The terminal draws nothing until placeholder cells referencing the image
+/// are printed, so the placement ID is required to address them.
+#[derive(Debug, Clone)]
+pub struct VirtualPlacement {
+    inner: CommandBuilder,
+    image_id: ImageId,
+    placement_id: Option<PlacementId>,
+    grid: UnicodePlaceholder,
+}
+
+impl VirtualPlacement {
+    /// Create a builder for a virtual placement of `columns` x `rows` cells
+    pub fn new(image_id: ImageId, columns: u16, rows: u16) -> Self {
+        Self {
+            inner: CommandBuilder::new()
+                .action(Action::Place)
+                .image_id(image_id)
+                .unicode_placeholder(columns, rows),
+            image_id,
+            placement_id: None,
+            grid: UnicodePlaceholder { columns, rows },
+        }
+    }
+
+    /// Set the placement ID
+    pub fn placement_id(mut self, id: PlacementId) -> Self {
+        self.inner = self.inner.placement_id(id);
+        self.placement_id = Some(id);
+        self
+    }
+
+    forward! {
+        /// Set z-index
+        z_index => z_index(z: i32);
+        /// Set quiet mode
+        quiet => quiet(mode: impl Into<Quiet>);
+        /// Wrap emitted sequences for a terminal multiplexer
+        passthrough => passthrough(mode: Passthrough);
+    }
+
+    /// Build the command along with what a placeholder renderer needs
+    ///
+    /// Fails with [`Error::MissingField`] if no placement ID was set.
+    pub fn try_build(self) -> Result<(Command, PlaceholderGrid)> {
+        let placement_id = self
+            .placement_id
+            .ok_or(Error::MissingField("placement_id"))?;
+        let grid = PlaceholderGrid {
+            image_id: self.image_id,
+            placement_id,
+            columns: self.grid.columns,
+            rows: self.grid.rows,
+        };
+        Ok((self.inner.try_build()?, grid))
+    }
+}
+
+/// Image, placement and grid size addressed by placeholder cells
+///
+/// [`rows`](Self::rows) renders the placeholder text to print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlaceholderGrid {
+    /// Image ID encoded in the placeholder foreground color
+    pub image_id: ImageId,
+    /// Placement ID encoded in the placeholder underline color
+    pub placement_id: PlacementId,
+    /// Number of placeholder columns
+    pub columns: u16,
+    /// Number of placeholder rows
+    pub rows: u16,
+}
+
+/// Builder for deleting images or placements (a=d)
+#[derive(Debug, Clone)]
+pub struct DeleteBuilder {
+    inner: CommandBuilder,
+}
+
+impl DeleteBuilder {
+    /// Create a builder deleting the given target
+    pub fn new(target: DeleteTarget) -> Self {
+        Self {
+            inner: CommandBuilder::new()
+                .action(Action::Delete)
+                .delete_target(target),
+        }
+    }
+
+    forward! {
+        /// Set the placement ID
+        placement_id => placement_id(id: PlacementId);
+    }
+
+    common_setters!();
+}
+
+/// Builder for transmitting animation frame data (a=f)
+#[derive(Debug, Clone)]
+pub struct FrameBuilder {
+    inner: CommandBuilder,
+}
+
+impl FrameBuilder {
+    /// Create a builder adding a frame to the image with the given ID
+    pub fn new(image_id: ImageId) -> Self {
+        Self {
+            inner: CommandBuilder::new()
+                .action(Action::Frame)
+                .image_id(image_id),
+        }
+    }
+
+    /// Set the position (x, y) of the data within the frame
+    pub fn position(mut self, x: u32, y: u32) -> Self {
+        self.inner = self.inner.source_position(x, y);
+        self
+    }
+
+    forward! {
+        /// Edit an existing frame instead of adding a new one
+        edit_frame => edit_frame(frame: u32);
+        /// Use the given frame as the background for the new frame
+        base_frame => base_frame(frame: u32);
+        /// Set frame gap in milliseconds (negative = gapless frame)
+        gap => frame_gap(gap_ms: i32);
+        /// Set background color for frame (RGBA)
+        background_color => background_color(color: u32);
+        /// Set how the data is combined with the base frame
+        composition_mode => composition_mode(mode: CompositionMode);
+    }
+
+    common_setters!();
+    data_setters!();
+}
+
+/// Builder for controlling an animation (a=a)
+#[derive(Debug, Clone)]
+pub struct AnimateBuilder {
+    inner: CommandBuilder,
+}
+
+impl AnimateBuilder {
+    /// Create a builder controlling the animation of the given image
+    pub fn new(image_id: ImageId) -> Self {
+        Self {
+            inner: CommandBuilder::new()
+                .action(Action::AnimationControl)
+                .image_id(image_id),
+        }
+    }
+
+    forward! {
+        /// Set the animation state
+        state => animation_control(control: AnimationControl);
+        /// Make the given frame the current frame
+        current_frame => current_frame(frame: u32);
+        /// Select the frame whose gap is changed by [`AnimateBuilder::gap`]
+        edit_frame => edit_frame(frame: u32);
+        /// Set frame gap in milliseconds
+        gap => frame_gap(gap_ms: i32);
+        /// Set loop count (0 = ignored, 1 = infinite)
+        loops => loop_count(count: u32);
+    }
+
+    common_setters!();
+}
+
+/// Builder for composing animation frames (a=c)
+#[derive(Debug, Clone)]
+pub struct ComposeBuilder {
+    inner: CommandBuilder,
+}
+
+impl ComposeBuilder {
+    /// Create a builder composing frames of the given image
+    pub fn new(image_id: ImageId) -> Self {
+        Self {
+            inner: CommandBuilder::new()
+                .action(Action::ComposeFrame)
+                .image_id(image_id),
+        }
+    }
+
+    forward! {
+        /// Set frame composition parameters
+        composition => composition(comp: FrameComposition);
+    }
+
+    common_setters!();
+}
+
+/// Entry points for the typed builders
+impl Command {
+    /// Start a typed builder for transmitting image data (a=t)
+    pub fn transmit() -> TransmitBuilder {
+        TransmitBuilder::new()
+    }
+
+    /// Start a typed builder that makes the terminal read the image from a file (t=f)
+    ///
+    /// The path is made absolute, since the terminal resolves it relative to
+    /// its own working directory. Use [`TransmitBuilder::data_range`] to read
+    /// only part of the file, and serialize with
+    /// [`Command::serialize_with_path`].
+    #[cfg(feature = "std")]
+    pub fn transmit_file(path: impl AsRef<Path>, format: ImageFormat) -> Result<TransmitBuilder> {
+        let path = std::path::absolute(path.as_ref())?;
+        let path = path
+            .into_os_string()
+            .into_string()
+            .map_err(|p| Error::InvalidPath(p.to_string_lossy().into_owned()))?;
+        Ok(Self::transmit()
+            .medium(TransmissionMedium::File)
+            .format(format)
+            .path(path))
+    }
+
+    /// Start a typed builder for placing a transmitted image (a=p)
+    pub fn placement(image_id: ImageId) -> PlaceBuilder {
+        PlaceBuilder::new(image_id)
+    }
+
+    /// Start a typed builder for placing an image relative to another placement
+    pub fn relative_placement(
+        image_id: ImageId,
+        parent_image: ImageId,
+        parent_placement: PlacementId,
+    ) -> RelativePlacement {
+        RelativePlacement::new(image_id, parent_image, parent_placement)
+    }
+
+    /// Start a typed builder for a virtual placement (a=p, U=1)
+    pub fn virtual_placement(image_id: ImageId, columns: u16, rows: u16) -> VirtualPlacement {
+        VirtualPlacement::new(image_id, columns, rows)
+    }
+
+    /// Start a typed builder for deleting images (a=d)
+    pub fn delete(target: DeleteTarget) -> DeleteBuilder {
+        DeleteBuilder::new(target)
+    }
+
+    /// Start a typed builder for transmitting an animation frame (a=f)
+    pub fn frame(image_id: ImageId) -> FrameBuilder {
+        FrameBuilder::new(image_id)
+    }
+
+    /// Start a typed builder for controlling an animation (a=a)
+    pub fn animate(image_id: ImageId) -> AnimateBuilder {
+        AnimateBuilder::new(image_id)
+    }
+
+    /// Start a typed builder for composing animation frames (a=c)
+    pub fn compose(image_id: ImageId) -> ComposeBuilder {
+        ComposeBuilder::new(image_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_builders_match_command_builder() {
+        let typed = Command::transmit()
+            .display()
+            .format(ImageFormat::Rgb)
+            .dimensions(2, 2)
+            .image_id(ImageId::new(5).unwrap())
+            .z_index(-1)
+            .build();
+        let untyped = Command::builder()
+            .action(Action::TransmitAndDisplay)
+            .format(ImageFormat::Rgb)
+            .dimensions(2, 2)
+            .image_id(ImageId::new(5).unwrap())
+            .z_index(-1)
+            .build();
+        assert_eq!(
+            typed.serialize(&[0; 12]).unwrap(),
+            untyped.serialize(&[0; 12]).unwrap()
+        );
+
+        let cmd = Command::animate(ImageId::new(3).unwrap())
+            .state(AnimationControl::Run)
+            .loops(1)
+            .build();
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1b_Ga=a,i=3,s=3,v=1\x1b\\"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_transmit_file_uses_absolute_path() {
+        use base64::Engine;
+
+        let cmd = Command::transmit_file("image.png", ImageFormat::Png)
+            .unwrap()
+            .data_range(100, 8)
+            .build();
+        let seq = cmd.serialize_with_path().unwrap();
+        assert!(seq.starts_with("\x1b_Ga=t,f=100,t=f,S=100,O=8;"));
+
+        let encoded = &seq[seq.find(';').unwrap() + 1..seq.len() - 2];
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .unwrap();
+        let path = String::from_utf8(decoded).unwrap();
+        assert!(Path::new(&path).is_absolute());
+        assert!(path.ends_with("image.png"));
+    }
+
+    #[test]
+    fn test_relative_placement() {
+        let image = ImageId::new(1).unwrap();
+        let placement = PlacementId::new(2).unwrap();
+
+        let cmd = Command::placement(ImageId::new(3).unwrap())
+            .relative_to(image, placement)
+            .offset(-1, 2)
+            .try_build()
+            .unwrap();
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1b_Ga=p,i=3,P=1,Q=2,H=-1,V=2\x1b\\"
+        );
+
+        let own_parent = Command::relative_placement(image, image, placement)
+            .placement_id(placement)
+            .try_build();
+        assert!(matches!(
+            own_parent,
+            Err(Error::InvalidRelativePlacement(_))
+        ));
+    }
+
+    #[test]
+    fn test_virtual_placement() {
+        let image = ImageId::new(42).unwrap();
+        let placement = PlacementId::new(3).unwrap();
+        let (cmd, grid) = Command::virtual_placement(image, 10, 4)
+            .placement_id(placement)
+            .try_build()
+            .unwrap();
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1b_Ga=p,i=42,p=3,c=10,r=4,U=1\x1b\\"
+        );
+        assert_eq!((grid.image_id, grid.placement_id), (image, placement));
+        assert_eq!((grid.columns, grid.rows), (10, 4));
+        assert_eq!(
+            Command::parse(&cmd.serialize_control_bytes().unwrap())
+                .unwrap()
+                .0,
+            cmd
+        );
+
+        assert!(matches!(
+            Command::virtual_placement(image, 10, 4).try_build(),
+            Err(Error::MissingField("placement_id"))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+//! Process-wide cache of terminal query results
+//!
+//! Probing the terminal switches it to raw mode and waits for a reply, which
+//! is too slow to repeat for every image. The functions here run each query
+//! once and reuse the result until [`invalidate`] is called. The cached
+//! window size is also dropped when the OS reports a different number of
+//! rows or columns, which is cheap to check and sends nothing to the
+//! terminal.
+
+use crate::error::Result;
+use crate::terminal::{self, WindowSize};
+use std::sync::{Mutex, MutexGuard};
+
+/// Cached query results
+struct Cache {
+    support: Option<bool>,
+    window_size: Option<WindowSize>,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    support: None,
+    window_size: None,
+});
+
+/// Lock the cache
+///
+/// The lock is held while querying, so concurrent callers wait for the
+/// first query instead of probing the terminal again.
+fn lock() -> MutexGuard<'static, Cache> {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Check protocol support once and remember the result
+///
+/// See [`terminal::check_protocol_support`]; an override set with
+/// [`terminal::force_support`] is never cached.
+pub fn protocol_support() -> Result<bool> {
+    if let Some(supported) = terminal::forced_support() {
+        return Ok(supported);
+    }
+    let mut cache = lock();
+    if let Some(supported) = cache.support {
+        return Ok(supported);
+    }
+    let supported = terminal::check_protocol_support()?;
+    cache.support = Some(supported);
+    Ok(supported)
+}
+
+/// Get the window size, querying the terminal only when nothing is cached
+/// or the terminal was resized
+///
+/// See [`terminal::effective_window_size`].
+pub fn window_size() -> Result<WindowSize> {
+    let mut cache = lock();
+    if let Some(size) = cache.window_size {
+        let resized = terminal::get_window_size()
+            .is_ok_and(|os| (os.rows, os.cols) != (size.rows, size.cols));
+        if !resized {
+            return Ok(size);
+        }
+    }
+    let size = terminal::effective_window_size()?;
+    cache.window_size = Some(size);
+    Ok(size)
+}
+
+/// Forget all cached results so the next call queries the terminal again
+pub fn invalidate() {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.support = None;
+    cache.window_size = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidate_clears_results() {
+        let size = WindowSize {
+            rows: 24,
+            cols: 80,
+            width: 800,
+            height: 480,
+        };
+        {
+            let mut cache = lock();
+            cache.support = Some(true);
+            cache.window_size = Some(size);
+        }
+        assert!(protocol_support().unwrap());
+
+        invalidate();
+        let cache = CACHE.lock().unwrap();
+        assert_eq!(cache.support, None);
+        assert_eq!(cache.window_size, None);
+    }
+}
@@ -0,0 +1,223 @@
+//! Adapting commands to what a terminal actually supports
+//!
+//! Several terminals implement only part of the kitty graphics protocol.
+//! [`TerminalCapabilities::adapt`] rewrites a command so the same code path
+//! works across them, or rejects it when no faithful rewrite exists.
+
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::types::{Action, ProtocolVersion, TransmissionMedium};
+
+/// Graphics protocol features a terminal supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TerminalCapabilities {
+    /// Animation frames and animation control (a=f, a=a, a=c)
+    pub animation: bool,
+    /// Virtual placements through Unicode placeholders (U=1)
+    pub unicode_placeholders: bool,
+    /// Placements relative to a parent placement (P, Q, H, V)
+    pub relative_placements: bool,
+    /// Reading image data from regular files (t=f)
+    pub file: bool,
+    /// Reading image data from temporary files (t=t)
+    pub temp_file: bool,
+    /// Reading image data from shared memory (t=s)
+    pub shared_memory: bool,
+}
+
+impl TerminalCapabilities {
+    /// Full protocol support, as in current kitty
+    pub const fn kitty() -> Self {
+        Self {
+            animation: true,
+            unicode_placeholders: true,
+            relative_placements: true,
+            file: true,
+            temp_file: true,
+            shared_memory: true,
+        }
+    }
+
+    /// WezTerm: every medium, but no animation, placeholders or relative placements
+    pub const fn wezterm() -> Self {
+        Self {
+            animation: false,
+            unicode_placeholders: false,
+            relative_placements: false,
+            ..Self::kitty()
+        }
+    }
+
+    /// Ghostty: every medium and placement kind, but no animation
+    pub const fn ghostty() -> Self {
+        Self {
+            animation: false,
+            ..Self::kitty()
+        }
+    }
+
+    /// Only direct transmission of static images, as in Konsole
+    pub const fn minimal() -> Self {
+        Self {
+            animation: false,
+            unicode_placeholders: false,
+            relative_placements: false,
+            file: false,
+            temp_file: false,
+            shared_memory: false,
+        }
+    }
+
+    /// Features implied by a protocol version, with every medium
+    pub fn from_version(version: ProtocolVersion) -> Self {
+        Self {
+            animation: version >= ProtocolVersion::Animation,
+            unicode_placeholders: version >= ProtocolVersion::UnicodePlaceholders,
+            relative_placements: version >= ProtocolVersion::RelativePlacements,
+            ..Self::kitty()
+        }
+    }
+
+    /// Look up a terminal by the name it reports through XTVERSION or
+    /// `TERM_PROGRAM`, or `None` if it is not known
+    ///
+    /// For kitty, the version (e.g. `0.35.2`) selects the feature level.
+    pub fn for_terminal(name: &str, version: Option<&str>) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.contains("kitty") {
+            Some(
+                version
+                    .and_then(kitty_protocol_version)
+                    .map_or(Self::kitty(), Self::from_version),
+            )
+        } else if name.contains("wezterm") {
+            Some(Self::wezterm())
+        } else if name.contains("ghostty") {
+            Some(Self::ghostty())
+        } else if name.contains("konsole") {
+            Some(Self::minimal())
+        } else {
+            None
+        }
+    }
+
+    /// Check whether the terminal accepts a transmission medium
+    pub fn supports_medium(&self, medium: TransmissionMedium) -> bool {
+        match medium {
+            TransmissionMedium::Direct => true,
+            TransmissionMedium::File => self.file,
+            TransmissionMedium::TempFile => self.temp_file,
+            TransmissionMedium::SharedMemory => self.shared_memory,
+        }
+    }
+
+    /// Rewrite `command` for this terminal
+    ///
+    /// - Animation commands return `Ok(None)` when animation is unsupported;
+    ///   skipping them leaves the first frame on screen.
+    /// - Virtual placements become regular placements of the same size.
+    /// - Unsupported mediums are switched to direct transmission, so the
+    ///   command must then be serialized with the image data.
+    /// - Relative placements fail with [`Error::UnsupportedByTerminal`],
+    ///   since dropping the parent would put the image somewhere else.
+    pub fn adapt(&self, command: Command) -> Result<Option<Command>> {
+        let mut builder: CommandBuilder = command.into_builder();
+
+        if !self.animation
+            && matches!(
+                builder.effective_action(),
+                Action::Frame | Action::AnimationControl | Action::ComposeFrame
+            )
+        {
+            return Ok(None);
+        }
+        if !self.relative_placements && builder.has_parent() {
+            return Err(Error::UnsupportedByTerminal("relative placements"));
+        }
+        if !self.unicode_placeholders {
+            builder.drop_unicode_placeholder();
+        }
+        if !self.supports_medium(builder.effective_medium()) {
+            builder.force_direct();
+        }
+
+        Ok(Some(builder.build()))
+    }
+}
+
+/// Protocol feature level of a kitty release
+fn kitty_protocol_version(version: &str) -> Option<ProtocolVersion> {
+    let mut parts = version.split('.').map(str::parse::<u32>);
+    let (Some(Ok(major)), Some(Ok(minor))) = (parts.next(), parts.next()) else {
+        return None;
+    };
+    Some(match (major, minor) {
+        (0, 0..20) => ProtocolVersion::Base,
+        (0, 20..28) => ProtocolVersion::Animation,
+        (0, 28..31) => ProtocolVersion::UnicodePlaceholders,
+        _ => ProtocolVersion::RelativePlacements,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ImageId, PlacementId};
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_adapt_to_capabilities() {
+        let id = ImageId::new(1).unwrap();
+        let wezterm = TerminalCapabilities::wezterm();
+
+        let animate = Command::animate(id).current_frame(2).build();
+        assert_eq!(wezterm.adapt(animate.clone()).unwrap(), None);
+        assert_eq!(
+            TerminalCapabilities::kitty()
+                .adapt(animate.clone())
+                .unwrap(),
+            Some(animate)
+        );
+
+        let (virt, _) = Command::virtual_placement(id, 8, 3)
+            .placement_id(PlacementId::new(1).unwrap())
+            .try_build()
+            .unwrap();
+        let placed = wezterm.adapt(virt).unwrap().unwrap();
+        assert_eq!(placed.to_string(), "\x1b_Ga=p,i=1,p=1,c=8,r=3\x1b\\");
+
+        let file = Command::builder()
+            .medium(TransmissionMedium::File)
+            .path("/tmp/image.png")
+            .build();
+        let direct = TerminalCapabilities::minimal()
+            .adapt(file)
+            .unwrap()
+            .unwrap();
+        assert_eq!(direct, Command::builder().build());
+
+        let relative =
+            Command::relative_placement(id, ImageId::new(2).unwrap(), PlacementId::new(1).unwrap())
+                .build();
+        assert!(matches!(
+            wezterm.adapt(relative),
+            Err(Error::UnsupportedByTerminal(_))
+        ));
+    }
+
+    #[test]
+    fn test_for_terminal() {
+        let old_kitty = TerminalCapabilities::for_terminal("kitty", Some("0.26.5")).unwrap();
+        assert!(old_kitty.animation && !old_kitty.unicode_placeholders);
+        assert_eq!(
+            TerminalCapabilities::for_terminal("kitty", Some("0.35.2")),
+            Some(TerminalCapabilities::kitty())
+        );
+        assert_eq!(
+            TerminalCapabilities::for_terminal("WezTerm", None),
+            Some(TerminalCapabilities::wezterm())
+        );
+        assert_eq!(TerminalCapabilities::for_terminal("xterm", None), None);
+    }
+}
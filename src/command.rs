@@ -1,13 +1,22 @@
 //! Command building and serialization for the Kitty graphics protocol
 
+use crate::encoding;
 use crate::error::{Error, Result};
 use crate::types::*;
-use crate::{APC_END, APC_START, GRAPHICS_PREFIX, MAX_CHUNK_SIZE};
+use crate::{GRAPHICS_PREFIX, MAX_CHUNK_SIZE};
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use base64::{Engine, engine::general_purpose::STANDARD};
-use std::fmt;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
 
 /// Builder for constructing graphics protocol commands
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct CommandBuilder {
     /// Action to perform
     action: Option<Action>,
@@ -20,17 +29,17 @@ pub struct CommandBuilder {
     /// Image height in pixels
     height: Option<u32>,
     /// Image ID (0-4294967295, must not be zero for some operations)
-    image_id: Option<u32>,
+    image_id: Option<ImageId>,
     /// Image number (alternative to image_id)
     image_number: Option<u32>,
     /// Placement ID
-    placement_id: Option<u32>,
+    placement_id: Option<PlacementId>,
     /// More data flag (0 = last chunk, 1 = more chunks)
     more_data: Option<bool>,
     /// Compression algorithm
     compression: Option<Compression>,
-    /// Quiet mode (1 = suppress OK, 2 = suppress errors)
-    quiet: Option<u8>,
+    /// Response verbosity
+    quiet: Option<Quiet>,
     /// Source rectangle X offset
     source_x: Option<u32>,
     /// Source rectangle Y offset
@@ -62,17 +71,21 @@ pub struct CommandBuilder {
     /// Unicode placeholder mode
     unicode_placeholder: Option<UnicodePlaceholder>,
     /// Parent image ID for relative placement
-    parent_image_id: Option<u32>,
+    parent_image_id: Option<ImageId>,
     /// Parent placement ID for relative placement
-    parent_placement_id: Option<u32>,
+    parent_placement_id: Option<PlacementId>,
     /// Horizontal offset for relative placement
     relative_h_offset: Option<i32>,
     /// Vertical offset for relative placement
     relative_v_offset: Option<i32>,
     /// Animation control
     animation_control: Option<AnimationControl>,
-    /// Animation frame number (for various operations)
+    /// Animation frame number, resolved by action (see `frame_number`)
     frame_number: Option<u32>,
+    /// Frame to edit (r) for a=f and a=a
+    edit_frame: Option<u32>,
+    /// Frame to make current (c) for a=a
+    current_frame: Option<u32>,
     /// Frame gap in milliseconds
     frame_gap: Option<i32>,
     /// Loop count (0 = ignored, 1 = infinite)
@@ -83,6 +96,22 @@ pub struct CommandBuilder {
     ref_frame: Option<u32>,
     /// Frame composition parameters
     composition: Option<FrameComposition>,
+    /// Composition mode for frame data (a=f)
+    composition_mode: Option<CompositionMode>,
+    /// Encoded payload bytes per chunk for chunked transmission
+    chunk_size: Option<usize>,
+    /// Payloads smaller than this are sent uncompressed
+    compress_threshold: Option<usize>,
+    /// Deflate payloads during serialization
+    deflate: bool,
+    /// Let the library decide whether to compress each payload
+    auto_compress: bool,
+    /// Keys not modelled by this crate, with their formatted values
+    raw_keys: Vec<(char, String)>,
+    /// Reject features newer than this protocol version
+    strict: Option<ProtocolVersion>,
+    /// Wrapping of the emitted sequences for terminal multiplexers
+    passthrough: Passthrough,
 }
 
 impl CommandBuilder {
@@ -117,7 +146,7 @@ impl CommandBuilder {
     }
 
     /// Set the image ID
-    pub fn image_id(mut self, id: u32) -> Self {
+    pub fn image_id(mut self, id: ImageId) -> Self {
         self.image_id = Some(id);
         self
     }
@@ -129,7 +158,7 @@ impl CommandBuilder {
     }
 
     /// Set the placement ID
-    pub fn placement_id(mut self, id: u32) -> Self {
+    pub fn placement_id(mut self, id: PlacementId) -> Self {
         self.placement_id = Some(id);
         self
     }
@@ -140,15 +169,37 @@ impl CommandBuilder {
         self
     }
 
-    /// Set compression
+    /// Declare the payload as compressed already
+    ///
+    /// The payload is sent as-is with `o=`, whatever features are enabled.
+    /// To have the library compress it, use `deflate` (`zlib` feature).
     pub fn compression(mut self, compression: Compression) -> Self {
         self.compression = Some(compression);
         self
     }
 
-    /// Set quiet mode (1 = suppress OK, 2 = suppress errors)
-    pub fn quiet(mut self, mode: u8) -> Self {
-        self.quiet = Some(mode);
+    /// Deflate the payload during serialization and send it with `o=z`
+    ///
+    /// Ignored when the payload is declared compressed with
+    /// [`compression`](Self::compression).
+    #[cfg(feature = "zlib")]
+    pub fn deflate(mut self) -> Self {
+        self.deflate = true;
+        self
+    }
+
+    /// Skip compression for payloads smaller than `bytes`
+    ///
+    /// Applies to `deflate` and [`auto_compress`](Self::auto_compress);
+    /// small payloads rarely shrink enough to be worth the extra work.
+    pub fn compress_threshold(mut self, bytes: usize) -> Self {
+        self.compress_threshold = Some(bytes);
+        self
+    }
+
+    /// Set quiet mode; raw `u8` values above 2 clamp to [`Quiet::SuppressAll`]
+    pub fn quiet(mut self, mode: impl Into<Quiet>) -> Self {
+        self.quiet = Some(mode.into());
         self
     }
 
@@ -161,6 +212,16 @@ impl CommandBuilder {
         self
     }
 
+    /// Set only the (x, y) origin of the source rectangle
+    ///
+    /// Used by actions where `x`/`y` are a position rather than a crop,
+    /// such as the frame position for a=f or the cell for a=d.
+    pub(crate) fn source_position(mut self, x: u32, y: u32) -> Self {
+        self.source_x = Some(x);
+        self.source_y = Some(y);
+        self
+    }
+
     /// Set cell offset (X, Y) within the current cell
     pub fn cell_offset(mut self, x: u32, y: u32) -> Self {
         self.cell_offset_x = Some(x);
@@ -206,14 +267,16 @@ impl CommandBuilder {
         self
     }
 
-    /// Set unicode placeholder mode
+    /// Set unicode placeholder mode (U=1) with the placeholder grid size
+    ///
+    /// The grid size is sent as `c`/`r` unless a display area is set.
     pub fn unicode_placeholder(mut self, columns: u16, rows: u16) -> Self {
         self.unicode_placeholder = Some(UnicodePlaceholder { columns, rows });
         self
     }
 
     /// Set parent for relative placement
-    pub fn parent(mut self, image_id: u32, placement_id: u32) -> Self {
+    pub fn parent(mut self, image_id: ImageId, placement_id: PlacementId) -> Self {
         self.parent_image_id = Some(image_id);
         self.parent_placement_id = Some(placement_id);
         self
@@ -233,11 +296,35 @@ impl CommandBuilder {
     }
 
     /// Set frame number
+    ///
+    /// This is the frame to edit for a=f and the frame to make current for
+    /// a=a. Prefer [`edit_frame`](Self::edit_frame) and
+    /// [`current_frame`](Self::current_frame), which say which one is meant.
     pub fn frame_number(mut self, frame: u32) -> Self {
         self.frame_number = Some(frame);
         self
     }
 
+    /// Set the 1-based frame to edit (r)
+    ///
+    /// For a=f the data replaces part of this frame instead of creating a
+    /// new one; for a=a this is the frame whose gap is changed.
+    pub fn edit_frame(mut self, frame: u32) -> Self {
+        self.edit_frame = Some(frame);
+        self
+    }
+
+    /// Set the 1-based frame to make the current frame (c, a=a only)
+    pub fn current_frame(mut self, frame: u32) -> Self {
+        self.current_frame = Some(frame);
+        self
+    }
+
+    /// Set the 1-based frame used as the background of a new frame (c, a=f only)
+    pub fn base_frame(self, frame: u32) -> Self {
+        self.ref_frame(frame)
+    }
+
     /// Set frame gap in milliseconds (negative = gapless frame)
     pub fn frame_gap(mut self, gap_ms: i32) -> Self {
         self.frame_gap = Some(gap_ms);
@@ -268,14 +355,365 @@ impl CommandBuilder {
         self
     }
 
+    /// Set how frame data is combined with the base frame (a=f)
+    pub fn composition_mode(mut self, mode: CompositionMode) -> Self {
+        self.composition_mode = Some(mode);
+        self
+    }
+
+    /// Compress payloads only when it noticeably reduces their size
+    ///
+    /// A prefix of each payload is compressed to estimate the ratio;
+    /// screenshots and flat graphics are compressed, photos and PNG data
+    /// usually are not. Requires the `zlib` feature, otherwise this is a no-op.
+    pub fn auto_compress(mut self) -> Self {
+        self.auto_compress = true;
+        self
+    }
+
+    /// Reject features the given protocol version doesn't support
+    ///
+    /// Serialization fails with [`Error::UnsupportedFeature`] if the command
+    /// needs a newer feature level, for example a relative placement when
+    /// targeting a terminal without them. Raw keys are not checked.
+    pub fn strict(mut self, version: ProtocolVersion) -> Self {
+        self.strict = Some(version);
+        self
+    }
+
+    /// Wrap emitted sequences for a terminal multiplexer such as tmux
+    pub fn passthrough(mut self, mode: Passthrough) -> Self {
+        self.passthrough = mode;
+        self
+    }
+
+    /// Set a control key this crate doesn't model yet
+    ///
+    /// An escape hatch for keys added by newer terminal versions. The key
+    /// must be an ASCII letter other than `m`; it is written after all typed
+    /// fields, and serialization fails with [`Error::ConflictingKey`] if a
+    /// typed setter or another raw key already wrote it. The value is checked
+    /// like any other control value.
+    pub fn raw_key(mut self, key: char, value: impl fmt::Display) -> Self {
+        self.raw_keys.push((key, value.to_string()));
+        self
+    }
+
+    /// Set the chunk size (encoded payload bytes per escape sequence)
+    ///
+    /// Must be a non-zero multiple of 4 and at most [`MAX_CHUNK_SIZE`];
+    /// this is checked when the command is serialized in chunks.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// Build the command, validating that the fields form a valid command
+    ///
+    /// Catches combinations the terminal would reject with `EINVAL`, such as
+    /// raw pixel data without dimensions, relative offsets without a parent,
+    /// a placement that is its own parent, placement IDs without an image
+    /// reference, or fields that have no meaning for the action.
+    pub fn try_build(self) -> Result<Command> {
+        self.check_fields_for_action()?;
+        let action = self.effective_action();
+
+        // Raw pixel data needs its dimensions
+        let raw = self.format.unwrap_or_default() != ImageFormat::Png;
+        if raw && matches!(action, Action::Transmit | Action::TransmitAndDisplay) {
+            match (self.width, self.height) {
+                (Some(width), Some(height)) if width == 0 || height == 0 => {
+                    return Err(Error::InvalidDimensions { width, height });
+                }
+                (Some(_), Some(_)) => {}
+                _ => return Err(Error::MissingField("dimensions")),
+            }
+        }
+
+        let has_image = self.image_id.is_some() || self.image_number.is_some();
+        if self.placement_id.is_some() && !has_image {
+            return Err(Error::MissingField("image_id"));
+        }
+
+        if (self.relative_h_offset.is_some() || self.relative_v_offset.is_some())
+            && self.parent_image_id.is_none()
+        {
+            return Err(Error::MissingField("parent"));
+        }
+
+        if self.parent_image_id.is_some() {
+            if self.parent_image_id == self.image_id
+                && self.parent_placement_id == self.placement_id
+            {
+                return Err(Error::InvalidRelativePlacement(
+                    "a placement cannot be its own parent",
+                ));
+            }
+            // Relative placements never move the cursor
+            if self.cursor_policy.is_some() {
+                return Err(Error::InvalidRelativePlacement(
+                    "cursor policy has no effect on relative placements",
+                ));
+            }
+        }
+
+        match self.delete_target {
+            Some(DeleteTarget::ById { .. }) if self.image_id.is_none() => {
+                return Err(Error::MissingField("image_id"));
+            }
+            Some(DeleteTarget::ByNumber { .. }) if self.image_number.is_none() => {
+                return Err(Error::MissingField("image_number"));
+            }
+            _ => {}
+        }
+
+        if matches!(
+            action,
+            Action::Frame | Action::AnimationControl | Action::ComposeFrame
+        ) && !has_image
+        {
+            return Err(Error::MissingField("image_id"));
+        }
+
+        Ok(self.build())
+    }
+
+    /// The feature level the command needs, with the field that needs it
+    fn required_version(&self) -> (ProtocolVersion, &'static str) {
+        use Action::*;
+
+        if self.has_parent() {
+            return (ProtocolVersion::RelativePlacements, "parent");
+        }
+        if self.unicode_placeholder.is_some() {
+            return (ProtocolVersion::UnicodePlaceholders, "unicode_placeholder");
+        }
+        if matches!(
+            self.effective_action(),
+            Frame | AnimationControl | ComposeFrame
+        ) {
+            return (ProtocolVersion::Animation, "action");
+        }
+        if self.image_number.is_some() {
+            return (ProtocolVersion::Animation, "image_number");
+        }
+        (ProtocolVersion::Base, "action")
+    }
+
+    /// Check the command against the version set with [`CommandBuilder::strict`]
+    fn check_version(&self) -> Result<()> {
+        let Some(version) = self.strict else {
+            return Ok(());
+        };
+        let (required, field) = self.required_version();
+        if required > version {
+            return Err(Error::UnsupportedFeature {
+                field,
+                required,
+                version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether an image ID or number is set, so the terminal will reply
+    #[cfg(feature = "std")]
+    pub(crate) fn has_image_ref(&self) -> bool {
+        self.image_id.is_some() || self.image_number.is_some()
+    }
+
+    /// The action, defaulting to a=t as the protocol does
+    pub(crate) fn effective_action(&self) -> Action {
+        self.action.unwrap_or(Action::Transmit)
+    }
+
+    /// The transmission medium, defaulting to t=d
+    pub(crate) fn effective_medium(&self) -> TransmissionMedium {
+        self.medium.unwrap_or(TransmissionMedium::Direct)
+    }
+
+    /// The pixel format, defaulting to f=32
+    pub(crate) fn effective_format(&self) -> ImageFormat {
+        self.format.unwrap_or_default()
+    }
+
+    /// Whether the placement is relative to a parent
+    pub(crate) fn has_parent(&self) -> bool {
+        self.parent_image_id.is_some()
+            || self.parent_placement_id.is_some()
+            || self.relative_h_offset.is_some()
+            || self.relative_v_offset.is_some()
+    }
+
+    /// Switch to direct transmission, dropping the path and data range
+    pub(crate) fn force_direct(&mut self) {
+        self.medium = None;
+        self.path = None;
+        self.data_size = None;
+        self.data_offset = None;
+    }
+
+    /// Turn a virtual placement into a regular one of the same size
+    pub(crate) fn drop_unicode_placeholder(&mut self) {
+        if let Some(u) = self.unicode_placeholder.take() {
+            self.columns.get_or_insert(u32::from(u.columns));
+            self.rows.get_or_insert(u32::from(u.rows));
+        }
+    }
+
+    /// Reject fields that have no meaning for the action
+    fn check_fields_for_action(&self) -> Result<()> {
+        use Action::*;
+
+        let action = self.effective_action();
+        let transmits = matches!(action, Query | Transmit | TransmitAndDisplay | Frame);
+        let places = matches!(action, TransmitAndDisplay | Place);
+        let allow = |set: bool, field: &'static str, allowed: bool| {
+            if set && !allowed {
+                Err(Error::InvalidFieldForAction { field, action })
+            } else {
+                Ok(())
+            }
+        };
+
+        allow(self.format.is_some(), "format", transmits)?;
+        allow(self.medium.is_some(), "medium", transmits)?;
+        allow(self.width.is_some(), "width", transmits)?;
+        allow(self.height.is_some(), "height", transmits)?;
+        allow(
+            self.placement_id.is_some(),
+            "placement_id",
+            matches!(action, Transmit | TransmitAndDisplay | Place | Delete),
+        )?;
+        allow(self.more_data.is_some(), "more_data", transmits)?;
+        allow(self.compression.is_some(), "compression", transmits)?;
+        allow(
+            self.source_x.is_some() || self.source_y.is_some(),
+            "source_rect",
+            places || action == Frame,
+        )?;
+        allow(
+            self.source_width.is_some() || self.source_height.is_some(),
+            "source_rect",
+            places,
+        )?;
+        allow(
+            self.cell_offset_x.is_some() || self.cell_offset_y.is_some(),
+            "cell_offset",
+            places,
+        )?;
+        allow(
+            self.columns.is_some() || self.rows.is_some(),
+            "display_area",
+            places,
+        )?;
+        allow(self.z_index.is_some(), "z_index", places)?;
+        allow(self.cursor_policy.is_some(), "cursor_policy", places)?;
+        allow(
+            self.delete_target.is_some(),
+            "delete_target",
+            action == Delete,
+        )?;
+        allow(
+            self.data_size.is_some() || self.data_offset.is_some(),
+            "data_range",
+            transmits,
+        )?;
+        allow(
+            self.unicode_placeholder.is_some(),
+            "unicode_placeholder",
+            places,
+        )?;
+        allow(
+            self.parent_image_id.is_some() || self.parent_placement_id.is_some(),
+            "parent",
+            places,
+        )?;
+        allow(
+            self.relative_h_offset.is_some() || self.relative_v_offset.is_some(),
+            "relative_offset",
+            places,
+        )?;
+        allow(
+            self.animation_control.is_some(),
+            "animation_control",
+            action == AnimationControl,
+        )?;
+        allow(
+            self.frame_number.is_some(),
+            "frame_number",
+            matches!(action, Frame | AnimationControl),
+        )?;
+        allow(
+            self.edit_frame.is_some(),
+            "edit_frame",
+            matches!(action, Frame | AnimationControl),
+        )?;
+        allow(
+            self.current_frame.is_some(),
+            "current_frame",
+            action == AnimationControl,
+        )?;
+        allow(
+            self.frame_gap.is_some(),
+            "frame_gap",
+            matches!(action, Frame | AnimationControl),
+        )?;
+        allow(
+            self.loop_count.is_some(),
+            "loop_count",
+            action == AnimationControl,
+        )?;
+        allow(
+            self.background_color.is_some(),
+            "background_color",
+            action == Frame,
+        )?;
+        allow(self.ref_frame.is_some(), "ref_frame", action == Frame)?;
+        allow(
+            self.composition.is_some(),
+            "composition",
+            action == ComposeFrame,
+        )?;
+        allow(
+            self.composition_mode.is_some(),
+            "composition_mode",
+            action == Frame,
+        )?;
+
+        Ok(())
+    }
+
     /// Build the command
     pub fn build(self) -> Command {
         Command { inner: self }
     }
 }
 
+impl TryFrom<CommandBuilder> for Command {
+    type Error = Error;
+
+    fn try_from(builder: CommandBuilder) -> Result<Command> {
+        builder.try_build()
+    }
+}
+
+impl From<Command> for CommandBuilder {
+    fn from(command: Command) -> Self {
+        command.into_builder()
+    }
+}
+
 /// A graphics protocol command ready for serialization
-#[derive(Debug, Clone)]
+///
+/// With the `serde` feature a command (de)serializes as its builder fields;
+/// deserialization runs [`CommandBuilder::try_build`] validation.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "CommandBuilder", into = "CommandBuilder")
+)]
 pub struct Command {
     inner: CommandBuilder,
 }
@@ -286,346 +724,1453 @@ impl Command {
         CommandBuilder::new()
     }
 
+    /// The oldest protocol feature level that understands this command
+    pub fn required_version(&self) -> ProtocolVersion {
+        self.inner.required_version().0
+    }
+
+    /// Turn the command back into a builder to tweak and rebuild it
+    ///
+    /// Handy for re-placing an image at a new location by changing only the
+    /// placement ID or z-index.
+    pub fn into_builder(self) -> CommandBuilder {
+        self.inner
+    }
+
+    /// Copy the command's fields into a new builder
+    pub fn to_builder(&self) -> CommandBuilder {
+        self.inner.clone()
+    }
+
     /// Build the control data string (key=value pairs)
-    fn build_control_data(&self) -> String {
-        let mut parts = Vec::new();
+    fn build_control_data(&self, payload: Option<&Payload<'_>>) -> Result<String> {
+        let mut buf = Vec::new();
+        self.write_control_data(&mut ControlWriter::new(&mut buf), payload)?;
+        String::from_utf8(buf).map_err(Error::from)
+    }
+
+    /// Write the control data (key=value pairs) without allocating
+    ///
+    /// Several keys mean different things depending on the action (for
+    /// example `s` is the image width when transmitting but the animation
+    /// state for `a=a`), so the key for each field is chosen from the action.
+    /// Fields that have no meaning for the action are rejected.
+    ///
+    /// `payload` describes the data that follows, if any, so that keys which
+    /// depend on it (such as `o=`) are only sent when they apply.
+    fn write_control_data<S: Sink>(
+        &self,
+        cw: &mut ControlWriter<'_, S>,
+        payload: Option<&Payload<'_>>,
+    ) -> Result<()> {
+        self.inner.check_fields_for_action()?;
+        self.inner.check_version()?;
+        self.write_keys(cw, payload, true)
+    }
+
+    /// Check that the control data can be written, by writing it nowhere
+    ///
+    /// Streaming serializers call this before writing the start of the
+    /// sequence, so a rejected command, including a raw key that repeats
+    /// another key, never leaves an unterminated escape sequence behind.
+    fn check_control_data(&self, payload: Option<&Payload<'_>>) -> Result<()> {
+        self.write_control_data(&mut ControlWriter::new(&mut Discard), payload)
+    }
+
+    /// Write the keys of the control data
+    ///
+    /// Raw keys that are invalid, repeat another key or hold unsafe bytes
+    /// fail when `strict`, and are skipped otherwise.
+    fn write_keys<S: Sink>(
+        &self,
+        cw: &mut ControlWriter<'_, S>,
+        payload: Option<&Payload<'_>>,
+        strict: bool,
+    ) -> Result<()> {
+        let inner = &self.inner;
+        let action = inner.effective_action();
 
         // Action (a)
-        if let Some(action) = &self.inner.action {
-            parts.push(format!("a={action}"));
+        if let Some(action) = &inner.action {
+            cw.key('a', action)?;
         }
 
         // Format (f)
-        if let Some(format) = &self.inner.format {
-            parts.push(format!("f={format}"));
+        if let Some(format) = &inner.format {
+            cw.key('f', format)?;
         }
 
         // Transmission medium (t)
-        if let Some(medium) = &self.inner.medium {
-            parts.push(format!("t={medium}"));
+        if let Some(medium) = &inner.medium {
+            cw.key('t', medium)?;
         }
 
         // Image dimensions (s, v)
-        if let Some(width) = self.inner.width {
-            parts.push(format!("s={width}"));
+        if let Some(width) = inner.width {
+            cw.key('s', width)?;
         }
-        if let Some(height) = self.inner.height {
-            parts.push(format!("v={height}"));
+        if let Some(height) = inner.height {
+            cw.key('v', height)?;
         }
 
         // Image ID (i) or Image Number (I)
-        if let Some(id) = self.inner.image_id {
-            parts.push(format!("i={id}"));
-        } else if let Some(num) = self.inner.image_number {
-            parts.push(format!("I={num}"));
+        if let Some(id) = inner.image_id {
+            cw.key('i', id)?;
+        } else if let Some(num) = inner.image_number {
+            cw.key('I', num)?;
         }
 
         // Placement ID (p)
-        if let Some(id) = self.inner.placement_id {
-            parts.push(format!("p={id}"));
+        if let Some(id) = inner.placement_id {
+            cw.key('p', id)?;
         }
 
         // More data flag (m)
-        if let Some(more) = self.inner.more_data {
-            parts.push(format!("m={}", if more { 1 } else { 0 }));
+        if let Some(more) = inner.more_data {
+            cw.key('m', if more { 1 } else { 0 })?;
         }
 
         // Compression (o)
-        if let Some(comp) = &self.inner.compression {
-            parts.push(format!("o={comp}"));
+        match payload {
+            Some(p) if p.compressed => {
+                cw.key('o', inner.compression.unwrap_or(Compression::Zlib))?
+            }
+            Some(_) => {}
+            None => {
+                if let Some(comp) = &inner.compression {
+                    cw.key('o', comp)?;
+                }
+            }
         }
 
         // Quiet mode (q)
-        if let Some(quiet) = self.inner.quiet {
-            parts.push(format!("q={quiet}"));
+        if let Some(quiet) = inner.quiet {
+            cw.key('q', quiet)?;
         }
 
-        // Source rectangle (x, y, w, h)
-        if let Some(x) = self.inner.source_x {
-            parts.push(format!("x={x}"));
+        // Source rectangle (x, y, w, h); for a=f the position in the frame
+        if let Some(x) = inner.source_x {
+            cw.key('x', x)?;
         }
-        if let Some(y) = self.inner.source_y {
-            parts.push(format!("y={y}"));
+        if let Some(y) = inner.source_y {
+            cw.key('y', y)?;
         }
-        if let Some(w) = self.inner.source_width {
-            parts.push(format!("w={w}"));
+        if let Some(w) = inner.source_width {
+            cw.key('w', w)?;
         }
-        if let Some(h) = self.inner.source_height {
-            parts.push(format!("h={h}"));
+        if let Some(h) = inner.source_height {
+            cw.key('h', h)?;
         }
 
         // Cell offset (X, Y)
-        if let Some(x) = self.inner.cell_offset_x {
-            parts.push(format!("X={x}"));
+        if let Some(x) = inner.cell_offset_x {
+            cw.key('X', x)?;
         }
-        if let Some(y) = self.inner.cell_offset_y {
-            parts.push(format!("Y={y}"));
+        if let Some(y) = inner.cell_offset_y {
+            cw.key('Y', y)?;
         }
 
-        // Display area (c, r)
-        if let Some(cols) = self.inner.columns {
-            parts.push(format!("c={cols}"));
+        // Display area (c, r); a virtual placement takes its grid size from
+        // the unicode placeholder
+        let placeholder = inner.unicode_placeholder;
+        if let Some(cols) = inner.columns.or(placeholder.map(|u| u32::from(u.columns))) {
+            cw.key('c', cols)?;
         }
-        if let Some(rows) = self.inner.rows {
-            parts.push(format!("r={rows}"));
+        if let Some(rows) = inner.rows.or(placeholder.map(|u| u32::from(u.rows))) {
+            cw.key('r', rows)?;
         }
 
         // Z-index (z)
-        if let Some(z) = self.inner.z_index {
-            parts.push(format!("z={z}"));
+        if let Some(z) = inner.z_index {
+            cw.key('z', z)?;
         }
 
         // Cursor policy (C)
-        if let Some(policy) = &self.inner.cursor_policy
+        if let Some(policy) = &inner.cursor_policy
             && matches!(policy, CursorPolicy::NoMove)
         {
-            parts.push(format!("C={policy}"));
+            cw.key('C', policy)?;
         }
 
-        // Delete target (d)
-        if let Some(target) = &self.inner.delete_target {
-            parts.push(format!("d={}", target.code()));
+        // Delete target (d) with its cell, range or z-index keys
+        if let Some(target) = &inner.delete_target {
+            cw.key('d', target.code())?;
+            let (x, y, z) = target.params();
+            if let Some(x) = x {
+                cw.key('x', x)?;
+            }
+            if let Some(y) = y {
+                cw.key('y', y)?;
+            }
+            if let Some(z) = z {
+                cw.key('z', z)?;
+            }
         }
 
-        // File path or shared memory name
-        if let Some(_path) = &self.inner.path {
-            // The path needs to be encoded in the payload, not control data
-            // We'll handle this separately in serialize_with_path
-        }
+        // The path (for file/shared memory) is sent as the payload by
+        // serialize_with_path, not as control data
 
         // Data size (S) and offset (O)
-        if let Some(size) = self.inner.data_size {
-            parts.push(format!("S={size}"));
+        // Compressed PNG data also needs the uncompressed size
+        if let Some(size) = inner.data_size {
+            cw.key('S', size)?;
+        } else if let Some(p) = payload
+            && p.compressed
+            && inner.format == Some(ImageFormat::Png)
+            && let Some(raw_len) = p.raw_len
+        {
+            cw.key('S', raw_len)?;
         }
-        if let Some(offset) = self.inner.data_offset {
-            parts.push(format!("O={offset}"));
+        if let Some(offset) = inner.data_offset {
+            cw.key('O', offset)?;
         }
 
         // Unicode placeholder (U)
-        if self.inner.unicode_placeholder.is_some() {
-            parts.push("U=1".to_string());
+        if inner.unicode_placeholder.is_some() {
+            cw.key('U', 1)?;
         }
 
         // Parent for relative placement (P, Q)
-        if let Some(id) = self.inner.parent_image_id {
-            parts.push(format!("P={id}"));
+        if let Some(id) = inner.parent_image_id {
+            cw.key('P', id)?;
         }
-        if let Some(id) = self.inner.parent_placement_id {
-            parts.push(format!("Q={id}"));
+        if let Some(id) = inner.parent_placement_id {
+            cw.key('Q', id)?;
         }
 
         // Relative offset (H, V)
-        if let Some(h) = self.inner.relative_h_offset {
-            parts.push(format!("H={h}"));
+        if let Some(h) = inner.relative_h_offset {
+            cw.key('H', h)?;
+        }
+        if let Some(v) = inner.relative_v_offset {
+            cw.key('V', v)?;
+        }
+
+        // Animation state (s), only valid for a=a
+        if let Some(control) = &inner.animation_control {
+            cw.key('s', control)?;
         }
-        if let Some(v) = self.inner.relative_v_offset {
-            parts.push(format!("V={v}"));
+
+        // Frame to edit (r) for a=f and a=a; a plain frame number means
+        // the edited frame for a=f
+        let legacy_edit = inner.frame_number.filter(|_| action == Action::Frame);
+        if let Some(frame) = inner.edit_frame.or(legacy_edit) {
+            cw.key('r', frame)?;
         }
 
-        // Animation control (s) - note: same letter as width, context matters
-        // For animation control, this is set via action=a
-        // The animation state is controlled by s=1/2/3
-        if let Some(control) = &self.inner.animation_control {
-            parts.push(format!("s={control}"));
+        // Frame to make current (c) for a=a; a plain frame number means the
+        // current frame for a=a
+        let legacy_current = inner
+            .frame_number
+            .filter(|_| action == Action::AnimationControl);
+        if let Some(frame) = inner.current_frame.or(legacy_current) {
+            cw.key('c', frame)?;
         }
 
-        // Frame number for various operations
-        // For animation frame: c=frame number
-        // For frame edit: r=frame number
-        if let Some(frame) = self.inner.frame_number {
-            // Context determines which key to use
-            // For now, use 'c' for frame selection
-            parts.push(format!("c={frame}"));
+        // Reference (background) frame (c) for a=f
+        if let Some(frame) = inner.ref_frame {
+            cw.key('c', frame)?;
         }
 
-        // Frame gap (z) - note: same letter as z-index
-        // When action=f, z means frame gap
-        if let Some(gap) = self.inner.frame_gap
+        // Frame gap (z), only valid for a=f and a=a
+        if let Some(gap) = inner.frame_gap
             && gap != 0
         {
-            parts.push(format!("z={gap}"));
+            cw.key('z', gap)?;
         }
 
-        // Loop count (v) - note: same letter as height
-        // When action=a, v means loop count
-        if let Some(count) = self.inner.loop_count
+        // Loop count (v), only valid for a=a
+        if let Some(count) = inner.loop_count
             && count > 0
         {
-            parts.push(format!("v={count}"));
+            cw.key('v', count)?;
+        }
+
+        // Background color (Y), only valid for a=f
+        if let Some(color) = inner.background_color {
+            cw.key('Y', color)?;
+        }
+
+        // Composition mode (X) for frame data, only valid for a=f
+        if let Some(mode) = inner.composition_mode
+            && mode != CompositionMode::AlphaBlend
+        {
+            cw.key('X', mode)?;
+        }
+
+        // Frame composition (a=c)
+        if let Some(comp) = &inner.composition {
+            write_composition(cw, comp)?;
+        }
+
+        // Raw keys, which must not repeat a key written above; `m` is
+        // reserved for chunking
+        for (key, value) in &inner.raw_keys {
+            let error = if !key.is_ascii_alphabetic() {
+                Some(Error::InvalidKey(*key))
+            } else if *key == 'm' || cw.has_key(*key) {
+                Some(Error::ConflictingKey(*key))
+            } else {
+                let byte = value.bytes().find(|&b| !is_control_safe(b));
+                byte.map(|byte| Error::UnsafeControlData { key: *key, byte })
+            };
+            match error {
+                Some(error) if strict => return Err(error),
+                Some(_) => {}
+                None => cw.key(*key, value)?,
+            }
         }
 
-        // Background color (Y)
-        if let Some(color) = self.inner.background_color {
-            parts.push(format!("Y={color}"));
+        Ok(())
+    }
+
+    /// Compress the payload if compression was requested
+    fn prepare_payload<'d>(&self, data: &'d [u8]) -> Result<Payload<'d>> {
+        let raw_len = data.len();
+        // Data declared compressed is never compressed again
+        let precompressed = self.inner.compression.is_some();
+
+        #[cfg(feature = "zlib")]
+        if !precompressed && raw_len >= self.inner.compress_threshold.unwrap_or(0) {
+            let compress = if self.inner.auto_compress {
+                crate::compress::worth_compressing(data)?
+            } else {
+                self.inner.deflate
+            };
+            if compress {
+                let compressed = crate::compress::deflate(data)?;
+                if !self.inner.auto_compress || compressed.len() < raw_len {
+                    return Ok(Payload {
+                        data: Cow::Owned(compressed),
+                        compressed: true,
+                        raw_len: Some(raw_len),
+                    });
+                }
+            }
         }
 
-        // Reference frame (c) for frame composition
-        // Already handled above as frame_number
+        Ok(Payload {
+            data: Cow::Borrowed(data),
+            compressed: precompressed,
+            // The uncompressed size of compressed data is unknown
+            raw_len: (!precompressed).then_some(raw_len),
+        })
+    }
+
+    /// Get the validated chunk size used for chunked serialization
+    pub(crate) fn chunk_size(&self) -> Result<usize> {
+        let size = self.inner.chunk_size.unwrap_or(MAX_CHUNK_SIZE);
+        validate_chunk_size(size)?;
+        Ok(size)
+    }
 
-        parts.join(",")
+    /// Number of bytes [`Command::serialize_chunked`] produces for a payload
+    /// of `data_len` bytes, without encoding anything
+    ///
+    /// Exact unless the payload is deflated with `deflate` or
+    /// [`CommandBuilder::auto_compress`];
+    /// compare it against [`ChunkedSerializer::total_bytes`] to see what
+    /// compression saved.
+    pub fn estimated_payload_len(&self, data_len: usize) -> Result<usize> {
+        let precompressed = self.inner.compression.is_some();
+        let payload = Payload {
+            data: Cow::Borrowed(&[]),
+            compressed: precompressed,
+            raw_len: (!precompressed).then_some(data_len),
+        };
+        let control = self.build_control_data(Some(&payload))?;
+        Ok(chunked_len(
+            control.len(),
+            self.continuation_control().len(),
+            data_len,
+            self.chunk_size()?,
+            self.inner.passthrough,
+        ))
     }
 
     /// Serialize the command to an escape sequence string
     pub fn serialize(&self, data: &[u8]) -> Result<String> {
-        let control = self.build_control_data();
-        let encoded = STANDARD.encode(data);
+        let result = self.serialize_bytes(data)?;
+        String::from_utf8(result).map_err(Error::from)
+    }
 
-        let mut result = Vec::new();
+    /// Serialize the command to bytes
+    pub fn serialize_bytes(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut result = Vec::with_capacity(64 + data.len().div_ceil(3) * 4);
+        self.serialize_to(data, &mut result)?;
+        Ok(result)
+    }
 
-        // Start sequence
-        result.extend_from_slice(APC_START);
+    /// Serialize a command that carries no payload
+    ///
+    /// Place, delete, animation control and similar commands have no data,
+    /// so only the control data is emitted, without the trailing `;`.
+    pub fn serialize_control(&self) -> Result<String> {
+        let result = self.serialize_control_bytes()?;
+        String::from_utf8(result).map_err(Error::from)
+    }
+
+    /// Serialize a command that carries no payload to bytes
+    pub fn serialize_control_bytes(&self) -> Result<Vec<u8>> {
+        let mut result = Vec::with_capacity(64);
+        result.extend_from_slice(self.inner.passthrough.apc_start());
         result.extend_from_slice(GRAPHICS_PREFIX.as_bytes());
+        self.write_control_data(&mut ControlWriter::new(&mut result), None)?;
+        result.extend_from_slice(self.inner.passthrough.apc_end());
+        Ok(result)
+    }
+
+    /// Serialize the command directly into a writer
+    ///
+    /// The control data and the base64 payload are streamed into `w` without
+    /// building any intermediate `String`s.
+    #[cfg(feature = "std")]
+    pub fn serialize_into<W: Write>(&self, data: &[u8], w: &mut W) -> Result<()> {
+        self.serialize_to(data, &mut IoSink(w))
+    }
+
+    /// Serialize the command into a sink
+    fn serialize_to<S: Sink>(&self, data: &[u8], s: &mut S) -> Result<()> {
+        let payload = self.prepare_payload(data)?;
+        self.check_control_data(Some(&payload))?;
+
+        // Start sequence
+        s.put(self.inner.passthrough.apc_start())?;
+        s.put(GRAPHICS_PREFIX.as_bytes())?;
 
         // Control data
-        result.extend_from_slice(control.as_bytes());
+        self.write_keys(&mut ControlWriter::new(s), Some(&payload), true)?;
 
         // Payload separator and payload
-        result.push(b';');
-        result.extend_from_slice(encoded.as_bytes());
+        s.put(b";")?;
+        encoding::encode_each(&payload.data, |encoded| s.put(encoded))?;
 
         // End sequence
-        result.extend_from_slice(APC_END);
-
-        String::from_utf8(result).map_err(Error::from)
+        s.put(self.inner.passthrough.apc_end())?;
+        Ok(())
     }
 
-    /// Serialize the command to bytes
-    pub fn serialize_bytes(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let control = self.build_control_data();
-        let encoded = STANDARD.encode(data);
-
-        let mut result = Vec::new();
+    /// Serialize command in chunks directly into a writer
+    ///
+    /// Produces the same escape sequences as [`Command::serialize_chunked`],
+    /// but encodes each chunk into a stack buffer instead of allocating.
+    #[cfg(feature = "std")]
+    pub fn serialize_chunked_into<W: Write>(&self, data: &[u8], w: &mut W) -> Result<()> {
+        self.serialize_chunked_to(data, &mut IoSink(w))
+    }
 
-        result.extend_from_slice(APC_START);
-        result.extend_from_slice(GRAPHICS_PREFIX.as_bytes());
-        result.extend_from_slice(control.as_bytes());
-        result.push(b';');
-        result.extend_from_slice(encoded.as_bytes());
-        result.extend_from_slice(APC_END);
+    /// Serialize command in chunks into a sink
+    fn serialize_chunked_to<S: Sink>(&self, data: &[u8], s: &mut S) -> Result<()> {
+        let payload = self.prepare_payload(data)?;
+        self.check_control_data(Some(&payload))?;
+        let mut buf = [0u8; MAX_CHUNK_SIZE];
+        let raw_chunk_size = self.chunk_size()? / 4 * 3;
+        let mut chunks = payload.data.chunks(raw_chunk_size).peekable();
+        let mut is_first = true;
+
+        while let Some(chunk) = chunks.next() {
+            let len = encoding::encode_slice(chunk, &mut buf);
+            let is_last = chunks.peek().is_none();
+
+            s.put(self.inner.passthrough.apc_start())?;
+            s.put(GRAPHICS_PREFIX.as_bytes())?;
+
+            let mut cw = ControlWriter::new(s);
+            if is_first {
+                // First chunk includes all control data
+                self.write_keys(&mut cw, Some(&payload), true)?;
+                is_first = false;
+            } else if let Some(quiet) = self.inner.quiet {
+                // Later chunks repeat q= so their responses are suppressed too
+                cw.key('q', quiet)?;
+            }
+            cw.key('m', if is_last { 0 } else { 1 })?;
+
+            s.put(b";")?;
+            s.put(&buf[..len])?;
+            s.put(self.inner.passthrough.apc_end())?;
+        }
 
-        Ok(result)
+        Ok(())
     }
 
     /// Serialize command in chunks for large data
     /// Returns an iterator of escape sequences
-    pub fn serialize_chunked(&self, data: &[u8]) -> Result<ChunkedSerializer> {
-        // First, encode all data to base64
-        let encoded = STANDARD.encode(data);
-
-        // Calculate chunk size that's a multiple of 4
-        let chunk_size = (MAX_CHUNK_SIZE / 4) * 4;
+    ///
+    /// The serializer borrows `data` and encodes one chunk at a time, so the
+    /// pixel data is never copied into an intermediate base64 buffer.
+    pub fn serialize_chunked<'a>(&self, data: &'a [u8]) -> Result<ChunkedSerializer<'a>> {
+        let chunk_size = self.chunk_size()?;
+        let payload = self.prepare_payload(data)?;
 
         Ok(ChunkedSerializer {
-            control: self.build_control_data(),
-            encoded,
+            control: self.build_control_data(Some(&payload))?,
+            continuation: self.continuation_control(),
+            passthrough: self.inner.passthrough,
+            data: payload.data,
             chunk_size,
             offset: 0,
             is_first: true,
+            buf: Vec::with_capacity(chunk_size + 64),
+        })
+    }
+
+    /// Serialize command in chunks into a reusable scratch buffer
+    ///
+    /// The buffer is cleared first and keeps its allocation, so repeated
+    /// transmissions (animation frames, live plots) don't allocate once it
+    /// has grown to fit. Returns all escape sequences back to back.
+    pub fn serialize_chunked_with_buffer<'b>(
+        &self,
+        data: &[u8],
+        scratch: &'b mut ScratchBuffer,
+    ) -> Result<&'b [u8]> {
+        scratch.buf.clear();
+        self.serialize_chunked_to(data, &mut scratch.buf)?;
+        Ok(&scratch.buf)
+    }
+
+    /// Serialize command in chunks, encoding them in parallel
+    ///
+    /// Chunk boundaries are multiples of 3 bytes, so every chunk encodes
+    /// independently; the chunks are encoded on the rayon pool and stitched
+    /// back together in order. Produces the same bytes as
+    /// [`Command::serialize_chunked_into`].
+    #[cfg(feature = "parallel")]
+    pub fn serialize_chunked_parallel(&self, data: &[u8]) -> Result<Vec<u8>> {
+        use rayon::prelude::*;
+
+        let payload = self.prepare_payload(data)?;
+        let control = self.build_control_data(Some(&payload))?;
+        let continuation = self.continuation_control();
+        let chunk_size = self.chunk_size()?;
+        let raw_chunk_size = chunk_size / 4 * 3;
+        let last = payload
+            .data
+            .len()
+            .div_ceil(raw_chunk_size)
+            .saturating_sub(1);
+
+        let chunks: Vec<Vec<u8>> = payload
+            .data
+            .par_chunks(raw_chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut buf = Vec::with_capacity(chunk_size + 64);
+                let control = if i == 0 { &control } else { &continuation };
+                encode_chunk(&mut buf, self.inner.passthrough, control, chunk, i == last);
+                buf
+            })
+            .collect();
+        Ok(chunks.concat())
+    }
+
+    /// Wrapping applied to every emitted sequence
+    pub fn passthrough(&self) -> Passthrough {
+        self.inner.passthrough
+    }
+
+    /// Control data repeated on every chunk after the first
+    ///
+    /// The terminal answers each chunk on its own, so `q=` has to be sent
+    /// with every chunk to suppress all responses.
+    pub(crate) fn continuation_control(&self) -> String {
+        match self.inner.quiet {
+            Some(quiet) => format!("q={quiet}"),
+            None => String::new(),
+        }
+    }
+
+    /// The `i`, `I` and `p` keys the terminal echoes in its reply, or `None`
+    /// when no reply can be matched back to this command
+    pub(crate) fn reply_key(&self) -> Option<(Option<ImageId>, Option<u32>, Option<PlacementId>)> {
+        let inner = &self.inner;
+        if inner.quiet == Some(Quiet::SuppressAll)
+            || (inner.image_id.is_none() && inner.image_number.is_none())
+        {
+            return None;
+        }
+        Some((inner.image_id, inner.image_number, inner.placement_id))
+    }
+
+    /// Quiet mode of the command, if set
+    #[cfg(feature = "std")]
+    pub(crate) fn quiet_mode(&self) -> Option<Quiet> {
+        self.inner.quiet
+    }
+
+    /// Whether a compression algorithm was requested
+    #[cfg(feature = "std")]
+    pub(crate) fn has_compression(&self) -> bool {
+        self.inner.compression.is_some()
+    }
+
+    /// Whether the payload should be deflated during serialization
+    #[cfg(feature = "tokio")]
+    pub(crate) fn deflates(&self) -> bool {
+        self.inner.deflate
+    }
+
+    /// Build the control data for a payload streamed from a reader
+    ///
+    /// The payload length is unknown up front, so only whether it is
+    /// compressed is taken into account.
+    #[cfg(feature = "std")]
+    pub(crate) fn stream_control_data(&self, compressed: bool) -> Result<String> {
+        let payload = Payload {
+            data: Cow::Borrowed(&[]),
+            compressed,
+            raw_len: None,
+        };
+        self.build_control_data(Some(&payload))
+    }
+
+    /// Serialize command in chunks read incrementally from `reader`
+    ///
+    /// Only one chunk (plus one chunk of lookahead to set `m=`) is held in
+    /// memory, so arbitrarily large files or pipes can be transmitted. With
+    /// `deflate` (`zlib` feature) the stream is deflated as it is read; the
+    /// uncompressed size is unknown then, so compressed PNG streams need it
+    /// set with [`CommandBuilder::data_range`].
+    #[cfg(feature = "std")]
+    pub fn serialize_chunked_from_reader<'r, R: Read + 'r>(
+        &self,
+        reader: R,
+    ) -> Result<ReaderChunks<'r>> {
+        let chunk_size = self.chunk_size()?;
+
+        let compressed = self.has_compression();
+        let reader: Box<dyn Read + 'r> = Box::new(reader);
+        #[cfg(feature = "zlib")]
+        let (reader, compressed) = if self.inner.deflate && !compressed {
+            (crate::compress::deflate_reader(reader), true)
+        } else {
+            (reader, compressed)
+        };
+
+        Ok(ReaderChunks {
+            control: self.stream_control_data(compressed)?,
+            continuation: self.continuation_control(),
+            passthrough: self.inner.passthrough,
+            reader,
+            raw_chunk_size: chunk_size / 4 * 3,
+            current: Vec::new(),
+            lookahead: Vec::new(),
+            started: false,
+            buf: Vec::with_capacity(chunk_size + 64),
+        })
+    }
+
+    /// Serialize command in chunks gathered from several byte slices
+    ///
+    /// The slices are read in order as if they were one buffer, so row-strided
+    /// or plane-separated pixel data needn't be copied into a contiguous
+    /// `Vec` first; chunk boundaries may fall anywhere within a slice. Pass
+    /// `bufs.iter().map(|b| &**b)` for a `&[IoSlice]`.
+    #[cfg(feature = "std")]
+    pub fn serialize_chunked_gather<'a, I>(&self, slices: I) -> Result<ReaderChunks<'a>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+        I::IntoIter: 'a,
+    {
+        self.serialize_chunked_from_reader(GatherReader {
+            slices: slices.into_iter(),
+            current: &[],
         })
     }
 
+    /// Serialize command in chunks, yielding raw bytes instead of strings
+    ///
+    /// Useful when writing to a raw fd or socket, as it skips the UTF-8
+    /// validation of the `String` iterator.
+    pub fn serialize_chunked_bytes<'a>(&self, data: &'a [u8]) -> Result<ChunkedBytes<'a>> {
+        Ok(self.serialize_chunked(data)?.bytes())
+    }
+
     /// Serialize a command with a path (for file/shared memory transmission)
     pub fn serialize_with_path(&self) -> Result<String> {
-        let control = self.build_control_data();
+        let control = self.build_control_data(None)?;
         let path = self
             .inner
             .path
             .as_ref()
             .ok_or(Error::MissingField("path"))?;
-        let encoded_path = STANDARD.encode(path.as_bytes());
+        validate_path(path)?;
+        let encoded_path = encoding::encode(path.as_bytes());
 
         let mut result = Vec::new();
 
-        result.extend_from_slice(APC_START);
+        result.extend_from_slice(self.inner.passthrough.apc_start());
         result.extend_from_slice(GRAPHICS_PREFIX.as_bytes());
         result.extend_from_slice(control.as_bytes());
         result.push(b';');
         result.extend_from_slice(encoded_path.as_bytes());
-        result.extend_from_slice(APC_END);
+        result.extend_from_slice(self.inner.passthrough.apc_end());
 
         String::from_utf8(result).map_err(Error::from)
     }
 }
 
-/// Iterator for chunked serialization of large data
-pub struct ChunkedSerializer {
-    control: String,
-    encoded: String,
-    chunk_size: usize,
-    offset: usize,
-    is_first: bool,
-}
+/// Parsing of escape sequences back into commands
+impl Command {
+    /// Parse a graphics escape sequence into a command and its payload
+    ///
+    /// Keys are interpreted according to the action, mirroring how they are
+    /// serialized, and the payload is base64-decoded (but not decompressed).
+    /// Useful for terminal implementations and for asserting semantic rather
+    /// than textual equality in tests.
+    pub fn parse(seq: &[u8]) -> Result<(Command, Vec<u8>)> {
+        use Action::*;
+
+        let body = seq
+            .strip_prefix(crate::APC_START)
+            .and_then(|s| s.strip_prefix(GRAPHICS_PREFIX.as_bytes()))
+            .and_then(|s| s.strip_suffix(crate::APC_END))
+            .ok_or_else(|| {
+                Error::protocol(format!(
+                    "not a graphics escape sequence: {}",
+                    String::from_utf8_lossy(seq)
+                ))
+            })?;
+
+        let (control, payload) = match body.iter().position(|&b| b == b';') {
+            Some(pos) => (&body[..pos], &body[pos + 1..]),
+            None => (body, &[][..]),
+        };
+        let control = core::str::from_utf8(control)?;
+        let data = STANDARD.decode(payload)?;
+
+        let mut pairs = Vec::new();
+        for part in control.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| Error::protocol(format!("malformed control data: {part}")))?;
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(key), None) => pairs.push((key, value)),
+                _ => return Err(Error::protocol(format!("invalid key: {key}"))),
+            }
+        }
 
-impl ChunkedSerializer {
-    /// Get the total number of chunks
-    pub fn total_chunks(&self) -> usize {
-        self.encoded.len().div_ceil(self.chunk_size)
-    }
+        let mut b = CommandBuilder::new();
+        if let Some((_, value)) = pairs.iter().find(|(key, _)| *key == 'a') {
+            b.action = Some(lookup('a', value, Action::from_code)?);
+        }
+        let action = b.effective_action();
+
+        let mut comp = FrameComposition::default();
+        let mut has_comp = false;
+        let mut delete_code = None;
+        let (mut delete_x, mut delete_y, mut delete_z) = (None, None, None);
+        let mut unicode = false;
+
+        for (key, v) in pairs {
+            match (key, action) {
+                ('a', _) => {}
+                ('f', _) => b.format = Some(lookup(key, v, ImageFormat::from_code)?),
+                ('t', _) => b.medium = Some(lookup(key, v, TransmissionMedium::from_code)?),
+                ('s', AnimationControl) => {
+                    b.animation_control =
+                        Some(lookup(key, v, crate::types::AnimationControl::from_code)?)
+                }
+                ('s', _) => b.width = Some(num(key, v)?),
+                ('v', AnimationControl) => b.loop_count = Some(num(key, v)?),
+                ('v', _) => b.height = Some(num(key, v)?),
+                ('i', _) => b.image_id = Some(num(key, v)?),
+                ('I', _) => b.image_number = Some(num(key, v)?),
+                ('p', _) => b.placement_id = Some(num(key, v)?),
+                ('m', _) => b.more_data = Some(num::<u8>(key, v)? != 0),
+                ('o', _) => b.compression = Some(lookup(key, v, Compression::from_code)?),
+                ('q', _) => b.quiet = Some(lookup(key, v, Quiet::from_code)?),
+                ('d', Delete) => delete_code = Some(lookup(key, v, Some)?),
+                ('x', Delete) => delete_x = Some(num(key, v)?),
+                ('y', Delete) => delete_y = Some(num(key, v)?),
+                ('z', Delete) => delete_z = Some(num(key, v)?),
+                ('x', ComposeFrame) => comp.dest_x = Some(num(key, v)?),
+                ('y', ComposeFrame) => comp.dest_y = Some(num(key, v)?),
+                ('w', ComposeFrame) => comp.width = Some(num(key, v)?),
+                ('h', ComposeFrame) => comp.height = Some(num(key, v)?),
+                ('X', ComposeFrame) => comp.source_x = Some(num(key, v)?),
+                ('Y', ComposeFrame) => comp.source_y = Some(num(key, v)?),
+                ('r', ComposeFrame) => comp.source_frame = num(key, v)?,
+                ('c', ComposeFrame) => comp.dest_frame = num(key, v)?,
+                ('C', ComposeFrame) => comp.mode = lookup(key, v, CompositionMode::from_code)?,
+                ('x', _) => b.source_x = Some(num(key, v)?),
+                ('y', _) => b.source_y = Some(num(key, v)?),
+                ('w', _) => b.source_width = Some(num(key, v)?),
+                ('h', _) => b.source_height = Some(num(key, v)?),
+                ('X', Frame) => {
+                    b.composition_mode = Some(lookup(key, v, CompositionMode::from_code)?)
+                }
+                ('X', _) => b.cell_offset_x = Some(num(key, v)?),
+                ('Y', Frame) => b.background_color = Some(num(key, v)?),
+                ('Y', _) => b.cell_offset_y = Some(num(key, v)?),
+                ('c', AnimationControl) => b.current_frame = Some(num(key, v)?),
+                ('c', Frame) => b.ref_frame = Some(num(key, v)?),
+                ('c', _) => b.columns = Some(num(key, v)?),
+                ('r', Frame | AnimationControl) => b.edit_frame = Some(num(key, v)?),
+                ('r', _) => b.rows = Some(num(key, v)?),
+                ('z', Frame | AnimationControl) => b.frame_gap = Some(num(key, v)?),
+                ('z', _) => b.z_index = Some(num(key, v)?),
+                ('C', _) => b.cursor_policy = Some(lookup(key, v, CursorPolicy::from_code)?),
+                ('S', _) => b.data_size = Some(num(key, v)?),
+                ('O', _) => b.data_offset = Some(num(key, v)?),
+                ('U', _) => unicode = num::<u8>(key, v)? != 0,
+                ('P', _) => b.parent_image_id = Some(num(key, v)?),
+                ('Q', _) => b.parent_placement_id = Some(num(key, v)?),
+                ('H', _) => b.relative_h_offset = Some(num(key, v)?),
+                ('V', _) => b.relative_v_offset = Some(num(key, v)?),
+                _ => {
+                    return Err(Error::protocol(format!(
+                        "unsupported key `{key}` for action {action:?}"
+                    )));
+                }
+            }
+            has_comp |= action == ComposeFrame && "xywhXYrcC".contains(key);
+        }
 
-    /// Check if there are more chunks
-    pub fn has_more(&self) -> bool {
-        self.offset < self.encoded.len()
+        if has_comp {
+            b.composition = Some(comp);
+        }
+        if let Some(code) = delete_code {
+            b.delete_target = Some(
+                DeleteTarget::from_code(code, delete_x, delete_y, delete_z)
+                    .ok_or_else(|| Error::protocol(format!("invalid delete target: {code}")))?,
+            );
+        }
+        if unicode {
+            let cells = |n: Option<u32>| {
+                let n = n.unwrap_or(0);
+                u16::try_from(n)
+                    .map_err(|_| Error::protocol(format!("placeholder grid too large: {n} cells")))
+            };
+            b.unicode_placeholder = Some(UnicodePlaceholder {
+                columns: cells(b.columns.take())?,
+                rows: cells(b.rows.take())?,
+            });
+        }
+
+        Ok((b.build(), data))
     }
 }
 
-impl Iterator for ChunkedSerializer {
-    type Item = String;
+/// Reassembles chunked (`m=1`) transmissions on the receiving side
+///
+/// Feed each parsed chunk in order; the first chunk carries the control
+/// data, later chunks only contribute payload. Once the final `m=0` chunk
+/// arrives the original command and the complete decoded data are returned.
+#[derive(Debug, Default)]
+pub struct ChunkAssembler {
+    command: Option<Command>,
+    data: Vec<u8>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.offset >= self.encoded.len() {
+impl ChunkAssembler {
+    /// Create an empty assembler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a parsed chunk, returning the command and data once complete
+    pub fn push(&mut self, command: Command, payload: Vec<u8>) -> Option<(Command, Vec<u8>)> {
+        let more = command.inner.more_data.unwrap_or(false);
+        match &self.command {
+            Some(_) => self.data.extend_from_slice(&payload),
+            None => {
+                self.command = Some(command);
+                self.data = payload;
+            }
+        }
+
+        if more {
             return None;
         }
 
-        let end = (self.offset + self.chunk_size).min(self.encoded.len());
-        let chunk = &self.encoded[self.offset..end];
-        let is_last = end >= self.encoded.len();
+        let mut command = self.command.take()?;
+        command.inner.more_data = None;
+        Some((command, core::mem::take(&mut self.data)))
+    }
 
-        let mut result = Vec::new();
-        result.extend_from_slice(APC_START);
-        result.extend_from_slice(GRAPHICS_PREFIX.as_bytes());
+    /// Parse an escape sequence and add it as the next chunk
+    pub fn push_sequence(&mut self, seq: &[u8]) -> Result<Option<(Command, Vec<u8>)>> {
+        let (command, payload) = Command::parse(seq)?;
+        Ok(self.push(command, payload))
+    }
 
-        if self.is_first {
-            // First chunk includes all control data
-            result.extend_from_slice(self.control.as_bytes());
-            result.push(b',');
-            self.is_first = false;
-        }
+    /// Check whether a chunked transmission is in progress
+    pub fn is_pending(&self) -> bool {
+        self.command.is_some()
+    }
 
-        // m=1 for more data, m=0 for last chunk
-        result.extend_from_slice(format!("m={}", if is_last { 0 } else { 1 }).as_bytes());
-        result.push(b';');
-        result.extend_from_slice(chunk.as_bytes());
-        result.extend_from_slice(APC_END);
+    /// Discard any partially assembled transmission
+    pub fn reset(&mut self) {
+        self.command = None;
+        self.data.clear();
+    }
+}
 
-        self.offset = end;
+/// Parse a numeric control value
+fn num<T: core::str::FromStr>(key: char, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::protocol(format!("invalid value for key `{key}`: {value}")))
+}
 
-        String::from_utf8(result).ok()
+/// Parse a control value that maps onto an enum through a lookup function
+fn lookup<C: core::str::FromStr, T>(
+    key: char,
+    value: &str,
+    from_code: impl FnOnce(C) -> Option<T>,
+) -> Result<T> {
+    from_code(num(key, value)?)
+        .ok_or_else(|| Error::protocol(format!("invalid value for key `{key}`: {value}")))
+}
+
+/// Write the keys of a frame composition (a=c)
+///
+/// `r`/`c` are the source and destination frames, `X`/`Y` the source
+/// offset, `x`/`y` the destination offset, `w`/`h` the rectangle size and
+/// `C` the composition mode.
+fn write_composition<S: Sink>(
+    cw: &mut ControlWriter<'_, S>,
+    comp: &FrameComposition,
+) -> Result<()> {
+    cw.key('r', comp.source_frame)?;
+    cw.key('c', comp.dest_frame)?;
+    if let Some(w) = comp.width {
+        cw.key('w', w)?;
+    }
+    if let Some(h) = comp.height {
+        cw.key('h', h)?;
+    }
+    if let Some(x) = comp.source_x {
+        cw.key('X', x)?;
+    }
+    if let Some(y) = comp.source_y {
+        cw.key('Y', y)?;
+    }
+    if let Some(x) = comp.dest_x {
+        cw.key('x', x)?;
+    }
+    if let Some(y) = comp.dest_y {
+        cw.key('y', y)?;
+    }
+    if comp.mode != CompositionMode::AlphaBlend {
+        cw.key('C', comp.mode)?;
     }
+    Ok(())
 }
 
-impl fmt::Display for Command {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Command({})", self.build_control_data())
+/// Payload data after optional compression
+struct Payload<'a> {
+    /// Bytes to base64-encode
+    data: Cow<'a, [u8]>,
+    /// Whether `data` is compressed
+    compressed: bool,
+    /// Size of the payload before compression, if known
+    raw_len: Option<usize>,
+}
+
+/// Check that a chunk size is a non-zero multiple of 4 and at most [`MAX_CHUNK_SIZE`]
+pub fn validate_chunk_size(size: usize) -> Result<()> {
+    if size == 0 || !size.is_multiple_of(4) || size > MAX_CHUNK_SIZE {
+        return Err(Error::InvalidChunkSize(size));
     }
+    Ok(())
 }
 
-/// Convenience functions for common operations
-impl Command {
-    /// Create a command to query protocol support
-    pub fn query_support() -> Self {
-        Self::builder().action(Action::Query).quiet(2).build()
+/// Destination for serialized escape sequences
+///
+/// Implemented for `Vec<u8>`, and with the `std` feature for any
+/// [`io::Write`] through [`IoSink`], so the same serialization code backs
+/// both `no_std` and writer-based output.
+trait Sink {
+    /// Append `bytes` to the output
+    fn put(&mut self, bytes: &[u8]) -> Result<()>;
+}
+
+/// Sink that drops its output, for checking control data before writing it
+struct Discard;
+
+impl Sink for Discard {
+    fn put(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
     }
+}
 
-    /// Create a command to transmit and display a PNG image
-    pub fn transmit_png(data: &[u8]) -> Result<Vec<String>> {
-        let cmd = Self::builder()
-            .action(Action::TransmitAndDisplay)
-            .format(ImageFormat::Png)
-            .quiet(2)
-            .build();
+impl Sink for Vec<u8> {
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
 
-        let chunks: Vec<String> = cmd.serialize_chunked(data)?.collect();
-        Ok(chunks)
+/// Adapts an [`io::Write`] into a [`Sink`]
+#[cfg(feature = "std")]
+struct IoSink<'a, W: Write>(&'a mut W);
+
+#[cfg(feature = "std")]
+impl<W: Write> Sink for IoSink<'_, W> {
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.0.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// Writes comma-separated `key=value` pairs of control data
+struct ControlWriter<'a, S: Sink> {
+    w: &'a mut S,
+    first: bool,
+    /// Bitset of the ASCII letter keys written so far
+    written: u64,
+}
+
+impl<'a, S: Sink> ControlWriter<'a, S> {
+    fn new(w: &'a mut S) -> Self {
+        Self {
+            w,
+            first: true,
+            written: 0,
+        }
+    }
+
+    /// Bit for an ASCII letter key in the `written` set
+    fn key_bit(key: char) -> u64 {
+        match key {
+            'a'..='z' => 1 << (key as u32 - 'a' as u32),
+            'A'..='Z' => 1 << (26 + key as u32 - 'A' as u32),
+            _ => 0,
+        }
+    }
+
+    /// Check whether `key` has already been written
+    fn has_key(&self, key: char) -> bool {
+        self.written & Self::key_bit(key) != 0
+    }
+
+    /// Write a single `key=value` pair, preceded by a comma if needed
+    ///
+    /// A value containing ESC, `;` or any other byte that could terminate
+    /// or split the escape sequence fails with [`Error::UnsafeControlData`].
+    /// The value is checked before anything is written, so nothing of a
+    /// rejected pair reaches the sink.
+    fn key(&mut self, key: char, value: impl fmt::Display) -> Result<()> {
+        // Format once without a sink to check the value
+        SafeValue::<S> {
+            w: None,
+            unsafe_byte: None,
+            error: None,
+        }
+        .write(key, &value)?;
+
+        if !self.first {
+            self.w.put(b",")?;
+        }
+        self.first = false;
+        self.written |= Self::key_bit(key);
+        let mut utf8 = [0u8; 4];
+        self.w.put(key.encode_utf8(&mut utf8).as_bytes())?;
+        self.w.put(b"=")?;
+
+        SafeValue {
+            w: Some(&mut *self.w),
+            unsafe_byte: None,
+            error: None,
+        }
+        .write(key, &value)
+    }
+}
+
+/// Check whether a byte may appear inside a control data value
+///
+/// Only printable ASCII is allowed, minus the `,`, `;` and `=` separators.
+/// This excludes ESC, BEL and the C1 string terminator, so a value can never
+/// end the APC sequence early or smuggle in extra keys.
+fn is_control_safe(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !matches!(byte, b',' | b';' | b'=')
+}
+
+/// Forwards a formatted control value to a sink, if any, rejecting unsafe
+/// bytes
+struct SafeValue<'a, S: Sink> {
+    w: Option<&'a mut S>,
+    unsafe_byte: Option<u8>,
+    error: Option<Error>,
+}
+
+impl<S: Sink> SafeValue<'_, S> {
+    /// Format `value` as the value of `key`
+    fn write(mut self, key: char, value: &impl fmt::Display) -> Result<()> {
+        if fmt::write(&mut self, format_args!("{value}")).is_ok() {
+            return Ok(());
+        }
+        if let Some(byte) = self.unsafe_byte {
+            return Err(Error::UnsafeControlData { key, byte });
+        }
+        Err(self
+            .error
+            .unwrap_or_else(|| Error::protocol("formatter error")))
+    }
+}
+
+impl<S: Sink> fmt::Write for SafeValue<'_, S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(byte) = s.bytes().find(|&b| !is_control_safe(b)) {
+            self.unsafe_byte = Some(byte);
+            return Err(fmt::Error);
+        }
+        let Some(w) = &mut self.w else {
+            return Ok(());
+        };
+        w.put(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// Check that a file or shared memory path can be sent to the terminal
+///
+/// The path itself is base64-encoded, so it cannot break out of the escape
+/// sequence, but an empty path or one containing NUL would be truncated or
+/// misread by the terminal when it opens the file.
+fn validate_path(path: &str) -> Result<()> {
+    if path.is_empty() || path.contains('\0') {
+        return Err(Error::InvalidPath(path.to_string()));
+    }
+    Ok(())
+}
+
+/// Reusable output buffer for [`Command::serialize_chunked_with_buffer`]
+#[derive(Debug, Clone, Default)]
+pub struct ScratchBuffer {
+    buf: Vec<u8>,
+}
+
+impl ScratchBuffer {
+    /// Create an empty scratch buffer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a scratch buffer sized for a payload of `data_len` bytes
+    pub fn with_capacity(data_len: usize) -> Self {
+        let chunks = data_len.div_ceil(MAX_CHUNK_SIZE / 4 * 3).max(1);
+        Self {
+            buf: Vec::with_capacity(data_len.div_ceil(3) * 4 + chunks * 32 + 128),
+        }
+    }
+
+    /// Currently allocated capacity in bytes
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+}
+
+/// Iterator for chunked serialization of large data
+///
+/// Borrows the input data and encodes each chunk on demand into a small
+/// reusable buffer. Use [`ChunkedSerializer::next_chunk`] to get each escape
+/// sequence as a borrowed slice without any per-chunk allocation, or iterate
+/// to get owned `String`s.
+pub struct ChunkedSerializer<'a> {
+    control: String,
+    continuation: String,
+    passthrough: Passthrough,
+    data: Cow<'a, [u8]>,
+    chunk_size: usize,
+    offset: usize,
+    is_first: bool,
+    buf: Vec<u8>,
+}
+
+impl ChunkedSerializer<'_> {
+    /// Change the chunk size (encoded payload bytes per escape sequence)
+    ///
+    /// Fails with [`Error::InvalidChunkSize`] if the size is not a non-zero
+    /// multiple of 4 no larger than [`MAX_CHUNK_SIZE`].
+    pub fn with_chunk_size(mut self, size: usize) -> Result<Self> {
+        validate_chunk_size(size)?;
+        self.chunk_size = size;
+        Ok(self)
+    }
+
+    /// Number of raw input bytes encoded into each chunk
+    fn raw_chunk_size(&self) -> usize {
+        self.chunk_size / 4 * 3
+    }
+
+    /// Get the total number of chunks
+    pub fn total_chunks(&self) -> usize {
+        self.data.len().div_ceil(self.raw_chunk_size())
+    }
+
+    /// Total number of bytes of all escape sequences
+    pub fn total_bytes(&self) -> usize {
+        chunked_len(
+            self.control.len(),
+            self.continuation.len(),
+            self.data.len(),
+            self.chunk_size,
+            self.passthrough,
+        )
+    }
+
+    /// Number of chunks not yet produced
+    pub fn remaining_chunks(&self) -> usize {
+        (self.data.len() - self.offset).div_ceil(self.raw_chunk_size())
+    }
+
+    /// Check if there are more chunks
+    pub fn has_more(&self) -> bool {
+        self.offset < self.data.len()
+    }
+
+    /// Encode the next chunk into the internal buffer and return it
+    ///
+    /// The returned slice is only valid until the next call; the buffer is
+    /// reused for every chunk.
+    pub fn next_chunk(&mut self) -> Option<&[u8]> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+
+        let end = (self.offset + self.raw_chunk_size()).min(self.data.len());
+        let chunk = &self.data[self.offset..end];
+        let is_last = end >= self.data.len();
+
+        let control = if self.is_first {
+            self.is_first = false;
+            self.control.as_str()
+        } else {
+            self.continuation.as_str()
+        };
+        encode_chunk(&mut self.buf, self.passthrough, control, chunk, is_last);
+
+        self.offset = end;
+
+        Some(&self.buf)
+    }
+}
+
+/// Length of a chunked transmission of `data_len` payload bytes
+///
+/// Every chunk adds the APC framing and an `m=` key; the first one also
+/// carries the control data and the others the continuation control data,
+/// each with a separating comma.
+fn chunked_len(
+    control_len: usize,
+    continuation_len: usize,
+    data_len: usize,
+    chunk_size: usize,
+    passthrough: Passthrough,
+) -> usize {
+    let chunks = data_len.div_ceil(chunk_size / 4 * 3);
+    if chunks == 0 {
+        return 0;
+    }
+    let framing = passthrough.apc_start().len()
+        + GRAPHICS_PREFIX.len()
+        + b"m=0;".len()
+        + passthrough.apc_end().len();
+    let with_comma = |len: usize| if len == 0 { 0 } else { len + 1 };
+    data_len.div_ceil(3) * 4
+        + chunks * framing
+        + with_comma(control_len)
+        + (chunks - 1) * with_comma(continuation_len)
+}
+
+/// Encode one escape sequence of a chunked transmission into `buf`
+///
+/// `control` is the full control data for the first chunk and the
+/// [`Command::continuation_control`] for the others.
+pub(crate) fn encode_chunk(
+    buf: &mut Vec<u8>,
+    passthrough: Passthrough,
+    control: &str,
+    chunk: &[u8],
+    is_last: bool,
+) {
+    buf.clear();
+    buf.extend_from_slice(passthrough.apc_start());
+    buf.extend_from_slice(GRAPHICS_PREFIX.as_bytes());
+
+    if !control.is_empty() {
+        buf.extend_from_slice(control.as_bytes());
+        buf.push(b',');
+    }
+
+    // m=1 for more data, m=0 for last chunk
+    buf.extend_from_slice(if is_last { b"m=0;" } else { b"m=1;" });
+    let start = buf.len();
+    buf.resize(start + chunk.len().div_ceil(3) * 4, 0);
+    let written = encoding::encode_slice(chunk, &mut buf[start..]);
+    buf.truncate(start + written);
+    buf.extend_from_slice(passthrough.apc_end());
+}
+
+impl Iterator for ChunkedSerializer<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.next_chunk()?;
+        String::from_utf8(chunk.to_vec()).ok()
+    }
+}
+
+/// Chunked serializer reading its payload from an [`io::Read`] source
+///
+/// Created by [`Command::serialize_chunked_from_reader`]. Iterating yields
+/// each escape sequence as a `Vec<u8>`, or a read error.
+#[cfg(feature = "std")]
+pub struct ReaderChunks<'r> {
+    control: String,
+    continuation: String,
+    passthrough: Passthrough,
+    reader: Box<dyn Read + 'r>,
+    raw_chunk_size: usize,
+    current: Vec<u8>,
+    lookahead: Vec<u8>,
+    started: bool,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl ReaderChunks<'_> {
+    /// Read and encode the next chunk into the internal buffer
+    ///
+    /// Returns `Ok(None)` once the reader is exhausted. The returned slice
+    /// is only valid until the next call.
+    pub fn next_chunk(&mut self) -> Result<Option<&[u8]>> {
+        let is_first = !self.started;
+        if is_first {
+            fill(&mut self.reader, &mut self.current, self.raw_chunk_size)?;
+            self.started = true;
+        } else {
+            core::mem::swap(&mut self.current, &mut self.lookahead);
+        }
+        if self.current.is_empty() {
+            return Ok(None);
+        }
+
+        fill(&mut self.reader, &mut self.lookahead, self.raw_chunk_size)?;
+        let is_last = self.lookahead.is_empty();
+        let control = if is_first {
+            &self.control
+        } else {
+            &self.continuation
+        };
+        encode_chunk(
+            &mut self.buf,
+            self.passthrough,
+            control,
+            &self.current,
+            is_last,
+        );
+
+        Ok(Some(&self.buf))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Iterator for ReaderChunks<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|c| c.map(<[u8]>::to_vec)).transpose()
+    }
+}
+
+/// Reads a sequence of byte slices as one continuous stream
+#[cfg(feature = "std")]
+struct GatherReader<'a, I> {
+    slices: I,
+    current: &'a [u8],
+}
+
+#[cfg(feature = "std")]
+impl<'a, I: Iterator<Item = &'a [u8]>> Read for GatherReader<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.slices.next() {
+                Some(slice) => self.current = slice,
+                None => return Ok(0),
+            }
+        }
+        let n = self.current.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current = &self.current[n..];
+        Ok(n)
+    }
+}
+
+/// Read up to `len` bytes into `buf`, stopping early only at end of input
+#[cfg(feature = "std")]
+fn fill(reader: &mut dyn Read, buf: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    buf.clear();
+    reader.take(len as u64).read_to_end(buf)?;
+    Ok(())
+}
+
+/// Iterator over chunked escape sequences as owned byte vectors
+pub struct ChunkedBytes<'a> {
+    inner: ChunkedSerializer<'a>,
+}
+
+impl<'a> ChunkedSerializer<'a> {
+    /// Yield chunks as `Vec<u8>` instead of `String`
+    pub fn bytes(self) -> ChunkedBytes<'a> {
+        ChunkedBytes { inner: self }
+    }
+}
+
+impl Iterator for ChunkedBytes<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next_chunk().map(<[u8]>::to_vec)
+    }
+}
+
+/// Formats the command as its escape sequence without a payload
+///
+/// For valid commands this is the same output as
+/// [`Command::serialize_control`], so control-only commands can be printed
+/// directly with `print!("{}", Command::delete_all())`. Nothing is validated
+/// and formatting never fails; raw keys that could not be sent safely are
+/// left out. Use [`Command::serialize_control`] to catch invalid commands.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut seq = Vec::with_capacity(64);
+        seq.extend_from_slice(self.inner.passthrough.apc_start());
+        seq.extend_from_slice(GRAPHICS_PREFIX.as_bytes());
+        // Not strict, so writing into a `Vec` cannot fail
+        let _ = self.write_keys(&mut ControlWriter::new(&mut seq), None, false);
+        seq.extend_from_slice(self.inner.passthrough.apc_end());
+        f.write_str(&String::from_utf8_lossy(&seq))
+    }
+}
+
+/// Convenience functions for common operations
+impl Command {
+    /// Create a command to query protocol support
+    pub fn query_support() -> Self {
+        Self::builder()
+            .action(Action::Query)
+            .quiet(Quiet::SuppressAll)
+            .build()
+    }
+
+    /// Create a command to transmit and display a PNG image
+    pub fn transmit_png(data: &[u8]) -> Result<Vec<String>> {
+        let cmd = Self::builder()
+            .action(Action::TransmitAndDisplay)
+            .format(ImageFormat::Png)
+            .quiet(Quiet::SuppressAll)
+            .build();
+
+        let chunks: Vec<String> = cmd.serialize_chunked(data)?.collect();
+        Ok(chunks)
     }
 
     /// Create a command to transmit and display raw RGBA data
@@ -639,7 +2184,7 @@ impl Command {
             .action(Action::TransmitAndDisplay)
             .format(ImageFormat::Rgba)
             .dimensions(width, height)
-            .quiet(2)
+            .quiet(Quiet::SuppressAll)
             .build();
 
         let chunks: Vec<String> = cmd.serialize_chunked(data)?.collect();
@@ -657,7 +2202,7 @@ impl Command {
             .action(Action::TransmitAndDisplay)
             .format(ImageFormat::Rgb)
             .dimensions(width, height)
-            .quiet(2)
+            .quiet(Quiet::SuppressAll)
             .build();
 
         let chunks: Vec<String> = cmd.serialize_chunked(data)?.collect();
@@ -673,7 +2218,7 @@ impl Command {
     }
 
     /// Create a command to delete an image by ID
-    pub fn delete_by_id(image_id: u32) -> Self {
+    pub fn delete_by_id(image_id: ImageId) -> Self {
         Self::builder()
             .action(Action::Delete)
             .delete_target(DeleteTarget::ById { free_data: true })
@@ -681,8 +2226,38 @@ impl Command {
             .build()
     }
 
+    /// Create a command to delete all images with IDs in `from..=to` (d=r/R)
+    ///
+    /// With `free_data` the image data is freed as well. Fails with
+    /// [`Error::InvalidIdRange`] if `from` is greater than `to`.
+    pub fn delete_id_range(from: ImageId, to: ImageId, free_data: bool) -> Result<Self> {
+        if from > to {
+            return Err(Error::InvalidIdRange {
+                from: from.get(),
+                to: to.get(),
+            });
+        }
+        Ok(Self::builder()
+            .action(Action::Delete)
+            .delete_target(DeleteTarget::ByIdRange {
+                from: from.get(),
+                to: to.get(),
+                free_data,
+            })
+            .build())
+    }
+
+    /// Create a command to compose one animation frame onto another (a=c)
+    pub fn compose_frames(image_id: ImageId, composition: FrameComposition) -> Self {
+        Self::builder()
+            .action(Action::ComposeFrame)
+            .image_id(image_id)
+            .composition(composition)
+            .build()
+    }
+
     /// Create a command to place a previously transmitted image
-    pub fn place(image_id: u32, columns: u32, rows: u32) -> Self {
+    pub fn place(image_id: ImageId, columns: u32, rows: u32) -> Self {
         Self::builder()
             .action(Action::Place)
             .image_id(image_id)
@@ -690,3 +2265,776 @@ impl Command {
             .build()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_serialize_into_matches_serialize() {
+        let cmd = Command::builder()
+            .action(Action::Transmit)
+            .format(ImageFormat::Rgb)
+            .dimensions(1, 1)
+            .image_id(ImageId::new(1).unwrap())
+            .build();
+        let mut out = Vec::new();
+        cmd.serialize_into(&[255, 0, 0], &mut out).unwrap();
+        assert_eq!(out, cmd.serialize(&[255, 0, 0]).unwrap().into_bytes());
+        assert_eq!(out, b"\x1b_Ga=t,f=24,s=1,v=1,i=1;/wAA\x1b\\");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_serialize_chunked_into_matches_iterator() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let cmd = Command::builder()
+            .action(Action::TransmitAndDisplay)
+            .format(ImageFormat::Png)
+            .build();
+        let mut out = Vec::new();
+        cmd.serialize_chunked_into(&data, &mut out).unwrap();
+        let expected: String = cmd.serialize_chunked(&data).unwrap().collect();
+        assert_eq!(out, expected.into_bytes());
+    }
+
+    #[test]
+    fn test_chunked_serializer_next_chunk() {
+        let data = vec![0u8; 3072 * 2 + 1];
+        let mut chunks = Command::builder()
+            .action(Action::Transmit)
+            .build()
+            .serialize_chunked(&data)
+            .unwrap();
+        assert_eq!(chunks.total_chunks(), 3);
+
+        let first = chunks.next_chunk().unwrap();
+        assert!(first.starts_with(b"\x1b_Ga=t,m=1;AAAA"));
+        assert_eq!(first.len(), 3 + 8 + 4096 + 2);
+        assert!(chunks.next_chunk().unwrap().starts_with(b"\x1b_Gm=1;"));
+        assert_eq!(chunks.next_chunk().unwrap(), b"\x1b_Gm=0;AA==\x1b\\");
+        assert!(chunks.next_chunk().is_none());
+    }
+
+    #[test]
+    fn test_action_aware_keys() {
+        let cmd = Command::builder()
+            .action(Action::AnimationControl)
+            .image_id(ImageId::new(1).unwrap())
+            .animation_control(AnimationControl::Run)
+            .frame_number(2)
+            .loop_count(1)
+            .build();
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1b_Ga=a,i=1,s=3,c=2,v=1\x1b\\"
+        );
+
+        let cmd = Command::builder()
+            .action(Action::Frame)
+            .image_id(ImageId::new(1).unwrap())
+            .frame_number(3)
+            .ref_frame(1)
+            .frame_gap(40)
+            .build();
+        assert_eq!(
+            cmd.serialize(&[]).unwrap(),
+            "\x1b_Ga=f,i=1,r=3,c=1,z=40;\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_compose_frames() {
+        let cmd = Command::compose_frames(
+            ImageId::new(2).unwrap(),
+            FrameComposition {
+                source_frame: 1,
+                dest_frame: 3,
+                width: Some(10),
+                height: Some(20),
+                source_x: Some(1),
+                source_y: Some(2),
+                dest_x: Some(3),
+                dest_y: Some(4),
+                mode: CompositionMode::Replace,
+            },
+        );
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1b_Ga=c,i=2,r=1,c=3,w=10,h=20,X=1,Y=2,x=3,y=4,C=1\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_reject_fields_for_action() {
+        // Width and animation state would both be emitted as `s=`
+        let cmd = Command::builder()
+            .action(Action::AnimationControl)
+            .dimensions(10, 10)
+            .animation_control(AnimationControl::Run)
+            .build();
+        assert!(matches!(
+            cmd.serialize_control(),
+            Err(Error::InvalidFieldForAction {
+                field: "width",
+                action: Action::AnimationControl
+            })
+        ));
+
+        let cmd = Command::builder()
+            .action(Action::Place)
+            .frame_gap(100)
+            .build();
+        assert!(cmd.serialize_control().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_rejected_command_writes_nothing() {
+        let cmd = Command::builder()
+            .action(Action::Place)
+            .frame_gap(100)
+            .build();
+        let mut out = Vec::new();
+        assert!(cmd.serialize_into(&[], &mut out).is_err());
+        assert!(cmd.serialize_chunked_into(&[1, 2, 3], &mut out).is_err());
+
+        // Raw keys are checked against all the other keys first
+        let conflict = Command::builder()
+            .format(ImageFormat::Rgb)
+            .dimensions(1, 1)
+            .image_id(ImageId::new(1).unwrap())
+            .raw_key('i', 2)
+            .build();
+        assert!(matches!(
+            conflict.serialize_chunked_into(&[1, 2, 3], &mut out),
+            Err(Error::ConflictingKey('i'))
+        ));
+        assert!(conflict.serialize_into(&[1, 2, 3], &mut out).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_try_build_validation() {
+        let missing_dims = Command::builder()
+            .action(Action::TransmitAndDisplay)
+            .format(ImageFormat::Rgb)
+            .try_build();
+        assert!(matches!(
+            missing_dims,
+            Err(Error::MissingField("dimensions"))
+        ));
+
+        let orphan_offset = Command::builder()
+            .action(Action::Place)
+            .image_id(ImageId::new(1).unwrap())
+            .relative_offset(2, 3)
+            .try_build();
+        assert!(matches!(orphan_offset, Err(Error::MissingField("parent"))));
+
+        let orphan_placement = Command::builder()
+            .action(Action::Place)
+            .placement_id(PlacementId::new(4).unwrap())
+            .try_build();
+        assert!(matches!(
+            orphan_placement,
+            Err(Error::MissingField("image_id"))
+        ));
+
+        let misplaced_delete = Command::builder()
+            .action(Action::Place)
+            .image_id(ImageId::new(1).unwrap())
+            .delete_target(DeleteTarget::All)
+            .try_build();
+        assert!(matches!(
+            misplaced_delete,
+            Err(Error::InvalidFieldForAction {
+                field: "delete_target",
+                ..
+            })
+        ));
+
+        assert!(
+            Command::builder()
+                .action(Action::TransmitAndDisplay)
+                .format(ImageFormat::Png)
+                .image_id(ImageId::new(1).unwrap())
+                .placement_id(PlacementId::new(2).unwrap())
+                .try_build()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_builder_round_trip() {
+        let cmd = Command::place(ImageId::new(4).unwrap(), 10, 5);
+        assert_eq!(cmd.to_builder().build(), cmd);
+
+        let moved = cmd
+            .to_builder()
+            .placement_id(PlacementId::new(2).unwrap())
+            .z_index(-1)
+            .build();
+        assert_eq!(moved.to_string(), "\x1b_Ga=p,i=4,p=2,c=10,r=5,z=-1\x1b\\");
+        assert_eq!(
+            cmd.into_builder().build().to_string(),
+            "\x1b_Ga=p,i=4,c=10,r=5\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_strict_version() {
+        let id = ImageId::new(1).unwrap();
+        let relative = Command::builder()
+            .action(Action::Place)
+            .image_id(id)
+            .parent(ImageId::new(2).unwrap(), PlacementId::new(1).unwrap());
+        assert_eq!(
+            relative.clone().build().required_version(),
+            ProtocolVersion::RelativePlacements
+        );
+        assert!(matches!(
+            relative
+                .strict(ProtocolVersion::UnicodePlaceholders)
+                .build()
+                .serialize_control(),
+            Err(Error::UnsupportedFeature {
+                field: "parent",
+                required: ProtocolVersion::RelativePlacements,
+                ..
+            })
+        ));
+
+        let animate = Command::animate(id).current_frame(2).build();
+        assert_eq!(animate.required_version(), ProtocolVersion::Animation);
+        let strict = animate
+            .to_builder()
+            .strict(ProtocolVersion::Animation)
+            .build();
+        assert!(strict.serialize_control().is_ok());
+        let place = Command::place(id, 4, 2).into_builder();
+        assert!(
+            place
+                .strict(ProtocolVersion::Base)
+                .build()
+                .serialize_control()
+                .is_ok()
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_quiet_repeated_on_every_chunk() {
+        let data = vec![1u8; 10_000];
+        let cmd = Command::builder()
+            .action(Action::TransmitAndDisplay)
+            .format(ImageFormat::Png)
+            .quiet(Quiet::SuppressAll)
+            .build();
+        let chunks: Vec<String> = cmd.serialize_chunked(&data).unwrap().collect();
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks[0].starts_with("\x1b_Ga=T,f=100,q=2,m=1;"));
+        assert!(chunks[1].starts_with("\x1b_Gq=2,m=1;"));
+        assert!(chunks[3].starts_with("\x1b_Gq=2,m=0;"));
+
+        let mut into = Vec::new();
+        cmd.serialize_chunked_into(&data, &mut into).unwrap();
+        assert_eq!(into, chunks.concat().into_bytes());
+        assert_eq!(cmd.estimated_payload_len(data.len()).unwrap(), into.len());
+    }
+
+    #[test]
+    fn test_raw_key() {
+        let base = Command::builder()
+            .action(Action::Place)
+            .image_id(ImageId::new(1).unwrap());
+        let cmd = base.clone().raw_key('K', 5).build();
+        assert_eq!(cmd.to_string(), "\x1b_Ga=p,i=1,K=5\x1b\\");
+
+        let conflict = base.clone().raw_key('i', 2).build();
+        assert!(matches!(
+            conflict.serialize_control(),
+            Err(Error::ConflictingKey('i'))
+        ));
+        let repeated = base.clone().raw_key('K', 1).raw_key('K', 2).build();
+        assert!(matches!(
+            repeated.serialize_control(),
+            Err(Error::ConflictingKey('K'))
+        ));
+        let unsafe_value = base.raw_key('K', "1;2").build();
+        assert!(matches!(
+            unsafe_value.serialize_control(),
+            Err(Error::UnsafeControlData {
+                key: 'K',
+                byte: b';'
+            })
+        ));
+    }
+
+    #[test]
+    fn test_delete_id_range() {
+        let id = |n| ImageId::new(n).unwrap();
+        let cmd = Command::delete_id_range(id(3), id(9), true).unwrap();
+        assert_eq!(cmd.to_string(), "\x1b_Ga=d,d=R,x=3,y=9\x1b\\");
+        assert!(matches!(
+            Command::delete_id_range(id(9), id(3), false),
+            Err(Error::InvalidIdRange { from: 9, to: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_delete_target_params() {
+        let cmd = Command::builder()
+            .action(Action::Delete)
+            .delete_target(DeleteTarget::AtCellWithZIndex {
+                x: 3,
+                y: 4,
+                z_index: -1,
+                free_data: true,
+            })
+            .build();
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1b_Ga=d,d=Q,x=3,y=4,z=-1\x1b\\"
+        );
+
+        let cmd = Command::builder()
+            .action(Action::Delete)
+            .delete_target(DeleteTarget::ByRow {
+                row: 7,
+                free_data: false,
+            })
+            .build();
+        assert_eq!(cmd.serialize_control().unwrap(), "\x1b_Ga=d,d=y,y=7\x1b\\");
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let cmd = Command::builder()
+            .action(Action::TransmitAndDisplay)
+            .format(ImageFormat::Rgb)
+            .dimensions(1, 1)
+            .image_id(ImageId::new(9).unwrap())
+            .placement_id(PlacementId::new(2).unwrap())
+            .z_index(-3)
+            .cursor_policy(CursorPolicy::NoMove)
+            .build();
+        let seq = cmd.serialize_bytes(&[1, 2, 3]).unwrap();
+        let (parsed, data) = Command::parse(&seq).unwrap();
+        assert_eq!(parsed, cmd);
+        assert_eq!(data, [1, 2, 3]);
+
+        for cmd in [
+            Command::delete_all(),
+            Command::builder()
+                .action(Action::Delete)
+                .delete_target(DeleteTarget::ByIdRange {
+                    from: 2,
+                    to: 8,
+                    free_data: true,
+                })
+                .build(),
+            Command::compose_frames(ImageId::new(1).unwrap(), FrameComposition::default()),
+            Command::builder()
+                .action(Action::AnimationControl)
+                .image_id(ImageId::new(1).unwrap())
+                .animation_control(AnimationControl::Loading)
+                .loop_count(2)
+                .build(),
+        ] {
+            let (parsed, data) = Command::parse(&cmd.serialize_control_bytes().unwrap()).unwrap();
+            assert_eq!(parsed, cmd);
+            assert!(data.is_empty());
+        }
+
+        assert!(Command::parse(b"\x1b_Ga=t,zz=1;\x1b\\").is_err());
+        assert!(Command::parse(b"not a command").is_err());
+        // A placeholder grid wider than a u16 is not truncated
+        assert!(Command::parse(b"\x1b_Ga=p,i=1,U=1,c=2,r=1\x1b\\").is_ok());
+        assert!(Command::parse(b"\x1b_Ga=p,i=1,U=1,c=70000,r=1\x1b\\").is_err());
+        assert!(Command::parse(b"\x1b_Ga=p,i=1,U=1,c=2,r=70000\x1b\\").is_err());
+    }
+
+    #[test]
+    fn test_chunk_assembler() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let cmd = Command::builder()
+            .action(Action::Transmit)
+            .format(ImageFormat::Png)
+            .image_id(ImageId::new(4).unwrap())
+            .build();
+
+        let mut assembler = ChunkAssembler::new();
+        let mut chunks = cmd.serialize_chunked(&data).unwrap();
+        let mut result = None;
+        while let Some(chunk) = chunks.next_chunk() {
+            assert!(result.is_none());
+            result = assembler.push_sequence(chunk).unwrap();
+        }
+
+        let (assembled, payload) = result.unwrap();
+        assert_eq!(assembled, cmd);
+        assert_eq!(payload, data);
+        assert!(!assembler.is_pending());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let cmd = Command::builder()
+            .action(Action::Place)
+            .image_id(ImageId::new(5).unwrap())
+            .display_area(10, 4)
+            .quiet(Quiet::SuppressAll)
+            .build();
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), cmd);
+
+        // Omitted fields default, and validation runs on deserialization
+        let cmd: Command = serde_json::from_str(r#"{"action":"Place","image_id":9}"#).unwrap();
+        assert_eq!(cmd.serialize_control().unwrap(), "\x1b_Ga=p,i=9\x1b\\");
+        assert!(serde_json::from_str::<Command>(r#"{"image_id":0}"#).is_err());
+    }
+
+    #[test]
+    fn test_display_emits_escape_sequence() {
+        let cmd = Command::delete_by_id(ImageId::new(7).unwrap());
+        assert_eq!(cmd.to_string(), cmd.serialize_control().unwrap());
+        assert_eq!(format!("{}", Command::delete_all()), "\x1b_Ga=d,d=a\x1b\\");
+
+        // Invalid commands still format, without the unsafe raw key
+        let cmd = Command::builder()
+            .action(Action::Delete)
+            .raw_key('Q', "\x1b")
+            .build();
+        assert!(cmd.serialize_control().is_err());
+        assert_eq!(cmd.to_string(), "\x1b_Ga=d\x1b\\");
+    }
+
+    #[test]
+    fn test_serialize_control() {
+        let cmd = Command::delete_by_id(ImageId::new(7).unwrap());
+        assert_eq!(cmd.serialize_control().unwrap(), "\x1b_Ga=d,i=7,d=I\x1b\\");
+        assert_eq!(
+            cmd.serialize_control_bytes().unwrap(),
+            cmd.serialize_control().unwrap().into_bytes()
+        );
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn test_zlib_compression() {
+        use std::io::Read;
+
+        let data = vec![7u8; 4096];
+        let cmd = Command::builder()
+            .action(Action::Transmit)
+            .format(ImageFormat::Png)
+            .deflate()
+            .compress_threshold(1024)
+            .build();
+
+        let seq = cmd.serialize(&data).unwrap();
+        assert!(seq.starts_with("\x1b_Ga=t,f=100,o=z,S=4096;"));
+        let encoded = &seq[seq.find(';').unwrap() + 1..seq.len() - 2];
+        let compressed = STANDARD.decode(encoded).unwrap();
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, data);
+
+        // Below the threshold the payload is sent uncompressed without `o=`
+        let seq = cmd.serialize(&[1, 2, 3]).unwrap();
+        assert_eq!(seq, "\x1b_Ga=t,f=100;AQID\x1b\\");
+    }
+
+    #[test]
+    fn test_declared_compression_sent_as_is() {
+        let cmd = Command::builder()
+            .action(Action::Transmit)
+            .format(ImageFormat::Rgb)
+            .dimensions(1, 1)
+            .compression(Compression::Zlib)
+            .build();
+        assert_eq!(
+            cmd.serialize(&[1, 2, 3]).unwrap(),
+            "\x1b_Ga=t,f=24,s=1,v=1,o=z;AQID\x1b\\"
+        );
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn test_auto_compress() {
+        let cmd = Command::builder()
+            .action(Action::Transmit)
+            .format(ImageFormat::Rgb)
+            .dimensions(64, 64)
+            .auto_compress()
+            .build();
+
+        // Flat colour compresses well
+        let flat = vec![0x20u8; 64 * 64 * 3];
+        assert!(cmd.serialize(&flat).unwrap().contains("o=z"));
+
+        // Pseudo-random noise does not
+        let mut state = 0x1234_5678u32;
+        let noise: Vec<u8> = (0..64 * 64 * 3)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        assert!(!cmd.serialize(&noise).unwrap().contains("o=z"));
+    }
+
+    #[test]
+    fn test_chunk_size_validation() {
+        let data = vec![0u8; 30];
+        let cmd = Command::builder().chunk_size(8).build();
+        assert_eq!(cmd.serialize_chunked(&data).unwrap().count(), 5);
+
+        for size in [0, 6, 4100] {
+            let cmd = Command::builder().chunk_size(size).build();
+            assert!(matches!(
+                cmd.serialize_chunked(&data),
+                Err(Error::InvalidChunkSize(s)) if s == size
+            ));
+        }
+
+        let chunks = Command::builder().build().serialize_chunked(&data).unwrap();
+        assert!(chunks.with_chunk_size(10).is_err());
+    }
+
+    #[test]
+    fn test_quiet_from_u8_clamps() {
+        let cmd = Command::builder().action(Action::Query).quiet(3).build();
+        assert_eq!(cmd.serialize_control().unwrap(), "\x1b_Ga=q,q=2\x1b\\");
+
+        let (parsed, _) = Command::parse(b"\x1b_Ga=q,q=1\x1b\\").unwrap();
+        assert_eq!(
+            parsed,
+            Command::builder()
+                .action(Action::Query)
+                .quiet(Quiet::SuppressOk)
+                .build()
+        );
+        assert!(Command::parse(b"\x1b_Ga=q,q=5\x1b\\").is_err());
+
+        // The checked lookup rejects what the conversion clamps
+        for code in [3, 255] {
+            assert_eq!(Quiet::from(code), Quiet::SuppressAll);
+            assert_eq!(Quiet::from_code(code), None);
+        }
+        assert_eq!(Quiet::from_code(1), Some(Quiet::SuppressOk));
+    }
+
+    #[test]
+    fn test_zero_ids_rejected() {
+        assert!(matches!(
+            ImageId::try_from(0),
+            Err(Error::InvalidImageId(0))
+        ));
+        assert!(matches!(
+            PlacementId::try_from(0),
+            Err(Error::InvalidPlacementId(0))
+        ));
+        assert!(Command::parse(b"\x1b_Ga=p,i=0\x1b\\").is_err());
+    }
+
+    #[test]
+    fn test_serialize_chunked_bytes_matches_strings() {
+        let data = vec![7u8; 10_000];
+        let cmd = Command::builder().format(ImageFormat::Png).build();
+        let strings: Vec<String> = cmd.serialize_chunked(&data).unwrap().collect();
+        let bytes: Vec<Vec<u8>> = cmd.serialize_chunked_bytes(&data).unwrap().collect();
+        assert_eq!(bytes.len(), strings.len());
+        for (b, s) in bytes.iter().zip(&strings) {
+            assert_eq!(b, s.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_apc_injection_rejected() {
+        let mut buf = Vec::new();
+        let mut cw = ControlWriter::new(&mut buf);
+        assert!(matches!(
+            cw.key('x', "1\x1b\\\x1b_Ga=d"),
+            Err(Error::UnsafeControlData {
+                key: 'x',
+                byte: 0x1b
+            })
+        ));
+        assert!(cw.key('y', "2;AAAA").is_err());
+        // Nothing of a rejected pair is written
+        assert!(buf.is_empty());
+
+        let cmd = Command::builder()
+            .medium(TransmissionMedium::File)
+            .path("/tmp/a\0b.png")
+            .build();
+        assert!(matches!(
+            cmd.serialize_with_path(),
+            Err(Error::InvalidPath(_))
+        ));
+
+        let cmd = Command::builder()
+            .medium(TransmissionMedium::File)
+            .path("/tmp/\x1b\\;.png")
+            .build();
+        let seq = cmd.serialize_with_path().unwrap();
+        assert_eq!(seq.matches('\x1b').count(), 2);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_serialize_chunked_parallel() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let cmd = Command::builder()
+            .format(ImageFormat::Rgba)
+            .dimensions(125, 200)
+            .build();
+
+        let mut expected = Vec::new();
+        cmd.serialize_chunked_into(&data, &mut expected).unwrap();
+        assert_eq!(cmd.serialize_chunked_parallel(&data).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_scratch_buffer_reuses_allocation() {
+        let data = vec![3u8; 20_000];
+        let cmd = Command::builder().format(ImageFormat::Png).build();
+        let expected: String = cmd.serialize_chunked(&data).unwrap().collect();
+
+        let mut scratch = ScratchBuffer::with_capacity(data.len());
+        let capacity = scratch.capacity();
+        for _ in 0..3 {
+            let out = cmd
+                .serialize_chunked_with_buffer(&data, &mut scratch)
+                .unwrap();
+            assert_eq!(out, expected.as_bytes());
+        }
+        assert_eq!(scratch.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_size_estimation() {
+        let cmd = Command::builder()
+            .format(ImageFormat::Rgba)
+            .dimensions(100, 50)
+            .build();
+        for len in [0, 1, 3072, 3073, 20_000] {
+            let data = vec![7u8; len];
+            let chunks = cmd.serialize_chunked(&data).unwrap();
+            let total = chunks.total_bytes();
+            assert_eq!(total, chunks.map(|c| c.len()).sum::<usize>());
+            assert_eq!(cmd.estimated_payload_len(len).unwrap(), total);
+        }
+
+        // Data compressed by the caller is sent as-is, so the estimate holds
+        let cmd = cmd.to_builder().compression(Compression::Zlib).build();
+        let total = cmd.serialize_chunked(&[7; 5000]).unwrap().total_bytes();
+        assert_eq!(cmd.estimated_payload_len(5000).unwrap(), total);
+    }
+
+    #[test]
+    fn test_explicit_frame_setters() {
+        let cmd = Command::builder()
+            .action(Action::AnimationControl)
+            .image_id(ImageId::new(1).unwrap())
+            .edit_frame(2)
+            .frame_gap(80)
+            .current_frame(4)
+            .build();
+        let seq = cmd.serialize_control().unwrap();
+        assert_eq!(seq, "\x1b_Ga=a,i=1,r=2,c=4,z=80\x1b\\");
+        assert_eq!(Command::parse(seq.as_bytes()).unwrap().0, cmd);
+
+        let cmd = Command::builder()
+            .action(Action::Frame)
+            .image_id(ImageId::new(1).unwrap())
+            .edit_frame(3)
+            .base_frame(1)
+            .build();
+        assert_eq!(cmd.serialize(&[]).unwrap(), "\x1b_Ga=f,i=1,r=3,c=1;\x1b\\");
+
+        let cmd = Command::builder()
+            .action(Action::Frame)
+            .current_frame(2)
+            .build();
+        assert!(cmd.serialize(&[]).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_serialize_chunked_from_reader_matches_slice() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 256) as u8).collect();
+        let cmd = Command::builder().format(ImageFormat::Png).build();
+        let expected: Vec<String> = cmd.serialize_chunked(&data).unwrap().collect();
+
+        let streamed: Vec<Vec<u8>> = cmd
+            .serialize_chunked_from_reader(std::io::Cursor::new(&data))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(streamed.len(), expected.len());
+        for (s, e) in streamed.iter().zip(&expected) {
+            assert_eq!(s, e.as_bytes());
+        }
+
+        let empty = cmd.serialize_chunked_from_reader(std::io::empty()).unwrap();
+        assert_eq!(empty.count(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_serialize_chunked_gather() {
+        let data: Vec<u8> = (0..30_000u32).map(|i| (i % 253) as u8).collect();
+        let cmd = Command::builder()
+            .format(ImageFormat::Rgb)
+            .dimensions(100, 100)
+            .build();
+        let expected: Vec<String> = cmd.serialize_chunked(&data).unwrap().collect();
+
+        // Rows of 300 bytes, plus a trailing empty slice
+        let rows = data.chunks(300).chain([&[][..]]);
+        let gathered: Vec<Vec<u8>> = cmd
+            .serialize_chunked_gather(rows)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(gathered.len(), expected.len());
+        for (g, e) in gathered.iter().zip(&expected) {
+            assert_eq!(g, e.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_tmux_passthrough() {
+        let cmd = Command::builder()
+            .action(Action::Place)
+            .image_id(ImageId::new(3).unwrap())
+            .passthrough(Passthrough::Tmux)
+            .build();
+        assert_eq!(
+            cmd.serialize_control().unwrap(),
+            "\x1bPtmux;\x1b\x1b_Ga=p,i=3\x1b\x1b\\\x1b\\"
+        );
+
+        let data = vec![7u8; 5000];
+        let cmd = Command::builder()
+            .format(ImageFormat::Png)
+            .passthrough(Passthrough::Tmux)
+            .build();
+        let chunks = cmd.serialize_chunked(&data).unwrap();
+        let total = chunks.total_bytes();
+        let joined: String = chunks.collect();
+        assert_eq!(joined.len(), total);
+        assert_eq!(joined.matches("\x1bPtmux;\x1b\x1b_G").count(), 2);
+        assert_eq!(cmd.estimated_payload_len(data.len()).unwrap(), total);
+    }
+}
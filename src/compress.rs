@@ -0,0 +1,33 @@
+//! Zlib compression of payload data (requires the `zlib` feature)
+
+use crate::error::Result;
+use flate2::{Compression as Level, write::ZlibEncoder};
+use std::io::{Read, Write};
+
+/// Compress data with ZLIB deflate (RFC 1950)
+pub(crate) fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len() / 2), Level::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Number of leading bytes compressed to estimate the compression ratio
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Estimate whether compressing `data` saves enough bytes to be worth it
+///
+/// Compresses a prefix of the data and requires it to shrink by at least
+/// 10%; base64 overhead applies equally to both, so raw sizes are compared.
+pub(crate) fn worth_compressing(data: &[u8]) -> Result<bool> {
+    let sample = &data[..data.len().min(SAMPLE_SIZE)];
+    if sample.is_empty() {
+        return Ok(false);
+    }
+    let compressed = deflate(sample)?;
+    Ok(compressed.len() * 10 < sample.len() * 9)
+}
+
+/// Wrap a reader so that it yields ZLIB-compressed data
+pub(crate) fn deflate_reader<'a, R: Read + 'a>(reader: R) -> Box<dyn Read + 'a> {
+    Box::new(flate2::read::ZlibEncoder::new(reader, Level::default()))
+}
@@ -0,0 +1,81 @@
+//! Base64 encoding backend
+//!
+//! Uses the `base64` crate by default, or `base64-simd` with the `simd`
+//! feature. Both produce standard padded base64.
+
+use alloc::string::String;
+use alloc::vec;
+
+/// Raw bytes encoded per block when streaming into a sink
+const BLOCK_SIZE: usize = 3 * 1024;
+
+/// Encode `input` into the start of `out`, returning the encoded length
+///
+/// `out` must hold at least `input.len().div_ceil(3) * 4` bytes.
+#[cfg(not(feature = "simd"))]
+pub(crate) fn encode_slice(input: &[u8], out: &mut [u8]) -> usize {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    STANDARD
+        .encode_slice(input, out)
+        .expect("buffer sized for encoded data")
+}
+
+/// Encode `input` into the start of `out`, returning the encoded length
+///
+/// `out` must hold at least `input.len().div_ceil(3) * 4` bytes.
+#[cfg(feature = "simd")]
+pub(crate) fn encode_slice(input: &[u8], out: &mut [u8]) -> usize {
+    base64_simd::STANDARD
+        .encode(input, base64_simd::Out::from_slice(out))
+        .len()
+}
+
+/// Encode `input` block by block, passing each encoded block to `emit`
+///
+/// Whole blocks are encoded into a stack buffer; since the block size is a
+/// multiple of 3, only the final block can need padding.
+pub(crate) fn encode_each<E>(
+    input: &[u8],
+    mut emit: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut buf = [0u8; BLOCK_SIZE / 3 * 4];
+    for block in input.chunks(BLOCK_SIZE) {
+        let len = encode_slice(block, &mut buf);
+        emit(&buf[..len])?;
+    }
+    Ok(())
+}
+
+/// Encode `input` into a new string
+pub(crate) fn encode(input: &[u8]) -> String {
+    let mut out = vec![0u8; input.len().div_ceil(3) * 4];
+    let len = encode_slice(input, &mut out);
+    out.truncate(len);
+    String::from_utf8(out).expect("base64 output is ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_encode_matches_reference() {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for len in [0, 1, 2, 3, 4, 3071, 3072, 3073, data.len()] {
+            let expected = STANDARD.encode(&data[..len]);
+            assert_eq!(encode(&data[..len]), expected);
+
+            let mut streamed = Vec::new();
+            encode_each(&data[..len], |block| {
+                streamed.extend_from_slice(block);
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+            assert_eq!(streamed, expected.as_bytes());
+        }
+    }
+}
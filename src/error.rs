@@ -1,16 +1,17 @@
 //! Error types for the Kitty graphics protocol
 
+use alloc::string::String;
 use thiserror::Error;
 
 /// Result type alias for Kitty graphics protocol operations
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
 
 /// Error type for Kitty graphics protocol operations
 #[derive(Error, Debug)]
 pub enum Error {
     /// Base64 decoding error
     #[error("Base64 decoding error: {0}")]
-    Base64Decode(#[from] base64::DecodeError),
+    Base64Decode(#[cfg_attr(feature = "std", from)] base64::DecodeError),
 
     /// Invalid image dimensions
     #[error("Invalid image dimensions: width={width}, height={height}")]
@@ -24,10 +25,54 @@ pub enum Error {
     #[error("Invalid placement ID: {0}")]
     InvalidPlacementId(u32),
 
+    /// Image ID range whose start is past its end
+    #[error("Invalid image ID range: {from}..={to}")]
+    InvalidIdRange { from: u32, to: u32 },
+
     /// Invalid chunk size
     #[error("Invalid chunk size: {0} (must be multiple of 4, max 4096)")]
     InvalidChunkSize(usize),
 
+    /// Field that has no meaning for the command's action
+    #[error("Field `{field}` is not valid for action {action:?}")]
+    InvalidFieldForAction {
+        field: &'static str,
+        action: crate::types::Action,
+    },
+
+    /// Control data value containing a byte that could break out of the
+    /// escape sequence
+    #[error("Value for key `{key}` contains unsafe byte 0x{byte:02x}")]
+    UnsafeControlData { key: char, byte: u8 },
+
+    /// Raw control key that is not an ASCII letter
+    #[error("Invalid control key: {0:?}")]
+    InvalidKey(char),
+
+    /// Raw control key already written by a typed field
+    #[error("Key `{0}` conflicts with a field that is already set")]
+    ConflictingKey(char),
+
+    /// Field needing a newer protocol version than the strict target
+    #[error("Field `{field}` needs protocol version {required:?}, but {version:?} is targeted")]
+    UnsupportedFeature {
+        field: &'static str,
+        required: crate::types::ProtocolVersion,
+        version: crate::types::ProtocolVersion,
+    },
+
+    /// Command the terminal cannot display faithfully
+    #[error("Not supported by the terminal: {0}")]
+    UnsupportedByTerminal(&'static str),
+
+    /// File path that cannot be transmitted safely
+    #[error("Invalid path: {0:?}")]
+    InvalidPath(String),
+
+    /// Relative placement the terminal would reject
+    #[error("Invalid relative placement: {0}")]
+    InvalidRelativePlacement(&'static str),
+
     /// Missing required field
     #[error("Missing required field: {0}")]
     MissingField(&'static str),
@@ -37,21 +82,45 @@ pub enum Error {
     TerminalError(String),
 
     /// IO error
+    #[cfg(feature = "std")]
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
     /// UTF-8 error (FromUtf8Error)
     #[error("UTF-8 error: {0}")]
-    Utf8(#[from] std::string::FromUtf8Error),
+    Utf8(#[from] alloc::string::FromUtf8Error),
 
     /// UTF-8 error (Utf8Error)
     #[error("UTF-8 error: {0}")]
-    Utf8Error(#[from] std::str::Utf8Error),
+    Utf8Error(#[from] core::str::Utf8Error),
+
+    /// Image decoding error
+    #[cfg(feature = "image")]
+    #[error("Image decoding error: {0}")]
+    Image(#[from] image::ImageError),
+
+    /// GIF decoding error
+    #[cfg(feature = "gif")]
+    #[error("GIF decoding error: {0}")]
+    Gif(#[from] gif::DecodingError),
+
+    /// WebP decoding error
+    #[cfg(feature = "webp")]
+    #[error("WebP decoding error: {0}")]
+    WebP(#[from] image_webp::DecodingError),
 
     /// Invalid response from terminal
     #[error("Invalid response from terminal: {0}")]
     InvalidResponse(String),
 
+    /// The terminal did not answer in time
+    #[error("Timed out waiting for the terminal")]
+    Timeout,
+
+    /// Response is cut off; more data is needed to parse it
+    #[error("Incomplete response from terminal, more data needed")]
+    IncompleteResponse,
+
     /// Protocol error
     #[error("Protocol error: {0}")]
     Protocol(String),
@@ -68,3 +137,12 @@ impl Error {
         Self::TerminalError(msg.into())
     }
 }
+
+/// `base64::DecodeError` only implements `Error` with `std`, so it cannot be
+/// a `#[from]` source without it
+#[cfg(not(feature = "std"))]
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Self {
+        Self::Base64Decode(err)
+    }
+}
@@ -0,0 +1,163 @@
+//! Allocation of image IDs that do not collide within a process
+//!
+//! Every image in a terminal shares one ID space, so applications that
+//! hard-code IDs collide with each other and with libraries drawing into the
+//! same terminal. [`IdAllocator`] hands out unused IDs from a range and
+//! reuses them once the image is deleted.
+
+use crate::error::{Error, Result};
+use crate::types::ImageId;
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard};
+
+/// First ID of the default range
+///
+/// Small IDs are the ones applications tend to hard-code.
+const DEFAULT_FIRST: u32 = 256;
+
+/// Last ID of the default range
+///
+/// IDs up to 24 bits fit into the foreground color of Unicode placeholders.
+const DEFAULT_LAST: u32 = 0xff_ffff;
+
+/// Thread-safe allocator of image IDs from an inclusive range
+#[derive(Debug)]
+pub struct IdAllocator {
+    first: u32,
+    last: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Lowest ID never handed out
+    next: u32,
+    /// Released IDs below `next`
+    free: BTreeSet<u32>,
+}
+
+static GLOBAL: IdAllocator = IdAllocator::new();
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdAllocator {
+    /// Create an allocator for IDs `256..=0xffffff`
+    pub const fn new() -> Self {
+        Self::range_unchecked(DEFAULT_FIRST, DEFAULT_LAST)
+    }
+
+    /// Create an allocator for IDs `from..=to`
+    ///
+    /// Fails with [`Error::InvalidIdRange`] if `from` is greater than `to`.
+    pub fn with_range(from: ImageId, to: ImageId) -> Result<Self> {
+        if from > to {
+            return Err(Error::InvalidIdRange {
+                from: from.get(),
+                to: to.get(),
+            });
+        }
+        Ok(Self::range_unchecked(from.get(), to.get()))
+    }
+
+    /// Create an allocator for IDs `first..=last` without checking the range
+    const fn range_unchecked(first: u32, last: u32) -> Self {
+        Self {
+            first,
+            last,
+            state: Mutex::new(State {
+                next: first,
+                free: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// The allocator shared by every [`ImageDisplay`](crate::ImageDisplay)
+    /// that has no allocator of its own
+    pub fn global() -> &'static Self {
+        &GLOBAL
+    }
+
+    /// Lock the state, ignoring poisoning since it is always consistent
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Hand out an unused ID, preferring the lowest released one
+    ///
+    /// Returns `None` when every ID in the range is in use.
+    pub fn allocate(&self) -> Option<ImageId> {
+        let mut state = self.lock();
+        let id = match state.free.pop_first() {
+            Some(id) => id,
+            None if state.next <= self.last && state.next >= self.first => {
+                let id = state.next;
+                // Past `last` (or wrapped to 0) means the range is used up
+                state.next = id.wrapping_add(1);
+                id
+            }
+            None => return None,
+        };
+        ImageId::new(id)
+    }
+
+    /// Return `id` to the allocator once its image has been deleted
+    ///
+    /// IDs this allocator did not hand out are ignored.
+    pub fn release(&self, id: ImageId) {
+        if self.is_allocated(id) {
+            self.lock().free.insert(id.get());
+        }
+    }
+
+    /// Check whether `id` was handed out and not released since
+    pub fn is_allocated(&self, id: ImageId) -> bool {
+        let id = id.get();
+        let state = self.lock();
+        let handed_out = if state.next == 0 {
+            id >= self.first
+        } else {
+            (self.first..state.next).contains(&id)
+        };
+        handed_out && id <= self.last && !state.free.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u32) -> ImageId {
+        ImageId::new(n).unwrap()
+    }
+
+    #[test]
+    fn test_allocate_and_recycle() {
+        let ids = IdAllocator::with_range(id(10), id(12)).unwrap();
+        assert_eq!(ids.allocate(), Some(id(10)));
+        assert_eq!(ids.allocate(), Some(id(11)));
+        assert_eq!(ids.allocate(), Some(id(12)));
+        assert_eq!(ids.allocate(), None);
+
+        ids.release(id(11));
+        ids.release(id(11));
+        ids.release(id(42));
+        assert!(!ids.is_allocated(id(11)));
+        assert_eq!(ids.allocate(), Some(id(11)));
+        assert_eq!(ids.allocate(), None);
+
+        assert!(IdAllocator::with_range(id(5), id(4)).is_err());
+    }
+
+    #[test]
+    fn test_full_range_ends() {
+        let ids = IdAllocator::with_range(id(u32::MAX), id(u32::MAX)).unwrap();
+        assert_eq!(ids.allocate(), Some(id(u32::MAX)));
+        assert_eq!(ids.allocate(), None);
+        ids.release(id(u32::MAX));
+        assert_eq!(ids.allocate(), Some(id(u32::MAX)));
+    }
+}
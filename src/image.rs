@@ -1,14 +1,278 @@
 //! High-level image display utilities
 
-use crate::command::Command;
-use crate::error::Result;
-use crate::types::{Action, ImageFormat};
-use std::io::Write;
+use crate::animation::{AnimatedImage, FrameCommands};
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::ids::IdAllocator;
+use crate::medium::{self, MediumStrategy};
+use crate::terminal::{self, Terminal, WindowSize};
+use crate::types::{
+    Action, CursorPolicy, DeleteTarget, DetectedFormat, ImageFormat, ImageId, Passthrough,
+    PlacementId, Quiet,
+};
+use std::borrow::Cow;
+use std::io::{IsTerminal, Read, Write};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Channel order of raw pixel data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelOrder {
+    /// 3 bytes per pixel: red, green, blue
+    Rgb,
+    /// 4 bytes per pixel: red, green, blue, alpha
+    Rgba,
+    /// 3 bytes per pixel: blue, green, red
+    Bgr,
+    /// 4 bytes per pixel: blue, green, red, alpha
+    Bgra,
+}
+
+impl ChannelOrder {
+    /// Bytes per pixel
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgb | Self::Bgr => 3,
+            Self::Rgba | Self::Bgra => 4,
+        }
+    }
+}
+
+/// Memory layout of a raw frame buffer, as handed over by GUI toolkits and
+/// video decoders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PixelLayout {
+    order: ChannelOrder,
+    stride: Option<usize>,
+}
+
+impl PixelLayout {
+    /// Tightly packed rows with the given channel order
+    pub fn new(order: ChannelOrder) -> Self {
+        Self {
+            order,
+            stride: None,
+        }
+    }
+
+    /// Set the number of bytes from the start of one row to the next
+    ///
+    /// Needed when rows are padded, e.g. to a multiple of 64 bytes.
+    pub fn stride(mut self, bytes: usize) -> Self {
+        self.stride = Some(bytes);
+        self
+    }
+
+    /// Repack a frame into tightly packed RGB or RGBA
+    ///
+    /// Data that already is packed RGB or RGBA is borrowed, not copied.
+    fn pack<'a>(
+        &self,
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(Cow<'a, [u8]>, ImageFormat)> {
+        let invalid = Error::InvalidDimensions { width, height };
+        let bpp = self.order.bytes_per_pixel();
+        let row_len = width as usize * bpp;
+        let stride = self.stride.unwrap_or(row_len);
+        let rows = height as usize;
+        let needed = match rows {
+            0 => 0,
+            rows => stride * (rows - 1) + row_len,
+        };
+        if stride < row_len || data.len() < needed {
+            return Err(invalid);
+        }
+
+        let format = if bpp == 3 {
+            ImageFormat::Rgb
+        } else {
+            ImageFormat::Rgba
+        };
+        let swap = matches!(self.order, ChannelOrder::Bgr | ChannelOrder::Bgra);
+        if !swap && stride == row_len {
+            return Ok((Cow::Borrowed(&data[..needed]), format));
+        }
+
+        let mut packed = Vec::with_capacity(row_len * rows);
+        for row in (0..rows).map(|y| &data[y * stride..y * stride + row_len]) {
+            if swap {
+                for pixel in row.chunks_exact(bpp) {
+                    packed.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                    packed.extend_from_slice(&pixel[3..]);
+                }
+            } else {
+                packed.extend_from_slice(row);
+            }
+        }
+        Ok((Cow::Owned(packed), format))
+    }
+}
+
+/// How an image is scaled to the cells available for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Fit {
+    /// Scale to fit inside the area, keeping the aspect ratio
+    Contain,
+    /// Scale to cover the whole area, keeping the aspect ratio and cropping
+    /// what does not fit
+    Cover,
+    /// Stretch to exactly the area
+    Fill,
+    /// Keep the image's own size, only shrinking it to `max_cols`/`max_rows`
+    #[default]
+    Native,
+}
+
+/// Cell size assumed when the terminal does not report pixel sizes
+pub(crate) const FALLBACK_CELL: (u32, u32) = (10, 20);
+
+/// Scaling options for [`ImageDisplay`]
+///
+/// The area an image is fitted to is the window, limited further by
+/// `max_cols` and `max_rows`. [`Fit::Native`] ignores the window size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct DisplayOptions {
+    fit: Fit,
+    max_cols: Option<u32>,
+    max_rows: Option<u32>,
+    #[cfg(feature = "image")]
+    resize_pixels: bool,
+}
+
+/// Where a fitted image ends up, computed by [`DisplayOptions::layout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FitLayout {
+    /// Columns to display the image in (`c=`)
+    pub cols: u32,
+    /// Rows to display the image in (`r=`)
+    pub rows: u32,
+    /// Part of the image to display as `(x, y, width, height)`, when cropped
+    pub source: Option<(u32, u32, u32, u32)>,
+    /// Width in pixels the image is shown at
+    pub width: u32,
+    /// Height in pixels the image is shown at
+    pub height: u32,
+}
+
+impl DisplayOptions {
+    /// Options that display images at their own size
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how images are scaled
+    pub fn fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    /// Limit the width to `cols` cells
+    pub fn max_cols(mut self, cols: u32) -> Self {
+        self.max_cols = Some(cols);
+        self
+    }
+
+    /// Limit the height to `rows` cells
+    pub fn max_rows(mut self, rows: u32) -> Self {
+        self.max_rows = Some(rows);
+        self
+    }
+
+    /// Resize decoded images to the size they are shown at before sending
+    ///
+    /// Saves bandwidth for large photos, at the cost of resampling on the
+    /// client. Only applies to [`ImageDisplay::display_dynamic`].
+    #[cfg(feature = "image")]
+    pub fn resize_pixels(mut self, resize: bool) -> Self {
+        self.resize_pixels = resize;
+        self
+    }
+
+    /// Check whether the window size is needed to lay out images
+    fn needs_window(&self) -> bool {
+        self.fit != Fit::Native || self.max_cols.is_some() || self.max_rows.is_some()
+    }
+
+    /// Compute the cells an image `width`x`height` pixels is displayed in
+    ///
+    /// Returns `None` when the image is shown at its own size. Without pixel
+    /// sizes in `window`, cells are assumed to be twice as high as wide.
+    pub fn layout(&self, width: u32, height: u32, window: &WindowSize) -> Option<FitLayout> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let (cell_w, cell_h) = match (window.cell_width(), window.cell_height()) {
+            (0, _) | (_, 0) => (u64::from(FALLBACK_CELL.0), u64::from(FALLBACK_CELL.1)),
+            (w, h) => (u64::from(w), u64::from(h)),
+        };
+        let limit = |max: Option<u32>, screen: u16| match (max, screen) {
+            (max, _) if self.fit == Fit::Native => max,
+            (max, 0) => max,
+            (Some(max), screen) => Some(max.min(u32::from(screen))),
+            (None, screen) => Some(u32::from(screen)),
+        };
+        let area_cols = limit(self.max_cols, window.cols);
+        let area_rows = limit(self.max_rows, window.rows);
+        let scale_x = area_cols.map(|c| (u64::from(c) * cell_w) as f64 / f64::from(width));
+        let scale_y = area_rows.map(|r| (u64::from(r) * cell_h) as f64 / f64::from(height));
+
+        match self.fit {
+            Fit::Native | Fit::Contain => {
+                let scale = match (scale_x, scale_y) {
+                    (Some(x), Some(y)) => x.min(y),
+                    (Some(s), None) | (None, Some(s)) => s,
+                    (None, None) => return None,
+                };
+                if self.fit == Fit::Native && scale >= 1.0 {
+                    return None;
+                }
+                let shown_w = ((f64::from(width) * scale).round() as u32).max(1);
+                let shown_h = ((f64::from(height) * scale).round() as u32).max(1);
+                let cells = |px: u32, cell: u64, area: Option<u32>| {
+                    let cells = u64::from(px).div_ceil(cell) as u32;
+                    cells.min(area.unwrap_or(u32::MAX)).max(1)
+                };
+                Some(FitLayout {
+                    cols: cells(shown_w, cell_w, area_cols),
+                    rows: cells(shown_h, cell_h, area_rows),
+                    source: None,
+                    width: shown_w,
+                    height: shown_h,
+                })
+            }
+            Fit::Fill | Fit::Cover => {
+                let (cols, rows) = (area_cols?.max(1), area_rows?.max(1));
+                let area_w = (u64::from(cols) * cell_w) as u32;
+                let area_h = (u64::from(rows) * cell_h) as u32;
+                let source = (self.fit == Fit::Cover).then(|| {
+                    let scale = scale_x.unwrap_or(1.0).max(scale_y.unwrap_or(1.0));
+                    let src_w = ((f64::from(area_w) / scale).round() as u32).clamp(1, width);
+                    let src_h = ((f64::from(area_h) / scale).round() as u32).clamp(1, height);
+                    ((width - src_w) / 2, (height - src_h) / 2, src_w, src_h)
+                });
+                Some(FitLayout {
+                    cols,
+                    rows,
+                    source,
+                    width: area_w,
+                    height: area_h,
+                })
+            }
+        }
+    }
+}
 
 /// A high-level interface for displaying images in the terminal
 pub struct ImageDisplay {
-    quiet: u8,
+    quiet: Quiet,
+    auto_compress: bool,
+    medium: MediumStrategy,
+    passthrough: Passthrough,
+    terminal: Option<Mutex<Terminal>>,
+    options: DisplayOptions,
+    ids: Option<Arc<IdAllocator>>,
 }
 
 impl Default for ImageDisplay {
@@ -20,15 +284,181 @@ impl Default for ImageDisplay {
 impl ImageDisplay {
     /// Create a new ImageDisplay instance
     pub fn new() -> Self {
-        Self { quiet: 2 }
+        Self {
+            quiet: Quiet::SuppressAll,
+            auto_compress: false,
+            medium: MediumStrategy::Direct,
+            passthrough: Passthrough::detect(),
+            terminal: None,
+            options: DisplayOptions::new(),
+            ids: None,
+        }
+    }
+
+    /// Set quiet mode; raw `u8` values above 2 clamp to [`Quiet::SuppressAll`]
+    pub fn quiet(mut self, mode: impl Into<Quiet>) -> Self {
+        self.quiet = mode.into();
+        self
     }
 
-    /// Set quiet mode (0 = all responses, 1 = suppress OK, 2 = suppress all)
-    pub fn quiet(mut self, mode: u8) -> Self {
-        self.quiet = mode;
+    /// Compress image data when it noticeably reduces the transmitted size
+    ///
+    /// Requires the `zlib` feature, otherwise this is a no-op.
+    pub fn auto_compress(mut self) -> Self {
+        self.auto_compress = true;
         self
     }
 
+    /// Choose how image data is sent to the terminal
+    ///
+    /// [`MediumStrategy::Auto`] uses shared memory or a temporary file for
+    /// local sessions. Over SSH every strategy falls back to direct
+    /// transmission, see [`medium::is_remote_session`].
+    pub fn medium_strategy(mut self, strategy: MediumStrategy) -> Self {
+        self.medium = strategy;
+        self
+    }
+
+    /// Wrap sequences for a terminal multiplexer
+    ///
+    /// Defaults to [`Passthrough::detect`], so images show up inside tmux
+    /// when it runs with `allow-passthrough on`.
+    pub fn passthrough(mut self, mode: Passthrough) -> Self {
+        self.passthrough = mode;
+        self
+    }
+
+    /// Write to `terminal` instead of stdout
+    ///
+    /// Replies are read from the terminal too. Since it may run elsewhere,
+    /// image data is always transmitted directly.
+    pub fn terminal(mut self, terminal: Terminal) -> Self {
+        self.terminal = Some(Mutex::new(terminal));
+        self
+    }
+
+    /// Scale images to the terminal as described by `options`
+    pub fn options(mut self, options: DisplayOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Allocate image IDs from `ids` instead of [`IdAllocator::global`]
+    pub fn id_allocator(mut self, ids: Arc<IdAllocator>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// The allocator image IDs come from
+    fn ids(&self) -> &IdAllocator {
+        self.ids.as_deref().unwrap_or(IdAllocator::global())
+    }
+
+    /// Allocate an image ID no other user of the allocator has
+    ///
+    /// Release it with [`release_id`](Self::release_id) once the image is
+    /// deleted; [`ImageHandle`] does so itself.
+    pub fn allocate_id(&self) -> Result<ImageId> {
+        self.ids()
+            .allocate()
+            .ok_or_else(|| Error::protocol("no free image IDs left"))
+    }
+
+    /// Return an image ID from [`allocate_id`](Self::allocate_id) for reuse
+    pub fn release_id(&self, image_id: ImageId) {
+        self.ids().release(image_id);
+    }
+
+    /// Create a command builder with this display's settings applied
+    fn builder(&self, action: Action, format: ImageFormat) -> CommandBuilder {
+        let builder = Command::builder()
+            .action(action)
+            .format(format)
+            .quiet(self.quiet)
+            .passthrough(self.passthrough);
+        if self.auto_compress {
+            builder.auto_compress()
+        } else {
+            builder
+        }
+    }
+
+    /// Lay out an image `width`x`height` pixels according to the options
+    ///
+    /// The window size comes from the configured terminal, or stdout; if it
+    /// cannot be determined the image is shown at its own size.
+    fn layout(&self, width: u32, height: u32) -> Option<FitLayout> {
+        if !self.options.needs_window() {
+            return None;
+        }
+        self.options
+            .layout(width, height, &self.window_size().ok()?)
+    }
+
+    /// Window size of the configured terminal, or stdout
+    pub(crate) fn window_size(&self) -> Result<WindowSize> {
+        match &self.terminal {
+            Some(terminal) => terminal
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .window_size(),
+            None => terminal::get_window_size(),
+        }
+    }
+
+    /// Apply the layout for an image `width`x`height` pixels to `builder`
+    fn fit(&self, builder: CommandBuilder, width: u32, height: u32) -> CommandBuilder {
+        apply_layout(builder, self.layout(width, height))
+    }
+
+    /// Send a command's data to the terminal with the configured medium
+    fn send(&self, builder: CommandBuilder, data: &[u8]) -> Result<()> {
+        if self.terminal.is_some() {
+            return self.write_out(|mut w| builder.build().serialize_chunked_into(data, &mut w));
+        }
+        medium::transmit(builder, data, self.medium, medium::DEFAULT_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Stream a command's data from `reader` to the terminal
+    ///
+    /// Only a chunk at a time is held in memory, so the data is always
+    /// sent directly, whatever the medium strategy.
+    fn send_reader(&self, builder: CommandBuilder, reader: impl Read) -> Result<()> {
+        let cmd = builder.build();
+        let mut chunks = cmd.serialize_chunked_from_reader(reader)?;
+        self.write_out(|w| {
+            while let Some(chunk) = chunks.next_chunk()? {
+                w.write_all(chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write to the configured terminal, or stdout, and flush
+    fn write_out(&self, write: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+        match &self.terminal {
+            Some(terminal) => {
+                let mut terminal = terminal.lock().unwrap_or_else(|e| e.into_inner());
+                write(&mut *terminal)?;
+                terminal.flush()?;
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                write(&mut stdout)?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a payload-less command to the terminal
+    pub(crate) fn write_control(&self, cmd: &Command) -> Result<()> {
+        let cmd = cmd.to_builder().passthrough(self.passthrough).build();
+        let seq = cmd.serialize_control_bytes()?;
+        self.write_out(|w| Ok(w.write_all(&seq)?))
+    }
+
     /// Display a PNG image from file
     pub fn display_png_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let data = std::fs::read(path)?;
@@ -37,76 +467,846 @@ impl ImageDisplay {
 
     /// Display a PNG image from memory
     pub fn display_png(&self, data: &[u8]) -> Result<()> {
-        let chunks = Command::transmit_png(data)?;
-        let mut stdout = std::io::stdout().lock();
-        for chunk in chunks {
-            stdout.write_all(chunk.as_bytes())?;
+        self.send(self.png_builder(data), data)
+    }
+
+    /// Display a PNG image read from `reader`, e.g. stdin or a socket
+    ///
+    /// The data is streamed in chunks instead of being read into memory
+    /// first, so arbitrarily large images can be piped through. Only the
+    /// header is read up front, to fit the image to the options.
+    pub fn display_png_reader(&self, mut reader: impl Read) -> Result<()> {
+        let mut header = Vec::with_capacity(24);
+        (&mut reader).take(24).read_to_end(&mut header)?;
+        let builder = self.png_builder(&header);
+        self.send_reader(builder, header.as_slice().chain(reader))
+    }
+
+    /// Display an image piped into stdin, like `kitten icat`
+    ///
+    /// The format is detected from the data: PNG images are streamed to the
+    /// terminal, other formats need the `image` feature to be decoded.
+    /// Without a configured terminal, commands go to the controlling
+    /// terminal (`/dev/tty`) instead of stdout, so pipelines keep working.
+    pub fn display_stdin(&self) -> Result<()> {
+        let stdin = std::io::stdin();
+        if stdin.is_terminal() {
+            return Err(Error::protocol("no image data piped into stdin"));
         }
-        stdout.flush()?;
-        Ok(())
+        if self.terminal.is_some() {
+            return self.display_sniffed(stdin.lock());
+        }
+        let display = Self {
+            quiet: self.quiet,
+            auto_compress: self.auto_compress,
+            medium: self.medium,
+            passthrough: self.passthrough,
+            terminal: Some(Mutex::new(Terminal::open()?)),
+            options: self.options,
+            ids: self.ids.clone(),
+        };
+        display.display_sniffed(stdin.lock())
+    }
+
+    /// Display image data from `reader`, detecting its format
+    fn display_sniffed(&self, mut reader: impl Read) -> Result<()> {
+        let mut header = Vec::with_capacity(24);
+        (&mut reader).take(24).read_to_end(&mut header)?;
+        match ImageFormat::detect(&header) {
+            DetectedFormat::Png => self.display_png_reader(header.as_slice().chain(reader)),
+            format => {
+                let mut data = header;
+                reader.read_to_end(&mut data)?;
+                self.display_detected(&data, format)
+            }
+        }
+    }
+
+    /// Display image data of any format read from `reader`
+    ///
+    /// `width` and `height` are the dimensions of raw RGB(A) data and are
+    /// ignored for PNG. At most one image worth of raw data is read; less
+    /// fails with [`Error::InvalidDimensions`] before anything is sent.
+    pub fn display_from_reader(
+        &self,
+        reader: impl Read,
+        format: ImageFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        let bpp = match format {
+            ImageFormat::Png => return self.display_png_reader(reader),
+            ImageFormat::Rgb => 3,
+            ImageFormat::Rgba => 4,
+        };
+        let mut data = Vec::new();
+        reader
+            .take(u64::from(width) * u64::from(height) * bpp)
+            .read_to_end(&mut data)?;
+        self.display_raw(&data, width, height, format)
+    }
+
+    /// Display a PNG image with its top left corner at `row`, `col`
+    ///
+    /// Rows and columns are 1-based, as in [`Terminal::cursor_position`].
+    /// The cursor is saved, moved there and restored afterwards, so text
+    /// output continues where it was.
+    pub fn display_at(&self, row: u16, col: u16, data: &[u8]) -> Result<()> {
+        let builder = self.png_builder(data).cursor_policy(CursorPolicy::NoMove);
+        self.at(row, col, || self.send(builder, data))
+    }
+
+    /// Display a PNG image centered in the window
+    ///
+    /// The image is scaled with `options` instead of the display's own
+    /// options, then placed like [`display_at`](Self::display_at).
+    pub fn display_centered(&self, data: &[u8], options: DisplayOptions) -> Result<()> {
+        let (width, height) = png_dimensions(data)
+            .ok_or_else(|| Error::protocol("cannot center: data is not a PNG image"))?;
+        let window = self.window_size()?;
+        let layout = options.layout(width, height, &window);
+        let (cols, rows) = match layout {
+            Some(layout) => (layout.cols, layout.rows),
+            None => native_cells(width, height, &window),
+        };
+        let (row, col) = centered(cols, rows, &window);
+
+        let builder = self
+            .builder(Action::TransmitAndDisplay, ImageFormat::Png)
+            .cursor_policy(CursorPolicy::NoMove);
+        let builder = apply_layout(builder, layout);
+        self.at(row, col, || self.send(builder, data))
+    }
+
+    /// Send a place command with the cursor at `row`, `col`, leaving the
+    /// cursor where it was
+    pub(crate) fn place_at(&self, row: u16, col: u16, builder: CommandBuilder) -> Result<()> {
+        let cmd = builder
+            .action(Action::Place)
+            .quiet(self.quiet)
+            .cursor_policy(CursorPolicy::NoMove)
+            .build();
+        self.at(row, col, || self.write_control(&cmd))
+    }
+
+    /// Run `display` with the cursor moved to `row`, `col`, restoring it after
+    fn at(&self, row: u16, col: u16, display: impl FnOnce() -> Result<()>) -> Result<()> {
+        let row = row.max(1);
+        let col = col.max(1);
+        self.write_out(|w| Ok(write!(w, "\x1b7\x1b[{row};{col}H")?))?;
+        let result = display();
+        self.write_out(|w| Ok(w.write_all(b"\x1b8")?))?;
+        result
+    }
+
+    /// Command builder for displaying PNG data, fitted to the options
+    fn png_builder(&self, data: &[u8]) -> CommandBuilder {
+        let builder = self.builder(Action::TransmitAndDisplay, ImageFormat::Png);
+        match png_dimensions(data) {
+            Some((width, height)) => self.fit(builder, width, height),
+            None => builder,
+        }
+    }
+
+    /// Display an image file, detecting its format
+    ///
+    /// See [`display_image_data`](Self::display_image_data).
+    pub fn display_image_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.display_image_data(&std::fs::read(path)?)
+    }
+
+    /// Display encoded image data, detecting its format
+    ///
+    /// PNG data is sent unchanged, since the terminal decodes it itself.
+    /// With the `gif` feature GIFs are played as animations; JPEG, BMP,
+    /// WebP and, without it, GIF need the `image` feature and are decoded
+    /// and sent as RGBA.
+    pub fn display_image_data(&self, data: &[u8]) -> Result<()> {
+        self.display_detected(data, ImageFormat::detect(data))
+    }
+
+    /// Display encoded image data of a detected format
+    fn display_detected(&self, data: &[u8], format: DetectedFormat) -> Result<()> {
+        match format {
+            DetectedFormat::Png => return self.display_png(data),
+            #[cfg(feature = "gif")]
+            DetectedFormat::Gif => return self.display_gif(data).map(drop),
+            DetectedFormat::Unknown => return Err(Error::protocol("unrecognized image format")),
+            _ => {}
+        }
+
+        #[cfg(feature = "image")]
+        {
+            let decoded = match format {
+                DetectedFormat::Jpeg => ::image::ImageFormat::Jpeg,
+                DetectedFormat::Gif => ::image::ImageFormat::Gif,
+                DetectedFormat::Bmp => ::image::ImageFormat::Bmp,
+                DetectedFormat::WebP => ::image::ImageFormat::WebP,
+                _ => return Err(Error::protocol(format!("cannot decode {format:?} images"))),
+            };
+            self.display_dynamic(&::image::load_from_memory_with_format(data, decoded)?)
+        }
+        #[cfg(not(feature = "image"))]
+        Err(Error::protocol(format!(
+            "{format:?} images need the `image` feature"
+        )))
     }
 
     /// Display raw RGBA data
     pub fn display_rgba(&self, data: &[u8], width: u32, height: u32) -> Result<()> {
-        let chunks = Command::transmit_rgba(data, width, height)?;
-        let mut stdout = std::io::stdout().lock();
-        for chunk in chunks {
-            stdout.write_all(chunk.as_bytes())?;
-        }
-        stdout.flush()?;
-        Ok(())
+        self.display_raw(data, width, height, ImageFormat::Rgba)
     }
 
     /// Display raw RGB data
     pub fn display_rgb(&self, data: &[u8], width: u32, height: u32) -> Result<()> {
-        let chunks = Command::transmit_rgb(data, width, height)?;
-        let mut stdout = std::io::stdout().lock();
-        for chunk in chunks {
-            stdout.write_all(chunk.as_bytes())?;
+        self.display_raw(data, width, height, ImageFormat::Rgb)
+    }
+
+    /// Display a frame buffer in any [`PixelLayout`]
+    ///
+    /// BGR(A) data and padded rows are repacked into RGB(A) first; packed
+    /// RGB(A) data is sent as is.
+    pub fn display_frame(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        layout: PixelLayout,
+    ) -> Result<()> {
+        let (data, format) = layout.pack(data, width, height)?;
+        self.display_raw(&data, width, height, format)
+    }
+
+    /// Display an image decoded with the `image` crate
+    ///
+    /// RGB8 and RGBA8 images are sent without conversion; other pixel types
+    /// are converted to RGBA8.
+    #[cfg(feature = "image")]
+    pub fn display_dynamic(&self, image: &::image::DynamicImage) -> Result<()> {
+        let (width, height) = (image.width(), image.height());
+        if self.options.resize_pixels
+            && let Some(layout) = self.layout(width, height)
+            && u64::from(layout.width) * u64::from(layout.height)
+                < u64::from(width) * u64::from(height)
+        {
+            let (x, y, w, h) = layout.source.unwrap_or((0, 0, width, height));
+            let resized = image.crop_imm(x, y, w, h).resize_exact(
+                layout.width,
+                layout.height,
+                ::image::imageops::FilterType::Triangle,
+            );
+            return self.display_dynamic_unscaled(&resized);
         }
-        stdout.flush()?;
-        Ok(())
+        self.display_dynamic_unscaled(image)
+    }
+
+    /// Send a decoded image as RGB8 or RGBA8
+    #[cfg(feature = "image")]
+    fn display_dynamic_unscaled(&self, image: &::image::DynamicImage) -> Result<()> {
+        let (width, height) = (image.width(), image.height());
+        match image {
+            ::image::DynamicImage::ImageRgb8(rgb) => self.display_rgb(rgb.as_raw(), width, height),
+            ::image::DynamicImage::ImageRgba8(rgba) => {
+                self.display_rgba(rgba.as_raw(), width, height)
+            }
+            other => self.display_rgba(other.to_rgba8().as_raw(), width, height),
+        }
+    }
+
+    /// Display an animation and start playing it in the terminal
+    ///
+    /// The first frame is displayed like [`display_rgba`](Self::display_rgba),
+    /// the others are sent as animation frames (see
+    /// [`AnimatedImage::frame_commands`]). The image gets a newly allocated
+    /// ID, which is returned; it stays allocated until released with
+    /// [`release_id`](Self::release_id).
+    pub fn display_animated(&self, image: &AnimatedImage) -> Result<ImageId> {
+        let image_id = self.allocate_id()?;
+        let result = self.transmit_frames(image, image_id).and_then(|commands| {
+            self.write_control(&commands.start.to_builder().quiet(self.quiet).build())
+        });
+        if result.is_err() {
+            self.release_id(image_id);
+        }
+        result.map(|()| image_id)
+    }
+
+    /// Display the first frame of `image` under `image_id` and send the
+    /// others, without starting the animation
+    pub(crate) fn transmit_frames(
+        &self,
+        image: &AnimatedImage,
+        image_id: ImageId,
+    ) -> Result<FrameCommands> {
+        let commands = image.frame_commands(image_id)?;
+        let root = self
+            .builder(Action::TransmitAndDisplay, commands.root_format)
+            .image_id(image_id);
+        let root = match commands.root_format {
+            ImageFormat::Png => root,
+            _ => root.dimensions(image.width, image.height),
+        };
+        self.send(self.fit(root, image.width, image.height), &commands.root)?;
+        self.write_control(&commands.root_gap.to_builder().quiet(self.quiet).build())?;
+        for (builder, data) in &commands.frames {
+            self.send_frame(builder.clone(), data)?;
+        }
+        Ok(commands)
+    }
+
+    /// Send animation frame data with this display's quiet and passthrough
+    /// modes
+    pub(crate) fn send_frame(&self, builder: CommandBuilder, data: &[u8]) -> Result<()> {
+        self.send(
+            builder.quiet(self.quiet).passthrough(self.passthrough),
+            data,
+        )
+    }
+
+    /// The quiet mode commands are sent with
+    pub(crate) fn quiet_mode(&self) -> Quiet {
+        self.quiet
+    }
+
+    /// Display an animated PNG from memory and start playing it
+    ///
+    /// The frames are sent as PNG images for the terminal to decode, see
+    /// [`split_apng`](crate::animation::split_apng). Returns the allocated
+    /// image ID, see [`display_animated`](Self::display_animated).
+    pub fn display_apng(&self, data: &[u8]) -> Result<ImageId> {
+        self.display_animated(&crate::animation::split_apng(data)?)
+    }
+
+    /// Display an animated PNG file and start playing it
+    pub fn display_apng_file<P: AsRef<Path>>(&self, path: P) -> Result<ImageId> {
+        self.display_apng(&std::fs::read(path)?)
+    }
+
+    /// Display an animated WebP image from memory and start playing it
+    ///
+    /// Returns the allocated image ID, see [`display_animated`](Self::display_animated).
+    #[cfg(feature = "webp")]
+    pub fn display_webp(&self, data: &[u8]) -> Result<ImageId> {
+        self.display_animated(&crate::animation::decode_webp(data)?)
+    }
+
+    /// Display an animated WebP file and start playing it
+    #[cfg(feature = "webp")]
+    pub fn display_webp_file<P: AsRef<Path>>(&self, path: P) -> Result<ImageId> {
+        self.display_webp(&std::fs::read(path)?)
+    }
+
+    /// Display an animated GIF from memory and start playing it
+    ///
+    /// Returns the allocated image ID, see [`display_animated`](Self::display_animated).
+    #[cfg(feature = "gif")]
+    pub fn display_gif(&self, data: &[u8]) -> Result<ImageId> {
+        self.display_animated(&crate::animation::decode_gif(data)?)
+    }
+
+    /// Display an animated GIF file and start playing it
+    #[cfg(feature = "gif")]
+    pub fn display_gif_file<P: AsRef<Path>>(&self, path: P) -> Result<ImageId> {
+        self.display_gif(&std::fs::read(path)?)
+    }
+
+    /// Display raw pixel data after checking it matches the dimensions
+    fn display_raw(&self, data: &[u8], width: u32, height: u32, format: ImageFormat) -> Result<()> {
+        let bpp = if format == ImageFormat::Rgb { 3 } else { 4 };
+        if data.len() != width as usize * height as usize * bpp {
+            return Err(Error::InvalidDimensions { width, height });
+        }
+
+        let builder = self
+            .builder(Action::TransmitAndDisplay, format)
+            .dimensions(width, height);
+        self.send(self.fit(builder, width, height), data)
+    }
+
+    /// Display a PNG file by letting the terminal read it directly (t=f)
+    ///
+    /// Only the path is sent, so this avoids streaming the file through the
+    /// tty. The terminal can only read it when it runs on the same machine,
+    /// so in remote sessions the file contents are sent directly instead.
+    pub fn display_file_via_medium<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if medium::is_remote_session() || self.terminal.is_some() {
+            return self.display_png_file(path);
+        }
+        let cmd = Command::transmit_file(path, ImageFormat::Png)?
+            .display()
+            .quiet(self.quiet)
+            .passthrough(self.passthrough)
+            .build();
+        let seq = cmd.serialize_with_path()?;
+        self.write_out(|w| Ok(w.write_all(seq.as_bytes())?))
     }
 
     /// Clear all visible images
     pub fn clear_all(&self) -> Result<()> {
-        let cmd = Command::delete_all();
-        let seq = cmd.serialize(&[])?;
-        let mut stdout = std::io::stdout().lock();
-        stdout.write_all(seq.as_bytes())?;
-        stdout.flush()?;
-        Ok(())
+        self.write_control(&Command::delete_all())
     }
 
     /// Transmit an image without displaying it (returns image ID for later use)
-    pub fn transmit_png(&self, data: &[u8], image_id: u32) -> Result<()> {
-        let cmd = Command::builder()
-            .action(Action::Transmit)
-            .format(ImageFormat::Png)
+    pub fn transmit_png(&self, data: &[u8], image_id: ImageId) -> Result<()> {
+        self.send(
+            self.builder(Action::Transmit, ImageFormat::Png)
+                .image_id(image_id),
+            data,
+        )
+    }
+
+    /// Transmit a PNG image under a fresh image number and return its ID
+    ///
+    /// The image is sent with `I=` so the terminal picks a free image ID,
+    /// and the reply is read to learn which one. This requires a controlling
+    /// terminal or a [`terminal`](Self::terminal) to read the reply from.
+    pub fn transmit_with_number(&self, data: &[u8]) -> Result<ImageId> {
+        let number = medium::next_image_number();
+        let builder = self
+            .builder(Action::Transmit, ImageFormat::Png)
+            .image_number(number)
+            .quiet(Quiet::Verbose);
+
+        let strategy = self.medium;
+        let response = if let Some(terminal) = &self.terminal {
+            let mut terminal = terminal.lock().unwrap_or_else(|e| e.into_inner());
+            Some(terminal.send_and_wait(&builder.build(), data, medium::DEFAULT_TIMEOUT)?)
+        } else if strategy.for_session(medium::is_remote_session()) == MediumStrategy::Direct {
+            let mut seq = Vec::new();
+            builder.build().serialize_chunked_into(data, &mut seq)?;
+            medium::send_and_wait(&seq, medium::DEFAULT_TIMEOUT)?
+        } else {
+            medium::transmit(builder, data, strategy, medium::DEFAULT_TIMEOUT)?
+        };
+        let response = response
+            .ok_or_else(|| Error::terminal("cannot read the response: no controlling terminal"))?;
+
+        if let Some(message) = response.error_message() {
+            return Err(Error::terminal(message));
+        }
+        match (response.image_number, response.image_id) {
+            (Some(n), Some(id)) if n == number => Ok(id),
+            _ => Err(Error::InvalidResponse(response.to_string())),
+        }
+    }
+
+    /// Display part of raw RGBA data, `source` being `(x, y, width, height)`
+    ///
+    /// The region is scaled with `options` instead of the display's own
+    /// options. The whole image is transmitted; to show other parts of it
+    /// later, place it again with [`place_region`](Self::place_region).
+    pub fn display_region(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        source: (u32, u32, u32, u32),
+        options: DisplayOptions,
+    ) -> Result<()> {
+        if data.len() != width as usize * height as usize * 4 {
+            return Err(Error::InvalidDimensions { width, height });
+        }
+        check_region(source, width, height)?;
+        let builder = self
+            .builder(Action::TransmitAndDisplay, ImageFormat::Rgba)
+            .dimensions(width, height);
+        self.send(self.crop(builder, source, &options)?, data)
+    }
+
+    /// Show part of a previously transmitted image, without sending pixels
+    ///
+    /// `source` is `(x, y, width, height)` in the image and is scaled with
+    /// `options`. Placing the same placement ID again replaces the shown
+    /// part, which lets viewers pan and zoom. Empty rectangles and ones
+    /// reaching past `u32::MAX` fail with [`Error::InvalidDimensions`].
+    pub fn place_region(
+        &self,
+        image_id: ImageId,
+        placement_id: PlacementId,
+        source: (u32, u32, u32, u32),
+        options: DisplayOptions,
+    ) -> Result<()> {
+        let builder = Command::builder()
+            .action(Action::Place)
             .image_id(image_id)
+            .placement_id(placement_id)
+            .quiet(self.quiet);
+        // The image size is not known here, only the rectangle itself
+        check_region(source, u32::MAX, u32::MAX)?;
+        self.write_control(&self.crop(builder, source, &options)?.build())
+    }
+
+    /// Show the `source` rectangle, laid out as a whole image with `options`
+    pub(crate) fn crop(
+        &self,
+        builder: CommandBuilder,
+        source: (u32, u32, u32, u32),
+        options: &DisplayOptions,
+    ) -> Result<CommandBuilder> {
+        let (x, y, w, h) = source;
+        let layout = if options.needs_window() {
+            let window = self.window_size().ok();
+            window.and_then(|window| options.layout(w, h, &window))
+        } else {
+            None
+        };
+        Ok(match layout {
+            Some(layout) => {
+                // A cover fit crops the region further
+                let (sx, sy, sw, sh) = layout.source.unwrap_or((0, 0, w, h));
+                let (Some(x), Some(y)) = (x.checked_add(sx), y.checked_add(sy)) else {
+                    return Err(Error::InvalidDimensions {
+                        width: w,
+                        height: h,
+                    });
+                };
+                builder
+                    .display_area(layout.cols, layout.rows)
+                    .source_rect(x, y, sw, sh)
+            }
+            None => builder.source_rect(x, y, w, h),
+        })
+    }
+
+    /// Place a previously transmitted image
+    pub fn place_image(&self, image_id: ImageId, cols: u32, rows: u32) -> Result<()> {
+        self.write_control(&Command::place(image_id, cols, rows))
+    }
+
+    /// Display a PNG image under a newly allocated ID and delete it when
+    /// the handle is dropped
+    pub fn display_png_handle(&self, data: &[u8]) -> Result<ImageHandle<'_>> {
+        let image_id = self.allocate_id()?;
+        // Create the handle first so a failed send deletes the image again
+        let handle = ImageHandle::new(self, image_id, None);
+        self.send(self.png_builder(data).image_id(image_id), data)?;
+        Ok(handle)
+    }
+
+    /// Display a PNG image at `row`, `col` like [`display_at`](Self::display_at),
+    /// under a newly allocated ID, and delete it when the handle is dropped
+    ///
+    /// The handle remembers the position, so the image can be swapped with
+    /// [`update_in_place`](Self::update_in_place).
+    pub fn display_png_handle_at(
+        &self,
+        row: u16,
+        col: u16,
+        data: &[u8],
+    ) -> Result<ImageHandle<'_>> {
+        self.display_png_handle_z(row, col, data, None)
+    }
+
+    /// Display a PNG image at `row`, `col` like [`display_png_handle_at`](Self::display_png_handle_at),
+    /// stacked at `z_index`
+    ///
+    /// [`update_in_place`](Self::update_in_place) keeps the z-index.
+    pub fn display_png_handle_layered(
+        &self,
+        row: u16,
+        col: u16,
+        z_index: i32,
+        data: &[u8],
+    ) -> Result<ImageHandle<'_>> {
+        self.display_png_handle_z(row, col, data, Some(z_index))
+    }
+
+    /// Display a PNG image at `row`, `col`, fitted by the display options
+    fn display_png_handle_z(
+        &self,
+        row: u16,
+        col: u16,
+        data: &[u8],
+        z_index: Option<i32>,
+    ) -> Result<ImageHandle<'_>> {
+        let layout = png_dimensions(data).and_then(|(w, h)| self.layout(w, h));
+        self.display_png_handle_with(row, col, data, layout, z_index)
+    }
+
+    /// Display a PNG image at `row`, `col` with the given layout and
+    /// z-index, under a newly allocated ID
+    pub(crate) fn display_png_handle_with(
+        &self,
+        row: u16,
+        col: u16,
+        data: &[u8],
+        layout: Option<FitLayout>,
+        z_index: Option<i32>,
+    ) -> Result<ImageHandle<'_>> {
+        let image_id = self.allocate_id()?;
+        let mut handle = ImageHandle::new(self, image_id, None);
+        handle.position = Some((row, col));
+        handle.z_index = z_index;
+        let builder = self.builder(Action::TransmitAndDisplay, ImageFormat::Png);
+        let mut builder = apply_layout(builder, layout)
+            .image_id(image_id)
+            .cursor_policy(CursorPolicy::NoMove);
+        if let Some(z) = z_index {
+            builder = builder.z_index(z);
+        }
+        self.at(row, col, || self.send(builder, data))?;
+        Ok(handle)
+    }
+
+    /// Display raw pixel data at `row`, `col` at its own size, under a
+    /// newly allocated ID
+    pub(crate) fn display_raw_handle_at(
+        &self,
+        row: u16,
+        col: u16,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<ImageHandle<'_>> {
+        let image_id = self.allocate_id()?;
+        let mut handle = ImageHandle::new(self, image_id, None);
+        handle.position = Some((row, col));
+        let builder = self
+            .builder(Action::TransmitAndDisplay, format)
+            .dimensions(width, height)
+            .image_id(image_id)
+            .cursor_policy(CursorPolicy::NoMove);
+        self.at(row, col, || self.send(builder, data))?;
+        Ok(handle)
+    }
+
+    /// Write `text` at `row`, `col`, restoring the cursor afterwards
+    pub(crate) fn text_at(&self, row: u16, col: u16, text: &str) -> Result<()> {
+        self.at(row, col, || {
+            self.write_out(|w| Ok(w.write_all(text.as_bytes())?))
+        })
+    }
+
+    /// Replace the image of `handle` with a new PNG image without flicker
+    ///
+    /// The new image is displayed under a fresh ID at the handle's position
+    /// before the old one is deleted, so there is never a frame without an
+    /// image, and stacked at the same z-index. Afterwards `handle` owns the
+    /// new image. Fails if the handle has no known position, see
+    /// [`display_png_handle_at`](Self::display_png_handle_at).
+    pub fn update_in_place(&self, handle: &mut ImageHandle<'_>, data: &[u8]) -> Result<()> {
+        let (row, col) = handle
+            .position
+            .ok_or(Error::MissingField("handle position"))?;
+        let mut new = self.display_png_handle_z(row, col, data, handle.z_index)?;
+        // Swap so the old image is deleted when `new` goes out of scope
+        std::mem::swap(&mut handle.image_id, &mut new.image_id);
+        std::mem::swap(&mut handle.placement_id, &mut new.placement_id);
+        new.delete()
+    }
+
+    /// Transmit a PNG image under a newly allocated ID and delete it when
+    /// the handle is dropped
+    pub fn transmit_png_handle(&self, data: &[u8]) -> Result<ImageHandle<'_>> {
+        let image_id = self.allocate_id()?;
+        let handle = ImageHandle::new(self, image_id, None);
+        self.transmit_png(data, image_id)?;
+        Ok(handle)
+    }
+
+    /// Transmit an image under a newly allocated ID without displaying it
+    pub(crate) fn transmit_handle(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<ImageHandle<'_>> {
+        let image_id = self.allocate_id()?;
+        let handle = ImageHandle::new(self, image_id, None);
+        let builder = self.builder(Action::Transmit, format).image_id(image_id);
+        let builder = match format {
+            ImageFormat::Png => builder,
+            _ => builder.dimensions(width, height),
+        };
+        self.send(builder, data)?;
+        Ok(handle)
+    }
+
+    /// Place a previously transmitted image and delete the placement when
+    /// the handle is dropped
+    ///
+    /// Only the placement is deleted; the image data stays available for
+    /// other placements.
+    pub fn place_image_handle(
+        &self,
+        image_id: ImageId,
+        placement_id: PlacementId,
+        cols: u32,
+        rows: u32,
+    ) -> Result<ImageHandle<'_>> {
+        let cmd = Command::place(image_id, cols, rows)
+            .into_builder()
+            .placement_id(placement_id)
             .quiet(self.quiet)
             .build();
+        self.write_control(&cmd)?;
+        Ok(ImageHandle::new(self, image_id, Some(placement_id)))
+    }
+}
+
+/// An image or placement that is deleted from the terminal when dropped
+///
+/// Without a placement ID the image and its data are deleted and the ID is
+/// released to the display's [`IdAllocator`] once the delete was written;
+/// with one, only that placement is deleted. Errors while deleting on drop
+/// are ignored, call [`delete`](Self::delete) to see them.
+#[must_use = "dropping the handle deletes the image"]
+pub struct ImageHandle<'a> {
+    display: &'a ImageDisplay,
+    image_id: ImageId,
+    placement_id: Option<PlacementId>,
+    /// Row and column the image was displayed at, if known
+    position: Option<(u16, u16)>,
+    /// Z-index the image was displayed at, if set
+    z_index: Option<i32>,
+}
 
-        let chunks: Vec<String> = cmd.serialize_chunked(data)?.collect();
-        let mut stdout = std::io::stdout().lock();
-        for chunk in chunks {
-            stdout.write_all(chunk.as_bytes())?;
+impl<'a> ImageHandle<'a> {
+    fn new(
+        display: &'a ImageDisplay,
+        image_id: ImageId,
+        placement_id: Option<PlacementId>,
+    ) -> Self {
+        Self {
+            display,
+            image_id,
+            placement_id,
+            position: None,
+            z_index: None,
         }
-        stdout.flush()?;
-        Ok(())
     }
 
-    /// Place a previously transmitted image
-    pub fn place_image(&self, image_id: u32, cols: u32, rows: u32) -> Result<()> {
-        let cmd = Command::place(image_id, cols, rows);
-        let seq = cmd.serialize(&[])?;
-        let mut stdout = std::io::stdout().lock();
-        stdout.write_all(seq.as_bytes())?;
-        stdout.flush()?;
+    /// ID of the image
+    pub fn image_id(&self) -> ImageId {
+        self.image_id
+    }
+
+    /// ID of the placement, if the handle owns a single placement
+    pub fn placement_id(&self) -> Option<PlacementId> {
+        self.placement_id
+    }
+
+    /// 1-based row and column of the image, if it was displayed at a known
+    /// position
+    pub fn position(&self) -> Option<(u16, u16)> {
+        self.position
+    }
+
+    /// Z-index the image was displayed at, if one was set
+    pub fn z_index(&self) -> Option<i32> {
+        self.z_index
+    }
+
+    /// Keep the image in the terminal and return its ID
+    ///
+    /// An allocated ID stays allocated; release it with
+    /// [`ImageDisplay::release_id`] after deleting the image.
+    pub fn leak(self) -> ImageId {
+        let image_id = self.image_id;
+        std::mem::forget(self);
+        image_id
+    }
+
+    /// Delete the image now, reporting errors
+    pub fn delete(self) -> Result<()> {
+        let result = self.send_delete();
+        std::mem::forget(self);
+        result
+    }
+
+    /// Send the delete command for the image or placement
+    fn send_delete(&self) -> Result<()> {
+        let builder = Command::builder()
+            .action(Action::Delete)
+            .image_id(self.image_id)
+            .quiet(Quiet::SuppressAll);
+        let cmd = match self.placement_id {
+            Some(placement_id) => builder
+                .delete_target(DeleteTarget::ById { free_data: false })
+                .placement_id(placement_id),
+            None => builder.delete_target(DeleteTarget::ById { free_data: true }),
+        };
+        self.display.write_control(&cmd.build())?;
+        // The image may still be in the terminal if the delete was not sent
+        if self.placement_id.is_none() {
+            self.display.release_id(self.image_id);
+        }
         Ok(())
     }
 }
 
+impl Drop for ImageHandle<'_> {
+    fn drop(&mut self) {
+        let _ = self.send_delete();
+    }
+}
+
+impl std::fmt::Debug for ImageHandle<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageHandle")
+            .field("image_id", &self.image_id)
+            .field("placement_id", &self.placement_id)
+            .finish()
+    }
+}
+
+/// Set the cells and source rectangle of `layout` on `builder`
+fn apply_layout(builder: CommandBuilder, layout: Option<FitLayout>) -> CommandBuilder {
+    let Some(layout) = layout else {
+        return builder;
+    };
+    let builder = builder.display_area(layout.cols, layout.rows);
+    match layout.source {
+        Some((x, y, w, h)) => builder.source_rect(x, y, w, h),
+        None => builder,
+    }
+}
+
+/// Check that `source`, as `(x, y, width, height)`, is a non-empty
+/// rectangle inside an image `width`x`height` pixels
+fn check_region(source: (u32, u32, u32, u32), width: u32, height: u32) -> Result<()> {
+    let (x, y, w, h) = source;
+    let inside = |offset: u32, len: u32, max: u32| {
+        len > 0 && offset.checked_add(len).is_some_and(|end| end <= max)
+    };
+    if !inside(x, w, width) || !inside(y, h, height) {
+        return Err(Error::InvalidDimensions {
+            width: w,
+            height: h,
+        });
+    }
+    Ok(())
+}
+
+/// Cells an image covers at its own size
+fn native_cells(width: u32, height: u32, window: &WindowSize) -> (u32, u32) {
+    match window.cells_for_image(width, height) {
+        (0, 0) => (
+            width.div_ceil(FALLBACK_CELL.0),
+            height.div_ceil(FALLBACK_CELL.1),
+        ),
+        cells => cells,
+    }
+}
+
+/// 1-based row and column placing `cols`x`rows` cells in the window's center
+fn centered(cols: u32, rows: u32, window: &WindowSize) -> (u16, u16) {
+    let offset = |cells: u32, screen: u16| (u32::from(screen).saturating_sub(cells) / 2 + 1) as u16;
+    (offset(rows, window.rows), offset(cols, window.cols))
+}
+
+/// Read the dimensions from a PNG's IHDR chunk
+pub(crate) fn png_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() < 24 || !data.starts_with(b"\x89PNG\r\n\x1a\n") || &data[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(data[16..20].try_into().ok()?);
+    let height = u32::from_be_bytes(data[20..24].try_into().ok()?);
+    Some((width, height))
+}
+
 /// Quick function to display a PNG file
 pub fn display_png<P: AsRef<Path>>(path: P) -> Result<()> {
     ImageDisplay::new().display_png_file(path)
@@ -125,10 +1325,281 @@ pub fn clear_all_images() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{Broken, Recorder};
 
     #[test]
     fn test_image_display_creation() {
-        let display = ImageDisplay::new().quiet(1);
-        assert_eq!(display.quiet, 1);
+        let display = ImageDisplay::new().quiet(Quiet::SuppressOk);
+        assert_eq!(display.quiet, Quiet::SuppressOk);
+    }
+
+    #[test]
+    fn test_display_over_transport() {
+        let output = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let terminal = Terminal::from_transport(Recorder::new(output.clone()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(terminal);
+
+        display.clear_all().unwrap();
+        assert_eq!(&*output.lock().unwrap(), b"\x1b_Ga=d,d=a\x1b\\");
+
+        output.lock().unwrap().clear();
+        display.display_png(b"\x89PNG").unwrap();
+        assert!(output.lock().unwrap().starts_with(b"\x1b_Ga=T,f=100,"));
+
+        output.lock().unwrap().clear();
+        display.display_at(3, 10, b"\x89PNG").unwrap();
+        let output = output.lock().unwrap();
+        assert!(output.starts_with(b"\x1b7\x1b[3;10H\x1b_Ga=T,f=100,"));
+        assert!(output.windows(4).any(|w| w == b"C=1,"));
+        assert!(output.ends_with(b"\x1b\\\x1b8"));
+    }
+
+    #[test]
+    fn test_display_png_reader_matches_buffered() {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x40\0\0\0\x20".to_vec();
+        data.resize(10_000, 7);
+        let output = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+
+        display.display_png(&data).unwrap();
+        let buffered = std::mem::take(&mut *output.lock().unwrap());
+        display.display_png_reader(&data[..]).unwrap();
+        assert_eq!(*output.lock().unwrap(), buffered);
+        output.lock().unwrap().clear();
+        display.display_sniffed(&data[..]).unwrap();
+        assert_eq!(*output.lock().unwrap(), buffered);
+        assert!(display.display_sniffed(&b"not an image"[..]).is_err());
+
+        output.lock().unwrap().clear();
+        display
+            .display_from_reader(&[1u8; 12][..], ImageFormat::Rgb, 1, 2)
+            .unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("\x1b_Ga=T,f=24,s=1,v=2,"));
+        assert!(sent.ends_with(";AQEBAQEB\x1b\\"));
+
+        output.lock().unwrap().clear();
+        let short = display.display_from_reader(&[1u8; 5][..], ImageFormat::Rgb, 1, 2);
+        assert!(matches!(
+            short,
+            Err(Error::InvalidDimensions {
+                width: 1,
+                height: 2
+            })
+        ));
+        assert!(output.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_display_region() {
+        let output = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let data = vec![0; 4 * 4 * 4];
+        display
+            .display_region(&data, 4, 4, (1, 2, 3, 2), DisplayOptions::new())
+            .unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("\x1b_Ga=T,f=32,s=4,v=4,"));
+        assert!(sent.contains("x=1,y=2,w=3,h=2"));
+        let outside = display.display_region(&data, 4, 4, (2, 0, 3, 1), DisplayOptions::new());
+        assert!(outside.is_err());
+
+        output.lock().unwrap().clear();
+        let (image, placement) = (ImageId::new(4).unwrap(), PlacementId::new(2).unwrap());
+        // Without a window size the region is shown at its own size
+        let options = DisplayOptions::new().fit(Fit::Contain);
+        display
+            .place_region(image, placement, (10, 20, 30, 40), options)
+            .unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(sent, "\x1b_Ga=p,i=4,p=2,q=2,x=10,y=20,w=30,h=40\x1b\\");
+
+        output.lock().unwrap().clear();
+        for source in [(u32::MAX, 0, 10, 10), (0, 5, 10, u32::MAX), (0, 0, 0, 10)] {
+            let region = display.place_region(image, placement, source, options);
+            assert!(matches!(region, Err(Error::InvalidDimensions { .. })));
+        }
+        assert!(output.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fit_layouts() {
+        // 80x24 cells of 10x20 pixels
+        let window = WindowSize {
+            rows: 24,
+            cols: 80,
+            width: 800,
+            height: 480,
+        };
+        let contain = DisplayOptions::new().fit(Fit::Contain);
+        let layout = contain.layout(4000, 3000, &window).unwrap();
+        assert_eq!((layout.cols, layout.rows), (64, 24));
+        assert_eq!((layout.width, layout.height), (640, 480));
+
+        let layout = contain.max_cols(20).layout(4000, 3000, &window).unwrap();
+        assert_eq!((layout.cols, layout.rows), (20, 8));
+
+        let cover = DisplayOptions::new().fit(Fit::Cover);
+        let layout = cover.layout(4000, 3000, &window).unwrap();
+        assert_eq!((layout.cols, layout.rows), (80, 24));
+        assert_eq!(layout.source, Some((0, 300, 4000, 2400)));
+
+        let fill = DisplayOptions::new().fit(Fit::Fill).max_rows(10);
+        let layout = fill.layout(4000, 3000, &window).unwrap();
+        assert_eq!((layout.cols, layout.rows, layout.source), (80, 10, None));
+
+        // Native only shrinks to the limits it was given
+        let native = DisplayOptions::new();
+        assert_eq!(native.layout(4000, 3000, &window), None);
+        assert_eq!(native.max_cols(100).layout(400, 300, &window), None);
+        let layout = native.max_cols(10).layout(400, 300, &window).unwrap();
+        assert_eq!((layout.cols, layout.rows), (10, 4));
+    }
+
+    #[test]
+    fn test_centered_position() {
+        let window = WindowSize {
+            rows: 24,
+            cols: 80,
+            width: 0,
+            height: 0,
+        };
+        // Unknown cell size falls back to 10x20 pixel cells
+        let (cols, rows) = native_cells(200, 200, &window);
+        assert_eq!((cols, rows), (20, 10));
+        assert_eq!(centered(cols, rows, &window), (8, 31));
+        assert_eq!(centered(100, 30, &window), (1, 1));
+    }
+
+    #[test]
+    fn test_png_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&[0, 0, 0x0f, 0xa0, 0, 0, 0x0b, 0xb8]);
+        assert_eq!(png_dimensions(&png), Some((4000, 3000)));
+        assert_eq!(png_dimensions(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn test_handle_deletes_on_drop() {
+        let output = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let id = ImageId::new(5).unwrap();
+        let display = display.id_allocator(Arc::new(IdAllocator::with_range(id, id).unwrap()));
+
+        let handle = display.transmit_png_handle(b"\x89PNG").unwrap();
+        assert!(display.allocate_id().is_err());
+        output.lock().unwrap().clear();
+        drop(handle);
+        assert_eq!(&*output.lock().unwrap(), b"\x1b_Ga=d,i=5,q=2,d=I\x1b\\");
+
+        output.lock().unwrap().clear();
+        let placement = PlacementId::new(2).unwrap();
+        let handle = display.place_image_handle(id, placement, 4, 2).unwrap();
+        output.lock().unwrap().clear();
+        handle.delete().unwrap();
+        assert_eq!(&*output.lock().unwrap(), b"\x1b_Ga=d,i=5,p=2,q=2,d=i\x1b\\");
+
+        output.lock().unwrap().clear();
+        assert_eq!(display.transmit_png_handle(b"\x89PNG").unwrap().leak(), id);
+        assert!(!output.lock().unwrap().starts_with(b"\x1b_Ga=d"));
+    }
+
+    #[test]
+    fn test_failed_delete_keeps_id() {
+        let id = ImageId::new(5).unwrap();
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(Terminal::from_transport(Broken))
+            .id_allocator(Arc::new(IdAllocator::with_range(id, id).unwrap()));
+
+        let handle = ImageHandle::new(&display, display.allocate_id().unwrap(), None);
+        assert!(handle.delete().is_err());
+        assert!(display.allocate_id().is_err());
+    }
+
+    #[test]
+    fn test_update_in_place_deletes_after_display() {
+        let output = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .id_allocator(Arc::new(IdAllocator::new()))
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+
+        let mut handle = display.display_png_handle_at(2, 5, b"\x89PNG").unwrap();
+        let old = handle.image_id();
+        output.lock().unwrap().clear();
+        display.update_in_place(&mut handle, b"\x89PNG").unwrap();
+        assert_ne!(handle.image_id(), old);
+        assert_eq!(handle.position(), Some((2, 5)));
+
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let shown = sent
+            .find(&format!("a=T,f=100,i={}", handle.image_id()))
+            .unwrap();
+        let deleted = sent.find(&format!("a=d,i={old},")).unwrap();
+        assert!(sent.starts_with("\x1b7\x1b[2;5H"));
+        assert!(shown < deleted);
+
+        let mut unplaced = display.transmit_png_handle(b"\x89PNG").unwrap();
+        assert!(display.update_in_place(&mut unplaced, b"\x89PNG").is_err());
+
+        // The z-index is sent again for the new image
+        let mut layered = display
+            .display_png_handle_layered(1, 1, -2, b"\x89PNG")
+            .unwrap();
+        output.lock().unwrap().clear();
+        display.update_in_place(&mut layered, b"\x89PNG").unwrap();
+        assert_eq!(layered.z_index(), Some(-2));
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.contains(&format!("a=T,f=100,i={},q=2,z=-2,C=1", layered.image_id())));
+    }
+
+    #[test]
+    fn test_pack_bgra_with_stride() {
+        // 2x2 BGRA with 4 padding bytes per row
+        let data = [
+            3, 2, 1, 255, 6, 5, 4, 255, 0, 0, 0, 0, //
+            9, 8, 7, 255, 12, 11, 10, 255, 0, 0, 0, 0,
+        ];
+        let layout = PixelLayout::new(ChannelOrder::Bgra).stride(12);
+        let (packed, format) = layout.pack(&data, 2, 2).unwrap();
+        assert_eq!(format, ImageFormat::Rgba);
+        assert_eq!(
+            &*packed,
+            &[1, 2, 3, 255, 4, 5, 6, 255, 7, 8, 9, 255, 10, 11, 12, 255]
+        );
+
+        let packed_rgb = PixelLayout::new(ChannelOrder::Rgb)
+            .pack(&data[..12], 2, 2)
+            .unwrap();
+        assert!(matches!(packed_rgb.0, Cow::Borrowed(_)));
+        assert!(layout.stride(7).pack(&data, 2, 2).is_err());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_display_image_file_decodes() {
+        let path = std::env::temp_dir().join(format!("kgp-test-{}.bmp", std::process::id()));
+        ::image::RgbaImage::new(3, 2).save(&path).unwrap();
+
+        let output = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let result = display.display_image_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+        let output = output.lock().unwrap();
+        assert!(output.starts_with(b"\x1b_Ga=T,f=32,"));
+        assert!(output.windows(8).any(|w| w == b"s=3,v=2,"));
     }
 }
@@ -6,20 +6,46 @@
 //! # Features
 //!
 //! - Full support for all graphics protocol commands
+//! - Typed per-action builders that only expose valid keys
 //! - Support for RGB, RGBA, and PNG image formats
 //! - Chunked data transmission for large images
 //! - Animation support
-//! - Unicode placeholder support
+//! - Unicode placeholder support, including the placeholder text
 //! - Terminal size detection
-//! - Protocol support detection
+//! - Protocol support detection, cached across calls
+//! - Scroll region helpers for pager-style applications
+//! - Image ID allocation and handles that delete images when dropped
+//! - Tracking placements to move, replace and delete them
+//! - Thumbnail grids with captions
+//! - Tiling images larger than the terminal accepts
+//! - Zooming and panning large images without retransmitting them
+//! - Optional zlib compression of payloads (`zlib` feature)
+//! - POSIX shared memory transmission on Unix (`shm` feature)
+//! - SIMD-accelerated base64 encoding (`simd` feature)
+//! - Async serialization and response reading with tokio (`tokio` feature)
+//! - `futures::Stream` adapter for chunked output (`futures` feature)
+//! - Parallel chunk encoding for large payloads (`parallel` feature)
+//! - Decoding JPEG, GIF, BMP and WebP files for display (`image` feature)
+//! - Playing APNG animations, animated GIFs (`gif` feature) and animated
+//!   WebP images (`webp` feature)
+//! - Client-side animation playback for terminals that do not animate
+//! - Streaming video frames with pacing and frame dropping (`video` feature)
+//! - serde support for commands, responses and protocol types (`serde` feature)
+//! - `no_std` + `alloc` core for building, serializing and parsing commands
+//!   (disable the default `std` feature)
 //!
 //! # Quick Start
 //!
 //! ```no_run
+//! # #[cfg(feature = "std")]
+//! # fn main() {
 //! use kitty_graphics_protocol::display_png;
 //!
 //! // Display a PNG file
 //! display_png("image.png").unwrap();
+//! # }
+//! # #[cfg(not(feature = "std"))]
+//! # fn main() {}
 //! ```
 //!
 //! # Advanced Usage
@@ -41,22 +67,124 @@
 //! }
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// The test harness needs std even when the library does not
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+#[cfg(feature = "std")]
+pub mod animation;
+#[cfg(feature = "tokio")]
+mod asynchronous;
+pub mod builders;
+#[cfg(feature = "std")]
+pub mod cache;
+pub mod capabilities;
 pub mod command;
+#[cfg(feature = "zlib")]
+mod compress;
+mod encoding;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod ids;
+#[cfg(feature = "std")]
 pub mod image;
+#[cfg(feature = "std")]
+pub mod medium;
+#[cfg(feature = "std")]
+pub mod montage;
+pub mod placeholder;
+#[cfg(feature = "std")]
+pub mod placement;
+#[cfg(feature = "std")]
+pub mod player;
 pub mod response;
+pub mod scroll;
+#[cfg(all(unix, feature = "shm"))]
+pub mod shm;
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(feature = "std")]
 pub mod terminal;
+#[cfg(all(test, feature = "std"))]
+mod test_support;
+#[cfg(feature = "std")]
+pub mod tiling;
 pub mod types;
+#[cfg(feature = "video")]
+pub mod video;
+#[cfg(feature = "std")]
+pub mod viewer;
 
-pub use command::{ChunkedSerializer, Command, CommandBuilder};
+#[cfg(feature = "gif")]
+pub use animation::decode_gif;
+#[cfg(feature = "webp")]
+pub use animation::decode_webp;
+#[cfg(feature = "std")]
+pub use animation::{
+    AnimatedFrame, AnimatedImage, Animation, Disposal, FrameCommands, Loop, is_apng, split_apng,
+};
+#[cfg(feature = "tokio")]
+pub use asynchronous::{ResponseStream, read_response_async};
+pub use builders::{
+    AnimateBuilder, ComposeBuilder, DeleteBuilder, FrameBuilder, PlaceBuilder, PlaceholderGrid,
+    RelativePlacement, TransmitBuilder, TransmitDisplayBuilder, VirtualPlacement,
+};
+pub use capabilities::TerminalCapabilities;
+#[cfg(feature = "std")]
+pub use command::ReaderChunks;
+pub use command::{
+    ChunkAssembler, ChunkedBytes, ChunkedSerializer, Command, CommandBuilder, ScratchBuffer,
+};
 pub use error::{Error, Result};
-pub use image::{ImageDisplay, clear_all_images, display_png, display_png_data};
-pub use response::Response;
-pub use terminal::{WindowSize, check_protocol_support, get_window_size, query_window_size};
+#[cfg(feature = "std")]
+pub use ids::IdAllocator;
+#[cfg(feature = "std")]
+pub use image::{
+    ChannelOrder, DisplayOptions, Fit, FitLayout, ImageDisplay, ImageHandle, PixelLayout,
+    clear_all_images, display_png, display_png_data,
+};
+#[cfg(feature = "std")]
+pub use medium::{
+    MediumStrategy, MediumSupport, TempFile, is_remote_session, probe_mediums,
+    transmit_via_tempfile,
+};
+#[cfg(feature = "std")]
+pub use montage::{Montage, MontageCell};
+#[cfg(feature = "std")]
+pub use placeholder::VirtualImage;
+pub use placeholder::{DIACRITICS, PLACEHOLDER};
+#[cfg(feature = "std")]
+pub use placement::{Placement, PlacementManager};
+#[cfg(feature = "std")]
+pub use player::{AnimationPlayer, PlayerState};
+pub use response::{
+    CommandHandle, ErrorCode, Response, ResponseCorrelator, ResponseParser, ResponseRef,
+    SupportReport,
+};
+pub use scroll::ScrollRegion;
+#[cfg(all(unix, feature = "std"))]
+pub use terminal::read_until_st;
+#[cfg(feature = "std")]
+pub use terminal::{
+    ProbeOptions, RawModeGuard, SUPPORT_OVERRIDE_VAR, Terminal, TerminalInfo, Transport,
+    WindowSize, check_protocol_support, check_protocol_support_with, clear_forced_support,
+    detect_terminal, effective_window_size, force_support, get_window_size, query_cell_size,
+    query_window_size, query_window_size_with_timeout, resize_events,
+};
+#[cfg(feature = "std")]
+pub use tiling::{MAX_TILE_SIZE, Tile, TiledImage, Tiler};
 pub use types::{
     Action, AnimationControl, CompositionMode, Compression, CursorPolicy, DeleteTarget,
-    FrameComposition, ImageFormat, TransmissionMedium, UnicodePlaceholder,
+    DetectedFormat, FrameComposition, ImageFormat, ImageId, Passthrough, PlacementId,
+    ProtocolVersion, Quiet, TransmissionMedium, UnicodePlaceholder,
 };
+#[cfg(feature = "video")]
+pub use video::{VideoStats, VideoStream};
+#[cfg(feature = "std")]
+pub use viewer::Viewer;
 
 /// The ESC character (0x1b)
 pub const ESC: u8 = 0x1b;
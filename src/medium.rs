@@ -0,0 +1,504 @@
+//! Transmission medium selection and the file-based mediums
+//!
+//! With the temporary file medium (t=t) the data is written to a file that
+//! the terminal reads and then deletes. kitty only deletes files whose name
+//! contains `tty-graphics-protocol`, so [`TempFile`] always uses that name
+//! and removes the file itself if the terminal never reads it.
+//!
+//! [`MediumStrategy`] decides between these and direct transmission, and
+//! [`transmit`] sends data with the chosen medium.
+
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::response::Response;
+use crate::types::{Action, ImageFormat, ImageId, Quiet, TransmissionMedium};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marker kitty requires in the name of temporary files it may delete
+pub const TEMP_FILE_MARKER: &str = "tty-graphics-protocol";
+
+/// Counter making file names unique within this process
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Counter for image numbers assigned so that the terminal replies
+static NEXT_IMAGE_NUMBER: AtomicU32 = AtomicU32::new(1);
+
+/// Output buffer used for direct transmission in remote sessions
+///
+/// Batching several chunks per write keeps the number of small packets sent
+/// over the network down.
+const REMOTE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How long to wait for the terminal to acknowledge a file-based transmission
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How image data is sent to the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MediumStrategy {
+    /// Always send the data inline as base64 (t=d)
+    #[default]
+    Direct,
+    /// Write the data to a temporary file (t=t)
+    TempFile,
+    /// Write the data to a POSIX shared memory object (t=s)
+    #[cfg(all(unix, feature = "shm"))]
+    SharedMemory,
+    /// Direct for remote sessions, shared memory or a temporary file for
+    /// local ones, falling back to direct if the terminal rejects the medium
+    Auto,
+}
+
+impl MediumStrategy {
+    /// Resolve [`MediumStrategy::Auto`] into a concrete medium for this session
+    ///
+    /// Over SSH the terminal runs on another machine and cannot see local
+    /// files or shared memory, so direct transmission is used.
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Auto if is_remote_session() => Self::Direct,
+            #[cfg(all(unix, feature = "shm"))]
+            Self::Auto => Self::SharedMemory,
+            #[cfg(not(all(unix, feature = "shm")))]
+            Self::Auto => Self::TempFile,
+            other => other,
+        }
+    }
+
+    /// Resolve this strategy for a session that may be remote
+    ///
+    /// File paths and shared memory objects name things on the local
+    /// machine, so in a remote session every strategy becomes
+    /// [`MediumStrategy::Direct`].
+    pub fn for_session(self, remote: bool) -> Self {
+        if remote { Self::Direct } else { self.resolve() }
+    }
+}
+
+/// Check the environment for signs of an SSH session
+///
+/// The terminal then runs on another host, so only direct transmission can
+/// reach it.
+pub fn is_remote_session() -> bool {
+    ["SSH_TTY", "SSH_CONNECTION", "SSH_CLIENT"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+}
+
+/// Allocate an image number (`I=`) unique within this process
+pub(crate) fn next_image_number() -> u32 {
+    NEXT_IMAGE_NUMBER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Transmit `data` to the terminal using `strategy`
+///
+/// Direct transmission streams the data to stdout in chunks and returns
+/// `None`. The file-based mediums wait up to `timeout` for the terminal's
+/// reply; an image number is assigned if `builder` has no image ID or number,
+/// since the terminal only replies to commands carrying one. With
+/// [`MediumStrategy::Auto`], an error reply, no reply before `timeout` or a
+/// reply that cannot be parsed falls back to direct transmission. Remote sessions always transmit directly, see
+/// [`MediumStrategy::for_session`].
+pub fn transmit(
+    builder: CommandBuilder,
+    data: &[u8],
+    strategy: MediumStrategy,
+    timeout: Duration,
+) -> Result<Option<Response>> {
+    let resolved = strategy.for_session(is_remote_session());
+    if resolved == MediumStrategy::Direct {
+        return transmit_direct(builder, data);
+    }
+
+    let builder = if builder.has_image_ref() {
+        builder
+    } else {
+        builder.image_number(next_image_number())
+    };
+
+    let result = match resolved {
+        #[cfg(all(unix, feature = "shm"))]
+        MediumStrategy::SharedMemory => crate::shm::transmit(builder.clone(), data, timeout),
+        _ => transmit_via_tempfile(builder.clone(), data, timeout),
+    };
+    or_direct(strategy, result, || transmit_direct(builder, data))
+}
+
+/// Fall back to `direct` when an [`MediumStrategy::Auto`] transmission
+/// through a file or shared memory failed because of the terminal
+///
+/// The terminal may have rejected the medium, never answered, or answered
+/// with something that is not a graphics response. Local errors, such as
+/// failing to create the file, are returned as they are.
+fn or_direct(
+    strategy: MediumStrategy,
+    result: Result<Option<Response>>,
+    direct: impl FnOnce() -> Result<Option<Response>>,
+) -> Result<Option<Response>> {
+    match result {
+        Err(Error::TerminalError(_) | Error::Timeout | Error::InvalidResponse(_))
+            if strategy == MediumStrategy::Auto =>
+        {
+            direct()
+        }
+        other => other,
+    }
+}
+
+/// Stream `data` to stdout inline as base64 chunks
+///
+/// In remote sessions the output is buffered so that several chunks are
+/// written at once.
+fn transmit_direct(builder: CommandBuilder, data: &[u8]) -> Result<Option<Response>> {
+    let cmd = builder.build();
+    let stdout = io::stdout().lock();
+    if is_remote_session() {
+        let mut out = BufWriter::with_capacity(REMOTE_BUFFER_SIZE, stdout);
+        cmd.serialize_chunked_into(data, &mut out)?;
+        out.flush()?;
+    } else {
+        let mut out = stdout;
+        cmd.serialize_chunked_into(data, &mut out)?;
+        out.flush()?;
+    }
+    Ok(None)
+}
+
+/// A temporary file holding image data for the terminal to read
+#[derive(Debug)]
+pub struct TempFile {
+    path: String,
+    len: usize,
+    exists: bool,
+}
+
+impl TempFile {
+    /// Create a new temporary file and write `data` into it
+    ///
+    /// The file is created exclusively and, on Unix, readable only by the
+    /// current user.
+    pub fn create(data: &[u8]) -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let name = format!(
+            "{TEMP_FILE_MARKER}-{}-{}-{nanos:08x}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let path: PathBuf = std::env::temp_dir().join(name);
+        let path = path
+            .into_os_string()
+            .into_string()
+            .map_err(|p| Error::InvalidPath(p.to_string_lossy().into_owned()))?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&path)?;
+
+        // From here on the guard removes the file if anything fails
+        let temp = Self {
+            path,
+            len: data.len(),
+            exists: true,
+        };
+        file.write_all(data)?;
+        file.sync_all()?;
+
+        Ok(temp)
+    }
+
+    /// Path of the temporary file
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+
+    /// Size of the data in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the file holds no data
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Point a command at this file (t=t with its path)
+    pub fn configure(&self, builder: CommandBuilder) -> CommandBuilder {
+        builder
+            .medium(TransmissionMedium::TempFile)
+            .path(self.path.clone())
+    }
+
+    /// Release the file once the terminal has replied to the command
+    ///
+    /// The terminal deletes the file after reading it, so this only removes
+    /// it if it still exists. An error response is returned as
+    /// [`Error::TerminalError`].
+    pub fn acknowledge(mut self, response: &Response) -> Result<()> {
+        self.remove()?;
+        match response.error_message() {
+            Some(message) if response.is_error() => Err(Error::terminal(message)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the file, ignoring a file the terminal already deleted
+    fn remove(&mut self) -> Result<()> {
+        if !self.exists {
+            return Ok(());
+        }
+        self.exists = false;
+
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Io(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+/// Transmit `data` through a temporary file and wait for the terminal's reply
+///
+/// `builder` supplies the action, format, dimensions and image ID; the
+/// terminal only replies to commands carrying an image ID or number. If no
+/// reply arrives within `timeout` the file is removed and an error returned.
+///
+/// Without a controlling terminal no reply can be read, so the command is
+/// written and `None` is returned; the file is then left for the terminal to
+/// delete after reading it.
+pub fn transmit_via_tempfile(
+    builder: CommandBuilder,
+    data: &[u8],
+    timeout: Duration,
+) -> Result<Option<Response>> {
+    let temp = TempFile::create(data)?;
+    let cmd = temp.configure(builder).quiet(Quiet::Verbose).build();
+    let seq = cmd.serialize_with_path()?;
+
+    match send_and_wait(seq.as_bytes(), timeout)? {
+        Some(response) => {
+            temp.acknowledge(&response)?;
+            Ok(Some(response))
+        }
+        None => {
+            std::mem::forget(temp);
+            Ok(None)
+        }
+    }
+}
+
+/// Transmission mediums accepted by the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MediumSupport {
+    /// Direct transmission (t=d)
+    pub direct: bool,
+    /// Regular files (t=f)
+    pub file: bool,
+    /// Temporary files (t=t)
+    pub temp_file: bool,
+    /// Shared memory (t=s); never probed without the `shm` feature
+    pub shared_memory: bool,
+}
+
+impl MediumSupport {
+    /// The most efficient strategy the terminal supports
+    pub fn preferred(&self) -> MediumStrategy {
+        #[cfg(all(unix, feature = "shm"))]
+        if self.shared_memory {
+            return MediumStrategy::SharedMemory;
+        }
+        if self.temp_file {
+            MediumStrategy::TempFile
+        } else {
+            MediumStrategy::Direct
+        }
+    }
+}
+
+/// Probe which transmission mediums the terminal accepts
+///
+/// Sends an `a=q` query with a 1x1 RGB image for each medium and records
+/// which ones the terminal answers with `OK`. Queries are checked but never
+/// stored by the terminal. Without a controlling terminal nothing is sent
+/// and only direct transmission is reported.
+pub fn probe_mediums() -> Result<MediumSupport> {
+    if crate::terminal::Tty::open().is_err() {
+        return Ok(MediumSupport {
+            direct: true,
+            ..MediumSupport::default()
+        });
+    }
+
+    const PIXEL: &[u8] = &[0, 0, 0];
+    let timeout = Duration::from_millis(200);
+    let query = |id: u32| {
+        Command::builder()
+            .action(Action::Query)
+            .image_id(ImageId::new(id).expect("non-zero probe id"))
+            .format(ImageFormat::Rgb)
+            .dimensions(1, 1)
+    };
+    let accepted = |seq: &[u8]| -> bool {
+        matches!(send_and_wait(seq, timeout), Ok(Some(response)) if response.is_ok())
+    };
+
+    let direct = accepted(&query(31).build().serialize_bytes(PIXEL)?);
+
+    let file = {
+        let temp = TempFile::create(PIXEL)?;
+        let cmd = query(32)
+            .medium(TransmissionMedium::File)
+            .path(temp.path.clone())
+            .build();
+        accepted(cmd.serialize_with_path()?.as_bytes())
+    };
+
+    let temp_file = {
+        let temp = TempFile::create(PIXEL)?;
+        let cmd = temp.configure(query(33)).build();
+        accepted(cmd.serialize_with_path()?.as_bytes())
+    };
+
+    #[cfg(all(unix, feature = "shm"))]
+    let shared_memory = {
+        let shm = crate::shm::SharedMemory::create(PIXEL)?;
+        let cmd = shm.configure(query(34)).build();
+        accepted(cmd.serialize_with_path()?.as_bytes())
+    };
+    #[cfg(not(all(unix, feature = "shm")))]
+    let shared_memory = false;
+
+    Ok(MediumSupport {
+        direct,
+        file,
+        temp_file,
+        shared_memory,
+    })
+}
+
+/// Send a command and read the graphics response it triggers
+///
+/// Returns `None` after writing the command when there is no controlling
+/// terminal, since no reply can be read then.
+pub(crate) fn send_and_wait(seq: &[u8], timeout: Duration) -> Result<Option<Response>> {
+    #[cfg(any(unix, windows))]
+    let reply = crate::terminal::query_terminal(seq, timeout)?;
+    #[cfg(not(any(unix, windows)))]
+    let reply: Option<Vec<u8>> = {
+        let _ = timeout;
+        None
+    };
+
+    let Some(reply) = reply else {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(seq)?;
+        stdout.flush()?;
+        return Ok(None);
+    };
+
+    let start = reply
+        .windows(3)
+        .position(|w| w == b"\x1b_G")
+        .ok_or(Error::Timeout)?;
+    Response::parse(&reply[start..]).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_file_lifecycle() {
+        let temp = TempFile::create(b"\x89PNG").unwrap();
+        let path = temp.path().to_path_buf();
+        assert!(path.to_str().unwrap().contains(TEMP_FILE_MARKER));
+        assert_eq!(fs::read(&path).unwrap(), b"\x89PNG");
+
+        let seq = temp
+            .configure(
+                Command::builder()
+                    .action(Action::Transmit)
+                    .format(ImageFormat::Png),
+            )
+            .build()
+            .serialize_with_path()
+            .unwrap();
+        assert!(seq.contains("t=t"));
+
+        drop(temp);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_explicit_strategies_resolve_to_themselves() {
+        assert_eq!(MediumStrategy::Direct.resolve(), MediumStrategy::Direct);
+        assert_eq!(MediumStrategy::TempFile.resolve(), MediumStrategy::TempFile);
+        assert_ne!(MediumStrategy::Auto.resolve(), MediumStrategy::Auto);
+    }
+
+    #[test]
+    fn test_remote_sessions_use_direct() {
+        assert_eq!(
+            MediumStrategy::TempFile.for_session(true),
+            MediumStrategy::Direct
+        );
+        assert_eq!(
+            MediumStrategy::Auto.for_session(true),
+            MediumStrategy::Direct
+        );
+        assert_eq!(
+            MediumStrategy::TempFile.for_session(false),
+            MediumStrategy::TempFile
+        );
+    }
+
+    #[test]
+    fn test_auto_falls_back_to_direct() {
+        let direct = || Ok(None);
+        for error in [
+            Error::terminal("ENOTSUPPORTED"),
+            Error::Timeout,
+            Error::InvalidResponse("\x1b_Gi=1".into()),
+        ] {
+            assert!(matches!(
+                or_direct(MediumStrategy::Auto, Err(error), direct),
+                Ok(None)
+            ));
+        }
+        assert!(matches!(
+            or_direct(MediumStrategy::TempFile, Err(Error::Timeout), direct),
+            Err(Error::Timeout)
+        ));
+        let local = Error::Io(io::Error::other("disk full"));
+        assert!(or_direct(MediumStrategy::Auto, Err(local), direct).is_err());
+    }
+
+    #[test]
+    fn test_medium_support_preferred() {
+        let direct_only = MediumSupport {
+            direct: true,
+            ..MediumSupport::default()
+        };
+        assert_eq!(direct_only.preferred(), MediumStrategy::Direct);
+
+        let with_temp = MediumSupport {
+            temp_file: true,
+            ..direct_only
+        };
+        assert_eq!(with_temp.preferred(), MediumStrategy::TempFile);
+    }
+}
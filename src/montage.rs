@@ -0,0 +1,239 @@
+//! Thumbnail grids for galleries and file managers
+//!
+//! [`Montage`] lays images out in a grid of equally sized cells derived from
+//! the window size, scales each image into its cell and prints an optional
+//! caption below it.
+
+use crate::error::Result;
+use crate::image::{DisplayOptions, FALLBACK_CELL, Fit, ImageDisplay, ImageHandle, png_dimensions};
+use crate::terminal::WindowSize;
+
+/// Window size assumed when the terminal does not report one
+const FALLBACK_WINDOW: WindowSize = WindowSize {
+    rows: 24,
+    cols: 80,
+    width: 0,
+    height: 0,
+};
+
+/// Position and size of one cell of a [`Montage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MontageCell {
+    /// 1-based row of the top left corner
+    pub row: u16,
+    /// 1-based column of the top left corner
+    pub col: u16,
+    /// Width of the thumbnail area in cells
+    pub cols: u32,
+    /// Height of the thumbnail area in cells
+    pub rows: u32,
+    /// Row the caption is printed on, below the thumbnail
+    pub caption_row: Option<u16>,
+}
+
+/// A grid of thumbnails with captions
+///
+/// ```no_run
+/// # fn main() -> kitty_graphics_protocol::Result<()> {
+/// use kitty_graphics_protocol::{ImageDisplay, Montage};
+///
+/// let display = ImageDisplay::new();
+/// let handles = Montage::new(4)
+///     .push_captioned(std::fs::read("a.png")?, "a.png")
+///     .push_captioned(std::fs::read("b.png")?, "b.png")
+///     .render(&display)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Montage {
+    cols: u32,
+    origin: (u16, u16),
+    gap: u16,
+    thumbnail_rows: Option<u32>,
+    fit: Fit,
+    items: Vec<(Vec<u8>, Option<String>)>,
+}
+
+impl Montage {
+    /// Create a grid with `cols` thumbnails per row
+    pub fn new(cols: u32) -> Self {
+        Self {
+            cols: cols.max(1),
+            origin: (1, 1),
+            gap: 1,
+            thumbnail_rows: None,
+            fit: Fit::Contain,
+            items: Vec::new(),
+        }
+    }
+
+    /// Add a PNG image
+    pub fn push(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.items.push((data.into(), None));
+        self
+    }
+
+    /// Add a PNG image with a caption printed below it
+    pub fn push_captioned(mut self, data: impl Into<Vec<u8>>, caption: impl Into<String>) -> Self {
+        self.items.push((data.into(), Some(caption.into())));
+        self
+    }
+
+    /// Set the 1-based row and column of the grid's top left corner
+    pub fn origin(mut self, row: u16, col: u16) -> Self {
+        self.origin = (row.max(1), col.max(1));
+        self
+    }
+
+    /// Set the number of blank cells between thumbnails (1 by default)
+    pub fn gap(mut self, cells: u16) -> Self {
+        self.gap = cells;
+        self
+    }
+
+    /// Set the height of the thumbnails in rows
+    ///
+    /// By default thumbnails are about as high as they are wide.
+    pub fn thumbnail_rows(mut self, rows: u32) -> Self {
+        self.thumbnail_rows = Some(rows.max(1));
+        self
+    }
+
+    /// Set how images are scaled into their cells ([`Fit::Contain`] by default)
+    pub fn fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    /// Compute the cell of every image, in the order they were added
+    ///
+    /// The columns right of the origin are shared by `cols` cells.
+    pub fn layout(&self, window: &WindowSize) -> Vec<MontageCell> {
+        let (cell_w, cell_h) = match (window.cell_width(), window.cell_height()) {
+            (0, _) | (_, 0) => FALLBACK_CELL,
+            (w, h) => (u32::from(w), u32::from(h)),
+        };
+        let gap = u32::from(self.gap);
+        let available = u32::from(window.cols).saturating_sub(u32::from(self.origin.1) - 1);
+        let cols = (available.saturating_sub(gap * (self.cols - 1)) / self.cols).max(1);
+        let rows = self
+            .thumbnail_rows
+            .unwrap_or_else(|| (cols * cell_w / cell_h).max(1));
+        let caption_rows = u32::from(self.items.iter().any(|(_, c)| c.is_some()));
+        let to_u16 = |n: u32| n.min(u32::from(u16::MAX)) as u16;
+
+        (0..self.items.len() as u32)
+            .map(|index| {
+                let row =
+                    u32::from(self.origin.0) + (index / self.cols) * (rows + caption_rows + gap);
+                let col = u32::from(self.origin.1) + (index % self.cols) * (cols + gap);
+                let caption = self.items[index as usize].1.is_some();
+                MontageCell {
+                    row: to_u16(row),
+                    col: to_u16(col),
+                    cols,
+                    rows,
+                    caption_row: caption.then(|| to_u16(row + rows)),
+                }
+            })
+            .collect()
+    }
+
+    /// Display every image in its cell and print the captions
+    ///
+    /// Images are centered horizontally in their cells. The returned
+    /// handles delete the images when dropped. Without a known window
+    /// size, an 80x24 window is assumed.
+    pub fn render<'a>(&self, display: &'a ImageDisplay) -> Result<Vec<ImageHandle<'a>>> {
+        let window = display.window_size().unwrap_or(FALLBACK_WINDOW);
+        let mut handles = Vec::with_capacity(self.items.len());
+        for ((data, caption), cell) in self.items.iter().zip(self.layout(&window)) {
+            let options = DisplayOptions::new()
+                .fit(self.fit)
+                .max_cols(cell.cols)
+                .max_rows(cell.rows);
+            let layout = png_dimensions(data).and_then(|(w, h)| options.layout(w, h, &window));
+            let shown = layout.map_or(cell.cols, |layout| layout.cols);
+            let col = cell.col + (cell.cols.saturating_sub(shown) / 2) as u16;
+            handles.push(display.display_png_handle_with(cell.row, col, data, layout, None)?);
+
+            if let (Some(caption), Some(row)) = (caption, cell.caption_row) {
+                // Drop control characters, which could inject escape codes
+                let text: String = caption
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(cell.cols as usize)
+                    .collect();
+                display.text_at(row, cell.col, &text)?;
+            }
+        }
+        Ok(handles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Terminal;
+    use crate::test_support::Recorder;
+    use crate::types::Passthrough;
+    use std::sync::{Arc, Mutex};
+
+    /// PNG header of an image `width`x`height` pixels
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_layout_grid() {
+        let window = WindowSize {
+            rows: 40,
+            cols: 80,
+            width: 800,
+            height: 800,
+        };
+        let montage = Montage::new(3)
+            .push(png(1, 1))
+            .push(png(1, 1))
+            .push(png(1, 1))
+            .push_captioned(png(1, 1), "d");
+        let cells = montage.layout(&window);
+        // (80 - 2 gaps) / 3 = 26 columns of 10px, 13 rows of 20px
+        assert_eq!(
+            cells[1],
+            MontageCell {
+                row: 1,
+                col: 28,
+                cols: 26,
+                rows: 13,
+                caption_row: None,
+            }
+        );
+        assert_eq!((cells[3].row, cells[3].col), (16, 1));
+        assert_eq!(cells[3].caption_row, Some(29));
+    }
+
+    #[test]
+    fn test_render_with_captions() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .id_allocator(Arc::new(crate::ids::IdAllocator::new()))
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let handles = Montage::new(2)
+            .thumbnail_rows(5)
+            .push_captioned(png(100, 100), "cat\x1b[2J.png")
+            .push(png(200, 100))
+            .render(&display)
+            .unwrap();
+        assert_eq!(handles.len(), 2);
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.contains("\x1b7\x1b[6;1Hcat[2J.png\x1b8"));
+        assert!(sent.contains("\x1b7\x1b[1;15H\x1b_Ga=T,f=100,i=256,"));
+        assert!(sent.contains("i=257,"));
+    }
+}
@@ -0,0 +1,541 @@
+//! Unicode placeholder text for virtual placements
+//!
+//! A virtual placement (`U=1`) is only drawn where the terminal finds
+//! placeholder cells referencing it: the character U+10EEEE followed by
+//! combining diacritics giving the cell's row and column within the image
+//! and, for image IDs above 24 bits, the most significant byte of the ID.
+//! The rest of the image ID is the cell's foreground color and the
+//! placement ID its underline color. Since the cells are ordinary text,
+//! they survive tmux panes, TUI buffers and scrollback.
+
+use crate::builders::PlaceholderGrid;
+use crate::error::{Error, Result};
+#[cfg(feature = "std")]
+use crate::{
+    command::Command,
+    ids::IdAllocator,
+    types::{DeleteTarget, ImageFormat, ImageId, Passthrough, PlacementId, Quiet},
+};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+/// The placeholder character, U+10EEEE
+pub const PLACEHOLDER: char = '\u{10EEEE}';
+
+/// Combining characters encoding the numbers 0 to 296, in order
+///
+/// This is kitty's `rowcolumn-diacritics.txt`.
+pub const DIACRITICS: [char; 297] = [
+    '\u{305}',
+    '\u{30D}',
+    '\u{30E}',
+    '\u{310}',
+    '\u{312}',
+    '\u{33D}',
+    '\u{33E}',
+    '\u{33F}',
+    '\u{346}',
+    '\u{34A}',
+    '\u{34B}',
+    '\u{34C}',
+    '\u{350}',
+    '\u{351}',
+    '\u{352}',
+    '\u{357}',
+    '\u{35B}',
+    '\u{363}',
+    '\u{364}',
+    '\u{365}',
+    '\u{366}',
+    '\u{367}',
+    '\u{368}',
+    '\u{369}',
+    '\u{36A}',
+    '\u{36B}',
+    '\u{36C}',
+    '\u{36D}',
+    '\u{36E}',
+    '\u{36F}',
+    '\u{483}',
+    '\u{484}',
+    '\u{485}',
+    '\u{486}',
+    '\u{487}',
+    '\u{592}',
+    '\u{593}',
+    '\u{594}',
+    '\u{595}',
+    '\u{597}',
+    '\u{598}',
+    '\u{599}',
+    '\u{59C}',
+    '\u{59D}',
+    '\u{59E}',
+    '\u{59F}',
+    '\u{5A0}',
+    '\u{5A1}',
+    '\u{5A8}',
+    '\u{5A9}',
+    '\u{5AB}',
+    '\u{5AC}',
+    '\u{5AF}',
+    '\u{5C4}',
+    '\u{610}',
+    '\u{611}',
+    '\u{612}',
+    '\u{613}',
+    '\u{614}',
+    '\u{615}',
+    '\u{616}',
+    '\u{617}',
+    '\u{657}',
+    '\u{658}',
+    '\u{659}',
+    '\u{65A}',
+    '\u{65B}',
+    '\u{65D}',
+    '\u{65E}',
+    '\u{6D6}',
+    '\u{6D7}',
+    '\u{6D8}',
+    '\u{6D9}',
+    '\u{6DA}',
+    '\u{6DB}',
+    '\u{6DC}',
+    '\u{6DF}',
+    '\u{6E0}',
+    '\u{6E1}',
+    '\u{6E2}',
+    '\u{6E4}',
+    '\u{6E7}',
+    '\u{6E8}',
+    '\u{6EB}',
+    '\u{6EC}',
+    '\u{730}',
+    '\u{732}',
+    '\u{733}',
+    '\u{735}',
+    '\u{736}',
+    '\u{73A}',
+    '\u{73D}',
+    '\u{73F}',
+    '\u{740}',
+    '\u{741}',
+    '\u{743}',
+    '\u{745}',
+    '\u{747}',
+    '\u{749}',
+    '\u{74A}',
+    '\u{7EB}',
+    '\u{7EC}',
+    '\u{7ED}',
+    '\u{7EE}',
+    '\u{7EF}',
+    '\u{7F0}',
+    '\u{7F1}',
+    '\u{7F3}',
+    '\u{816}',
+    '\u{817}',
+    '\u{818}',
+    '\u{819}',
+    '\u{81B}',
+    '\u{81C}',
+    '\u{81D}',
+    '\u{81E}',
+    '\u{81F}',
+    '\u{820}',
+    '\u{821}',
+    '\u{822}',
+    '\u{823}',
+    '\u{825}',
+    '\u{826}',
+    '\u{827}',
+    '\u{829}',
+    '\u{82A}',
+    '\u{82B}',
+    '\u{82C}',
+    '\u{82D}',
+    '\u{951}',
+    '\u{953}',
+    '\u{954}',
+    '\u{F82}',
+    '\u{F83}',
+    '\u{F86}',
+    '\u{F87}',
+    '\u{135D}',
+    '\u{135E}',
+    '\u{135F}',
+    '\u{17DD}',
+    '\u{193A}',
+    '\u{1A17}',
+    '\u{1A75}',
+    '\u{1A76}',
+    '\u{1A77}',
+    '\u{1A78}',
+    '\u{1A79}',
+    '\u{1A7A}',
+    '\u{1A7B}',
+    '\u{1A7C}',
+    '\u{1B6B}',
+    '\u{1B6D}',
+    '\u{1B6E}',
+    '\u{1B6F}',
+    '\u{1B70}',
+    '\u{1B71}',
+    '\u{1B72}',
+    '\u{1B73}',
+    '\u{1CD0}',
+    '\u{1CD1}',
+    '\u{1CD2}',
+    '\u{1CDA}',
+    '\u{1CDB}',
+    '\u{1CE0}',
+    '\u{1DC0}',
+    '\u{1DC1}',
+    '\u{1DC3}',
+    '\u{1DC4}',
+    '\u{1DC5}',
+    '\u{1DC6}',
+    '\u{1DC7}',
+    '\u{1DC8}',
+    '\u{1DC9}',
+    '\u{1DCB}',
+    '\u{1DCC}',
+    '\u{1DD1}',
+    '\u{1DD2}',
+    '\u{1DD3}',
+    '\u{1DD4}',
+    '\u{1DD5}',
+    '\u{1DD6}',
+    '\u{1DD7}',
+    '\u{1DD8}',
+    '\u{1DD9}',
+    '\u{1DDA}',
+    '\u{1DDB}',
+    '\u{1DDC}',
+    '\u{1DDD}',
+    '\u{1DDE}',
+    '\u{1DDF}',
+    '\u{1DE0}',
+    '\u{1DE1}',
+    '\u{1DE2}',
+    '\u{1DE3}',
+    '\u{1DE4}',
+    '\u{1DE5}',
+    '\u{1DE6}',
+    '\u{1DFE}',
+    '\u{20D0}',
+    '\u{20D1}',
+    '\u{20D4}',
+    '\u{20D5}',
+    '\u{20D6}',
+    '\u{20D7}',
+    '\u{20DB}',
+    '\u{20DC}',
+    '\u{20E1}',
+    '\u{20E7}',
+    '\u{20E9}',
+    '\u{20F0}',
+    '\u{2CEF}',
+    '\u{2CF0}',
+    '\u{2CF1}',
+    '\u{2DE0}',
+    '\u{2DE1}',
+    '\u{2DE2}',
+    '\u{2DE3}',
+    '\u{2DE4}',
+    '\u{2DE5}',
+    '\u{2DE6}',
+    '\u{2DE7}',
+    '\u{2DE8}',
+    '\u{2DE9}',
+    '\u{2DEA}',
+    '\u{2DEB}',
+    '\u{2DEC}',
+    '\u{2DED}',
+    '\u{2DEE}',
+    '\u{2DEF}',
+    '\u{2DF0}',
+    '\u{2DF1}',
+    '\u{2DF2}',
+    '\u{2DF3}',
+    '\u{2DF4}',
+    '\u{2DF5}',
+    '\u{2DF6}',
+    '\u{2DF7}',
+    '\u{2DF8}',
+    '\u{2DF9}',
+    '\u{2DFA}',
+    '\u{2DFB}',
+    '\u{2DFC}',
+    '\u{2DFD}',
+    '\u{2DFE}',
+    '\u{2DFF}',
+    '\u{A66F}',
+    '\u{A67C}',
+    '\u{A67D}',
+    '\u{A6F0}',
+    '\u{A6F1}',
+    '\u{A8E0}',
+    '\u{A8E1}',
+    '\u{A8E2}',
+    '\u{A8E3}',
+    '\u{A8E4}',
+    '\u{A8E5}',
+    '\u{A8E6}',
+    '\u{A8E7}',
+    '\u{A8E8}',
+    '\u{A8E9}',
+    '\u{A8EA}',
+    '\u{A8EB}',
+    '\u{A8EC}',
+    '\u{A8ED}',
+    '\u{A8EE}',
+    '\u{A8EF}',
+    '\u{A8F0}',
+    '\u{A8F1}',
+    '\u{AAB0}',
+    '\u{AAB2}',
+    '\u{AAB3}',
+    '\u{AAB7}',
+    '\u{AAB8}',
+    '\u{AABE}',
+    '\u{AABF}',
+    '\u{AAC1}',
+    '\u{FE20}',
+    '\u{FE21}',
+    '\u{FE22}',
+    '\u{FE23}',
+    '\u{FE24}',
+    '\u{FE25}',
+    '\u{FE26}',
+    '\u{10A0F}',
+    '\u{10A38}',
+    '\u{1D185}',
+    '\u{1D186}',
+    '\u{1D187}',
+    '\u{1D188}',
+    '\u{1D189}',
+    '\u{1D1AA}',
+    '\u{1D1AB}',
+    '\u{1D1AC}',
+    '\u{1D1AD}',
+    '\u{1D242}',
+    '\u{1D243}',
+    '\u{1D244}',
+];
+
+/// Diacritic encoding `n`, or `None` if it is too large to encode
+pub fn diacritic(n: u32) -> Option<char> {
+    DIACRITICS.get(n as usize).copied()
+}
+
+impl PlaceholderGrid {
+    /// Placeholder text of one row, with the colors set and reset
+    ///
+    /// Fails with [`Error::InvalidDimensions`] if the row or the grid's
+    /// columns cannot be encoded, and with [`Error::InvalidPlacementId`] if
+    /// the placement ID does not fit into 24 bits.
+    pub fn row(&self, row: u16) -> Result<String> {
+        let invalid = || Error::InvalidDimensions {
+            width: u32::from(self.columns),
+            height: u32::from(self.rows),
+        };
+        if self.columns == 0 || usize::from(self.columns) > DIACRITICS.len() {
+            return Err(invalid());
+        }
+        let row_mark = diacritic(u32::from(row)).ok_or_else(invalid)?;
+        let placement = self.placement_id.get();
+        if placement > 0xff_ffff {
+            return Err(Error::InvalidPlacementId(placement));
+        }
+
+        let id = self.image_id.get();
+        let high = diacritic(id >> 24).filter(|_| id >> 24 != 0);
+        let mut text = format!(
+            "\x1b[38;2;{};{};{}m\x1b[58;2;{};{};{}m",
+            (id >> 16) & 0xff,
+            (id >> 8) & 0xff,
+            id & 0xff,
+            placement >> 16,
+            (placement >> 8) & 0xff,
+            placement & 0xff,
+        );
+        for col in &DIACRITICS[..usize::from(self.columns)] {
+            text.push(PLACEHOLDER);
+            text.push(row_mark);
+            text.push(*col);
+            text.extend(high);
+        }
+        text.push_str("\x1b[39;59m");
+        Ok(text)
+    }
+
+    /// Placeholder text of every row, top to bottom
+    ///
+    /// Print each row at the same column, e.g. one per line, to show the
+    /// image. See [`row`](Self::row) for the errors.
+    pub fn rows(&self) -> Result<Vec<String>> {
+        (0..self.rows).map(|row| self.row(row)).collect()
+    }
+}
+
+/// A transmitted image with a virtual placement, ready to be printed
+///
+/// ```no_run
+/// # fn main() -> kitty_graphics_protocol::Result<()> {
+/// use kitty_graphics_protocol::{Terminal, VirtualImage};
+///
+/// let mut term = Terminal::open()?;
+/// let image = VirtualImage::create(&mut term, &std::fs::read("logo.png")?, 20, 10)?;
+/// for row in image.rows() {
+///     println!("{row}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualImage {
+    grid: PlaceholderGrid,
+    rows: Vec<String>,
+    passthrough: Passthrough,
+}
+
+#[cfg(feature = "std")]
+impl VirtualImage {
+    /// Transmit PNG data and create a virtual placement of `cols`x`rows`
+    /// cells for it
+    ///
+    /// The image ID comes from [`IdAllocator::global`]. Commands are wrapped
+    /// for tmux when running inside it; the rows are printed as plain text.
+    pub fn create<W: Write>(out: &mut W, data: &[u8], cols: u16, rows: u16) -> Result<Self> {
+        let image_id = IdAllocator::global()
+            .allocate()
+            .ok_or_else(|| Error::protocol("no free image IDs left"))?;
+        let image = Self::create_with_id(out, data, image_id, cols, rows, Passthrough::detect());
+        if image.is_err() {
+            IdAllocator::global().release(image_id);
+        }
+        image
+    }
+
+    /// Transmit and place the image under a given ID
+    fn create_with_id<W: Write>(
+        out: &mut W,
+        data: &[u8],
+        image_id: ImageId,
+        cols: u16,
+        rows: u16,
+        passthrough: Passthrough,
+    ) -> Result<Self> {
+        let placement_id = PlacementId::new(1).ok_or(Error::InvalidPlacementId(0))?;
+        let (place, grid) = Command::virtual_placement(image_id, cols, rows)
+            .placement_id(placement_id)
+            .quiet(Quiet::SuppressAll)
+            .passthrough(passthrough)
+            .try_build()?;
+        let text = grid.rows()?;
+
+        Command::transmit()
+            .image_id(image_id)
+            .format(ImageFormat::Png)
+            .quiet(Quiet::SuppressAll)
+            .passthrough(passthrough)
+            .build()
+            .serialize_chunked_into(data, out)?;
+        out.write_all(&place.serialize_control_bytes()?)?;
+        out.flush()?;
+        Ok(Self {
+            grid,
+            rows: text,
+            passthrough,
+        })
+    }
+
+    /// ID of the transmitted image
+    pub fn image_id(&self) -> ImageId {
+        self.grid.image_id
+    }
+
+    /// Image, placement and size addressed by the rows
+    pub fn grid(&self) -> PlaceholderGrid {
+        self.grid
+    }
+
+    /// Placeholder text of every row, top to bottom
+    pub fn rows(&self) -> &[String] {
+        &self.rows
+    }
+
+    /// Take the placeholder rows, leaving the image in the terminal
+    pub fn into_rows(self) -> Vec<String> {
+        self.rows
+    }
+
+    /// Delete the image and release its ID
+    ///
+    /// Printed placeholder cells turn blank.
+    pub fn delete<W: Write>(self, out: &mut W) -> Result<()> {
+        let cmd = Command::delete(DeleteTarget::ById { free_data: true })
+            .image_id(self.grid.image_id)
+            .quiet(Quiet::SuppressAll)
+            .passthrough(self.passthrough)
+            .build();
+        out.write_all(&cmd.serialize_control_bytes()?)?;
+        out.flush()?;
+        IdAllocator::global().release(self.grid.image_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ImageId, PlacementId};
+
+    fn grid(image_id: u32, columns: u16, rows: u16) -> PlaceholderGrid {
+        PlaceholderGrid {
+            image_id: ImageId::new(image_id).unwrap(),
+            placement_id: PlacementId::new(5).unwrap(),
+            columns,
+            rows,
+        }
+    }
+
+    #[test]
+    fn test_placeholder_rows() {
+        let rows = grid(0x0001_0203, 2, 2).rows().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[1],
+            "\x1b[38;2;1;2;3m\x1b[58;2;0;0;5m\
+             \u{10EEEE}\u{30D}\u{305}\u{10EEEE}\u{30D}\u{30D}\x1b[39;59m"
+        );
+
+        // The most significant byte becomes a third diacritic
+        let row = grid(0x0201_0203, 1, 1).row(0).unwrap();
+        assert!(row.contains("\u{10EEEE}\u{305}\u{305}\u{30E}\x1b"));
+
+        assert!(grid(1, 298, 1).rows().is_err());
+        assert!(grid(1, 1, 1).row(297).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_virtual_image() {
+        let mut out = Vec::new();
+        let id = ImageId::new(9).unwrap();
+        let image = VirtualImage::create_with_id(&mut out, b"\x89PNG", id, 3, 2, Passthrough::None)
+            .unwrap();
+        let sent = String::from_utf8(out).unwrap();
+        assert!(sent.starts_with("\x1b_Ga=t,f=100,i=9,q=2"));
+        assert!(sent.ends_with("\x1b_Ga=p,i=9,p=1,q=2,c=3,r=2,U=1\x1b\\"));
+        assert_eq!(image.rows().len(), 2);
+        assert_eq!(image.rows()[0].matches(PLACEHOLDER).count(), 3);
+        assert!(image.rows()[0].starts_with("\x1b[38;2;0;0;9m"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Bookkeeping for the placements an application created
+//!
+//! The terminal offers no way to list placements, so TUIs keep track of
+//! where they put each image to move, replace or remove it later.
+//! [`PlacementManager`] does that bookkeeping and sends the commands.
+
+use crate::command::Command;
+use crate::error::{Error, Result};
+use crate::image::ImageDisplay;
+use crate::types::{Action, DeleteTarget, ImageId, PlacementId, Quiet};
+
+/// A placement created through a [`PlacementManager`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Placement {
+    /// Image shown by the placement
+    pub image_id: ImageId,
+    /// ID of the placement, unique within its manager
+    pub placement_id: PlacementId,
+    /// 1-based row of the top left corner
+    pub row: u16,
+    /// 1-based column of the top left corner
+    pub col: u16,
+    /// Width in cells
+    pub cols: u32,
+    /// Height in cells
+    pub rows: u32,
+    /// Stacking order relative to text and other images
+    pub z_index: i32,
+}
+
+/// Tracks every placement created through it
+///
+/// Placements are positioned with the cursor, which is restored afterwards,
+/// so they don't disturb text output. Dropping the manager leaves its
+/// placements on screen; call [`clear`](Self::clear) to remove them.
+pub struct PlacementManager {
+    display: ImageDisplay,
+    placements: Vec<Placement>,
+    next_id: u32,
+}
+
+impl PlacementManager {
+    /// Create a manager sending commands through `display`
+    pub fn new(display: ImageDisplay) -> Self {
+        Self {
+            display,
+            placements: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// The display commands are sent through
+    pub fn display(&self) -> &ImageDisplay {
+        &self.display
+    }
+
+    /// Place a transmitted image at `row`, `col`, `cols`x`rows` cells large
+    pub fn place(
+        &mut self,
+        image_id: ImageId,
+        row: u16,
+        col: u16,
+        cols: u32,
+        rows: u32,
+        z_index: i32,
+    ) -> Result<PlacementId> {
+        let placement_id = PlacementId::new(self.next_id)
+            .ok_or_else(|| Error::protocol("no free placement IDs left"))?;
+        let placement = Placement {
+            image_id,
+            placement_id,
+            row,
+            col,
+            cols,
+            rows,
+            z_index,
+        };
+        self.send(&placement)?;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.placements.push(placement);
+        Ok(placement_id)
+    }
+
+    /// Move a placement so its top left corner is at `row`, `col`
+    pub fn move_to(&mut self, placement_id: PlacementId, row: u16, col: u16) -> Result<()> {
+        let index = self.index(placement_id)?;
+        let placement = Placement {
+            row,
+            col,
+            ..self.placements[index]
+        };
+        // Placing the same image and placement ID again replaces it
+        self.send(&placement)?;
+        self.placements[index] = placement;
+        Ok(())
+    }
+
+    /// Show another image in a placement, keeping its position and size
+    pub fn replace_image(&mut self, placement_id: PlacementId, image_id: ImageId) -> Result<()> {
+        let index = self.index(placement_id)?;
+        let old = self.placements[index];
+        let placement = Placement { image_id, ..old };
+        self.send(&placement)?;
+        if old.image_id != image_id {
+            self.send_delete(&old)?;
+        }
+        self.placements[index] = placement;
+        Ok(())
+    }
+
+    /// Remove a placement from the screen
+    ///
+    /// The image data stays in the terminal for other placements.
+    pub fn delete(&mut self, placement_id: PlacementId) -> Result<()> {
+        let index = self.index(placement_id)?;
+        self.send_delete(&self.placements[index])?;
+        self.placements.remove(index);
+        Ok(())
+    }
+
+    /// Remove every tracked placement from the screen
+    pub fn clear(&mut self) -> Result<()> {
+        while let Some(placement) = self.placements.last() {
+            self.send_delete(placement)?;
+            self.placements.pop();
+        }
+        Ok(())
+    }
+
+    /// Look up a tracked placement
+    pub fn get(&self, placement_id: PlacementId) -> Option<&Placement> {
+        self.placements
+            .iter()
+            .find(|p| p.placement_id == placement_id)
+    }
+
+    /// All tracked placements, in the order they were created
+    pub fn list(&self) -> &[Placement] {
+        &self.placements
+    }
+
+    /// Index of a tracked placement
+    fn index(&self, placement_id: PlacementId) -> Result<usize> {
+        self.placements
+            .iter()
+            .position(|p| p.placement_id == placement_id)
+            .ok_or(Error::InvalidPlacementId(placement_id.get()))
+    }
+
+    /// Send the place command for `placement`
+    fn send(&self, placement: &Placement) -> Result<()> {
+        let builder = Command::builder()
+            .image_id(placement.image_id)
+            .placement_id(placement.placement_id)
+            .display_area(placement.cols, placement.rows)
+            .z_index(placement.z_index);
+        self.display.place_at(placement.row, placement.col, builder)
+    }
+
+    /// Send the command deleting `placement` but not its image
+    fn send_delete(&self, placement: &Placement) -> Result<()> {
+        let cmd = Command::builder()
+            .action(Action::Delete)
+            .delete_target(DeleteTarget::ById { free_data: false })
+            .image_id(placement.image_id)
+            .placement_id(placement.placement_id)
+            .quiet(Quiet::SuppressAll)
+            .build();
+        self.display.write_control(&cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Terminal;
+    use crate::test_support::Recorder;
+    use crate::types::Passthrough;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_move_replace_delete() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let mut manager = PlacementManager::new(display);
+        let image = ImageId::new(7).unwrap();
+        let other = ImageId::new(8).unwrap();
+
+        let first = manager.place(image, 2, 3, 10, 5, -1).unwrap();
+        let second = manager.place(image, 10, 3, 10, 5, 0).unwrap();
+        assert_ne!(first, second);
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("\x1b7\x1b[2;3H\x1b_Ga=p,i=7,p=1,"));
+        assert!(sent.contains("z=-1"));
+
+        output.lock().unwrap().clear();
+        manager.move_to(first, 4, 6).unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("\x1b7\x1b[4;6H\x1b_Ga=p,i=7,p=1,"));
+        assert_eq!(
+            (
+                manager.get(first).unwrap().row,
+                manager.get(first).unwrap().col
+            ),
+            (4, 6)
+        );
+
+        output.lock().unwrap().clear();
+        manager.replace_image(second, other).unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.contains("a=p,i=8,p=2,"));
+        assert!(sent.ends_with("\x1b_Ga=d,i=7,p=2,q=2,d=i\x1b\\"));
+
+        manager.delete(first).unwrap();
+        assert_eq!(manager.list().len(), 1);
+        assert_eq!(manager.list()[0].image_id, other);
+        assert!(manager.delete(first).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Client-side animation playback
+//!
+//! Some terminals implementing the graphics protocol store animation frames
+//! but never advance them on their own. [`AnimationPlayer`] transmits the
+//! frames once and then drives the animation itself, copying each frame
+//! onto the root frame (`a=c`) when it is due.
+
+use crate::animation::{AnimatedImage, Loop};
+use crate::command::Command;
+use crate::error::Result;
+use crate::image::ImageDisplay;
+use crate::types::{
+    Action, CompositionMode, DeleteTarget, FrameComposition, ImageFormat, ImageId, Quiet,
+};
+use std::time::{Duration, Instant};
+
+/// Longest a frame stays on screen, well within what an [`Instant`] can
+/// be moved by
+const MAX_DELAY: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// Playback state of an [`AnimationPlayer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlayerState {
+    /// Frames advance when due
+    Playing,
+    /// The current frame stays on screen
+    Paused,
+    /// Back at the first frame, or done playing
+    Stopped,
+}
+
+/// Plays an animation by swapping frames into the root frame on a timer
+///
+/// Call [`tick`](Self::tick) from an event loop, or [`run`](Self::run) to
+/// block until the animation is done. The image stays in the terminal when
+/// the player is dropped.
+pub struct AnimationPlayer<'a> {
+    display: &'a ImageDisplay,
+    image_id: ImageId,
+    /// Kitty frame holding each frame's canvas; the first is a copy of the
+    /// root frame, which is overwritten during playback
+    sources: Vec<u32>,
+    delays: Vec<u32>,
+    loops: Loop,
+    current: usize,
+    plays: u32,
+    state: PlayerState,
+    due: Instant,
+    frame_rate: Option<f64>,
+    speed: f64,
+}
+
+impl<'a> AnimationPlayer<'a> {
+    /// Display the first frame of `image` and transmit the others
+    ///
+    /// The image gets an ID allocated by `display`. Playback starts with
+    /// [`play`](Self::play).
+    pub fn new(display: &'a ImageDisplay, image: &AnimatedImage) -> Result<Self> {
+        let image_id = display.allocate_id()?;
+        let commands = match display.transmit_frames(image, image_id) {
+            Ok(commands) => commands,
+            Err(err) => {
+                display.release_id(image_id);
+                return Err(err);
+            }
+        };
+
+        // Keep the root frame in a gapless copy, blending a transparent
+        // pixel onto it
+        let copy = Command::builder()
+            .action(Action::Frame)
+            .image_id(image_id)
+            .format(ImageFormat::Rgba)
+            .dimensions(1, 1)
+            .base_frame(1)
+            .frame_gap(-1);
+        if let Err(err) = display.send_frame(copy, &[0; 4]) {
+            // The first frame is already on screen; take it down again
+            let delete = Command::delete(DeleteTarget::ById { free_data: true })
+                .image_id(image_id)
+                .quiet(Quiet::SuppressAll)
+                .build();
+            if display.write_control(&delete).is_ok() {
+                display.release_id(image_id);
+            }
+            return Err(err);
+        }
+
+        let mut sources = commands.numbers;
+        sources[0] = commands.frame_count + 1;
+        Ok(Self {
+            display,
+            image_id,
+            sources,
+            delays: image.frames.iter().map(|f| f.delay_ms).collect(),
+            loops: image.loops,
+            current: 0,
+            plays: 0,
+            state: PlayerState::Stopped,
+            due: Instant::now(),
+            frame_rate: None,
+            speed: 1.0,
+        })
+    }
+
+    /// Show frames at a fixed rate instead of their own delays
+    pub fn frame_rate(mut self, fps: f64) -> Self {
+        self.frame_rate = (fps > 0.0).then_some(fps);
+        self
+    }
+
+    /// Scale the playback speed, e.g. `2.0` for twice as fast
+    pub fn speed(mut self, factor: f64) -> Self {
+        if factor > 0.0 {
+            self.speed = factor;
+        }
+        self
+    }
+
+    /// ID of the animated image
+    pub fn image_id(&self) -> ImageId {
+        self.image_id
+    }
+
+    /// Current playback state
+    pub fn state(&self) -> PlayerState {
+        self.state
+    }
+
+    /// Index of the frame on screen
+    pub fn current_frame(&self) -> usize {
+        self.current
+    }
+
+    /// Start or resume playback
+    pub fn play(&mut self) {
+        if self.state != PlayerState::Playing {
+            self.state = PlayerState::Playing;
+            self.due = Instant::now() + self.delay(self.current);
+        }
+    }
+
+    /// Keep the current frame on screen
+    pub fn pause(&mut self) {
+        if self.state == PlayerState::Playing {
+            self.state = PlayerState::Paused;
+        }
+    }
+
+    /// Stop playback and show the first frame again
+    pub fn stop(&mut self) -> Result<()> {
+        self.state = PlayerState::Stopped;
+        self.plays = 0;
+        self.show(0)
+    }
+
+    /// Show the next frame now, wrapping around after the last one
+    ///
+    /// Counts a play each time the animation wraps around and stops once
+    /// all plays of a [`Loop::Times`] animation are done.
+    pub fn next_frame(&mut self) -> Result<()> {
+        let mut next = self.current + 1;
+        if next == self.sources.len() {
+            self.plays += 1;
+            if matches!(self.loops, Loop::Times(n) if self.plays >= n) {
+                self.state = PlayerState::Stopped;
+                return Ok(());
+            }
+            next = 0;
+        }
+        self.show(next)
+    }
+
+    /// Advance the animation if the next frame is due
+    ///
+    /// Returns how long to wait before calling again, or `None` when not
+    /// playing.
+    pub fn tick(&mut self) -> Result<Option<Duration>> {
+        if self.state != PlayerState::Playing {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        if now >= self.due {
+            self.next_frame()?;
+            if self.state != PlayerState::Playing {
+                return Ok(None);
+            }
+            // Catch up from the due time, but never schedule in the past
+            self.due = (self.due + self.delay(self.current)).max(now);
+        }
+        Ok(Some(self.due.saturating_duration_since(now)))
+    }
+
+    /// Play until the animation is done, or forever for [`Loop::Infinite`]
+    pub fn run(&mut self) -> Result<()> {
+        self.play();
+        while let Some(wait) = self.tick()? {
+            std::thread::sleep(wait);
+        }
+        Ok(())
+    }
+
+    /// How long the frame at `index` stays on screen
+    ///
+    /// Delays longer than [`MAX_DELAY`] at very low speeds are clamped.
+    fn delay(&self, index: usize) -> Duration {
+        let ms = match self.frame_rate {
+            Some(fps) => 1000.0 / fps,
+            None => f64::from(self.delays[index]),
+        };
+        Duration::try_from_secs_f64(ms / self.speed / 1000.0)
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+    }
+
+    /// Copy the canvas of the frame at `index` onto the root frame
+    fn show(&mut self, index: usize) -> Result<()> {
+        let composition = FrameComposition {
+            source_frame: self.sources[index],
+            dest_frame: 1,
+            mode: CompositionMode::Replace,
+            ..FrameComposition::default()
+        };
+        let cmd = Command::compose_frames(self.image_id, composition)
+            .into_builder()
+            .quiet(self.display.quiet_mode())
+            .build();
+        self.display.write_control(&cmd)?;
+        self.current = index;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::{AnimatedFrame, Disposal};
+    use crate::terminal::Terminal;
+    use crate::test_support::Recorder;
+    use crate::types::Passthrough;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_player_swaps_frames_into_root() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .id_allocator(Arc::new(crate::ids::IdAllocator::new()))
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let frame = AnimatedFrame {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            format: ImageFormat::Rgba,
+            data: vec![0; 4],
+            delay_ms: 40,
+            blend: false,
+            disposal: Disposal::Keep,
+        };
+        let image = AnimatedImage {
+            width: 1,
+            height: 1,
+            frames: vec![frame.clone(), frame],
+            loops: Loop::Times(1),
+        };
+
+        let mut player = AnimationPlayer::new(&display, &image).unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(!sent.contains("s=3"));
+        assert!(sent.ends_with("\x1b_Ga=f,f=32,s=1,v=1,i=256,q=2,c=1,z=-1,m=0;AAAAAA==\x1b\\"));
+
+        output.lock().unwrap().clear();
+        player.play();
+        player.next_frame().unwrap();
+        assert_eq!(player.current_frame(), 1);
+        player.next_frame().unwrap();
+        assert_eq!(player.state(), PlayerState::Stopped);
+        player.stop().unwrap();
+        assert_eq!(player.current_frame(), 0);
+
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            sent,
+            "\x1b_Ga=c,i=256,q=2,r=2,c=1,C=1\x1b\\\x1b_Ga=c,i=256,q=2,r=3,c=1,C=1\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_delay_clamped_at_low_speed() {
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .id_allocator(Arc::new(crate::ids::IdAllocator::new()))
+            .terminal(Terminal::from_transport(Recorder::new(Arc::default())));
+        let image = AnimatedImage {
+            width: 1,
+            height: 1,
+            frames: vec![AnimatedFrame {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+                format: ImageFormat::Rgba,
+                data: vec![0; 4],
+                delay_ms: 40,
+                blend: false,
+                disposal: Disposal::Keep,
+            }],
+            loops: Loop::Infinite,
+        };
+
+        let mut player = AnimationPlayer::new(&display, &image).unwrap().speed(1e-30);
+        assert_eq!(player.delay(0), MAX_DELAY);
+        player.play();
+        let wait = player.tick().unwrap().unwrap();
+        assert!(wait > MAX_DELAY - Duration::from_secs(1));
+
+        let player = player.speed(1.0).frame_rate(1e-300);
+        assert_eq!(player.delay(0), MAX_DELAY);
+    }
+}
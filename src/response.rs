@@ -1,104 +1,162 @@
 //! Response parsing for the Kitty graphics protocol
 
+use crate::command::Command;
 use crate::error::{Error, Result};
+use crate::types::{ImageFormat, ImageId, PlacementId, TransmissionMedium};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 /// Response from the terminal
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Responses compare equal when they carry the same information, whatever
+/// bytes they were parsed from.
+#[derive(Debug, Clone, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Response {
     /// Image ID (if applicable)
-    pub image_id: Option<u32>,
+    pub image_id: Option<ImageId>,
     /// Image number (if applicable)
     pub image_number: Option<u32>,
     /// Placement ID (if applicable)
-    pub placement_id: Option<u32>,
+    pub placement_id: Option<PlacementId>,
     /// Whether the operation was successful
     pub success: bool,
-    /// Error message (if failed)
+    /// Error message (if failed), prefixed with a description of the code
     pub error: Option<String>,
+    /// Error code (if failed)
+    pub code: Option<ErrorCode>,
+    /// Error message as sent by the terminal, without the code
+    pub message: Option<String>,
+    /// Control keys not recognized by this crate, in the order received
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extra: Vec<(char, String)>,
+    /// The exact bytes the terminal sent; empty for constructed responses
+    /// and ignored when comparing
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub raw: Vec<u8>,
+}
+
+impl PartialEq for Response {
+    fn eq(&self, other: &Self) -> bool {
+        self.image_id == other.image_id
+            && self.image_number == other.image_number
+            && self.placement_id == other.placement_id
+            && self.success == other.success
+            && self.error == other.error
+            && self.code == other.code
+            && self.message == other.message
+            && self.extra == other.extra
+    }
 }
 
 impl Response {
     /// Parse a response from the terminal
     pub fn parse(data: &[u8]) -> Result<Self> {
-        // Expected format: <ESC>_Gi=<id>;OK<ESC>\ or <ESC>_Gi=<id>;ERROR:message<ESC>\
-        // Also: <ESC>_Gi=<id>,p=<placement_id>;OK<ESC>\
-        // And: <ESC>_Gi=<id>,I=<number>;OK<ESC>\
+        Ok(ResponseRef::parse(data)?.to_response())
+    }
 
-        // Check for APC start
-        if data.len() < 6 {
-            return Err(Error::InvalidResponse(
-                String::from_utf8_lossy(data).into_owned(),
-            ));
-        }
+    /// Parse a response without copying its text; see [`ResponseRef`]
+    pub fn parse_ref(data: &[u8]) -> Result<ResponseRef<'_>> {
+        ResponseRef::parse(data)
+    }
 
-        if data[0] != crate::ESC || data[1] != b'_' || data[2] != b'G' {
-            return Err(Error::InvalidResponse(
-                String::from_utf8_lossy(data).into_owned(),
-            ));
+    /// The exact bytes the terminal sent, for logging and diagnostics
+    ///
+    /// Empty for responses built with [`Response::ok`] or [`Response::error`].
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Create a success response for an image, as a terminal would send it
+    pub fn ok(image_id: ImageId) -> Self {
+        Self {
+            image_id: Some(image_id),
+            image_number: None,
+            placement_id: None,
+            success: true,
+            error: None,
+            code: None,
+            message: None,
+            extra: Vec::new(),
+            raw: Vec::new(),
         }
+    }
 
-        // Find the semicolon separator
-        let semicolon_pos = data
-            .iter()
-            .position(|&b| b == b';')
-            .ok_or_else(|| Error::InvalidResponse(String::from_utf8_lossy(data).into_owned()))?;
+    /// Create an error response with the given code and message
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        let message = message.into();
+        let raw = match code.name() {
+            Some(name) => format!("{name}:{message}"),
+            None => message.clone(),
+        };
+        Self {
+            image_id: None,
+            image_number: None,
+            placement_id: None,
+            success: false,
+            error: Some(describe(code, &message, &raw)),
+            code: Some(code),
+            message: Some(message),
+            extra: Vec::new(),
+            raw: Vec::new(),
+        }
+    }
 
-        // Parse control data (between G and ;)
-        let control = &data[3..semicolon_pos];
-        let control_str = std::str::from_utf8(control).map_err(Error::from)?;
+    /// Set the image ID the response refers to
+    pub fn image(mut self, id: ImageId) -> Self {
+        self.image_id = Some(id);
+        self
+    }
 
-        // Parse the message (after semicolon until ESC \)
-        let end_pos = data
-            .iter()
-            .rposition(|&b| b == crate::ESC)
-            .ok_or_else(|| Error::InvalidResponse(String::from_utf8_lossy(data).into_owned()))?;
+    /// Set the image number the response refers to
+    pub fn number(mut self, number: u32) -> Self {
+        self.image_number = Some(number);
+        self
+    }
 
-        let message = &data[semicolon_pos + 1..end_pos];
-        let message_str = std::str::from_utf8(message).map_err(Error::from)?;
+    /// Set the placement ID the response refers to
+    pub fn placement(mut self, id: PlacementId) -> Self {
+        self.placement_id = Some(id);
+        self
+    }
 
-        // Parse control fields
-        let mut image_id = None;
-        let mut image_number = None;
-        let mut placement_id = None;
-
-        for part in control_str.split(',') {
-            let parts: Vec<&str> = part.splitn(2, '=').collect();
-            if parts.len() == 2 {
-                match parts[0] {
-                    "i" => image_id = parts[1].parse().ok(),
-                    "I" => image_number = parts[1].parse().ok(),
-                    "p" => placement_id = parts[1].parse().ok(),
-                    _ => {}
-                }
-            }
+    /// Serialize the response to the escape sequence a terminal sends
+    pub fn to_escape_sequence(&self) -> String {
+        let mut keys = Vec::new();
+        if let Some(id) = self.image_id {
+            keys.push(format!("i={id}"));
+        }
+        if let Some(number) = self.image_number {
+            keys.push(format!("I={number}"));
+        }
+        if let Some(id) = self.placement_id {
+            keys.push(format!("p={id}"));
+        }
+        for (key, value) in &self.extra {
+            keys.push(format!("{key}={value}"));
         }
 
-        // Parse message
-        let (success, error) = if message_str == "OK" {
-            (true, None)
-        } else if let Some(err_msg) = message_str.strip_prefix("ENOENT:") {
-            (false, Some(format!("Not found: {err_msg}")))
-        } else if let Some(err_msg) = message_str.strip_prefix("EINVAL:") {
-            (false, Some(format!("Invalid argument: {err_msg}")))
-        } else if let Some(err_msg) = message_str.strip_prefix("EIO:") {
-            (false, Some(format!("IO error: {err_msg}")))
-        } else if let Some(err_msg) = message_str.strip_prefix("ETOODEEP:") {
-            (false, Some(format!("Chain too deep: {err_msg}")))
-        } else if let Some(err_msg) = message_str.strip_prefix("ECYCLE:") {
-            (false, Some(format!("Cycle detected: {err_msg}")))
-        } else if let Some(err_msg) = message_str.strip_prefix("ENOPARENT:") {
-            (false, Some(format!("Parent not found: {err_msg}")))
+        let message = if self.success {
+            String::from("OK")
         } else {
-            (false, Some(message_str.to_string()))
+            let text = self.message.as_deref().unwrap_or_default();
+            match self.code.and_then(ErrorCode::name) {
+                Some(name) => format!("{name}:{text}"),
+                None => text.to_string(),
+            }
         };
+        format!("\x1b_G{};{message}\x1b\\", keys.join(","))
+    }
 
-        Ok(Response {
-            image_id,
-            image_number,
-            placement_id,
-            success,
-            error,
-        })
+    /// Parse all graphics responses in a buffer read from the terminal
+    ///
+    /// Other terminal reports interleaved with the responses are skipped.
+    /// Returns the responses and the unconsumed remainder, an incomplete
+    /// sequence to prepend to the next read.
+    pub fn parse_all(data: &[u8]) -> (Vec<Response>, &[u8]) {
+        let (responses, consumed) = scan(data);
+        (responses, &data[consumed..])
     }
 
     /// Check if this is a success response
@@ -115,10 +173,15 @@ impl Response {
     pub fn error_message(&self) -> Option<&str> {
         self.error.as_deref()
     }
+
+    /// Get the error code if this is an error
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        self.code
+    }
 }
 
-impl std::fmt::Display for Response {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Response {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         if self.success {
             write!(f, "OK")?;
             if let Some(id) = self.image_id {
@@ -135,8 +198,418 @@ impl std::fmt::Display for Response {
     }
 }
 
+/// Response from the terminal, borrowing its text from the input
+///
+/// Parsing does not allocate, which suits high-frequency acks such as those
+/// sent during animation streaming. Convert with [`ResponseRef::to_response`]
+/// when a [`Response`] is needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseRef<'a> {
+    /// Image ID (if applicable)
+    pub image_id: Option<ImageId>,
+    /// Image number (if applicable)
+    pub image_number: Option<u32>,
+    /// Placement ID (if applicable)
+    pub placement_id: Option<PlacementId>,
+    /// Whether the operation was successful
+    pub success: bool,
+    /// Error code (if failed)
+    pub code: Option<ErrorCode>,
+    /// Error as sent by the terminal, including the code (if failed)
+    pub error: Option<&'a str>,
+    /// Error message as sent by the terminal, without the code
+    pub message: Option<&'a str>,
+    /// The exact bytes the terminal sent, including the terminator
+    pub raw: &'a [u8],
+    /// Control data as sent by the terminal
+    control: &'a str,
+}
+
+impl<'a> ResponseRef<'a> {
+    /// Parse a response from the terminal without copying its text
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        // Expected format: <ESC>_Gi=<id>;OK<ESC>\ or <ESC>_Gi=<id>;ERROR:message<ESC>\
+        // Also: <ESC>_Gi=<id>,p=<placement_id>;OK<ESC>\
+        // And: <ESC>_Gi=<id>,I=<number>;OK<ESC>\
+        // Some terminals end the sequence with BEL instead of ESC \
+        let invalid = || Error::InvalidResponse(String::from_utf8_lossy(data).into_owned());
+
+        // Check for APC start; a cut-off start may still become a response
+        let header = b"\x1b_G";
+        if !data.starts_with(header) {
+            return Err(if header.starts_with(data) {
+                Error::IncompleteResponse
+            } else {
+                invalid()
+            });
+        }
+
+        // Find the terminator
+        let end_pos = data[3..]
+            .iter()
+            .enumerate()
+            .find(|&(i, &b)| b == 0x07 || (b == crate::ESC && data.get(i + 4) == Some(&b'\\')))
+            .map(|(i, _)| i + 3)
+            .ok_or(Error::IncompleteResponse)?;
+
+        // Find the semicolon separator
+        let semicolon_pos = data[..end_pos]
+            .iter()
+            .position(|&b| b == b';')
+            .ok_or_else(invalid)?;
+
+        // Parse control data (between G and ;)
+        let control = &data[3..semicolon_pos];
+        let control = core::str::from_utf8(control).map_err(Error::from)?;
+
+        // Parse the message (after semicolon until the terminator)
+        let message = &data[semicolon_pos + 1..end_pos];
+        let message_str = core::str::from_utf8(message).map_err(Error::from)?;
+
+        // Parse control fields
+        let mut response = ResponseRef {
+            image_id: None,
+            image_number: None,
+            placement_id: None,
+            success: message_str == "OK",
+            code: None,
+            error: None,
+            message: None,
+            raw: &data[..end_pos + if data[end_pos] == 0x07 { 1 } else { 2 }],
+            control,
+        };
+        for (key, value) in response.pairs() {
+            match key {
+                "i" => response.image_id = value.parse().ok(),
+                "I" => response.image_number = value.parse().ok(),
+                "p" => response.placement_id = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        // Split an error into its code and message
+        if !response.success {
+            response.code = Some(ErrorCode::from_message(message_str));
+            response.error = Some(message_str);
+            response.message = Some(
+                message_str
+                    .split_once(':')
+                    .map_or(message_str, |(_, text)| text),
+            );
+        }
+
+        Ok(response)
+    }
+
+    /// Control keys not recognized by this crate, in the order received
+    pub fn extra(&self) -> impl Iterator<Item = (char, &'a str)> + 'a {
+        self.pairs().filter_map(|(key, value)| {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !matches!(c, 'i' | 'I' | 'p') => Some((c, value)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Copy the response into an owned [`Response`]
+    pub fn to_response(&self) -> Response {
+        let error = match (self.code, self.error, self.message) {
+            (Some(code), Some(raw), Some(text)) if raw.contains(':') => {
+                Some(describe(code, text, raw))
+            }
+            (_, raw, _) => raw.map(str::to_string),
+        };
+        Response {
+            image_id: self.image_id,
+            image_number: self.image_number,
+            placement_id: self.placement_id,
+            success: self.success,
+            error,
+            code: self.code,
+            message: self.message.map(str::to_string),
+            extra: self
+                .extra()
+                .map(|(key, value)| (key, value.to_string()))
+                .collect(),
+            raw: self.raw.to_vec(),
+        }
+    }
+
+    /// Check if the response indicates success
+    pub fn is_ok(&self) -> bool {
+        self.success
+    }
+
+    /// Check if the response indicates an error
+    pub fn is_error(&self) -> bool {
+        !self.success
+    }
+
+    /// `key=value` pairs of the control data
+    fn pairs(&self) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.control
+            .split(',')
+            .filter_map(|part| part.split_once('='))
+    }
+}
+
+impl From<ResponseRef<'_>> for Response {
+    fn from(response: ResponseRef<'_>) -> Self {
+        response.to_response()
+    }
+}
+
+/// What a terminal said about an `a=q` support query
+///
+/// Pairs the format and medium that were queried with the terminal's
+/// verdict, so callers can tell an unsupported medium (`ENOTSUPPORTED`,
+/// `EBADF`) from a malformed query (`EINVAL`) and pick a fallback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SupportReport {
+    /// Pixel format that was queried
+    pub format: ImageFormat,
+    /// Transmission medium that was queried
+    pub medium: TransmissionMedium,
+    /// Whether the terminal answered `OK`
+    pub supported: bool,
+    /// Error code (if the query failed)
+    pub code: Option<ErrorCode>,
+    /// Error message as sent by the terminal (if the query failed)
+    pub message: Option<String>,
+}
+
+impl SupportReport {
+    /// Build a report from a query command and the terminal's reply to it
+    pub fn new(query: &Command, response: &Response) -> Self {
+        let builder = query.to_builder();
+        Self {
+            format: builder.effective_format(),
+            medium: builder.effective_medium(),
+            supported: response.success,
+            code: response.code,
+            message: response.message.clone(),
+        }
+    }
+
+    /// Whether the terminal rejected the medium or format itself, so another
+    /// medium or format may still work
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self.code,
+            Some(ErrorCode::NotSupported | ErrorCode::BadFile | ErrorCode::PermissionDenied)
+        )
+    }
+
+    /// Whether the terminal rejected the query as malformed, which says
+    /// nothing about the medium or format
+    pub fn is_invalid(&self) -> bool {
+        self.code == Some(ErrorCode::InvalidArgument)
+    }
+}
+
+/// Identifies a command tracked by a [`ResponseCorrelator`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CommandHandle(u64);
+
+/// A command waiting for its reply
+#[derive(Debug, Clone)]
+struct PendingReply {
+    handle: CommandHandle,
+    image_id: Option<ImageId>,
+    image_number: Option<u32>,
+    placement_id: Option<PlacementId>,
+}
+
+impl PendingReply {
+    fn matches(&self, response: &Response) -> bool {
+        // The terminal echoes I= for numbered images, together with the ID it
+        // assigned, so the number alone identifies the command
+        if let Some(number) = self.image_number {
+            return response.image_number == Some(number);
+        }
+        response.image_number.is_none()
+            && self.image_id == response.image_id
+            && self.placement_id == response.placement_id
+    }
+}
+
+/// Matches terminal responses back to the commands that caused them
+///
+/// Commands are matched on the `i`, `I` and `p` keys the terminal echoes.
+/// When several in-flight commands share the same keys, responses resolve
+/// them in the order they were tracked.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseCorrelator {
+    next: u64,
+    pending: Vec<PendingReply>,
+}
+
+impl ResponseCorrelator {
+    /// Create an empty correlator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an outgoing command
+    ///
+    /// Returns `None` if the terminal will not send an identifiable reply,
+    /// i.e. the command has no image ID or number, or uses `q=2`.
+    pub fn track(&mut self, command: &Command) -> Option<CommandHandle> {
+        let (image_id, image_number, placement_id) = command.reply_key()?;
+        let handle = CommandHandle(self.next);
+        self.next += 1;
+        self.pending.push(PendingReply {
+            handle,
+            image_id,
+            image_number,
+            placement_id,
+        });
+        Some(handle)
+    }
+
+    /// Match a response to the oldest tracked command it answers, and stop
+    /// tracking that command
+    pub fn resolve(&mut self, response: &Response) -> Option<CommandHandle> {
+        let index = self.pending.iter().position(|p| p.matches(response))?;
+        Some(self.pending.remove(index).handle)
+    }
+
+    /// Stop tracking a command, e.g. one sent with `q=1` that succeeded
+    pub fn forget(&mut self, handle: CommandHandle) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|p| p.handle != handle);
+        self.pending.len() != before
+    }
+
+    /// Number of commands still waiting for a reply
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether no commands are waiting for a reply
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Format the `error` text of a response: the description of a known code
+/// followed by the message, or the raw text for unknown codes
+fn describe(code: ErrorCode, message: &str, raw: &str) -> String {
+    match code.description() {
+        Some(description) => format!("{description}: {message}"),
+        None => raw.to_string(),
+    }
+}
+
+/// Longest incomplete response kept while waiting for its terminator
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Incremental parser for graphics responses read from the terminal
+///
+/// Feed it bytes as they are read; responses split across reads are
+/// reassembled, several responses in one read are all returned, and bytes
+/// outside a graphics response are discarded.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseParser {
+    buf: Vec<u8>,
+}
+
+impl ResponseParser {
+    /// Create an empty parser
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes and return every response completed by them
+    ///
+    /// Malformed responses are skipped, as are other terminal reports.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Response> {
+        self.buf.extend_from_slice(data);
+
+        let (responses, consumed) = scan(&self.buf);
+        self.buf.drain(..consumed);
+        if self.buf.len() > MAX_PENDING {
+            self.buf.clear();
+        }
+        responses
+    }
+
+    /// Check whether an incomplete response is buffered
+    pub fn is_pending(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Discard any buffered partial response
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+}
+
+/// Parse every complete graphics response in `data`
+///
+/// Walks the input escape sequence by escape sequence, so other reports
+/// (cursor positions, device attributes, XTVERSION, OSC replies) and plain
+/// bytes are skipped without being mistaken for responses. Returns the
+/// responses and the number of bytes consumed; everything after that is an
+/// incomplete sequence waiting for more input.
+fn scan(data: &[u8]) -> (Vec<Response>, usize) {
+    let mut responses = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        if data[pos] != crate::ESC {
+            pos += 1;
+            continue;
+        }
+        let Some(len) = sequence_len(&data[pos..]) else {
+            break;
+        };
+        let seq = &data[pos..pos + len];
+        if seq.starts_with(b"\x1b_G")
+            && let Ok(response) = Response::parse(seq)
+        {
+            responses.push(response);
+        }
+        pos += len;
+    }
+
+    (responses, pos)
+}
+
+/// Length of the escape sequence at the start of `data`, or `None` if it is
+/// not complete yet
+fn sequence_len(data: &[u8]) -> Option<usize> {
+    match *data.get(1)? {
+        // CSI: parameter and intermediate bytes, then a final byte
+        b'[' => data[2..]
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+            .map(|i| i + 3),
+        // APC, DCS, OSC, PM and SOS strings end with ST; OSC and APC also
+        // with BEL
+        kind @ (b'_' | b'P' | b']' | b'^' | b'X') => {
+            let body = &data[2..];
+            let st = body.windows(2).position(|w| w == crate::APC_END);
+            let bel = matches!(kind, b']' | b'_')
+                .then(|| body.iter().position(|&b| b == 0x07))
+                .flatten();
+            match (st, bel) {
+                (Some(st), Some(bel)) if bel < st => Some(bel + 3),
+                (Some(st), _) => Some(st + 4),
+                (None, Some(bel)) => Some(bel + 3),
+                (None, None) => None,
+            }
+        }
+        // Two-byte escape
+        _ => Some(2),
+    }
+}
+
 /// Common error codes returned by the terminal
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ErrorCode {
     /// Not found (ENOENT)
     NotFound,
@@ -150,28 +623,78 @@ pub enum ErrorCode {
     Cycle,
     /// Parent not found (ENOPARENT)
     NoParent,
+    /// Operation or medium not supported (ENOTSUPPORTED)
+    NotSupported,
+    /// Image or data too large (EFBIG)
+    TooLarge,
+    /// File could not be read (EBADF)
+    BadFile,
+    /// Resource busy (EBUSY)
+    Busy,
+    /// Not enough data for the declared size (ENODATA)
+    NoData,
+    /// PNG data could not be decoded (EBADPNG)
+    BadPng,
+    /// Storage quota exceeded (ENOSPC)
+    NoSpace,
+    /// Permission denied (EPERM)
+    PermissionDenied,
     /// Unknown error
     Unknown,
 }
 
+/// Known codes with their wire names and descriptions
+const ERROR_CODES: &[(ErrorCode, &str, &str)] = &[
+    (ErrorCode::NotFound, "ENOENT", "Not found"),
+    (ErrorCode::InvalidArgument, "EINVAL", "Invalid argument"),
+    (ErrorCode::IoError, "EIO", "IO error"),
+    (ErrorCode::TooDeep, "ETOODEEP", "Chain too deep"),
+    (ErrorCode::Cycle, "ECYCLE", "Cycle detected"),
+    (ErrorCode::NoParent, "ENOPARENT", "Parent not found"),
+    (ErrorCode::NotSupported, "ENOTSUPPORTED", "Not supported"),
+    (ErrorCode::TooLarge, "EFBIG", "Too large"),
+    (ErrorCode::BadFile, "EBADF", "Bad file"),
+    (ErrorCode::Busy, "EBUSY", "Busy"),
+    (ErrorCode::NoData, "ENODATA", "Insufficient data"),
+    (ErrorCode::BadPng, "EBADPNG", "Bad PNG data"),
+    (ErrorCode::NoSpace, "ENOSPC", "Out of storage"),
+    (ErrorCode::PermissionDenied, "EPERM", "Permission denied"),
+];
+
 impl ErrorCode {
+    /// The code as sent by the terminal, or `None` for [`ErrorCode::Unknown`]
+    pub fn name(self) -> Option<&'static str> {
+        ERROR_CODES
+            .iter()
+            .find(|(code, ..)| *code == self)
+            .map(|(_, name, _)| *name)
+    }
+
+    /// Human-readable description of the code
+    fn description(self) -> Option<&'static str> {
+        ERROR_CODES
+            .iter()
+            .find(|(code, ..)| *code == self)
+            .map(|(.., description)| *description)
+    }
+
+    /// Look up a code by the name the terminal sends, e.g. `EFBIG`
+    pub fn from_name(name: &str) -> Self {
+        ERROR_CODES
+            .iter()
+            .find(|(_, known, _)| *known == name)
+            .map_or(Self::Unknown, |(code, ..)| *code)
+    }
+
     /// Parse an error code from a response
+    ///
+    /// The code is the leading run of uppercase letters, e.g. `EBADF` in
+    /// `EBADF:Failed to open file`.
     pub fn from_message(msg: &str) -> Self {
-        if msg.starts_with("ENOENT") {
-            Self::NotFound
-        } else if msg.starts_with("EINVAL") {
-            Self::InvalidArgument
-        } else if msg.starts_with("EIO") {
-            Self::IoError
-        } else if msg.starts_with("ETOODEEP") {
-            Self::TooDeep
-        } else if msg.starts_with("ECYCLE") {
-            Self::Cycle
-        } else if msg.starts_with("ENOPARENT") {
-            Self::NoParent
-        } else {
-            Self::Unknown
-        }
+        let end = msg
+            .find(|c: char| !c.is_ascii_uppercase())
+            .unwrap_or(msg.len());
+        Self::from_name(&msg[..end])
     }
 }
 
@@ -184,7 +707,7 @@ mod tests {
         let data = b"\x1b_Gi=42;OK\x1b\\";
         let resp = Response::parse(data).unwrap();
         assert!(resp.is_ok());
-        assert_eq!(resp.image_id, Some(42));
+        assert_eq!(resp.image_id.map(ImageId::get), Some(42));
     }
 
     #[test]
@@ -192,8 +715,8 @@ mod tests {
         let data = b"\x1b_Gi=42,p=7;OK\x1b\\";
         let resp = Response::parse(data).unwrap();
         assert!(resp.is_ok());
-        assert_eq!(resp.image_id, Some(42));
-        assert_eq!(resp.placement_id, Some(7));
+        assert_eq!(resp.image_id.map(ImageId::get), Some(42));
+        assert_eq!(resp.placement_id.map(PlacementId::get), Some(7));
     }
 
     #[test]
@@ -201,7 +724,9 @@ mod tests {
         let data = b"\x1b_Gi=42;ENOENT:Image not found\x1b\\";
         let resp = Response::parse(data).unwrap();
         assert!(resp.is_error());
-        assert_eq!(resp.image_id, Some(42));
+        assert_eq!(resp.image_id.map(ImageId::get), Some(42));
+        assert_eq!(resp.error_code(), Some(ErrorCode::NotFound));
+        assert_eq!(resp.message.as_deref(), Some("Image not found"));
         assert!(resp.error.unwrap().contains("Not found"));
     }
 
@@ -210,7 +735,180 @@ mod tests {
         let data = b"\x1b_Gi=99,I=13;OK\x1b\\";
         let resp = Response::parse(data).unwrap();
         assert!(resp.is_ok());
-        assert_eq!(resp.image_id, Some(99));
+        assert_eq!(resp.image_id.map(ImageId::get), Some(99));
         assert_eq!(resp.image_number, Some(13));
     }
+
+    #[test]
+    fn test_response_parser_feed() {
+        let mut parser = ResponseParser::new();
+        assert!(parser.feed(b"noise\x1b[12;3R\x1b_Gi=1;O").is_empty());
+        assert!(parser.is_pending());
+
+        let responses = parser.feed(b"K\x1b\\\x1b_Gi=2;EINVAL:bad\x1b\\\x1b");
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].image_id.map(ImageId::get), Some(1));
+        assert!(responses[1].is_error());
+        assert!(parser.is_pending());
+
+        let responses = parser.feed(b"_Gi=3;OK\x1b\\");
+        assert_eq!(responses[0].image_id.map(ImageId::get), Some(3));
+        assert!(!parser.is_pending());
+    }
+
+    #[test]
+    fn test_parse_all_skips_other_reports() {
+        let data = b"\x1b[12;3R\x1bP>|kitty(0.35)\x1b\\\x1b]11;rgb:0/0/0\x07\
+            \x1b_Gi=1;OK\x1b\\\x1b[?62;4c\x1b_Gi=2;ENOENT:x\x1b\\\x1b_Gi=3;O";
+        let (responses, rest) = Response::parse_all(data);
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].is_ok());
+        assert!(responses[1].is_error());
+        assert_eq!(rest, b"\x1b_Gi=3;O");
+
+        // A graphics-looking string inside another report is not a response
+        let (responses, rest) = Response::parse_all(b"\x1bPx\x1b_Gi=1;OK\x1b\\");
+        assert!(responses.is_empty());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_response_emitters_round_trip() {
+        let ok = Response::ok(ImageId::new(7).unwrap()).placement(PlacementId::new(2).unwrap());
+        let seq = ok.to_escape_sequence();
+        assert_eq!(seq, "\x1b_Gi=7,p=2;OK\x1b\\");
+        let parsed = Response::parse(seq.as_bytes()).unwrap();
+        assert_eq!(parsed.raw(), seq.as_bytes());
+        assert_eq!(parsed, ok);
+
+        let err = Response::error(ErrorCode::InvalidArgument, "bad width")
+            .image(ImageId::new(7).unwrap())
+            .number(3);
+        let seq = err.to_escape_sequence();
+        assert_eq!(seq, "\x1b_Gi=7,I=3;EINVAL:bad width\x1b\\");
+        let parsed = Response::parse(seq.as_bytes()).unwrap();
+        assert_eq!(parsed, err);
+    }
+
+    #[test]
+    fn test_response_correlator() {
+        let id = |n| ImageId::new(n).unwrap();
+        let mut correlator = ResponseCorrelator::new();
+        let first = correlator
+            .track(&Command::builder().image_id(id(1)).build())
+            .unwrap();
+        let second = correlator
+            .track(&Command::builder().image_id(id(2)).build())
+            .unwrap();
+        let numbered = correlator
+            .track(&Command::builder().image_number(9).build())
+            .unwrap();
+        assert!(correlator.track(&Command::builder().build()).is_none());
+
+        let reply = |s: &str| Response::parse(s.as_bytes()).unwrap();
+        assert_eq!(
+            correlator.resolve(&reply("\x1b_Gi=2;OK\x1b\\")),
+            Some(second)
+        );
+        assert_eq!(
+            correlator.resolve(&reply("\x1b_Gi=31,I=9;OK\x1b\\")),
+            Some(numbered)
+        );
+        assert_eq!(correlator.resolve(&reply("\x1b_Gi=2;OK\x1b\\")), None);
+        assert_eq!(
+            correlator.resolve(&reply("\x1b_Gi=1;ENOENT:gone\x1b\\")),
+            Some(first)
+        );
+        assert!(correlator.is_empty());
+    }
+
+    #[test]
+    fn test_response_keeps_unknown_keys() {
+        let data = b"\x1b_Gi=4,X=12,v=ext,long=1;OK\x1b\\";
+        let response = Response::parse(data).unwrap();
+        assert_eq!(
+            response.extra,
+            [('X', String::from("12")), ('v', String::from("ext"))]
+        );
+        assert_eq!(
+            response.to_escape_sequence(),
+            "\x1b_Gi=4,X=12,v=ext;OK\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_parse_ref_borrows() {
+        let data = b"\x1b_Gi=5,p=2,X=1;EINVAL:bad data\x1b\\";
+        let response = Response::parse_ref(data).unwrap();
+        assert!(response.is_error());
+        assert_eq!(response.image_id.map(ImageId::get), Some(5));
+        assert_eq!(response.code, Some(ErrorCode::InvalidArgument));
+        assert_eq!(response.error, Some("EINVAL:bad data"));
+        assert_eq!(response.message, Some("bad data"));
+        assert_eq!(response.extra().collect::<Vec<_>>(), [('X', "1")]);
+        assert_eq!(response.to_response(), Response::parse(data).unwrap());
+        assert_eq!(Response::from(response), Response::parse(data).unwrap());
+    }
+
+    #[test]
+    fn test_extended_error_codes() {
+        assert_eq!(
+            ErrorCode::from_message("EFBIG:too big"),
+            ErrorCode::TooLarge
+        );
+        assert_eq!(ErrorCode::from_message("EBADF:no file"), ErrorCode::BadFile);
+        assert_eq!(
+            ErrorCode::from_message("EBADPNG:corrupt"),
+            ErrorCode::BadPng
+        );
+        assert_eq!(
+            ErrorCode::from_message("ENOTSUPPORTED"),
+            ErrorCode::NotSupported
+        );
+        assert_eq!(ErrorCode::from_message("EWHATEVER:x"), ErrorCode::Unknown);
+
+        let resp = Response::parse(b"\x1b_Gi=3;EBUSY:try later\x1b\\").unwrap();
+        assert_eq!(resp.error_code(), Some(ErrorCode::Busy));
+        assert_eq!(resp.error.as_deref(), Some("Busy: try later"));
+    }
+
+    #[test]
+    fn test_bel_terminated_and_truncated() {
+        let resp = Response::parse(b"\x1b_Gi=8;ENOENT:gone\x07trailing").unwrap();
+        assert_eq!(resp.raw(), b"\x1b_Gi=8;ENOENT:gone\x07");
+        assert_eq!(resp.image_id.map(ImageId::get), Some(8));
+        assert_eq!(resp.message.as_deref(), Some("gone"));
+
+        for cut in [&b"\x1b_"[..], b"\x1b_Gi=8;O", b"\x1b_Gi=8;OK\x1b"] {
+            assert!(matches!(
+                Response::parse(cut),
+                Err(Error::IncompleteResponse)
+            ));
+        }
+        assert!(matches!(
+            Response::parse(b"\x1b[?62c"),
+            Err(Error::InvalidResponse(_))
+        ));
+
+        let (responses, rest) = Response::parse_all(b"\x1b_Gi=1;OK\x07\x1b_Gi=2;OK");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(rest, b"\x1b_Gi=2;OK");
+    }
+
+    #[test]
+    fn test_support_report() {
+        let query = Command::builder()
+            .action(crate::types::Action::Query)
+            .image_id(ImageId::new(31).unwrap())
+            .format(ImageFormat::Rgb)
+            .medium(TransmissionMedium::SharedMemory)
+            .build();
+        let reply = Response::parse(b"\x1b_Gi=31;ENOTSUPPORTED:no shm\x1b\\").unwrap();
+        let report = SupportReport::new(&query, &reply);
+        assert!(!report.supported);
+        assert_eq!(report.format, ImageFormat::Rgb);
+        assert_eq!(report.medium, TransmissionMedium::SharedMemory);
+        assert!(report.is_unsupported());
+        assert!(!report.is_invalid());
+    }
 }
@@ -0,0 +1,135 @@
+//! Scroll regions and whether a placement will scroll out of view
+//!
+//! Images move with the text they are placed in, so an image placed near
+//! the bottom of the scroll region scrolls the content above it, and later
+//! output scrolls the image itself. Pager-style applications can use
+//! [`ScrollRegion`] to decide between a normal placement and placing the
+//! image again after scrolling.
+
+use crate::error::{Error, Result};
+use alloc::format;
+use alloc::string::String;
+
+/// The lines that scroll, set with DECSTBM (`CSI <top> ; <bottom> r`)
+///
+/// Rows are 1-based and inclusive, as in the escape sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScrollRegion {
+    /// First row of the region
+    pub top: u16,
+    /// Last row of the region
+    pub bottom: u16,
+}
+
+impl ScrollRegion {
+    /// Escape sequence resetting the region to the whole screen
+    pub const RESET_SEQUENCE: &'static str = "\x1b[r";
+
+    /// Escape sequence asking the terminal for the region (DECRQSS)
+    pub const QUERY_SEQUENCE: &'static str = "\x1bP$qr\x1b\\";
+
+    /// Create a region, failing unless `1 <= top < bottom`
+    pub fn new(top: u16, bottom: u16) -> Result<Self> {
+        if top == 0 || top >= bottom {
+            return Err(Error::protocol(format!(
+                "invalid scroll region: {top}..={bottom}"
+            )));
+        }
+        Ok(Self { top, bottom })
+    }
+
+    /// The whole screen, which is the default region
+    ///
+    /// Fails like [`new`](Self::new) for screens less than two rows tall.
+    pub fn full(rows: u16) -> Result<Self> {
+        Self::new(1, rows)
+    }
+
+    /// Number of rows in the region
+    pub fn height(&self) -> u16 {
+        self.bottom - self.top + 1
+    }
+
+    /// Check whether `row` lies within the region
+    pub fn contains(&self, row: u16) -> bool {
+        (self.top..=self.bottom).contains(&row)
+    }
+
+    /// How many lines the region scrolls when an image `rows` tall is placed
+    /// with the cursor at `row`
+    ///
+    /// The cursor moves to the last row of the image, so everything below
+    /// the region's bottom margin is scrolled in. Placements starting
+    /// outside the region never scroll it.
+    pub fn scroll_for(&self, row: u16, rows: u16) -> u16 {
+        if !self.contains(row) || rows == 0 {
+            return 0;
+        }
+        (row.saturating_add(rows - 1)).saturating_sub(self.bottom)
+    }
+
+    /// Check whether part of an image `rows` tall placed at `row` will be
+    /// scrolled out through the top of the region
+    ///
+    /// This happens when the image is taller than the region, since every
+    /// line scrolled in beyond that pushes its top rows out of view.
+    pub fn scrolls_out_of_view(&self, row: u16, rows: u16) -> bool {
+        row.saturating_sub(self.scroll_for(row, rows)) < self.top
+    }
+
+    /// Escape sequence setting this region (DECSTBM)
+    ///
+    /// Like every DECSTBM, it also moves the cursor to the top left corner.
+    pub fn set_sequence(&self) -> String {
+        format!("\x1b[{};{}r", self.top, self.bottom)
+    }
+
+    /// Parse the DECRQSS reply `DCS 1 $ r <top> ; <bottom> r ST`
+    ///
+    /// Returns `None` if `reply` holds no valid report, e.g. when the
+    /// terminal rejected the request with `DCS 0 $ r`.
+    pub fn parse_report(reply: &[u8]) -> Option<Self> {
+        let start = reply.windows(5).position(|w| w == b"\x1bP1$r")?;
+        let params = &reply[start + 5..];
+        let end = params.iter().position(|&b| b == b'r')?;
+        let (top, bottom) = core::str::from_utf8(&params[..end]).ok()?.split_once(';')?;
+        Self::new(top.parse().ok()?, bottom.parse().ok()?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scroll_for_placement() {
+        let region = ScrollRegion::new(1, 24).unwrap();
+        assert_eq!(region.scroll_for(20, 5), 0);
+        assert_eq!(region.scroll_for(22, 5), 2);
+        assert!(!region.scrolls_out_of_view(22, 5));
+        assert!(region.scrolls_out_of_view(10, 30));
+
+        // Rows outside a pager's region never scroll it
+        let pager = ScrollRegion::new(2, 23).unwrap();
+        assert_eq!(pager.scroll_for(24, 3), 0);
+        assert_eq!(pager.height(), 22);
+    }
+
+    #[test]
+    fn test_region_sequences() {
+        let region = ScrollRegion::new(3, 20).unwrap();
+        assert_eq!(region.set_sequence(), "\x1b[3;20r");
+        assert_eq!(
+            ScrollRegion::parse_report(b"\x1bP1$r3;20r\x1b\\"),
+            Some(region)
+        );
+        assert_eq!(ScrollRegion::parse_report(b"\x1bP0$r\x1b\\"), None);
+        assert!(ScrollRegion::new(5, 5).is_err());
+        assert_eq!(
+            ScrollRegion::full(24).unwrap(),
+            ScrollRegion::new(1, 24).unwrap()
+        );
+        assert!(ScrollRegion::full(1).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! POSIX shared memory transmission (t=s)
+//!
+//! Pixel data is written into a `shm_open` object and only its name is sent
+//! to the terminal, which avoids the base64 overhead entirely for large local
+//! images. The terminal unlinks the object once it has read it; the
+//! [`SharedMemory`] guard unlinks it as well if that never happens.
+
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::response::Response;
+use crate::types::{Quiet, TransmissionMedium};
+use std::ffi::CString;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Counter making object names unique within this process
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// A shared memory object holding image data for the terminal to read
+#[derive(Debug)]
+pub struct SharedMemory {
+    name: String,
+    len: usize,
+    linked: bool,
+}
+
+impl SharedMemory {
+    /// Create a new shared memory object and copy `data` into it
+    pub fn create(data: &[u8]) -> Result<Self> {
+        if data.is_empty() {
+            return Err(Error::protocol(
+                "cannot transmit empty data via shared memory",
+            ));
+        }
+
+        let name = format!(
+            "/kitty-graphics-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let c_name = CString::new(name.as_str()).map_err(|_| Error::InvalidPath(name.clone()))?;
+
+        let fd = unsafe {
+            libc::shm_open(
+                c_name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o600 as libc::c_uint,
+            )
+        };
+        if fd == -1 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+
+        // From here on the guard unlinks the object if anything fails
+        let shm = Self {
+            name,
+            len: data.len(),
+            linked: true,
+        };
+        let result = write_object(fd, data);
+        unsafe { libc::close(fd) };
+        result?;
+
+        Ok(shm)
+    }
+
+    /// Name of the shared memory object
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size of the data in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the object holds no data
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Point a command at this object (t=s with its name and size)
+    pub fn configure(&self, builder: CommandBuilder) -> CommandBuilder {
+        builder
+            .medium(TransmissionMedium::SharedMemory)
+            .path(self.name.clone())
+            .data_range(self.len, 0)
+    }
+
+    /// Release the object once the terminal has replied to the command
+    ///
+    /// The terminal unlinks the object after reading it, so this only
+    /// unlinks it if it still exists. An error response is returned as
+    /// [`Error::TerminalError`].
+    pub fn acknowledge(mut self, response: &Response) -> Result<()> {
+        self.unlink()?;
+        match response.error_message() {
+            Some(message) if response.is_error() => Err(Error::terminal(message)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Unlink the object, ignoring an object the terminal already removed
+    fn unlink(&mut self) -> Result<()> {
+        if !self.linked {
+            return Ok(());
+        }
+        self.linked = false;
+
+        let c_name =
+            CString::new(self.name.as_str()).map_err(|_| Error::InvalidPath(self.name.clone()))?;
+        if unsafe { libc::shm_unlink(c_name.as_ptr()) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(Error::Io(err));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        let _ = self.unlink();
+    }
+}
+
+/// Size the object and copy the data into it through a shared mapping
+///
+/// `write(2)` is not supported on shared memory objects on every platform,
+/// so the data is copied through `mmap` instead.
+fn write_object(fd: libc::c_int, data: &[u8]) -> Result<()> {
+    let len = libc::off_t::try_from(data.len())
+        .map_err(|_| Error::Io(io::Error::from(io::ErrorKind::FileTooLarge)))?;
+    if unsafe { libc::ftruncate(fd, len) } == -1 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            data.len(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.cast::<u8>(), data.len());
+        libc::munmap(ptr, data.len());
+    }
+    Ok(())
+}
+
+/// Transmit `data` through shared memory and wait for the terminal's reply
+///
+/// `builder` supplies the action, format, dimensions and image ID; the
+/// terminal only replies to commands carrying an image ID or number. The
+/// object is unlinked once the reply arrives, or after `timeout`.
+///
+/// Without a controlling terminal no reply can be read, so the command is
+/// written and `None` is returned; the object is then left for the terminal
+/// to unlink after reading it.
+pub fn transmit(
+    builder: CommandBuilder,
+    data: &[u8],
+    timeout: Duration,
+) -> Result<Option<Response>> {
+    let shm = SharedMemory::create(data)?;
+    let cmd: Command = shm.configure(builder).quiet(Quiet::Verbose).build();
+    let seq = cmd.serialize_with_path()?;
+
+    match crate::medium::send_and_wait(seq.as_bytes(), timeout)? {
+        Some(response) => {
+            shm.acknowledge(&response)?;
+            Ok(Some(response))
+        }
+        None => {
+            std::mem::forget(shm);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Action, ImageFormat};
+
+    #[test]
+    fn test_shared_memory_lifecycle() {
+        let shm = SharedMemory::create(&[1, 2, 3, 4]).unwrap();
+        let name = shm.name().to_string();
+
+        let seq = shm
+            .configure(
+                Command::builder()
+                    .action(Action::Transmit)
+                    .format(ImageFormat::Rgba)
+                    .dimensions(1, 1),
+            )
+            .build()
+            .serialize_with_path()
+            .unwrap();
+        assert!(seq.contains("t=s"));
+        assert!(seq.contains("S=4,O=0"));
+
+        let ok = Response::parse(b"\x1b_Gi=1;OK\x1b\\").unwrap();
+        shm.acknowledge(&ok).unwrap();
+
+        let c_name = CString::new(name).unwrap();
+        let fd = unsafe { libc::shm_open(c_name.as_ptr(), libc::O_RDONLY, 0) };
+        assert_eq!(fd, -1);
+    }
+}
@@ -0,0 +1,55 @@
+//! `futures::Stream` adapter for chunked output (requires the `futures` feature)
+
+use crate::command::ChunkedSerializer;
+use bytes::Bytes;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Stream of chunked escape sequences, created by [`ChunkedSerializer::into_stream`]
+pub struct ChunkStream<'a> {
+    inner: ChunkedSerializer<'a>,
+}
+
+impl<'a> ChunkedSerializer<'a> {
+    /// Turn the serializer into a stream of escape sequences
+    ///
+    /// Each chunk is encoded only when the stream is polled, so piping it
+    /// into an async sink applies backpressure to the encoding.
+    pub fn into_stream(self) -> impl Stream<Item = Bytes> + 'a {
+        ChunkStream { inner: self }
+    }
+}
+
+impl Stream for ChunkStream<'_> {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        Poll::Ready(self.inner.next_chunk().map(Bytes::copy_from_slice))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.inner.remaining_chunks();
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::Command;
+    use crate::types::ImageFormat;
+
+    #[test]
+    fn test_into_stream_matches_iterator() {
+        let data = vec![9u8; 10_000];
+        let cmd = Command::builder().format(ImageFormat::Png).build();
+        let expected: Vec<String> = cmd.serialize_chunked(&data).unwrap().collect();
+
+        let stream = cmd.serialize_chunked(&data).unwrap().into_stream();
+        let chunks: Vec<_> = futures::executor::block_on_stream(Box::pin(stream)).collect();
+        assert_eq!(chunks.len(), expected.len());
+        for (c, e) in chunks.iter().zip(&expected) {
+            assert_eq!(c.as_ref(), e.as_bytes());
+        }
+    }
+}
@@ -1,10 +1,19 @@
 //! Terminal utilities for the Kitty graphics protocol
 
+use crate::capabilities::TerminalCapabilities;
+use crate::command::Command;
 use crate::error::{Error, Result};
+use crate::response::{Response, ResponseCorrelator};
+use crate::scroll::ScrollRegion;
+use crate::types::{Action, ImageFormat, ImageId, Quiet};
 use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 /// Terminal window size information
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WindowSize {
     /// Number of rows (lines)
     pub rows: u16,
@@ -19,19 +28,20 @@ pub struct WindowSize {
 impl WindowSize {
     /// Get the cell width in pixels
     pub fn cell_width(&self) -> u16 {
-        if self.cols > 0 {
-            self.width / self.cols
-        } else {
-            0
-        }
+        self.width.checked_div(self.cols).unwrap_or(0)
     }
 
     /// Get the cell height in pixels
     pub fn cell_height(&self) -> u16 {
-        if self.rows > 0 {
-            self.height / self.rows
-        } else {
-            0
+        self.height.checked_div(self.rows).unwrap_or(0)
+    }
+
+    /// Derive the pixel size from the size of one cell
+    pub fn with_cell_size(self, cell_width: u16, cell_height: u16) -> Self {
+        Self {
+            width: self.cols.saturating_mul(cell_width),
+            height: self.rows.saturating_mul(cell_height),
+            ..self
         }
     }
 
@@ -55,12 +65,162 @@ impl WindowSize {
 mod unix {
     use super::*;
     use libc::{STDOUT_FILENO, TIOCGWINSZ, ioctl, winsize};
+    use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+
+    impl RawModeGuard {
+        /// Switch stdin to raw mode; fails if stdin is not a terminal
+        pub fn new() -> Result<Self> {
+            Self::from_fd(io::stdin().as_raw_fd())
+        }
+
+        /// Switch the terminal behind `fd` to raw mode
+        pub fn from_fd(fd: RawFd) -> Result<Self> {
+            let mut original: libc::termios = unsafe { std::mem::zeroed() };
+            if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+
+            let mut raw = original;
+            unsafe { libc::cfmakeraw(&mut raw) };
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+            Ok(Self { fd, original })
+        }
+    }
+
+    impl Drop for RawModeGuard {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.original) };
+        }
+    }
 
     /// Get the terminal window size using TIOCGWINSZ ioctl
+    ///
+    /// Falls back to the controlling terminal when stdout is redirected.
     pub fn get_window_size() -> Result<WindowSize> {
+        window_size_of(STDOUT_FILENO).or_else(|err| {
+            let tty = Tty::open().map_err(|_| err)?;
+            tty.window_size()
+        })
+    }
+
+    impl Tty {
+        /// Window size of this terminal
+        pub(crate) fn window_size(&self) -> Result<WindowSize> {
+            window_size_of(self.output.as_raw_fd())
+        }
+
+        /// Unix implementation of [`Tty::exchange`], waiting with poll
+        pub(crate) fn exchange(
+            &mut self,
+            seq: &[u8],
+            timeout: Duration,
+            on_read: impl FnMut(&[u8]) -> bool,
+        ) -> Result<bool> {
+            let fd = self.input.as_raw_fd();
+
+            // Set terminal to raw mode until the guard is dropped
+            let Ok(_guard) = RawModeGuard::from_fd(fd) else {
+                return Ok(false);
+            };
+
+            let _ = self.output.write_all(seq);
+            let _ = self.output.flush();
+
+            read_until(fd, timeout, on_read)?;
+            Ok(true)
+        }
+    }
+
+    /// Read from `fd` and pass the bytes to `on_read` until it returns
+    /// `true`, the input ends, or `timeout` elapses
+    ///
+    /// Returns whether `on_read` finished. Reads interrupted by a signal are
+    /// retried without extending the deadline.
+    pub(super) fn read_until(
+        fd: RawFd,
+        timeout: Duration,
+        mut on_read: impl FnMut(&[u8]) -> bool,
+    ) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 256];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+
+            // Round up so a sub-millisecond remainder does not busy-loop
+            let wait_ms = remaining.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+            let mut pfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut pfd, 1, wait_ms) };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Io(err));
+            }
+            if ready == 0 {
+                continue;
+            }
+            if pfd.revents & libc::POLLIN == 0 {
+                // Hang-up or error without data left to read
+                return Ok(false);
+            }
+
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                ) {
+                    continue;
+                }
+                return Err(Error::Io(err));
+            }
+            if n == 0 {
+                return Ok(false);
+            }
+            if on_read(&buf[..n as usize]) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Read a terminal reply from `fd`, e.g. `&io::stdin()`, up to its
+    /// string terminator
+    ///
+    /// Reading stops after `ESC \` or BEL; bytes that arrived in the same
+    /// read after the terminator are included. Partial reads are collected
+    /// and signals do not extend the overall `timeout`. The terminal should
+    /// already be in raw mode, e.g. through a [`RawModeGuard`].
+    ///
+    /// Fails with [`Error::Timeout`] if no terminator arrives before the
+    /// deadline or the input ends.
+    pub fn read_until_st(fd: impl AsFd, timeout: Duration) -> Result<Vec<u8>> {
+        let mut response = Vec::new();
+        let complete = read_until(fd.as_fd().as_raw_fd(), timeout, |bytes| {
+            response.extend_from_slice(bytes);
+            response.windows(2).any(|w| *w == [0x1b, b'\\']) || response.contains(&0x07)
+        })?;
+        if complete {
+            Ok(response)
+        } else {
+            Err(Error::Timeout)
+        }
+    }
+
+    fn window_size_of(fd: RawFd) -> Result<WindowSize> {
         unsafe {
             let mut ws: winsize = std::mem::zeroed();
-            let result = ioctl(STDOUT_FILENO, TIOCGWINSZ, &mut ws);
+            let result = ioctl(fd, TIOCGWINSZ, &mut ws);
 
             if result == -1 {
                 return Err(Error::Io(io::Error::last_os_error()));
@@ -76,88 +236,715 @@ mod unix {
     }
 }
 
-#[cfg(not(unix))]
+/// Keeps the terminal in raw mode and restores its settings on drop
+///
+/// Dropping the guard restores the original settings even when a panic
+/// unwinds through the code that holds it, so a failed query never leaves
+/// the terminal in raw mode.
+#[derive(Debug)]
+pub struct RawModeGuard {
+    #[cfg(unix)]
+    fd: std::os::unix::io::RawFd,
+    #[cfg(unix)]
+    original: libc::termios,
+    #[cfg(windows)]
+    handle: windows_sys::Win32::Foundation::HANDLE,
+    #[cfg(windows)]
+    mode: u32,
+}
+
+/// The controlling terminal, used for protocol queries
+///
+/// Opening `/dev/tty` (`CONIN$`/`CONOUT$` on Windows) reaches the terminal
+/// even when stdin or stdout is redirected, e.g. `myapp | tee log`.
+#[derive(Debug)]
+pub(crate) struct Tty {
+    pub(crate) input: std::fs::File,
+    pub(crate) output: std::fs::File,
+}
+
+impl Tty {
+    /// Open the controlling terminal
+    #[cfg(unix)]
+    pub(crate) fn open() -> io::Result<Self> {
+        let output = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")?;
+        Ok(Self {
+            input: output.try_clone()?,
+            output,
+        })
+    }
+
+    /// Open the console input and output buffers
+    #[cfg(windows)]
+    pub(crate) fn open() -> io::Result<Self> {
+        let open = |name| std::fs::File::options().read(true).write(true).open(name);
+        Ok(Self {
+            input: open("CONIN$")?,
+            output: open("CONOUT$")?,
+        })
+    }
+
+    /// There is no controlling terminal on this platform
+    #[cfg(not(any(unix, windows)))]
+    pub(crate) fn open() -> io::Result<Self> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // `exchange(&mut self, seq, timeout, on_read) -> Result<bool>` is
+    // implemented per platform: it writes `seq` in raw mode and passes
+    // everything read back to `on_read` until it returns `true`, the
+    // terminal closes, or `timeout` elapses. It returns `false` without
+    // sending anything when the terminal cannot be put into raw mode.
+}
+
+/// A reader and writer a [`Terminal`] can run over
+///
+/// Implemented for every `Read + Write + Send` type, e.g. a PTY pair, a
+/// serial port or a test double.
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+/// What a [`Terminal`] talks to
+enum Io {
+    /// A terminal device, switched to raw mode while waiting for replies
+    Tty(Tty),
+    /// Any other transport, used as is
+    Stream(Box<dyn Transport>),
+}
+
+impl core::fmt::Debug for Io {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Tty(tty) => f.debug_tuple("Tty").field(tty).finish(),
+            Self::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+impl Io {
+    /// Write `seq` and pass everything read back to `on_read` until it
+    /// returns `true`, the input ends, or `timeout` elapses
+    ///
+    /// Returns `false` without sending anything when a tty cannot be put into
+    /// raw mode.
+    fn exchange(
+        &mut self,
+        seq: &[u8],
+        timeout: Duration,
+        mut on_read: impl FnMut(&[u8]) -> bool,
+    ) -> Result<bool> {
+        let stream = match self {
+            Self::Tty(tty) => {
+                let _lock = lock_queries();
+                return tty.exchange(seq, timeout, on_read);
+            }
+            Self::Stream(stream) => stream,
+        };
+        stream.write_all(seq)?;
+        stream.flush()?;
+
+        // The deadline can only be checked between reads, so a transport
+        // that blocks forever needs a read timeout of its own
+        let mut buf = [0u8; 256];
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) if on_read(&buf[..n]) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Like `exchange`, but failing when nothing could be sent
+    fn exchange_or_fail(
+        &mut self,
+        seq: &[u8],
+        timeout: Duration,
+        on_read: impl FnMut(&[u8]) -> bool,
+    ) -> Result<()> {
+        if self.exchange(seq, timeout, on_read)? {
+            Ok(())
+        } else {
+            Err(Error::terminal("not a terminal"))
+        }
+    }
+
+    /// Send an escape sequence and collect the reply up to the first string
+    /// terminator (`ESC \` or BEL)
+    fn query(&mut self, seq: &[u8], timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let mut response = Vec::new();
+        let sent = self.exchange(seq, timeout, |bytes| {
+            response.extend_from_slice(bytes);
+            response.windows(2).any(|w| *w == [0x1b, b'\\']) || response.contains(&0x07)
+        })?;
+        Ok(sent.then_some(response))
+    }
+
+    /// Window size as reported by the OS, only known for a tty
+    fn window_size(&self) -> Result<WindowSize> {
+        match self {
+            Self::Tty(tty) => tty.window_size(),
+            Self::Stream(_) => Err(Error::terminal("window size is only known for a tty")),
+        }
+    }
+}
+
+impl Write for Io {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tty(tty) => tty.output.write(buf),
+            Self::Stream(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tty(tty) => tty.output.flush(),
+            Self::Stream(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Write `seq` to the controlling terminal in raw mode and pass everything
+/// read back to `on_read` until it returns `true`, the terminal closes, or
+/// `timeout` elapses
+///
+/// Returns `false` without sending anything when there is no controlling
+/// terminal.
+fn exchange(seq: &[u8], timeout: Duration, on_read: impl FnMut(&[u8]) -> bool) -> Result<bool> {
+    // Talk to the controlling terminal, which works even if stdio is redirected
+    match Tty::open() {
+        Ok(tty) => Io::Tty(tty).exchange(seq, timeout, on_read),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Serializes raw-mode query transactions across the process
+///
+/// Queries from different threads would otherwise interleave their escape
+/// sequences and read each other's replies, and one thread could restore the
+/// terminal settings while another still waits in raw mode.
+static QUERY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Hold the query lock for one transaction
+fn lock_queries() -> MutexGuard<'static, ()> {
+    QUERY_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+    use std::os::windows::io::{AsRawHandle, RawHandle};
+    use windows_sys::Win32::Foundation::{HANDLE, INVALID_HANDLE_VALUE, WAIT_OBJECT_0};
+    use windows_sys::Win32::Storage::FileSystem::ReadFile;
+    use windows_sys::Win32::System::Console::{
+        CONSOLE_SCREEN_BUFFER_INFO, ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT,
+        ENABLE_VIRTUAL_TERMINAL_INPUT, ENABLE_VIRTUAL_TERMINAL_PROCESSING, GetConsoleMode,
+        GetConsoleScreenBufferInfo, GetStdHandle, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE,
+        SetConsoleMode,
+    };
+    use windows_sys::Win32::System::Threading::WaitForSingleObject;
+
+    /// The console mode of a handle, or `None` if it is not a console
+    fn console_mode(handle: HANDLE) -> Option<u32> {
+        if handle.is_null() || handle == INVALID_HANDLE_VALUE {
+            return None;
+        }
+        let mut mode = 0;
+        (unsafe { GetConsoleMode(handle, &mut mode) } != 0).then_some(mode)
+    }
+
+    /// Get the console window size using GetConsoleScreenBufferInfo
+    ///
+    /// Falls back to the console output buffer when stdout is redirected.
+    /// The console API has no pixel sizes, so `width` and `height` are 0;
+    /// [`effective_window_size`](super::effective_window_size) queries them
+    /// from the terminal in VT input mode.
+    pub fn get_window_size() -> Result<WindowSize> {
+        window_size_of(unsafe { GetStdHandle(STD_OUTPUT_HANDLE) }).or_else(|err| {
+            let tty = Tty::open().map_err(|_| err)?;
+            tty.window_size()
+        })
+    }
+
+    fn window_size_of(handle: HANDLE) -> Result<WindowSize> {
+        let mut info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+        if unsafe { GetConsoleScreenBufferInfo(handle, &mut info) } == 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+
+        let window = info.srWindow;
+        Ok(WindowSize {
+            rows: (window.Bottom - window.Top + 1) as u16,
+            cols: (window.Right - window.Left + 1) as u16,
+            width: 0,
+            height: 0,
+        })
+    }
+
+    impl Tty {
+        /// Window size of this console
+        pub(crate) fn window_size(&self) -> Result<WindowSize> {
+            window_size_of(self.output.as_raw_handle() as HANDLE)
+        }
+
+        /// Windows implementation of [`Tty::exchange`], using VT input
+        pub(crate) fn exchange(
+            &mut self,
+            seq: &[u8],
+            timeout: Duration,
+            mut on_read: impl FnMut(&[u8]) -> bool,
+        ) -> Result<bool> {
+            let input = self.input.as_raw_handle() as HANDLE;
+            let Ok(guard) = RawModeGuard::from_handle(input) else {
+                return Ok(false);
+            };
+            let output = self.output.as_raw_handle() as HANDLE;
+            let output_mode = console_mode(output);
+            if let Some(mode) = output_mode {
+                unsafe { SetConsoleMode(output, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) };
+            }
+
+            let _ = self.output.write_all(seq);
+            let _ = self.output.flush();
+
+            let mut buf = [0u8; 256];
+            let deadline = std::time::Instant::now() + timeout;
+            loop {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+
+                // Wait at most 50ms at a time, like the Unix select loop
+                let wait_ms = remaining.as_millis().min(50) as u32;
+                if unsafe { WaitForSingleObject(input, wait_ms) } != WAIT_OBJECT_0 {
+                    continue;
+                }
+
+                let mut n = 0u32;
+                let ok = unsafe {
+                    ReadFile(
+                        input,
+                        buf.as_mut_ptr(),
+                        buf.len() as u32,
+                        &mut n,
+                        std::ptr::null_mut(),
+                    )
+                };
+                if ok == 0 || n == 0 || on_read(&buf[..n as usize]) {
+                    break;
+                }
+            }
+
+            // Restore original console modes
+            if let Some(mode) = output_mode {
+                unsafe { SetConsoleMode(output, mode) };
+            }
+            drop(guard);
+
+            Ok(true)
+        }
+    }
+
+    impl RawModeGuard {
+        /// Switch the stdin console to raw VT input; fails if stdin is not
+        /// a console
+        pub fn new() -> Result<Self> {
+            Self::from_handle(unsafe { GetStdHandle(STD_INPUT_HANDLE) })
+        }
+
+        /// Switch a console input handle to raw VT input
+        ///
+        /// Line input and echo are disabled and VT input enabled so terminal
+        /// replies arrive as raw bytes.
+        pub fn from_handle(handle: RawHandle) -> Result<Self> {
+            let handle = handle as HANDLE;
+            let mode =
+                console_mode(handle).ok_or_else(|| Error::terminal("handle is not a console"))?;
+            let raw_mode = (mode
+                & !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT))
+                | ENABLE_VIRTUAL_TERMINAL_INPUT;
+            if unsafe { SetConsoleMode(handle, raw_mode) } == 0 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+            Ok(Self { handle, mode })
+        }
+    }
+
+    impl Drop for RawModeGuard {
+        fn drop(&mut self) {
+            unsafe { SetConsoleMode(self.handle, self.mode) };
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
 mod other {
     use super::*;
 
-    /// Get the terminal window size (stub for non-Unix systems)
+    /// Get the terminal window size (stub for unsupported platforms)
     pub fn get_window_size() -> Result<WindowSize> {
         Err(Error::protocol(
-            "get_window_size is only supported on Unix systems",
+            "get_window_size is only supported on Unix and Windows",
         ))
     }
+
+    impl Tty {
+        /// Window sizes are not supported on this platform
+        pub(crate) fn window_size(&self) -> Result<WindowSize> {
+            get_window_size()
+        }
+
+        /// Raw mode is not supported, so nothing is ever sent
+        pub(crate) fn exchange(
+            &mut self,
+            _seq: &[u8],
+            _timeout: Duration,
+            _on_read: impl FnMut(&[u8]) -> bool,
+        ) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    impl RawModeGuard {
+        /// Raw mode is not supported on this platform
+        pub fn new() -> Result<Self> {
+            Err(Error::protocol(
+                "raw mode is only supported on Unix and Windows",
+            ))
+        }
+    }
 }
 
-#[cfg(not(unix))]
+#[cfg(not(any(unix, windows)))]
 pub use other::get_window_size;
 #[cfg(unix)]
-pub use unix::get_window_size;
+pub use unix::{get_window_size, read_until_st};
+#[cfg(windows)]
+pub use windows::get_window_size;
 
-/// Query the terminal for window size using CSI 14 t escape code
-/// This works across more terminals but requires terminal interaction
-pub fn query_window_size() -> Result<WindowSize> {
-    let mut stdout = io::stdout();
-    let mut stdin = io::stdin();
+/// What [`detect_terminal`] found out about the terminal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerminalInfo {
+    /// Terminal program name, e.g. `kitty` or `WezTerm`
+    pub name: Option<String>,
+    /// Terminal program version
+    pub version: Option<String>,
+    /// Graphics support, or `None` if the terminal has no graphics protocol
+    pub capabilities: Option<TerminalCapabilities>,
+}
 
-    // Save current terminal settings
-    #[cfg(unix)]
-    {
-        use std::os::unix::io::AsRawFd;
-        let fd = stdin.as_raw_fd();
-        let mut termios = std::mem::MaybeUninit::uninit();
-        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } == 0 {
-            let termios = unsafe { termios.assume_init() };
-            let _ = unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) };
-        }
+/// Time to wait for the terminal to answer detection queries
+const DETECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Identify the terminal emulator and the graphics features it supports
+///
+/// The terminal is first identified from `KITTY_WINDOW_ID`,
+/// `WEZTERM_EXECUTABLE`, `TERM_PROGRAM` and `TERM`, then asked for its name
+/// through XTVERSION (`CSI > 0 q`) together with a graphics query. Known
+/// terminals get their capabilities from [`TerminalCapabilities::for_terminal`];
+/// unknown ones that answer the graphics query get
+/// [`TerminalCapabilities::minimal`]. Without a controlling terminal only
+/// the environment is used.
+pub fn detect_terminal() -> Result<TerminalInfo> {
+    let (mut name, mut version) = identify_from_env();
+
+    // XTVERSION, a graphics query, then primary device attributes: every
+    // terminal answers the latter, so its reply marks the end of the others
+    let query = b"\x1b[>0q\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[c";
+    let mut reply = Vec::new();
+    let sent = exchange(query, DETECT_TIMEOUT, |bytes| {
+        reply.extend_from_slice(bytes);
+        reply
+            .windows(3)
+            .position(|w| w == b"\x1b[?")
+            .is_some_and(|start| reply[start..].contains(&b'c'))
+    })?;
+
+    let known = |name: &Option<String>, version: &Option<String>| {
+        name.as_deref()
+            .and_then(|name| TerminalCapabilities::for_terminal(name, version.as_deref()))
+    };
+    if !sent {
+        let capabilities = known(&name, &version);
+        return Ok(TerminalInfo {
+            name,
+            version,
+            capabilities,
+        });
+    }
+
+    if let Some((reported_name, reported_version)) = parse_xtversion(&reply) {
+        name = Some(reported_name);
+        version = reported_version;
     }
+    let graphics = Response::parse_all(&reply)
+        .0
+        .iter()
+        .any(|response| response.image_id == Some(PROBE_IMAGE_ID));
+    let capabilities =
+        graphics.then(|| known(&name, &version).unwrap_or(TerminalCapabilities::minimal()));
+
+    Ok(TerminalInfo {
+        name,
+        version,
+        capabilities,
+    })
+}
 
-    // Send CSI 14 t query
-    write!(stdout, "\x1b[14t")?;
-    stdout.flush()?;
+/// Terminal name and version from the environment
+fn identify_from_env() -> (Option<String>, Option<String>) {
+    let var = |key| {
+        std::env::var(key)
+            .ok()
+            .filter(|value: &String| !value.is_empty())
+    };
+    let term = var("TERM").unwrap_or_default();
 
-    // Read response: ESC [ 4 ; <height> ; <width> t
-    let mut response = Vec::new();
-    let mut buf = [0u8; 1];
+    if var("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" {
+        (Some("kitty".to_string()), None)
+    } else if var("WEZTERM_EXECUTABLE").is_some() {
+        (Some("WezTerm".to_string()), var("TERM_PROGRAM_VERSION"))
+    } else if let Some(program) = var("TERM_PROGRAM") {
+        (Some(program), var("TERM_PROGRAM_VERSION"))
+    } else if term.contains("ghostty") {
+        (Some("ghostty".to_string()), None)
+    } else {
+        (None, None)
+    }
+}
 
-    loop {
-        let n = stdin.read(&mut buf)?;
-        if n == 0 {
-            break;
+/// Parse an XTVERSION reply (`DCS > | name(version) ST` or `name version`)
+fn parse_xtversion(reply: &[u8]) -> Option<(String, Option<String>)> {
+    let start = reply.windows(4).position(|w| w == b"\x1bP>|")? + 4;
+    let len = reply[start..]
+        .windows(2)
+        .position(|w| w == crate::APC_END)?;
+    let text = core::str::from_utf8(&reply[start..start + len])
+        .ok()?
+        .trim();
+
+    let (name, version) = match text.split_once('(') {
+        Some((name, version)) => (name, Some(version.trim_end_matches(')'))),
+        None => match text.split_once(' ') {
+            Some((name, version)) => (name, Some(version)),
+            None => (text, None),
+        },
+    };
+    Some((
+        name.trim().to_string(),
+        version.map(|v| v.trim().to_string()),
+    ))
+}
+
+/// Subscribe to terminal resizes
+///
+/// The first call installs a `SIGWINCH` handler that wakes a background
+/// thread through a self-pipe; the thread reads the new size with
+/// [`get_window_size`] and sends it to every subscriber. A handler the
+/// application installed before keeps being called. Dropping the receiver
+/// unsubscribes.
+#[cfg(unix)]
+pub fn resize_events() -> Result<std::sync::mpsc::Receiver<WindowSize>> {
+    resize::subscribe()
+}
+
+/// Subscribe to terminal resizes (only supported on Unix)
+#[cfg(not(unix))]
+pub fn resize_events() -> Result<std::sync::mpsc::Receiver<WindowSize>> {
+    Err(Error::protocol("resize events are only supported on Unix"))
+}
+
+#[cfg(unix)]
+mod resize {
+    use super::*;
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+    use std::sync::{Mutex, OnceLock, mpsc};
+
+    /// Write end of the self-pipe, used by the signal handler
+    static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+    /// Handler installed before ours, called after waking the watcher
+    static PREVIOUS_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+    /// Whether the previous handler takes `siginfo_t` (`SA_SIGINFO`)
+    static PREVIOUS_SIGINFO: AtomicBool = AtomicBool::new(false);
+    /// Senders of everyone watching for resizes
+    static SUBSCRIBERS: Mutex<Vec<mpsc::Sender<WindowSize>>> = Mutex::new(Vec::new());
+    /// Outcome of installing the handler: `None` on success, else the errno
+    static INSTALLED: OnceLock<Option<i32>> = OnceLock::new();
+
+    extern "C" fn on_sigwinch(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        // Only async-signal-safe calls here: wake the watcher thread
+        let fd = PIPE_WRITE.load(Ordering::Relaxed);
+        if fd >= 0 {
+            unsafe { libc::write(fd, [1u8].as_ptr().cast(), 1) };
         }
-        response.push(buf[0]);
-        if buf[0] == b't' {
-            break;
+
+        // Keep the application's own handler (ncurses, crossterm, ...) working
+        let previous = PREVIOUS_HANDLER.load(Ordering::Relaxed);
+        if previous == libc::SIG_DFL || previous == libc::SIG_IGN {
+            return;
         }
-        if response.len() > 100 {
-            break; // Safety limit
+        if PREVIOUS_SIGINFO.load(Ordering::Relaxed) {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                unsafe { std::mem::transmute(previous) };
+            handler(signal, info, context);
+        } else {
+            let handler: extern "C" fn(libc::c_int) = unsafe { std::mem::transmute(previous) };
+            handler(signal);
         }
     }
 
-    // Parse response
-    let response_str = String::from_utf8(response).map_err(Error::from)?;
-    parse_size_response(&response_str)
-}
+    pub(super) fn subscribe() -> Result<mpsc::Receiver<WindowSize>> {
+        if let Some(errno) = *INSTALLED.get_or_init(|| install().err()) {
+            return Err(Error::Io(io::Error::from_raw_os_error(errno)));
+        }
 
-fn parse_size_response(response: &str) -> Result<WindowSize> {
-    // Expected format: ESC[4;<height>;<width>t
-    if !response.starts_with("\x1b[4;") {
-        return Err(Error::InvalidResponse(response.to_string()));
+        let (tx, rx) = mpsc::channel();
+        SUBSCRIBERS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(tx);
+        Ok(rx)
     }
 
-    let parts: Vec<&str> = response[4..].trim_end_matches('t').split(';').collect();
-    if parts.len() < 2 {
-        return Err(Error::InvalidResponse(response.to_string()));
+    /// Create the self-pipe, start the watcher thread and install the handler
+    fn install() -> std::result::Result<(), i32> {
+        let last_errno = || io::Error::last_os_error().raw_os_error().unwrap_or(0);
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(last_errno());
+        }
+        let [read_fd, write_fd] = fds;
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        // A full pipe already means a pending wake-up, so never block the handler
+        unsafe { libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK) };
+        PIPE_WRITE.store(write_fd, Ordering::Relaxed);
+
+        std::thread::Builder::new()
+            .name("kitty-resize".into())
+            .spawn(move || watch(read_fd))
+            .map_err(|e| e.raw_os_error().unwrap_or(0))?;
+
+        type Handler = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_sigwinch as Handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+        // Record the previous handler before ours can run
+        let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sigaction(libc::SIGWINCH, std::ptr::null(), &mut previous) } != 0 {
+            return Err(last_errno());
+        }
+        PREVIOUS_SIGINFO.store(previous.sa_flags & libc::SA_SIGINFO != 0, Ordering::Relaxed);
+        PREVIOUS_HANDLER.store(previous.sa_sigaction, Ordering::Relaxed);
+        if unsafe { libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut()) } != 0 {
+            return Err(last_errno());
+        }
+        Ok(())
     }
 
-    let height: u16 = parts[0]
-        .parse()
-        .map_err(|_| Error::InvalidResponse(response.to_string()))?;
-    let width: u16 = parts[1]
-        .parse()
-        .map_err(|_| Error::InvalidResponse(response.to_string()))?;
+    /// Watcher thread: forward the window size after every wake-up
+    fn watch(read_fd: RawFd) {
+        let mut buf = [0u8; 64];
+        loop {
+            let n = unsafe { libc::read(read_fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            if n <= 0 {
+                return;
+            }
+
+            let Ok(size) = get_window_size() else {
+                continue;
+            };
+            SUBSCRIBERS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|tx| tx.send(size).is_ok());
+        }
+    }
+}
 
-    // Get rows/cols using stty or default values
-    let (rows, cols) = get_terminal_size_from_stty()?;
+/// Query the terminal for window size using CSI 14 t and CSI 18 t
+///
+/// The pixel size comes from the `CSI 14 t` report and rows/cols from the
+/// `CSI 18 t` report, falling back to the OS window size for terminals that
+/// do not answer the latter. This works across more terminals but requires
+/// terminal interaction; waits at most [`DEFAULT_QUERY_TIMEOUT`].
+pub fn query_window_size() -> Result<WindowSize> {
+    query_window_size_with_timeout(DEFAULT_QUERY_TIMEOUT)
+}
+
+/// Default time to wait for the terminal to answer a size query
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Query the terminal for window size, waiting at most `timeout`
+///
+/// Fails with [`Error::Timeout`] if the terminal does not answer in time,
+/// so callers can fall back to [`get_window_size`].
+pub fn query_window_size_with_timeout(timeout: Duration) -> Result<WindowSize> {
+    Terminal::open()?.query_window_size(timeout)
+}
+
+/// Parse the `CSI 14 t` and `CSI 18 t` reports from a terminal reply
+///
+/// `fallback` provides rows and columns when there is no `CSI 18 t` report.
+fn parse_size_response(
+    response: &[u8],
+    fallback: impl FnOnce() -> Result<WindowSize>,
+) -> Result<WindowSize> {
+    let invalid = || Error::InvalidResponse(String::from_utf8_lossy(response).into_owned());
+
+    let mut pixels = None;
+    let mut cells = None;
+    for report in response.split(|&b| b == crate::ESC).skip(1) {
+        // Expected format: [<kind>;<a>;<b>t
+        let Some(params) = report.strip_prefix(b"[").and_then(|r| r.strip_suffix(b"t")) else {
+            continue;
+        };
+        let params = core::str::from_utf8(params).map_err(Error::from)?;
+        let mut parts = params.split(';').map(str::parse::<u16>);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(4)), Some(Ok(height)), Some(Ok(width))) => pixels = Some((width, height)),
+            (Some(Ok(8)), Some(Ok(rows)), Some(Ok(cols))) => cells = Some((rows, cols)),
+            _ => {}
+        }
+    }
+
+    let (width, height) = pixels.ok_or_else(invalid)?;
+    let (rows, cols) = match cells {
+        Some(cells) => cells,
+        None => {
+            let size = fallback()?;
+            (size.rows, size.cols)
+        }
+    };
 
     Ok(WindowSize {
         rows,
@@ -167,125 +954,238 @@ fn parse_size_response(response: &str) -> Result<WindowSize> {
     })
 }
 
-#[cfg(unix)]
-fn get_terminal_size_from_stty() -> Result<(u16, u16)> {
-    use std::process::Command;
-
-    let output = Command::new("stty").arg("size").output()?;
+/// Query the terminal for the size of one cell in pixels using CSI 16 t
+///
+/// Returns `(width, height)`. Fails with [`Error::Timeout`] if the terminal
+/// does not answer within `timeout`.
+pub fn query_cell_size(timeout: Duration) -> Result<(u16, u16)> {
+    Terminal::open()?.query_cell_size(timeout)
+}
 
-    if !output.status.success() {
-        return Err(Error::Io(io::Error::other("stty size failed")));
-    }
+/// Parse the `CSI 6 ; <height> ; <width> t` cell size report
+fn parse_cell_size(response: &[u8]) -> Option<(u16, u16)> {
+    response.split(|&b| b == crate::ESC).find_map(|report| {
+        let params = report.strip_prefix(b"[6;")?.strip_suffix(b"t")?;
+        let (height, width) = core::str::from_utf8(params).ok()?.split_once(';')?;
+        Some((width.parse().ok()?, height.parse().ok()?))
+    })
+}
 
-    let size_str = String::from_utf8_lossy(&output.stdout);
-    let size_owned = size_str.into_owned();
-    let parts: Vec<&str> = size_owned.split_whitespace().collect();
+/// Parse the `CSI <row> ; <column> R` cursor position report
+fn parse_cursor_position(response: &[u8]) -> Option<(u16, u16)> {
+    response.split(|&b| b == crate::ESC).find_map(|report| {
+        let report = report.strip_prefix(b"[")?;
+        let end = report.iter().position(|&b| b == b'R')?;
+        let (row, col) = core::str::from_utf8(&report[..end]).ok()?.split_once(';')?;
+        Some((row.parse().ok()?, col.parse().ok()?))
+    })
+}
 
-    if parts.len() < 2 {
-        return Err(Error::InvalidResponse(size_owned));
+/// Get the window size, filling in the pixel size when the OS reports none
+///
+/// The OS window size is used as is when it includes pixel dimensions.
+/// Otherwise the cell size is queried with `CSI 16 t` and multiplied by the
+/// rows and columns, and as a last resort the pixel size is queried with
+/// `CSI 14 t`. If the terminal answers neither, the OS size is returned
+/// with zero pixel dimensions. On Windows, where the console API never
+/// reports pixel sizes, this is how to get them.
+pub fn effective_window_size() -> Result<WindowSize> {
+    let size = get_window_size()?;
+    if size.width != 0 && size.height != 0 {
+        return Ok(size);
     }
+    match Terminal::open() {
+        Ok(mut terminal) => Ok(terminal.fill_pixel_size(size)),
+        Err(_) => Ok(size),
+    }
+}
 
-    let rows: u16 = parts[0]
-        .parse()
-        .map_err(|_| Error::InvalidResponse(size_owned.clone()))?;
-    let cols: u16 = parts[1]
-        .parse()
-        .map_err(|_| Error::InvalidResponse(size_owned))?;
-
-    Ok((rows, cols))
+/// Send an escape sequence and collect the terminal's reply
+///
+/// The terminal is switched to raw mode while waiting, and reading stops at
+/// the first string terminator (`ESC \`) or once `timeout` has elapsed.
+/// Returns `None` when there is no controlling terminal or raw mode cannot be
+/// entered, in which case nothing is sent.
+#[cfg(any(unix, windows))]
+pub(crate) fn query_terminal(seq: &[u8], timeout: Duration) -> Result<Option<Vec<u8>>> {
+    match Tty::open() {
+        Ok(tty) => Io::Tty(tty).query(seq, timeout),
+        Err(_) => Ok(None),
+    }
 }
 
-#[cfg(not(unix))]
-fn get_terminal_size_from_stty() -> Result<(u16, u16)> {
-    // Default values for non-Unix systems
-    Ok((24, 80))
+/// Handle to a terminal that owns its tty
+///
+/// All queries made through one handle share the same file descriptors
+/// (console handles on Windows) instead of reopening the terminal, and a
+/// handle can be opened on a tty other than the controlling one, or run
+/// over any [`Transport`]. A tty is switched to raw mode only while waiting
+/// for a reply, and queries on ttys are serialized across the process so
+/// threads never read each other's replies.
+///
+/// Writing to the handle writes to the terminal.
+#[derive(Debug)]
+pub struct Terminal {
+    io: Io,
 }
 
-/// Check if the terminal supports the Kitty graphics protocol
-pub fn check_protocol_support() -> Result<bool> {
+impl Terminal {
+    /// Open the controlling terminal
+    ///
+    /// This is `/dev/tty` on Unix and the console on Windows, so it works
+    /// even when stdin or stdout is redirected.
+    pub fn open() -> Result<Self> {
+        let tty = Tty::open().map_err(|_| Error::terminal("no controlling terminal"))?;
+        Ok(Self { io: Io::Tty(tty) })
+    }
+
+    /// Open the terminal device at `path`, e.g. `/dev/pts/3`
     #[cfg(unix)]
-    {
-        use std::os::unix::io::AsRawFd;
+    pub fn open_path<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let output = std::fs::File::options().read(true).write(true).open(path)?;
+        Ok(Self::from_files(output.try_clone()?, output))
+    }
 
-        let stdin = io::stdin();
-        let fd = stdin.as_raw_fd();
+    /// Use already opened input and output files of a terminal
+    pub fn from_files(input: std::fs::File, output: std::fs::File) -> Self {
+        Self {
+            io: Io::Tty(Tty { input, output }),
+        }
+    }
 
-        // Check if stdin is a TTY
-        if unsafe { libc::isatty(fd) } != 1 {
-            // Not a TTY, can't reliably check - assume supported
-            // This happens when running through cargo run or pipes
-            return Ok(true);
+    /// Run over an arbitrary transport instead of a tty
+    ///
+    /// Nothing is switched to raw mode and the window size is unknown.
+    /// Timeouts are checked between reads, so a transport whose reads block
+    /// indefinitely should have a read timeout of its own.
+    pub fn from_transport(transport: impl Transport + 'static) -> Self {
+        Self {
+            io: Io::Stream(Box::new(transport)),
         }
+    }
+
+    /// Get the window size as reported by the OS
+    pub fn window_size(&self) -> Result<WindowSize> {
+        self.io.window_size()
+    }
 
-        let mut stdout = io::stdout();
+    /// Query the window size using CSI 14 t and CSI 18 t
+    ///
+    /// See [`query_window_size_with_timeout`].
+    pub fn query_window_size(&mut self, timeout: Duration) -> Result<WindowSize> {
+        let response = self.report(b"\x1b[14t\x1b[18t\x1b[5n", timeout)?;
+        parse_size_response(&response, || self.io.window_size())
+    }
 
-        // Save original terminal settings
-        let mut original_termios: libc::termios = unsafe { std::mem::zeroed() };
-        if unsafe { libc::tcgetattr(fd, &mut original_termios) } != 0 {
-            // Can't get terminal attributes - assume supported
-            return Ok(true);
-        }
+    /// Query the size of one cell in pixels using CSI 16 t
+    ///
+    /// See [`query_cell_size`].
+    pub fn query_cell_size(&mut self, timeout: Duration) -> Result<(u16, u16)> {
+        let response = self.report(b"\x1b[16t\x1b[5n", timeout)?;
+        parse_cell_size(&response)
+            .ok_or_else(|| Error::InvalidResponse(String::from_utf8_lossy(&response).into_owned()))
+    }
 
-        // Set terminal to raw mode
-        let mut raw_termios = original_termios;
-        unsafe { libc::cfmakeraw(&mut raw_termios) };
-        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw_termios) } != 0 {
-            // Can't set raw mode - assume supported
-            return Ok(true);
+    /// Get the window size, filling in the pixel size when the OS reports none
+    ///
+    /// See [`effective_window_size`].
+    pub fn effective_window_size(&mut self) -> Result<WindowSize> {
+        let size = self.window_size()?;
+        if size.width != 0 && size.height != 0 {
+            return Ok(size);
         }
+        Ok(self.fill_pixel_size(size))
+    }
 
-        // Send query command
-        // a=q means query, i=31 is image ID, s=1,v=1 is 1x1 pixel, f=24 is RGB format
-        let _ = write!(stdout, "\x1b_Ga=q,i=31,s=1,v=1,f=24;AAAA\x1b\\");
-        let _ = stdout.flush();
+    /// Fill in the pixel size of `size` from CSI 16 t, or else CSI 14 t
+    fn fill_pixel_size(&mut self, size: WindowSize) -> WindowSize {
+        if let Ok((cell_width, cell_height)) = self.query_cell_size(DEFAULT_QUERY_TIMEOUT) {
+            return size.with_cell_size(cell_width, cell_height);
+        }
+        self.query_window_size(DEFAULT_QUERY_TIMEOUT)
+            .unwrap_or(size)
+    }
 
-        // Read response with timeout
+    /// Send CSI queries and read the reports up to the device status reply
+    ///
+    /// `seq` must end with a device status request (`CSI 5 n`): every
+    /// terminal answers it, so its reply marks the end of the reports.
+    fn report(&mut self, seq: &[u8], timeout: Duration) -> Result<Vec<u8>> {
         let mut response = Vec::new();
-        let mut buf = [0u8; 256];
-
-        let start = std::time::Instant::now();
+        let mut complete = false;
+        self.io.exchange_or_fail(seq, timeout, |bytes| {
+            response.extend_from_slice(bytes);
+            complete = response.contains(&b'n') || response.len() > 256;
+            complete
+        })?;
+        if !complete {
+            return Err(Error::Timeout);
+        }
+        Ok(response)
+    }
 
-        loop {
-            if start.elapsed() > std::time::Duration::from_millis(200) {
-                break;
-            }
+    /// Query the cursor position using CSI 6 n
+    ///
+    /// Returns the 1-based `(row, column)` reported by the terminal. Input
+    /// arriving before the report is skipped. Fails with [`Error::Timeout`]
+    /// if no report arrives within `timeout`.
+    pub fn cursor_position(&mut self, timeout: Duration) -> Result<(u16, u16)> {
+        let mut response = Vec::new();
+        let mut position = None;
+        self.io.exchange_or_fail(b"\x1b[6n", timeout, |bytes| {
+            response.extend_from_slice(bytes);
+            position = parse_cursor_position(&response);
+            position.is_some()
+        })?;
+        position.ok_or(Error::Timeout)
+    }
 
-            // Use select for timeout
-            let mut tv = libc::timeval {
-                tv_sec: 0,
-                tv_usec: 50_000, // 50ms
-            };
+    /// Query the scroll region using DECRQSS
+    ///
+    /// Fails with [`Error::Timeout`] if the terminal does not answer within
+    /// `timeout`, and with [`Error::InvalidResponse`] if it does not report
+    /// a region.
+    pub fn scroll_region(&mut self, timeout: Duration) -> Result<ScrollRegion> {
+        let response = self
+            .io
+            .query(ScrollRegion::QUERY_SEQUENCE.as_bytes(), timeout)?
+            .ok_or_else(|| Error::terminal("not a terminal"))?;
+        if response.is_empty() {
+            return Err(Error::Timeout);
+        }
+        ScrollRegion::parse_report(&response)
+            .ok_or_else(|| Error::InvalidResponse(String::from_utf8_lossy(&response).into_owned()))
+    }
 
-            // Set up fd_set for select
-            let mut read_fds: libc::fd_set = unsafe { std::mem::zeroed() };
-            unsafe { libc::FD_SET(fd, &mut read_fds) };
-
-            let ready = unsafe {
-                libc::select(
-                    fd + 1,
-                    &mut read_fds,
-                    std::ptr::null_mut(),
-                    std::ptr::null_mut(),
-                    &mut tv,
-                )
-            };
+    /// Set the scroll region, or reset it to the whole screen with `None`
+    ///
+    /// This also moves the cursor to the top left corner.
+    pub fn set_scroll_region(&mut self, region: Option<ScrollRegion>) -> Result<()> {
+        match region {
+            Some(region) => self.write_all(region.set_sequence().as_bytes())?,
+            None => self.write_all(ScrollRegion::RESET_SEQUENCE.as_bytes())?,
+        }
+        self.flush()?;
+        Ok(())
+    }
 
-            if ready > 0 {
-                let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
-                if n > 0 {
-                    response.extend_from_slice(&buf[..n as usize]);
-                    // Check if we got the complete response (ends with ESC \)
-                    if response.windows(2).any(|w| *w == [0x1b, b'\\']) {
-                        break;
-                    }
-                } else {
-                    break;
-                }
-            }
+    /// Check if the terminal supports the Kitty graphics protocol
+    ///
+    /// See [`check_protocol_support_with`].
+    pub fn check_protocol_support(&mut self, options: ProbeOptions) -> Result<bool> {
+        if let Some(supported) = options.known_support() {
+            return Ok(supported);
         }
+        self.probe(&options)?
+            .ok_or_else(|| Error::terminal("not a terminal"))
+    }
 
-        // Restore original terminal settings
-        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original_termios) };
+    /// Run the support query, returning `None` if nothing could be sent
+    fn probe(&mut self, options: &ProbeOptions) -> Result<Option<bool>> {
+        let query = options.sequence()?;
+        let Some(response) = self.io.query(&query, options.timeout)? else {
+            return Ok(None);
+        };
 
         let response_str = String::from_utf8_lossy(&response);
 
@@ -295,19 +1195,207 @@ pub fn check_protocol_support() -> Result<bool> {
         let has_ok = response_str.contains("OK");
         let has_error = response_str.contains("ENO");
 
-        Ok(has_apc && (has_ok || has_error))
+        Ok(Some(has_apc && (has_ok || has_error)))
     }
 
-    #[cfg(not(unix))]
-    {
-        // On non-Unix systems, assume supported
-        Ok(true)
+    /// Wait for the next graphics response from the terminal
+    ///
+    /// Other terminal reports are skipped. Fails with [`Error::Timeout`] if
+    /// no response arrives within `timeout`.
+    pub fn read_response(&mut self, timeout: Duration) -> Result<Response> {
+        let mut parser = crate::response::ResponseParser::new();
+        let mut reply = None;
+        self.io.exchange_or_fail(&[], timeout, |bytes| {
+            reply = parser.feed(bytes).into_iter().next();
+            reply.is_some()
+        })?;
+        reply.ok_or(Error::Timeout)
+    }
+
+    /// Send a command and wait for the terminal's reply to it
+    ///
+    /// The terminal is switched to raw mode while waiting and restored
+    /// afterwards. Replies to other commands and unrelated terminal reports
+    /// are skipped. The command needs an image ID or number, and must not
+    /// use `q=1` or `q=2`, or the terminal would not reply to success.
+    pub fn send_and_wait(
+        &mut self,
+        command: &Command,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<Response> {
+        let mut correlator = ResponseCorrelator::new();
+        if command.quiet_mode() == Some(Quiet::SuppressOk) || correlator.track(command).is_none() {
+            return Err(Error::TerminalError(
+                "command does not request a reply".to_string(),
+            ));
+        }
+
+        let mut seq = Vec::new();
+        command.serialize_chunked_into(data, &mut seq)?;
+
+        let mut parser = crate::response::ResponseParser::new();
+        let mut reply = None;
+        self.io.exchange_or_fail(&seq, timeout, |bytes| {
+            reply = parser
+                .feed(bytes)
+                .into_iter()
+                .find(|response| correlator.resolve(response).is_some());
+            reply.is_some()
+        })?;
+        reply.ok_or(Error::Timeout)
+    }
+}
+
+impl Write for Terminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+/// Image ID the support query uses by default
+const PROBE_IMAGE_ID: ImageId = match ImageId::new(31) {
+    Some(id) => id,
+    None => unreachable!(),
+};
+
+/// Options for [`check_protocol_support_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeOptions {
+    timeout: Duration,
+    image_id: ImageId,
+    trust_env: bool,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProbeOptions {
+    /// Create options with a 200ms timeout, image ID 31 and the environment
+    /// trusted
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_millis(200),
+            image_id: PROBE_IMAGE_ID,
+            trust_env: true,
+        }
+    }
+
+    /// Set how long to wait for the terminal's reply
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the image ID of the 1x1 test image
+    ///
+    /// A query never stores the image, so the ID only tags the reply.
+    pub fn image_id(mut self, id: ImageId) -> Self {
+        self.image_id = id;
+        self
+    }
+
+    /// Report support without querying when `KITTY_WINDOW_ID` is set
+    pub fn trust_env(mut self, trust: bool) -> Self {
+        self.trust_env = trust;
+        self
+    }
+
+    /// Support known without querying: an override, or `KITTY_WINDOW_ID`
+    fn known_support(&self) -> Option<bool> {
+        forced_support().or_else(|| {
+            let in_kitty = std::env::var_os("KITTY_WINDOW_ID").is_some_and(|v| !v.is_empty());
+            (self.trust_env && in_kitty).then_some(true)
+        })
+    }
+
+    /// The bytes sent to the terminal
+    fn sequence(&self) -> Result<Vec<u8>> {
+        let query = Command::builder()
+            .action(Action::Query)
+            .image_id(self.image_id)
+            .format(ImageFormat::Rgb)
+            .dimensions(1, 1)
+            .build();
+        Ok(query.serialize(&[0, 0, 0])?.into_bytes())
+    }
+}
+
+/// Environment variable forcing protocol support on (`1`, `on`, `true`,
+/// `yes`) or off (`0`, `off`, `false`, `no`)
+pub const SUPPORT_OVERRIDE_VAR: &str = "KITTY_GRAPHICS_PROTOCOL";
+
+/// Support set through [`force_support`]: 0 unset, 1 off, 2 on
+static FORCED_SUPPORT: AtomicU8 = AtomicU8::new(0);
+
+/// Make support checks report `supported` without probing the terminal
+///
+/// Takes precedence over [`SUPPORT_OVERRIDE_VAR`]. Undo with
+/// [`clear_forced_support`].
+pub fn force_support(supported: bool) {
+    FORCED_SUPPORT.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+}
+
+/// Undo [`force_support`], so support is detected again
+pub fn clear_forced_support() {
+    FORCED_SUPPORT.store(0, Ordering::Relaxed);
+}
+
+/// Support forced through [`force_support`] or [`SUPPORT_OVERRIDE_VAR`]
+pub(crate) fn forced_support() -> Option<bool> {
+    match FORCED_SUPPORT.load(Ordering::Relaxed) {
+        1 => Some(false),
+        2 => Some(true),
+        _ => std::env::var(SUPPORT_OVERRIDE_VAR)
+            .ok()
+            .and_then(|value| parse_override(&value)),
+    }
+}
+
+/// Parse the value of [`SUPPORT_OVERRIDE_VAR`]; anything else is ignored
+fn parse_override(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "on" | "true" | "yes" => Some(true),
+        "0" | "off" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Check if the terminal supports the Kitty graphics protocol
+///
+/// Uses the default [`ProbeOptions`]. [`force_support`] and the
+/// [`SUPPORT_OVERRIDE_VAR`] environment variable bypass the probe.
+pub fn check_protocol_support() -> Result<bool> {
+    check_protocol_support_with(ProbeOptions::new())
+}
+
+/// Check if the terminal supports the Kitty graphics protocol with `options`
+///
+/// A 1x1 test image is queried (a=q). The terminal checks a query without
+/// storing the image, so there is nothing to clean up afterwards.
+pub fn check_protocol_support_with(options: ProbeOptions) -> Result<bool> {
+    if let Some(supported) = options.known_support() {
+        return Ok(supported);
+    }
+    match Terminal::open() {
+        // Not a TTY, can't reliably check - assume supported
+        // This happens when running through cargo run or pipes
+        Ok(mut terminal) => Ok(terminal.probe(&options)?.unwrap_or(true)),
+        Err(_) => Ok(true),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::Recorder;
 
     #[test]
     fn test_window_size_calculation() {
@@ -339,4 +1427,173 @@ mod tests {
         assert_eq!(ws.cell_height(), 0);
         assert_eq!(ws.cells_for_image(100, 100), (0, 0));
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_and_wait_needs_reply() {
+        let mut terminal = Terminal::open_path("/dev/null").unwrap();
+        let command = Command::builder().build();
+        let result = terminal.send_and_wait(&command, &[], Duration::from_millis(10));
+        assert!(matches!(result, Err(Error::TerminalError(_))));
+
+        // /dev/null is not a terminal, so queries fail instead of hanging
+        assert!(
+            terminal
+                .query_window_size(Duration::from_millis(10))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_send_and_wait_over_transport() {
+        // Replies to other commands come first and are skipped
+        let replies = b"\x1b_Gi=3;OK\x1b\\\x1b_Gi=9;ENOENT:missing\x1b\\".to_vec();
+        let mut terminal = Terminal::from_transport(Recorder::replaying(replies));
+
+        let command = Command::builder()
+            .action(Action::Place)
+            .image_id(ImageId::new(9).unwrap())
+            .build();
+        let response = terminal
+            .send_and_wait(&command, &[], Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(response.image_id, ImageId::new(9));
+        assert!(response.is_error());
+        assert!(terminal.window_size().is_err());
+
+        // With q=1 a success would never be answered
+        let command = command.to_builder().quiet(Quiet::SuppressOk).build();
+        let result = terminal.send_and_wait(&command, &[], Duration::from_millis(100));
+        assert!(matches!(result, Err(Error::TerminalError(_))));
+    }
+
+    #[test]
+    fn test_parse_size_reports() {
+        let reply = b"\x1b[4;800;1200t\x1b[8;40;120t\x1b[0n";
+        let ws = parse_size_response(reply, get_window_size).unwrap();
+        assert_eq!(
+            ws,
+            WindowSize {
+                rows: 40,
+                cols: 120,
+                width: 1200,
+                height: 800,
+            }
+        );
+
+        assert!(matches!(
+            parse_size_response(b"\x1b[8;40;120t\x1b[0n", get_window_size),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_until_st() {
+        use std::os::unix::io::{FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+
+        // The reply arrives in two parts
+        let mut writer = std::fs::File::from(write_fd);
+        for part in [&b"\x1b_Gi=1;"[..], b"OK\x1b\\"] {
+            writer.write_all(part).unwrap();
+        }
+        let reply = read_until_st(&read_fd, Duration::from_secs(1)).unwrap();
+        assert_eq!(reply, b"\x1b_Gi=1;OK\x1b\\");
+
+        // Nothing more arrives
+        let result = read_until_st(&read_fd, Duration::from_millis(20));
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn test_support_override() {
+        assert_eq!(parse_override("1"), Some(true));
+        assert_eq!(parse_override(" OFF "), Some(false));
+        assert_eq!(parse_override("maybe"), None);
+
+        force_support(true);
+        assert!(check_protocol_support().unwrap());
+        clear_forced_support();
+    }
+
+    #[test]
+    fn test_parse_cursor_position() {
+        assert_eq!(parse_cursor_position(b"\x1b[12;40R"), Some((12, 40)));
+        // Other reports and typed input before the position are skipped
+        assert_eq!(
+            parse_cursor_position(b"x\x1b[0n\x1b_Gi=1;OK\x1b\\\x1b[3;1R"),
+            Some((3, 1))
+        );
+        assert_eq!(parse_cursor_position(b"\x1b[12;4"), None);
+        assert_eq!(parse_cursor_position(b"\x1b[;R"), None);
+    }
+
+    #[test]
+    fn test_cell_size_fallback() {
+        assert_eq!(parse_cell_size(b"\x1b[6;20;10t\x1b[0n"), Some((10, 20)));
+        assert_eq!(parse_cell_size(b"\x1b[0n"), None);
+
+        let os = WindowSize {
+            rows: 40,
+            cols: 120,
+            width: 0,
+            height: 0,
+        };
+        let ws = os.with_cell_size(10, 20);
+        assert_eq!((ws.width, ws.height), (1200, 800));
+        assert_eq!(ws.cell_width(), 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resize_events_subscribe() {
+        use std::sync::atomic::AtomicUsize;
+
+        // A handler installed by the application before subscribing
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(_: libc::c_int) {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = count as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe { libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut()) };
+
+        let first = resize_events().unwrap();
+        let second = resize_events().unwrap();
+        // The handler must survive a signal even when no size can be read,
+        // and still call the previous one
+        unsafe { libc::raise(libc::SIGWINCH) };
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        drop((first, second));
+    }
+
+    #[test]
+    fn test_parse_xtversion() {
+        let reply = b"\x1bP>|kitty(0.35.2)\x1b\\\x1b_Gi=31;OK\x1b\\\x1b[?62;c";
+        assert_eq!(
+            parse_xtversion(reply),
+            Some(("kitty".to_string(), Some("0.35.2".to_string())))
+        );
+        assert_eq!(
+            parse_xtversion(b"\x1bP>|WezTerm 20240203\x1b\\"),
+            Some(("WezTerm".to_string(), Some("20240203".to_string())))
+        );
+        assert_eq!(parse_xtversion(b"\x1b[?62;c"), None);
+    }
+
+    #[test]
+    fn test_probe_sequence_only_queries() {
+        let id = ImageId::new(77).unwrap();
+        let seq = ProbeOptions::new().image_id(id).sequence().unwrap();
+        let seq = String::from_utf8(seq).unwrap();
+        assert!(seq.starts_with("\x1b_Ga=q,"));
+        assert!(seq.contains("i=77"));
+        // The query stores nothing, and deleting the ID would hit a real image
+        assert!(!seq.contains("a=d"));
+        assert_eq!(seq.matches("\x1b_G").count(), 1);
+    }
 }
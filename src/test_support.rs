@@ -0,0 +1,65 @@
+//! Test doubles shared by the unit tests
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// In-memory terminal transport: reads come from canned replies, writes
+/// are recorded
+pub(crate) struct Recorder {
+    input: io::Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Recorder {
+    /// Record everything written into `output`, with nothing to read
+    pub(crate) fn new(output: Arc<Mutex<Vec<u8>>>) -> Self {
+        Self {
+            input: io::Cursor::new(Vec::new()),
+            output,
+        }
+    }
+
+    /// Replay `input` as the terminal's replies
+    pub(crate) fn replaying(input: impl Into<Vec<u8>>) -> Self {
+        Self {
+            input: io::Cursor::new(input.into()),
+            output: Arc::default(),
+        }
+    }
+}
+
+impl Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transport whose reads and writes fail, like a closed terminal
+pub(crate) struct Broken;
+
+impl Read for Broken {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl Write for Broken {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Displaying images larger than the terminal accepts
+//!
+//! Terminals cap the size of a single image (kitty: 10000 pixels per side)
+//! and may refuse very large uploads. [`Tiler`] splits raw pixel data into
+//! tiles whose sizes are whole multiples of the cell size, displays each as
+//! its own image and places them next to each other, so the composite looks
+//! like one image. The returned [`TiledImage`] deletes all tiles at once.
+
+use crate::error::{Error, Result};
+use crate::image::{FALLBACK_CELL, ImageDisplay, ImageHandle};
+use crate::types::{ImageFormat, ImageId};
+
+/// Largest image side kitty accepts, in pixels
+pub const MAX_TILE_SIZE: u32 = 10_000;
+
+/// One tile of a tiled image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tile {
+    /// Left edge in the source image, in pixels
+    pub x: u32,
+    /// Top edge in the source image, in pixels
+    pub y: u32,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Row offset from the image's top left cell
+    pub row: u32,
+    /// Column offset from the image's top left cell
+    pub col: u32,
+}
+
+/// Splits oversized images into tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tiler {
+    max_tile: u32,
+    origin: (u16, u16),
+}
+
+impl Default for Tiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tiler {
+    /// Create a tiler for tiles up to [`MAX_TILE_SIZE`] pixels per side
+    pub fn new() -> Self {
+        Self {
+            max_tile: MAX_TILE_SIZE,
+            origin: (1, 1),
+        }
+    }
+
+    /// Limit tiles to `pixels` per side, e.g. to keep uploads small
+    ///
+    /// Tiles are rounded down to whole cells but never smaller than one
+    /// cell, so a limit below the cell size gives one-cell tiles that are
+    /// larger than `pixels`.
+    pub fn max_tile_size(mut self, pixels: u32) -> Self {
+        self.max_tile = pixels.max(1);
+        self
+    }
+
+    /// Set the 1-based row and column of the image's top left corner
+    pub fn origin(mut self, row: u16, col: u16) -> Self {
+        self.origin = (row.max(1), col.max(1));
+        self
+    }
+
+    /// Split an image `width`x`height` pixels into tiles, row by row
+    ///
+    /// Tiles are whole multiples of the `cell` size in pixels (except at
+    /// the right and bottom edges), so they line up without gaps. At least
+    /// one cell fits in each tile, whatever the maximum tile size.
+    pub fn tiles(&self, width: u32, height: u32, cell: (u32, u32)) -> Vec<Tile> {
+        let (cell_w, cell_h) = (cell.0.max(1), cell.1.max(1));
+        let tile_w = (self.max_tile / cell_w).max(1) * cell_w;
+        let tile_h = (self.max_tile / cell_h).max(1) * cell_h;
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(tile_h as usize) {
+            for x in (0..width).step_by(tile_w as usize) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: tile_w.min(width - x),
+                    height: tile_h.min(height - y),
+                    row: y / cell_h,
+                    col: x / cell_w,
+                });
+            }
+        }
+        tiles
+    }
+
+    /// Display raw RGB or RGBA data with its top left corner at the origin
+    ///
+    /// Each tile gets an ID allocated by `display`. The cell size comes from
+    /// the display's terminal; if it is unknown, 10x20 pixels is assumed.
+    pub fn display<'a>(
+        &self,
+        display: &'a ImageDisplay,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<TiledImage<'a>> {
+        let bpp = match format {
+            ImageFormat::Rgb => 3,
+            ImageFormat::Rgba => 4,
+            ImageFormat::Png => return Err(Error::protocol("tiling needs raw RGB or RGBA data")),
+        };
+        if data.len() != width as usize * height as usize * bpp {
+            return Err(Error::InvalidDimensions { width, height });
+        }
+        let cell = match display.window_size() {
+            Ok(window) if window.cell_width() > 0 && window.cell_height() > 0 => (
+                u32::from(window.cell_width()),
+                u32::from(window.cell_height()),
+            ),
+            _ => FALLBACK_CELL,
+        };
+
+        let stride = width as usize * bpp;
+        let mut tiles = Vec::new();
+        for tile in self.tiles(width, height, cell) {
+            let mut pixels = Vec::with_capacity(tile.width as usize * tile.height as usize * bpp);
+            for y in tile.y..tile.y + tile.height {
+                let start = y as usize * stride + tile.x as usize * bpp;
+                pixels.extend_from_slice(&data[start..start + tile.width as usize * bpp]);
+            }
+            let at = |base: u16, offset: u32| (u32::from(base) + offset).min(u32::from(u16::MAX));
+            tiles.push(display.display_raw_handle_at(
+                at(self.origin.0, tile.row) as u16,
+                at(self.origin.1, tile.col) as u16,
+                &pixels,
+                tile.width,
+                tile.height,
+                format,
+            )?);
+        }
+        Ok(TiledImage { tiles })
+    }
+}
+
+/// The tiles of an image displayed by [`Tiler::display`]
+///
+/// Dropping it deletes every tile, like an [`ImageHandle`].
+#[must_use = "dropping the tiled image deletes it"]
+pub struct TiledImage<'a> {
+    tiles: Vec<ImageHandle<'a>>,
+}
+
+impl TiledImage<'_> {
+    /// IDs of the tiles, row by row
+    pub fn image_ids(&self) -> Vec<ImageId> {
+        self.tiles.iter().map(ImageHandle::image_id).collect()
+    }
+
+    /// Keep the tiles on screen after the handle is gone
+    pub fn leak(self) -> Vec<ImageId> {
+        self.tiles.into_iter().map(ImageHandle::leak).collect()
+    }
+
+    /// Delete every tile, returning the first error
+    pub fn delete(self) -> Result<()> {
+        let mut result = Ok(());
+        for tile in self.tiles {
+            let deleted = tile.delete();
+            if result.is_ok() {
+                result = deleted;
+            }
+        }
+        result
+    }
+}
+
+impl std::fmt::Debug for TiledImage<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TiledImage")
+            .field("image_ids", &self.image_ids())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Terminal;
+    use crate::test_support::Recorder;
+    use crate::types::Passthrough;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_tiles_align_to_cells() {
+        let tiles = Tiler::new().max_tile_size(25).tiles(50, 30, (10, 20));
+        let sizes: Vec<_> = tiles
+            .iter()
+            .map(|t| (t.x, t.y, t.width, t.height, t.row, t.col))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (0, 0, 20, 20, 0, 0),
+                (20, 0, 20, 20, 0, 2),
+                (40, 0, 10, 20, 0, 4),
+                (0, 20, 20, 10, 1, 0),
+                (20, 20, 20, 10, 1, 2),
+                (40, 20, 10, 10, 1, 4),
+            ]
+        );
+
+        // A limit below the cell size still gives whole cells
+        let tiles = Tiler::new().max_tile_size(5).tiles(20, 20, (10, 20));
+        assert_eq!(tiles.len(), 2);
+        assert!(tiles.iter().all(|t| (t.width, t.height) == (10, 20)));
+    }
+
+    #[test]
+    fn test_display_and_delete_tiles() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .id_allocator(Arc::new(crate::ids::IdAllocator::new()))
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let data: Vec<u8> = (0..25 * 2 * 3).map(|i| i as u8).collect();
+
+        let image = Tiler::new()
+            .max_tile_size(10)
+            .origin(3, 4)
+            .display(&display, &data, 25, 2, ImageFormat::Rgb)
+            .unwrap();
+        assert_eq!(image.image_ids().len(), 3);
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.contains("\x1b[3;4H\x1b_Ga=T,f=24,s=10,v=2,i=256,"));
+        assert!(sent.contains("\x1b[3;5H\x1b_Ga=T,f=24,s=10,v=2,i=257,"));
+        assert!(sent.contains("\x1b[3;6H\x1b_Ga=T,f=24,s=5,v=2,i=258,"));
+
+        output.lock().unwrap().clear();
+        image.delete().unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(sent.matches("a=d,").count(), 3);
+        let tiler = Tiler::new();
+        assert!(
+            tiler
+                .display(&display, &data, 5, 5, ImageFormat::Rgb)
+                .is_err()
+        );
+    }
+}
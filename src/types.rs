@@ -1,11 +1,90 @@
 //! Type definitions for the Kitty graphics protocol
 
-use std::fmt;
+use alloc::format;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::str::FromStr;
+
+/// Define a non-zero protocol identifier newtype
+macro_rules! nonzero_id {
+    ($(#[$doc:meta])* $name:ident, $err:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(transparent))]
+        pub struct $name(NonZeroU32);
+
+        impl $name {
+            /// Create an identifier, returning `None` for the reserved value 0
+            pub const fn new(id: u32) -> Option<Self> {
+                match NonZeroU32::new(id) {
+                    Some(id) => Some(Self(id)),
+                    None => None,
+                }
+            }
+
+            /// The raw identifier value
+            pub const fn get(self) -> u32 {
+                self.0.get()
+            }
+        }
+
+        impl From<NonZeroU32> for $name {
+            fn from(id: NonZeroU32) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(id: $name) -> Self {
+                id.get()
+            }
+        }
+
+        impl TryFrom<u32> for $name {
+            type Error = crate::error::Error;
+
+            fn try_from(id: u32) -> crate::error::Result<Self> {
+                Self::new(id).ok_or(crate::error::Error::$err(id))
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = crate::error::Error;
+
+            fn from_str(s: &str) -> crate::error::Result<Self> {
+                let id: u32 = s
+                    .parse()
+                    .map_err(|_| crate::error::Error::protocol(format!("invalid id: {s}")))?;
+                Self::try_from(id)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+nonzero_id!(
+    /// Image ID (`i=` key); 0 is reserved by the protocol
+    ImageId,
+    InvalidImageId
+);
+
+nonzero_id!(
+    /// Placement ID (`p=` key); 0 is reserved by the protocol
+    PlacementId,
+    InvalidPlacementId
+);
 
 /// Image format for pixel data transmission
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ImageFormat {
     /// 24-bit RGB format (3 bytes per pixel)
     Rgb = 24,
@@ -16,6 +95,68 @@ pub enum ImageFormat {
     Png = 100,
 }
 
+impl ImageFormat {
+    /// Look up a format from its `f=` value
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            24 => Some(Self::Rgb),
+            32 => Some(Self::Rgba),
+            100 => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    /// Detect the file format of encoded image data from its magic bytes
+    pub fn detect(data: &[u8]) -> DetectedFormat {
+        match data {
+            [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => DetectedFormat::Png,
+            [0xff, 0xd8, 0xff, ..] => DetectedFormat::Jpeg,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => DetectedFormat::Gif,
+            [b'B', b'M', ..] => DetectedFormat::Bmp,
+            [
+                b'R',
+                b'I',
+                b'F',
+                b'F',
+                _,
+                _,
+                _,
+                _,
+                b'W',
+                b'E',
+                b'B',
+                b'P',
+                ..,
+            ] => DetectedFormat::WebP,
+            [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => DetectedFormat::Tiff,
+            [b'q', b'o', b'i', b'f', ..] => DetectedFormat::Qoi,
+            _ => DetectedFormat::Unknown,
+        }
+    }
+}
+
+/// File format of encoded image data, from [`ImageFormat::detect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DetectedFormat {
+    /// PNG, which the terminal decodes itself
+    Png,
+    /// JPEG
+    Jpeg,
+    /// GIF, possibly animated
+    Gif,
+    /// Windows bitmap
+    Bmp,
+    /// WebP, possibly animated
+    WebP,
+    /// TIFF, in either byte order
+    Tiff,
+    /// Quite OK Image format
+    Qoi,
+    /// Not a recognized image format
+    Unknown,
+}
+
 impl fmt::Display for ImageFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", *self as u8)
@@ -24,6 +165,7 @@ impl fmt::Display for ImageFormat {
 
 /// Transmission medium for image data
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TransmissionMedium {
     /// Direct transmission within the escape code itself (default)
     #[default]
@@ -36,6 +178,19 @@ pub enum TransmissionMedium {
     SharedMemory,
 }
 
+impl TransmissionMedium {
+    /// Look up a medium from its `t=` value
+    pub fn from_code(code: char) -> Option<Self> {
+        match code {
+            'd' => Some(Self::Direct),
+            'f' => Some(Self::File),
+            't' => Some(Self::TempFile),
+            's' => Some(Self::SharedMemory),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for TransmissionMedium {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let c = match self {
@@ -50,6 +205,7 @@ impl fmt::Display for TransmissionMedium {
 
 /// Action to perform with the graphics command
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Action {
     /// Query support and transmission medium availability
     Query,
@@ -70,6 +226,23 @@ pub enum Action {
     ComposeFrame,
 }
 
+impl Action {
+    /// Look up an action from its `a=` value
+    pub fn from_code(code: char) -> Option<Self> {
+        match code {
+            'q' => Some(Self::Query),
+            't' => Some(Self::Transmit),
+            'T' => Some(Self::TransmitAndDisplay),
+            'p' => Some(Self::Place),
+            'd' => Some(Self::Delete),
+            'f' => Some(Self::Frame),
+            'a' => Some(Self::AnimationControl),
+            'c' => Some(Self::ComposeFrame),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -88,6 +261,7 @@ impl fmt::Display for Action {
 
 /// Delete target specification
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DeleteTarget {
     /// Delete all visible placements (a/A)
     All,
@@ -101,18 +275,23 @@ pub enum DeleteTarget {
     AtCursor { free_data: bool },
     /// Delete animation frames (f/F)
     Frames { free_data: bool },
-    /// Delete at specific cell (p/P)
-    AtCell { free_data: bool },
+    /// Delete at specific cell (p/P), 1-based column `x` and row `y`
+    AtCell { x: u32, y: u32, free_data: bool },
     /// Delete at cell with z-index (q/Q)
-    AtCellWithZIndex { free_data: bool },
-    /// Delete by ID range (r/R)
-    ByIdRange { free_data: bool },
-    /// Delete by column (x/X)
-    ByColumn { free_data: bool },
-    /// Delete by row (y/Y)
-    ByRow { free_data: bool },
+    AtCellWithZIndex {
+        x: u32,
+        y: u32,
+        z_index: i32,
+        free_data: bool,
+    },
+    /// Delete images with IDs in the inclusive range `from..=to` (r/R)
+    ByIdRange { from: u32, to: u32, free_data: bool },
+    /// Delete by 1-based column (x/X)
+    ByColumn { column: u32, free_data: bool },
+    /// Delete by 1-based row (y/Y)
+    ByRow { row: u32, free_data: bool },
     /// Delete by z-index (z/Z)
-    ByZIndex { free_data: bool },
+    ByZIndex { z_index: i32, free_data: bool },
 }
 
 impl DeleteTarget {
@@ -129,24 +308,101 @@ impl DeleteTarget {
             Self::AtCursor { free_data: true } => 'C',
             Self::Frames { free_data: false } => 'f',
             Self::Frames { free_data: true } => 'F',
-            Self::AtCell { free_data: false } => 'p',
-            Self::AtCell { free_data: true } => 'P',
-            Self::AtCellWithZIndex { free_data: false } => 'q',
-            Self::AtCellWithZIndex { free_data: true } => 'Q',
-            Self::ByIdRange { free_data: false } => 'r',
-            Self::ByIdRange { free_data: true } => 'R',
-            Self::ByColumn { free_data: false } => 'x',
-            Self::ByColumn { free_data: true } => 'X',
-            Self::ByRow { free_data: false } => 'y',
-            Self::ByRow { free_data: true } => 'Y',
-            Self::ByZIndex { free_data: false } => 'z',
-            Self::ByZIndex { free_data: true } => 'Z',
+            Self::AtCell {
+                free_data: false, ..
+            } => 'p',
+            Self::AtCell {
+                free_data: true, ..
+            } => 'P',
+            Self::AtCellWithZIndex {
+                free_data: false, ..
+            } => 'q',
+            Self::AtCellWithZIndex {
+                free_data: true, ..
+            } => 'Q',
+            Self::ByIdRange {
+                free_data: false, ..
+            } => 'r',
+            Self::ByIdRange {
+                free_data: true, ..
+            } => 'R',
+            Self::ByColumn {
+                free_data: false, ..
+            } => 'x',
+            Self::ByColumn {
+                free_data: true, ..
+            } => 'X',
+            Self::ByRow {
+                free_data: false, ..
+            } => 'y',
+            Self::ByRow {
+                free_data: true, ..
+            } => 'Y',
+            Self::ByZIndex {
+                free_data: false, ..
+            } => 'z',
+            Self::ByZIndex {
+                free_data: true, ..
+            } => 'Z',
+        }
+    }
+
+    /// Reconstruct a delete target from its `d=` code and the accompanying
+    /// `x=`, `y=` and `z=` values
+    ///
+    /// Missing coordinates default to zero.
+    pub fn from_code(code: char, x: Option<u32>, y: Option<u32>, z: Option<i32>) -> Option<Self> {
+        let free_data = code.is_ascii_uppercase();
+        let (x, y, z_index) = (x.unwrap_or(0), y.unwrap_or(0), z.unwrap_or(0));
+        let target = match code.to_ascii_lowercase() {
+            'a' if free_data => Self::AllWithFree,
+            'a' => Self::All,
+            'i' => Self::ById { free_data },
+            'n' => Self::ByNumber { free_data },
+            'c' => Self::AtCursor { free_data },
+            'f' => Self::Frames { free_data },
+            'p' => Self::AtCell { x, y, free_data },
+            'q' => Self::AtCellWithZIndex {
+                x,
+                y,
+                z_index,
+                free_data,
+            },
+            'r' => Self::ByIdRange {
+                from: x,
+                to: y,
+                free_data,
+            },
+            'x' => Self::ByColumn {
+                column: x,
+                free_data,
+            },
+            'y' => Self::ByRow { row: y, free_data },
+            'z' => Self::ByZIndex { z_index, free_data },
+            _ => return None,
+        };
+        Some(target)
+    }
+
+    /// Get the coordinate keys that accompany this delete target
+    ///
+    /// Returns `(x, y, z)` values for the `x=`, `y=` and `z=` keys.
+    pub fn params(&self) -> (Option<u32>, Option<u32>, Option<i32>) {
+        match *self {
+            Self::AtCell { x, y, .. } => (Some(x), Some(y), None),
+            Self::AtCellWithZIndex { x, y, z_index, .. } => (Some(x), Some(y), Some(z_index)),
+            Self::ByIdRange { from, to, .. } => (Some(from), Some(to), None),
+            Self::ByColumn { column, .. } => (Some(column), None, None),
+            Self::ByRow { row, .. } => (None, Some(row), None),
+            Self::ByZIndex { z_index, .. } => (None, None, Some(z_index)),
+            _ => (None, None, None),
         }
     }
 }
 
 /// Animation control commands
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AnimationControl {
     /// Stop the animation
     Stop,
@@ -156,6 +412,18 @@ pub enum AnimationControl {
     Run,
 }
 
+impl AnimationControl {
+    /// Look up an animation state from its `s=` value
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(Self::Stop),
+            2 => Some(Self::Loading),
+            3 => Some(Self::Run),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for AnimationControl {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let n = match self {
@@ -169,6 +437,7 @@ impl fmt::Display for AnimationControl {
 
 /// Composition mode for frame operations
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CompositionMode {
     /// Alpha blend (default)
     #[default]
@@ -177,6 +446,17 @@ pub enum CompositionMode {
     Replace,
 }
 
+impl CompositionMode {
+    /// Look up a composition mode from its numeric value
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::AlphaBlend),
+            1 => Some(Self::Replace),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for CompositionMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let n = match self {
@@ -189,6 +469,7 @@ impl fmt::Display for CompositionMode {
 
 /// Frame composition parameters for a=c action
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FrameComposition {
     /// Source frame number (1-based)
     pub source_frame: u32,
@@ -228,6 +509,7 @@ impl Default for FrameComposition {
 
 /// Unicode placeholder configuration for virtual placements
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnicodePlaceholder {
     /// Number of columns for the placeholder
     pub columns: u16,
@@ -237,11 +519,22 @@ pub struct UnicodePlaceholder {
 
 /// Compression algorithm
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Compression {
     /// ZLIB deflate compression (RFC 1950)
     Zlib,
 }
 
+impl Compression {
+    /// Look up a compression algorithm from its `o=` value
+    pub fn from_code(code: char) -> Option<Self> {
+        match code {
+            'z' => Some(Self::Zlib),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for Compression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -253,6 +546,7 @@ impl fmt::Display for Compression {
 
 /// Cursor movement policy after placing an image
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CursorPolicy {
     /// Default: move cursor right by columns and down by rows
     #[default]
@@ -261,6 +555,17 @@ pub enum CursorPolicy {
     NoMove,
 }
 
+impl CursorPolicy {
+    /// Look up a cursor policy from its `C=` value
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Default),
+            1 => Some(Self::NoMove),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for CursorPolicy {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let n = match self {
@@ -270,3 +575,139 @@ impl fmt::Display for CursorPolicy {
         write!(f, "{n}")
     }
 }
+
+/// Wrapping of escape sequences for terminal multiplexers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Passthrough {
+    /// Send sequences as they are
+    #[default]
+    None,
+    /// Wrap each sequence in a tmux passthrough (`ESC Ptmux; ... ESC \`)
+    /// with its escapes doubled; tmux needs `allow-passthrough on`
+    Tmux,
+}
+
+impl Passthrough {
+    /// Detect tmux from the `TMUX` environment variable
+    #[cfg(feature = "std")]
+    pub fn detect() -> Self {
+        if std::env::var_os("TMUX").is_some() {
+            Self::Tmux
+        } else {
+            Self::None
+        }
+    }
+
+    /// Bytes opening a graphics sequence
+    pub(crate) fn apc_start(self) -> &'static [u8] {
+        match self {
+            Self::None => crate::APC_START,
+            Self::Tmux => b"\x1bPtmux;\x1b\x1b_",
+        }
+    }
+
+    /// Bytes closing a graphics sequence
+    pub(crate) fn apc_end(self) -> &'static [u8] {
+        match self {
+            Self::None => crate::APC_END,
+            Self::Tmux => b"\x1b\x1b\\\x1b\\",
+        }
+    }
+}
+
+/// Response verbosity requested from the terminal (`q=` key)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Quiet {
+    /// Report both successes and errors
+    #[default]
+    Verbose,
+    /// Suppress `OK` responses, still report errors
+    SuppressOk,
+    /// Suppress all responses
+    SuppressAll,
+}
+
+impl Quiet {
+    /// Look up a quiet mode from its `q=` value
+    ///
+    /// This is the checked conversion: values above 2 give `None`, while
+    /// the `From<u8>` conversion clamps them.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Verbose),
+            1 => Some(Self::SuppressOk),
+            2 => Some(Self::SuppressAll),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a raw `q=` value, clamping anything above 2 to
+/// [`Quiet::SuppressAll`]
+///
+/// Out-of-range values are not reported; use [`Quiet::from_code`] to reject
+/// them instead.
+impl From<u8> for Quiet {
+    fn from(code: u8) -> Self {
+        Self::from_code(code).unwrap_or(Self::SuppressAll)
+    }
+}
+
+impl fmt::Display for Quiet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = match self {
+            Self::Verbose => 0,
+            Self::SuppressOk => 1,
+            Self::SuppressAll => 2,
+        };
+        write!(f, "{n}")
+    }
+}
+
+/// Protocol feature level a terminal implements, in the order kitty added them
+///
+/// Used with [`CommandBuilder::strict`](crate::command::CommandBuilder::strict)
+/// to reject commands an older or partially compatible terminal would not
+/// understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProtocolVersion {
+    /// Transmission, placement, deletion and queries
+    Base,
+    /// Animation frames and image numbers (kitty 0.20)
+    Animation,
+    /// Virtual placements through Unicode placeholders (kitty 0.28)
+    UnicodePlaceholders,
+    /// Placements relative to a parent placement (kitty 0.31)
+    RelativePlacements,
+}
+
+impl ProtocolVersion {
+    /// The newest feature level this crate knows about
+    pub const LATEST: Self = Self::RelativePlacements;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        let cases: [(&[u8], DetectedFormat); 9] = [
+            (b"\x89PNG\r\n\x1a\n\0\0", DetectedFormat::Png),
+            (b"\xff\xd8\xff\xe0", DetectedFormat::Jpeg),
+            (b"GIF89a\x01\0", DetectedFormat::Gif),
+            (b"BM\x36\0", DetectedFormat::Bmp),
+            (b"RIFF\0\0\0\0WEBPVP8 ", DetectedFormat::WebP),
+            (b"RIFF\0\0\0\0WAVE", DetectedFormat::Unknown),
+            (b"MM\0\x2a\0\0\0\x08", DetectedFormat::Tiff),
+            (b"qoif\0\0\0\x01", DetectedFormat::Qoi),
+            (b"\x89PN", DetectedFormat::Unknown),
+        ];
+        for (data, format) in cases {
+            assert_eq!(ImageFormat::detect(data), format, "{data:?}");
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Streaming video playback (requires the `video` feature)
+//!
+//! [`VideoStream`] feeds decoded frames, e.g. from ffmpeg bindings or a
+//! camera, into an animation running in loading mode (`s=2`): the terminal
+//! shows each frame as it arrives and waits at the last one for more.
+//! Frames are paced against the target frame rate. When the terminal takes
+//! longer than a frame interval to accept a frame, the time it fell behind
+//! is made up by dropping frames instead of delaying every later one.
+
+use crate::command::{Command, CommandBuilder};
+use crate::error::{Error, Result};
+use crate::types::{
+    Action, AnimationControl, CompositionMode, ImageFormat, ImageId, Passthrough, Quiet,
+};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Frames sent and dropped by a [`VideoStream`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct VideoStats {
+    /// Frames sent to the terminal
+    pub sent: u64,
+    /// Frames dropped because they were late
+    pub dropped: u64,
+}
+
+/// Sends a stream of frames to the terminal as a loading animation
+///
+/// ```no_run
+/// # fn main() -> kitty_graphics_protocol::Result<()> {
+/// use kitty_graphics_protocol::{ImageId, Terminal, VideoStream};
+///
+/// let frames = (0..=255u8).map(|v| vec![v; 320 * 240 * 3]);
+/// let mut video = VideoStream::new(Terminal::open()?, ImageId::new(7).unwrap(), 320, 240)
+///     .frame_rate(30.0);
+/// let stats = video.play(frames)?;
+/// eprintln!("dropped {} frames", stats.dropped);
+/// # Ok(())
+/// # }
+/// ```
+pub struct VideoStream<W: Write> {
+    out: W,
+    image_id: ImageId,
+    width: u32,
+    height: u32,
+    format: ImageFormat,
+    interval: Duration,
+    quiet: Quiet,
+    passthrough: Passthrough,
+    max_frames: Option<u32>,
+    /// Frames stored in the terminal since the image was last transmitted
+    stored: u32,
+    /// When the next frame is due
+    next_due: Option<Instant>,
+    /// Time the terminal fell behind the frame rate
+    debt: Duration,
+    /// Whether the animation is in loading mode
+    loading: bool,
+    stats: VideoStats,
+}
+
+impl<W: Write> VideoStream<W> {
+    /// Create a stream of `width`x`height` RGB frames at 30 frames per
+    /// second, shown at the cursor of `out`
+    pub fn new(out: W, image_id: ImageId, width: u32, height: u32) -> Self {
+        Self {
+            out,
+            image_id,
+            width,
+            height,
+            format: ImageFormat::Rgb,
+            interval: Duration::from_secs(1) / 30,
+            quiet: Quiet::SuppressAll,
+            passthrough: Passthrough::None,
+            max_frames: None,
+            stored: 0,
+            next_due: None,
+            debt: Duration::ZERO,
+            loading: false,
+            stats: VideoStats::default(),
+        }
+    }
+
+    /// Set the target frame rate
+    ///
+    /// Non-positive rates and rates too low for a [`Duration`] interval are
+    /// ignored.
+    pub fn frame_rate(mut self, fps: f64) -> Self {
+        if let Ok(interval) = Duration::try_from_secs_f64(1.0 / fps) {
+            self.interval = interval;
+        }
+        self
+    }
+
+    /// Set the format of the frames, RGB by default
+    pub fn format(mut self, format: ImageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set quiet mode (suppress all replies by default)
+    pub fn quiet(mut self, mode: impl Into<Quiet>) -> Self {
+        self.quiet = mode.into();
+        self
+    }
+
+    /// Wrap emitted sequences for a terminal multiplexer
+    pub fn passthrough(mut self, mode: Passthrough) -> Self {
+        self.passthrough = mode;
+        self
+    }
+
+    /// Re-transmit the image after `frames` frames
+    ///
+    /// The terminal keeps every frame of an animation, so long streams
+    /// should bound how many pile up. Re-transmitting under the same ID
+    /// discards them and keeps the placements.
+    pub fn max_frames(mut self, frames: u32) -> Self {
+        self.max_frames = (frames > 0).then_some(frames);
+        self
+    }
+
+    /// Frames sent and dropped so far
+    pub fn stats(&self) -> VideoStats {
+        self.stats
+    }
+
+    /// Send a frame when it is due, or drop it if it is late
+    ///
+    /// Blocks until one frame interval after the previous frame was sent.
+    /// Frames are dropped while writing frames has fallen behind the frame
+    /// rate by an interval or more. Returns whether the frame was sent.
+    pub fn push(&mut self, frame: &[u8]) -> Result<bool> {
+        let bpp = match self.format {
+            ImageFormat::Rgb => 3,
+            ImageFormat::Rgba => 4,
+            ImageFormat::Png => 0,
+        };
+        if bpp != 0 && frame.len() != self.width as usize * self.height as usize * bpp {
+            return Err(Error::InvalidDimensions {
+                width: self.width,
+                height: self.height,
+            });
+        }
+
+        if self.debt >= self.interval {
+            self.debt -= self.interval;
+            self.stats.dropped += 1;
+            return Ok(false);
+        }
+        let now = Instant::now();
+        // A frame arriving late starts a new schedule instead of rushing
+        // the following ones
+        let due = self.next_due.map_or(now, |due| due.max(now));
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        self.next_due = Some(due + self.interval);
+
+        let started = Instant::now();
+
+        if self.stats.sent == 0 || self.max_frames == Some(self.stored) {
+            self.send_root(frame)?;
+        } else {
+            if !self.loading {
+                self.write_control(self.loading_mode())?;
+                self.loading = true;
+            }
+            let builder = self
+                .data_builder(Action::Frame)
+                .composition_mode(CompositionMode::Replace)
+                .frame_gap(self.gap());
+            self.command(builder)
+                .serialize_chunked_into(frame, &mut self.out)?;
+            self.stored += 1;
+        }
+        self.out.flush()?;
+        self.debt += started.elapsed().saturating_sub(self.interval);
+        self.stats.sent += 1;
+        Ok(true)
+    }
+
+    /// Send every frame of `frames`, then [`finish`](Self::finish)
+    pub fn play<I>(&mut self, frames: I) -> Result<VideoStats>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        for frame in frames {
+            self.push(frame.as_ref())?;
+        }
+        self.finish()?;
+        Ok(self.stats)
+    }
+
+    /// Stop the animation on the last frame sent
+    ///
+    /// A later [`push`](Self::push) resumes loading mode.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.stats.sent > 0 {
+            let stop = Command::builder()
+                .action(Action::AnimationControl)
+                .image_id(self.image_id)
+                .animation_control(AnimationControl::Stop);
+            self.write_control(stop)?;
+            self.out.flush()?;
+        }
+        self.next_due = None;
+        self.debt = Duration::ZERO;
+        self.loading = false;
+        Ok(())
+    }
+
+    /// The writer frames are sent to
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Transmit `frame` as the root frame and enter loading mode
+    ///
+    /// The first root frame is displayed at the cursor; later ones replace
+    /// the image data of the existing placement.
+    fn send_root(&mut self, frame: &[u8]) -> Result<()> {
+        let action = if self.stats.sent == 0 {
+            Action::TransmitAndDisplay
+        } else {
+            Action::Transmit
+        };
+        let root = self.command(self.data_builder(action));
+        root.serialize_chunked_into(frame, &mut self.out)?;
+        let gap = Command::builder()
+            .action(Action::AnimationControl)
+            .image_id(self.image_id)
+            .edit_frame(1)
+            .frame_gap(self.gap());
+        self.write_control(gap)?;
+        self.write_control(self.loading_mode())?;
+        self.loading = true;
+        self.stored = 1;
+        Ok(())
+    }
+
+    /// Command switching the animation to loading mode
+    fn loading_mode(&self) -> CommandBuilder {
+        Command::builder()
+            .action(Action::AnimationControl)
+            .image_id(self.image_id)
+            .animation_control(AnimationControl::Loading)
+    }
+
+    /// Command builder for sending a frame's data
+    fn data_builder(&self, action: Action) -> CommandBuilder {
+        let builder = Command::builder()
+            .action(action)
+            .image_id(self.image_id)
+            .format(self.format);
+        match self.format {
+            ImageFormat::Png => builder,
+            _ => builder.dimensions(self.width, self.height),
+        }
+    }
+
+    /// Frame gap in milliseconds
+    fn gap(&self) -> i32 {
+        self.interval.as_millis().clamp(1, i32::MAX as u128) as i32
+    }
+
+    /// Apply the quiet and passthrough modes to a command
+    fn command(&self, builder: CommandBuilder) -> Command {
+        builder
+            .quiet(self.quiet)
+            .passthrough(self.passthrough)
+            .build()
+    }
+
+    /// Write a payload-less command
+    fn write_control(&mut self, builder: CommandBuilder) -> Result<()> {
+        let seq = self.command(builder).serialize_control_bytes()?;
+        self.out.write_all(&seq)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream() -> VideoStream<Vec<u8>> {
+        VideoStream::new(Vec::new(), ImageId::new(5).unwrap(), 1, 1).frame_rate(1000.0)
+    }
+
+    #[test]
+    fn test_unusable_frame_rates_ignored() {
+        for fps in [0.0, -1.0, f64::NAN, 1e-30] {
+            assert_eq!(stream().frame_rate(fps).interval, Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_frames_sent_in_loading_mode() {
+        let mut video = stream().max_frames(2);
+        let stats = video.play([[0u8; 3], [1; 3], [2; 3]]).unwrap();
+        let sent = String::from_utf8(video.into_inner()).unwrap();
+        assert_eq!(
+            stats,
+            VideoStats {
+                sent: 3,
+                dropped: 0
+            }
+        );
+        assert_eq!(
+            sent,
+            "\x1b_Ga=T,f=24,s=1,v=1,i=5,q=2,m=0;AAAA\x1b\\\
+             \x1b_Ga=a,i=5,q=2,r=1,z=1\x1b\\\x1b_Ga=a,i=5,q=2,s=2\x1b\\\
+             \x1b_Ga=f,f=24,s=1,v=1,i=5,q=2,z=1,X=1,m=0;AQEB\x1b\\\
+             \x1b_Ga=t,f=24,s=1,v=1,i=5,q=2,m=0;AgIC\x1b\\\
+             \x1b_Ga=a,i=5,q=2,r=1,z=1\x1b\\\x1b_Ga=a,i=5,q=2,s=2\x1b\\\
+             \x1b_Ga=a,i=5,q=2,s=1\x1b\\"
+        );
+    }
+
+    /// Terminal taking 10ms to accept each frame
+    struct Slow;
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            std::thread::sleep(Duration::from_millis(10));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_frames_dropped_under_backpressure() {
+        let mut video = VideoStream::new(Slow, ImageId::new(5).unwrap(), 1, 1).frame_rate(1000.0);
+        assert!(video.push(&[0; 3]).unwrap());
+        assert!(!video.push(&[1; 3]).unwrap());
+        assert_eq!(video.stats().dropped, 1);
+        assert!(video.push(&[0; 2]).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Zooming and panning large images
+//!
+//! [`Viewer`] transmits an image once and shows a part of it by placing it
+//! with a source rectangle (`x`, `y`, `w`, `h`). Zooming and panning only
+//! place the image again under the same placement ID, which replaces the
+//! shown part without sending any pixels.
+
+use crate::command::Command;
+use crate::error::{Error, Result};
+use crate::image::{DisplayOptions, Fit, ImageDisplay, ImageHandle};
+use crate::types::{ImageFormat, ImageId, PlacementId};
+
+/// Placement ID the view is shown under
+const VIEW_PLACEMENT: u32 = 1;
+
+/// Interactive view into one transmitted image
+///
+/// The zoom level is 1 when the whole image is visible and grows until a
+/// single pixel fills the view. Dropping the viewer deletes the image.
+///
+/// ```no_run
+/// # fn main() -> kitty_graphics_protocol::Result<()> {
+/// use kitty_graphics_protocol::{ImageDisplay, ImageFormat, Viewer};
+///
+/// let display = ImageDisplay::new();
+/// let pixels = vec![0; 4000 * 3000 * 4];
+/// let mut viewer = Viewer::new(&display, &pixels, 4000, 3000, ImageFormat::Rgba)?;
+/// viewer.show()?;
+/// viewer.zoom(2.0)?;
+/// viewer.pan(0.25, 0.0)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Viewer<'a> {
+    display: &'a ImageDisplay,
+    image: ImageHandle<'a>,
+    width: u32,
+    height: u32,
+    origin: (u16, u16),
+    area: Option<(u32, u32)>,
+    zoom: f64,
+    /// Center of the view in image pixels
+    center: (f64, f64),
+}
+
+impl<'a> Viewer<'a> {
+    /// Transmit an image `width`x`height` pixels without showing it yet
+    ///
+    /// The image gets an ID allocated by `display`. PNG data must match the
+    /// given size, which source rectangles are computed from.
+    pub fn new(
+        display: &'a ImageDisplay,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<Self> {
+        let bpp = match format {
+            ImageFormat::Rgb => 3,
+            ImageFormat::Rgba => 4,
+            ImageFormat::Png => 0,
+        };
+        if width == 0
+            || height == 0
+            || (bpp != 0 && data.len() != width as usize * height as usize * bpp)
+        {
+            return Err(Error::InvalidDimensions { width, height });
+        }
+        let image = display.transmit_handle(data, width, height, format)?;
+        Ok(Self {
+            display,
+            image,
+            width,
+            height,
+            origin: (1, 1),
+            area: None,
+            zoom: 1.0,
+            center: (f64::from(width) / 2.0, f64::from(height) / 2.0),
+        })
+    }
+
+    /// Set the 1-based row and column of the view's top left corner
+    pub fn origin(mut self, row: u16, col: u16) -> Self {
+        self.origin = (row.max(1), col.max(1));
+        self
+    }
+
+    /// Limit the view to `cols`x`rows` cells, the whole window by default
+    pub fn area(mut self, cols: u32, rows: u32) -> Self {
+        self.area = Some((cols.max(1), rows.max(1)));
+        self
+    }
+
+    /// ID of the transmitted image
+    pub fn image_id(&self) -> ImageId {
+        self.image.image_id()
+    }
+
+    /// Current zoom level
+    pub fn zoom_level(&self) -> f64 {
+        self.zoom
+    }
+
+    /// Part of the image in view as `(x, y, width, height)`
+    pub fn view(&self) -> (u32, u32, u32, u32) {
+        let w = self.view_size(self.width);
+        let h = self.view_size(self.height);
+        let edge = |center: f64, len: u32, max: u32| {
+            (center - f64::from(len) / 2.0)
+                .round()
+                .clamp(0.0, f64::from(max - len)) as u32
+        };
+        (
+            edge(self.center.0, w, self.width),
+            edge(self.center.1, h, self.height),
+            w,
+            h,
+        )
+    }
+
+    /// Place the part of the image in view
+    pub fn show(&self) -> Result<()> {
+        let mut options = DisplayOptions::new().fit(Fit::Contain);
+        if let Some((cols, rows)) = self.area {
+            options = options.max_cols(cols).max_rows(rows);
+        }
+        let placement_id =
+            PlacementId::new(VIEW_PLACEMENT).ok_or(Error::InvalidPlacementId(VIEW_PLACEMENT))?;
+        let builder = Command::builder()
+            .image_id(self.image.image_id())
+            .placement_id(placement_id);
+        let builder = self.display.crop(builder, self.view(), &options)?;
+        self.display.place_at(self.origin.0, self.origin.1, builder)
+    }
+
+    /// Multiply the zoom level by `factor` around the center of the view
+    pub fn zoom(&mut self, factor: f64) -> Result<()> {
+        if factor > 0.0 {
+            self.set_zoom(self.zoom * factor);
+        }
+        self.show()
+    }
+
+    /// Move the view by `dx` and `dy` times its width and height
+    ///
+    /// Positive values move right and down. The view stops at the edges
+    /// of the image.
+    pub fn pan(&mut self, dx: f64, dy: f64) -> Result<()> {
+        let (_, _, w, h) = self.view();
+        self.center.0 += dx * f64::from(w);
+        self.center.1 += dy * f64::from(h);
+        self.clamp_center();
+        self.show()
+    }
+
+    /// Show the whole image again
+    pub fn reset(&mut self) -> Result<()> {
+        self.set_zoom(1.0);
+        self.show()
+    }
+
+    /// Delete the image, reporting errors
+    pub fn close(self) -> Result<()> {
+        self.image.delete()
+    }
+
+    /// Set the zoom level, keeping at least a pixel in view
+    fn set_zoom(&mut self, zoom: f64) {
+        let max = f64::from(self.width.min(self.height));
+        self.zoom = zoom.clamp(1.0, max);
+        self.clamp_center();
+    }
+
+    /// Keep the view inside the image
+    fn clamp_center(&mut self) {
+        let clamp = |center: f64, len: u32, max: u32| {
+            let half = f64::from(len) / 2.0;
+            center.clamp(half, f64::from(max) - half)
+        };
+        self.center = (
+            clamp(self.center.0, self.view_size(self.width), self.width),
+            clamp(self.center.1, self.view_size(self.height), self.height),
+        );
+    }
+
+    /// Length in pixels of a side `len` pixels long at the current zoom
+    fn view_size(&self, len: u32) -> u32 {
+        ((f64::from(len) / self.zoom).round() as u32).clamp(1, len)
+    }
+}
+
+impl std::fmt::Debug for Viewer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Viewer")
+            .field("image_id", &self.image_id())
+            .field("zoom", &self.zoom)
+            .field("view", &self.view())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Terminal;
+    use crate::test_support::Recorder;
+    use crate::types::Passthrough;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_zoom_and_pan_views() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let display = ImageDisplay::new()
+            .passthrough(Passthrough::None)
+            .id_allocator(Arc::new(crate::ids::IdAllocator::new()))
+            .terminal(Terminal::from_transport(Recorder::new(output.clone())));
+        let data = vec![0; 100 * 80 * 3];
+        let mut viewer = Viewer::new(&display, &data, 100, 80, ImageFormat::Rgb).unwrap();
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("\x1b_Ga=t,f=24,s=100,v=80,i=256,"));
+        assert_eq!(viewer.view(), (0, 0, 100, 80));
+
+        output.lock().unwrap().clear();
+        viewer.zoom(4.0).unwrap();
+        assert_eq!(viewer.view(), (38, 30, 25, 20));
+        viewer.pan(10.0, -0.5).unwrap();
+        assert_eq!(viewer.view(), (75, 20, 25, 20));
+        viewer.zoom(0.5).unwrap();
+        assert_eq!(viewer.view(), (50, 10, 50, 40));
+        viewer.reset().unwrap();
+        assert_eq!(viewer.zoom_level(), 1.0);
+
+        let sent = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(!sent.contains("a=t"));
+        assert_eq!(sent.matches("a=p,i=256,p=1,").count(), 4);
+        assert!(sent.starts_with("\x1b7\x1b[1;1H\x1b_Ga=p,i=256,p=1,q=2,x=38,y=30,w=25,h=20,C=1"));
+    }
+}